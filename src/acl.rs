@@ -0,0 +1,139 @@
+use errors::Error;
+use failure::Fallible;
+
+/// The xattr holding a file's access ACL
+pub const ACCESS_XATTR: &str = "system.posix_acl_access";
+/// The xattr holding the ACL a directory's new children inherit
+pub const DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+/// Version number the kernel puts at the start of every ACL xattr
+const VERSION: u32 = 2;
+
+// Entry tags, as in linux/posix_acl.h
+const USER_OBJ: u16 = 0x01;
+const USER: u16 = 0x02;
+const GROUP_OBJ: u16 = 0x04;
+const GROUP: u16 = 0x08;
+const MASK: u16 = 0x10;
+const OTHER: u16 = 0x20;
+
+/// One entry of an ACL; the qualifier (a uid or gid) doesn't matter for the mode
+struct Entry {
+    tag: u16,
+    perm: u16
+}
+
+/// Split an ACL xattr into its entries
+///
+/// The format is a little-endian u32 version followed by 8 byte entries, each a u16 tag,
+/// a u16 permission (rwx in the low three bits), and a u32 qualifier.
+fn parse(value: &[u8]) -> Fallible<Vec<Entry>> {
+    require!(value.len() >= 4 && (value.len() - 4).is_multiple_of(8), Error::InvalidArgument);
+    let version = u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+    require!(version == VERSION, Error::InvalidArgument);
+    Ok(value[4..].chunks(8)
+        .map(|entry| Entry{
+            tag: u16::from_le_bytes([entry[0], entry[1]]),
+            perm: u16::from_le_bytes([entry[2], entry[3]]) & 0o7
+        })
+        .collect())
+}
+
+/// The permission bits an access ACL implies
+///
+/// The owner and other classes mirror their entries, and the group class mirrors the mask
+/// if there is one (it caps every named entry) or the owning group if not.
+pub fn mode_of(value: &[u8]) -> Fallible<u16> {
+    let entries = parse(value)?;
+    let find = |tag| entries.iter().find(|entry| entry.tag == tag).map(|entry| entry.perm);
+    let named = entries.iter().any(|entry| entry.tag == USER || entry.tag == GROUP);
+    require!(!named || find(MASK).is_some(), Error::InvalidArgument);
+    match (find(USER_OBJ), find(MASK).or(find(GROUP_OBJ)), find(OTHER)) {
+        (Some(user), Some(group), Some(other)) => Ok(user << 6 | group << 3 | other),
+        _ => raise!(Error::InvalidArgument)
+    }
+}
+
+/// Check a default ACL is well formed; an empty one (just the version) is allowed too
+pub fn validate_default(value: &[u8]) -> Fallible<()> {
+    if parse(value)?.is_empty() {
+        return Ok(());
+    }
+    mode_of(value).map(|_| ())
+}
+
+/// Rewrite an access ACL so it agrees with new permission bits, as chmod does
+///
+/// Owner and other take the new bits, and so does the mask if there is one, leaving the
+/// named entries and the owning group's own entry as they were; otherwise the owning group
+/// takes them.
+pub fn with_mode(value: &[u8], mode: u16) -> Fallible<Vec<u8>> {
+    let has_mask = parse(value)?.iter().any(|entry| entry.tag == MASK);
+    let mut value = value.to_vec();
+    for entry in value[4..].chunks_mut(8) {
+        let perm = match u16::from_le_bytes([entry[0], entry[1]]) {
+            USER_OBJ => mode >> 6 & 0o7,
+            GROUP_OBJ if !has_mask => mode >> 3 & 0o7,
+            MASK => mode >> 3 & 0o7,
+            OTHER => mode & 0o7,
+            _ => continue
+        };
+        entry[2..4].copy_from_slice(&perm.to_le_bytes());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ACL xattr with these (tag, perm, qualifier) entries
+    fn acl(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut value = VERSION.to_le_bytes().to_vec();
+        for &(tag, perm, id) in entries {
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&perm.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+        value
+    }
+
+    #[test]
+    fn minimal_acls_mirror_the_owning_group() {
+        let value = acl(&[(USER_OBJ, 0o6, 0), (GROUP_OBJ, 0o4, 0), (OTHER, 0o0, 0)]);
+        assert_eq!(mode_of(&value).unwrap(), 0o640);
+    }
+
+    #[test]
+    fn the_mask_stands_for_the_group_class() {
+        let value = acl(&[(USER_OBJ, 0o7, 0), (USER, 0o7, 1000), (GROUP_OBJ, 0o7, 0), (MASK, 0o5, 0), (OTHER, 0o1, 0)]);
+        assert_eq!(mode_of(&value).unwrap(), 0o751);
+    }
+
+    #[test]
+    fn malformed_acls_are_refused() {
+        assert!(mode_of(&[]).is_err());
+        assert!(mode_of(&acl(&[(USER_OBJ, 0o7, 0), (OTHER, 0o7, 0)])).is_err());
+        // Named entries need a mask
+        assert!(mode_of(&acl(&[(USER_OBJ, 0o7, 0), (GROUP, 0o7, 9), (GROUP_OBJ, 0o7, 0), (OTHER, 0o7, 0)])).is_err());
+        let mut value = acl(&[(USER_OBJ, 0o7, 0), (GROUP_OBJ, 0o7, 0), (OTHER, 0o7, 0)]);
+        value[0] = 1;
+        assert!(mode_of(&value).is_err());
+        value.pop();
+        assert!(mode_of(&value).is_err());
+    }
+
+    #[test]
+    fn with_mode_rewrites_the_mask_and_leaves_named_entries() {
+        let value = acl(&[(USER_OBJ, 0o7, 0), (USER, 0o7, 1000), (GROUP_OBJ, 0o7, 0), (MASK, 0o7, 0), (OTHER, 0o7, 0)]);
+        let changed = with_mode(&value, 0o640).unwrap();
+        assert_eq!(changed, acl(&[(USER_OBJ, 0o6, 0), (USER, 0o7, 1000), (GROUP_OBJ, 0o7, 0), (MASK, 0o4, 0), (OTHER, 0o0, 0)]));
+        assert_eq!(mode_of(&changed).unwrap(), 0o640);
+    }
+
+    #[test]
+    fn with_mode_rewrites_the_owning_group_without_a_mask() {
+        let value = acl(&[(USER_OBJ, 0o7, 0), (GROUP_OBJ, 0o7, 0), (OTHER, 0o7, 0)]);
+        assert_eq!(mode_of(&with_mode(&value, 0o520).unwrap()).unwrap(), 0o520);
+    }
+}
@@ -0,0 +1,55 @@
+use fuse::FileAttr;
+use std::collections::HashMap;
+
+/// Most inodes whose attributes are kept at once, before starting over
+pub const MAX_CACHED_ATTRS: usize = 1 << 16;
+
+/// Attributes of inodes recently listed or stat'ed, for the getattrs that follow
+///
+/// `ls -l` and `find` list a directory and then stat every entry in it, and programs stat
+/// the same files over and over; readdir reads the whole Inode row for each entry anyway,
+/// and getattr keeps what it reads, so repeats cost no query. Our own changes forget what
+/// they touch. Another process writing the database directly is caught by PRAGMA
+/// data_version, which moves on whenever another connection commits: the next getattr
+/// after that starts over, so it's never stale apart from what the kernel keeps itself.
+#[derive(Default)]
+pub struct AttrCache {
+    attrs: HashMap<u64, FileAttr>,
+    /// PRAGMA data_version when the attributes were read
+    version: i64
+}
+
+impl AttrCache {
+    /// Drop everything if the database has changed under us since it was cached
+    pub fn revalidate(&mut self, version: i64) {
+        if version != self.version {
+            self.attrs.clear();
+            self.version = version;
+        }
+    }
+
+    /// Keep the attributes of some inodes, such as one directory's entries
+    pub fn fill<I: IntoIterator<Item = FileAttr>>(&mut self, attrs: I) {
+        for attr in attrs {
+            if self.attrs.len() >= MAX_CACHED_ATTRS {
+                self.attrs.clear();
+            }
+            self.attrs.insert(attr.ino, attr);
+        }
+    }
+
+    /// The cached attributes of an inode
+    pub fn get(&self, ino: u64) -> Option<FileAttr> {
+        self.attrs.get(&ino).copied()
+    }
+
+    /// Drop an inode's attributes, which must happen whenever any of them change
+    pub fn forget(&mut self, ino: u64) {
+        self.attrs.remove(&ino);
+    }
+
+    /// Drop everything, for changes to the tree that touch more inodes than are easily listed
+    pub fn clear(&mut self) {
+        self.attrs.clear();
+    }
+}
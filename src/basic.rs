@@ -1,158 +1,3167 @@
+use failure;
 use failure::Fallible;
-use Elkridge;
+use {Elkridge, PAGE_SIZE, ROOT_INODE};
+use acl;
+use checkpoint;
+use control::{self, CONTROL_DIR_INODE};
+use errors;
+use freeze;
+use fsck;
+use macos;
+use mime;
+use quota;
+use errors::Error;
+use options::{AtimePolicy, ReaddirOrder};
+use pages::PageStore;
+use readahead::PageCache;
+use readdir_cursor::{self, SortKey};
+use tables;
+use tables::TABLES_DIR_INODE;
+use write_buffer;
 use fuse::{FileType, FileAttr, Request};
+use fuse::consts::FOPEN_DIRECT_IO;
+use handles::Handles;
+use layers::{self, Layer};
+use libc;
 use rusqlite as sql;
-use std::ffi::{OsStr, OsString};
+use rusqlite::OptionalExtension;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::File;
+use std::iter;
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Component, Path, PathBuf};
+use time::{self, Timespec};
+
+/// Fail instead of replacing an existing target (same value as Linux's renameat2)
+pub const RENAME_NOREPLACE: u32 = 1;
+/// Atomically swap the source and target (same value as Linux's renameat2)
+pub const RENAME_EXCHANGE: u32 = 2;
+
+/// The setgid bit, as a u32 on every platform, unlike libc's mode_t
+const S_ISGID: u32 = 0o2000;
+
+/// The sticky bit, likewise
+const S_ISVTX: u32 = 0o1000;
+
+/// Most symlinks resolve_path follows in one path, as Linux's MAXSYMLINKS
+const MAX_SYMLINKS: u32 = 40;
+
+/// About what an empty file with a short name takes in the database: its Inode and Path rows
+/// and their index entries
+const INODE_BYTES: u64 = 128;
+
+/// The readdir offset of the first synthetic entry at the root, past any Path rowid
+const SYNTHETIC_OFFSET: i64 = i64::MAX - 2;
+
+/// Who an operation is done for, as far as the basic layer needs to know
+///
+/// Only the fuse crate can make a Request, so the basic layer takes this instead, which lets
+/// the same operations run without a mount, as `elkridge bench` runs them.
+#[derive(Debug, Clone, Copy)]
+pub struct Caller {
+    uid: u32,
+    gid: u32,
+    pid: u32
+}
+
+impl Caller {
+    pub fn new(uid: u32, gid: u32, pid: u32) -> Caller {
+        Caller{uid, gid, pid}
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+}
+
+impl<'a, 'b> From<&'b Request<'a>> for Caller {
+    fn from(req: &'b Request<'a>) -> Caller {
+        Caller::new(req.uid(), req.gid(), req.pid())
+    }
+}
+
+/// The attributes one setattr asks to change, each None to leave it as it is
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AttrChanges {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub atime: Option<Timespec>,
+    pub mtime: Option<Timespec>
+}
+
+/// SQL expression for an Inode row's link count
+///
+/// Directories get the usual 2 plus one per child (read from the maintained child_count
+/// rather than counted), everything else has one link per Path row naming it.
+const NLINK: &str = "CASE WHEN kind = 3
+        THEN 2 + child_count
+        ELSE (SELECT count(*) FROM Path WHERE Path.inode = Inode.inode)
+    END";
 
 /// Implementation of Filesystem, returning Fallible responses instead of using reply objects
 /// 
 /// The major advantage of this is just the use of Try.
 pub trait BasicFilesystem {
-    fn lookup_basic(&mut self, req: &Request, parent: u64, name: &OsStr) -> Fallible<FileAttr>;
-    fn getattr_basic(&mut self, req: &Request, ino: u64) -> Fallible<FileAttr>;
-    fn read_basic(&mut self, req: &Request, ino: u64, _fh: u64, offset: i64, size: u32) -> Fallible<Vec<u8>>;
-    fn readdir_basic(&mut self, req: &Request, ino: u64, _fh: u64, _offset: i64) -> Fallible<Vec<DirectoryEntry>>;
+    fn lookup_basic(&mut self, req: &Caller, parent: u64, name: &OsStr) -> Fallible<FileAttr>;
+    fn getattr_basic(&mut self, req: &Caller, ino: u64) -> Fallible<FileAttr>;
+    fn access_basic(&mut self, req: &Caller, ino: u64, mask: u32) -> Fallible<()>;
+    fn open_basic(&mut self, req: &Caller, ino: u64, flags: u32) -> Fallible<(u64, u32)>;
+    fn release_basic(&mut self, req: &Caller, ino: u64, fh: u64) -> Fallible<()>;
+    fn flush_basic(&mut self, req: &Caller, ino: u64, fh: u64) -> Fallible<()>;
+    fn forget_basic(&mut self, req: &Caller, ino: u64, nlookup: u64) -> Fallible<()>;
+    fn fsync_basic(&mut self, req: &Caller, ino: u64, fh: u64) -> Fallible<()>;
+    fn fsyncdir_basic(&mut self, req: &Caller, ino: u64, fh: u64) -> Fallible<()>;
+    fn read_basic(&mut self, req: &Caller, ino: u64, fh: u64, offset: i64, size: u32, buf: &mut Vec<u8>) -> Fallible<()>;
+    fn readlink_basic(&mut self, req: &Caller, ino: u64) -> Fallible<Vec<u8>>;
+    fn write_basic(&mut self, req: &Caller, ino: u64, _fh: u64, offset: i64, data: &[u8]) -> Fallible<u32>;
+    fn setattr_basic(&mut self, req: &Caller, ino: u64, changes: AttrChanges) -> Fallible<FileAttr>;
+    fn readdir_basic(&mut self, req: &Caller, ino: u64, _fh: u64, offset: i64) -> Fallible<Vec<DirectoryEntry>>;
+    fn mknod_basic(
+        &mut self, 
+        req: &Caller, 
+        parent: u64, 
+        name: &OsStr, 
+        mode: u32, 
+        rdev: u32
+    ) -> Fallible<FileAttr>;
+    fn create_basic(&mut self, req: &Caller, parent: u64, name: &OsStr, mode: u32, flags: u32) -> Fallible<(FileAttr, u64, u32)>;
     fn mkdir_basic(
         &mut self, 
-        req: &Request, 
+        req: &Caller, 
         parent: u64, 
         name: &OsStr, 
         mode: u32
     ) -> Fallible<FileAttr>;
+    fn symlink_basic(&mut self, req: &Caller, parent: u64, name: &OsStr, link: &Path) -> Fallible<FileAttr>;
     fn rmdir_basic(
         &mut self, 
-        req: &Request, 
+        req: &Caller, 
+        parent: u64, 
+        name: &OsStr
+    ) -> Fallible<()>;
+    fn unlink_basic(
+        &mut self, 
+        req: &Caller, 
         parent: u64, 
         name: &OsStr
     ) -> Fallible<()>;
+    fn rename_basic(
+        &mut self, 
+        req: &Caller, 
+        parent: u64, 
+        name: &OsStr, 
+        newparent: u64, 
+        newname: &OsStr, 
+        flags: u32
+    ) -> Fallible<()>;
+    fn statfs_basic(&mut self, req: &Caller, ino: u64) -> Fallible<FilesystemStats>;
+    fn destroy_basic(&mut self, req: &Caller) -> Fallible<()>;
+    fn setxattr_basic(&mut self, req: &Caller, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32) -> Fallible<()>;
+    fn getxattr_basic(&mut self, req: &Caller, ino: u64, name: &OsStr) -> Fallible<Vec<u8>>;
+    fn listxattr_basic(&mut self, req: &Caller, ino: u64) -> Fallible<Vec<u8>>;
+    fn removexattr_basic(&mut self, req: &Caller, ino: u64, name: &OsStr) -> Fallible<()>;
 }
 
 impl BasicFilesystem for Elkridge {
     /// Search for an inode by parent and name (e.g. using the path)
-    fn lookup_basic(&mut self, _req: &Request, parent: u64, name: &OsStr) -> Fallible<FileAttr> {
+    fn lookup_basic(&mut self, _req: &Caller, parent: u64, name: &OsStr) -> Fallible<FileAttr> {
+        if let Some(dir) = self.synthetic_dir(parent, name) {
+            return self.synthetic_attr(dir);
+        }
+        if self.options.expose_tables && parent == TABLES_DIR_INODE {
+            let ino = tables::find(&self.conn, &name.to_string_lossy())?
+                .ok_or_else(|| sql::Error::QueryReturnedNoRows)?;
+            return self.synthetic_attr(ino);
+        }
+        if self.options.control_dir && parent == CONTROL_DIR_INODE {
+            let ino = control::find(&name.to_string_lossy())
+                .ok_or_else(|| sql::Error::QueryReturnedNoRows)?;
+            return self.synthetic_attr(ino);
+        }
+        let name = &self.stored_name(parent, name)?;
+        if !self.layers.is_empty() {
+            if freeze::is_frozen() {
+                // Copying up would write, and the upper layer has nothing else to answer with
+                require!(!layers::unmerged(&self.conn, &self.layers, parent, name)?, Error::ReadOnly);
+            } else if layers::merge(&self.conn, &self.layers, self.options.stable_inodes, parent, name)? {
+                self.attr_cache.forget(parent);
+            }
+        }
         Ok(self.conn.query_row(
-            "SELECT *,
-                (SELECT count(*) FROM Path WHERE Path.inode = Inode.inode) AS nlink
+            &format!("SELECT *, {} AS nlink
             FROM Inode
-            WHERE parent = ? AND name = ?",
+            NATURAL JOIN Path
+            -- Like readdir, never find the root as its own child
+            WHERE parent = ? AND name = ? AND inode != parent {}", NLINK, self.hide_whiteouts()),
             &[
                 &(parent as i64) as &dyn sql::ToSql,
-                &name.to_str().unwrap_or("")
+                // Lossy, the same as every name is when stored, so a name that isn't UTF-8
+                // finds what was made under it
+                &name.to_string_lossy()
             ],
             |row| self.generate_fileattr_from_row(row)
         )?)
     }
 
-    /// Directly retrieve the info for an inode
-    fn getattr_basic(&mut self, _req: &Request, ino: u64) -> Fallible<FileAttr> {
-        Ok(self.conn.query_row(
-            "SELECT *,
-                (SELECT count(*) FROM Path WHERE Path.inode = Inode.inode) AS nlink
+    /// Directly retrieve the info for an inode, from the attribute cache if it can
+    fn getattr_basic(&mut self, _req: &Caller, ino: u64) -> Fallible<FileAttr> {
+        if self.is_synthetic(ino) {
+            return self.synthetic_attr(ino);
+        }
+        let version = self.data_version()?;
+        self.attr_cache.revalidate(version);
+        if let Some(attr) = self.attr_cache.get(ino) {
+            return Ok(attr);
+        }
+        let attr = self.conn.query_row(
+            &format!("SELECT *, {} AS nlink
             FROM Inode
-            WHERE inode = ?",
+            WHERE inode = ?", NLINK),
             &[
                 &(ino as i64) as &dyn sql::ToSql,
             ],
             |row| self.generate_fileattr_from_row(row)
-        )?)
+        )?;
+        self.attr_cache.fill(Some(attr));
+        Ok(attr)
     }
 
-    /// Read some data from a page
-    fn read_basic(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32) -> Fallible<Vec<u8>> {
-        // Wrap so we can use ?
-        let mut stmt = self.conn.prepare(
-            "SELECT content, start
-            FROM Page
-            WHERE inode = ?
-                AND start <= ?
-                AND finish >= ?
-            ORDER BY start")?;
-        let mut buf : Vec<u8> = Vec::with_capacity(size as usize * 4/3);
-        stmt.query_and_then(
-            &[
-                &(ino as i64),
-                &offset,
-                &(offset + size as i64)
-            ],
-            // TODO: The type annotations here seem ugly
-            |row| Ok(buf.extend_from_slice(&row.get::<&str, Vec<u8>>("content")?)) as sql::Result<()>
-        )?;
-        Ok(buf)
+    /// Check the caller's access to an inode for access(2), `mask` being F_OK or any of
+    /// R_OK, W_OK and X_OK
+    ///
+    /// Under --enforce-permissions the kernel checks every operation itself, including this
+    /// one, so only existence is left to confirm. Otherwise nothing else is refused for want
+    /// of permission, but access still answers by the mode bits as reported, so programs that
+    /// ask first get the answer they'd get on a local filesystem.
+    fn access_basic(&mut self, req: &Caller, ino: u64, mask: u32) -> Fallible<()> {
+        let attr = self.getattr_basic(req, ino)?;
+        if self.options.enforce_permissions || mask == libc::F_OK as u32 {
+            return Ok(());
+        }
+        if mask & libc::W_OK as u32 != 0 {
+            self.ensure_writable(ino)?;
+        }
+        require!(mode_allows(&attr, req.uid(), req.gid(), mask), Error::PermissionDenied);
+        Ok(())
     }
 
-    /// Get the list of children in a directory
-    fn readdir_basic(&mut self, _req: &Request, ino: u64, _fh: u64, _offset: i64) -> Fallible<Vec<DirectoryEntry>> {
-        // Wrap so we can use ?
-        let mut stmt = self.conn.prepare(
-            "SELECT inode, name, kind
-            FROM Path
-            NATURAL JOIN Inode
-            WHERE Path.parent = ?")?;
-        let entries = stmt.query_map(
-            &[ &(ino as i64) ],
-            // TODO: The type annotations here seem ugly
-            |row| Ok(DirectoryEntry{
-                ino: row.get::<&str, i64>("inode")? as u64,              // ino
-                offset: 0,                                               // offset
-                kind: Elkridge::filetype_from_code(row.get("inode")?),   // kind
-                name: row.get::<&str, String>("name")?.into()            // name
-            })
-        )?.collect::<sql::Result<_>>()?;
+    /// Open a file, returning the handle and the FOPEN_* flags for the reply
+    ///
+    /// O_DIRECT (or --direct-io) turns on FOPEN_DIRECT_IO, so the kernel sends every read
+    /// and write straight through at the caller's exact offset and size, with no readahead.
+    /// O_NOATIME keeps reads through the handle from touching atime, whatever --atime says,
+    /// and like the kernel only the owner or root may ask for it, or it's EPERM.
+    fn open_basic(&mut self, req: &Caller, ino: u64, flags: u32) -> Fallible<(u64, u32)> {
+        // Make sure it exists
+        let attr = self.getattr_basic(req, ino)?;
+        if flags & libc::O_NOATIME as u32 != 0 {
+            require!(req.uid() == 0 || req.uid() == attr.uid, Error::NotPermitted);
+        }
+        let direct = self.options.direct_io || flags & libc::O_DIRECT as u32 != 0;
+        Ok((self.handles.open(ino, flags, req.uid(), req.pid()), if direct { FOPEN_DIRECT_IO } else { 0 }))
+    }
+
+    /// Close a file, dropping any pages read ahead for it once nothing else has it open
+    ///
+    /// Its buffered writes are stored first, so a file is in the database once it's closed,
+    /// and a failure doing that, now or earlier, is the error; the kernel ignores it, though,
+    /// which is why flush_basic reports the same. The handle is gone either way, and so is
+    /// the inode if nothing else refers to it, buffered writes and all. Under --compat-macos
+    /// a closed AppleDouble file is folded into the file it belongs to.
+    fn release_basic(&mut self, req: &Caller, ino: u64, fh: u64) -> Fallible<()> {
+        let deferred = self.handles.release(fh).and_then(|handle| handle.deferred);
+        if !self.handles.is_open(ino) {
+            self.page_cache.forget(ino);
+        }
+        let stored = self.flush_writes(Some(ino));
+        if stored.is_ok() && self.options.compat_macos && !self.handles.is_open(ino) && !freeze::is_frozen() {
+            let names : Vec<(i64, String)> = self.conn.prepare(
+                "SELECT parent, name FROM Path WHERE inode = ? AND substr(name, 1, 2) = ?")?
+                .query_map(&[ &(ino as i64) as &dyn sql::ToSql, &macos::APPLE_DOUBLE_PREFIX ], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<sql::Result<_>>()?;
+            for (parent, name) in names {
+                self.fold_apple_double(req, parent as u64, OsStr::new(&name));
+            }
+        }
+        self.reclaim(ino)?;
+        stored?;
+        if let Some(errno) = deferred {
+            raise!(Error::Deferred{errno});
+        }
+        Ok(())
+    }
+
+    /// Store a file's buffered writes as a descriptor on it is closed, so close(2) can fail
+    ///
+    /// The kernel sends this for every close, while release only comes after the last one
+    /// and its answer goes nowhere, so this is how a program finds out its writes were never
+    /// stored: from storing them now, or from an earlier try it wasn't there for (see
+    /// flush_writes). Each handle reports a failure once, the way Linux reports writeback
+    /// errors. Without --write-buffer there's never anything to store.
+    fn flush_basic(&mut self, _req: &Caller, ino: u64, fh: u64) -> Fallible<()> {
+        let stored = self.flush_writes(Some(ino));
+        let deferred = self.handles.take_deferred(fh);
+        stored?;
+        if let Some(errno) = deferred {
+            raise!(Error::Deferred{errno});
+        }
+        Ok(())
+    }
+
+    /// The kernel no longer needs `nlookup` of the references it had to an inode
+    fn forget_basic(&mut self, _req: &Caller, ino: u64, nlookup: u64) -> Fallible<()> {
+        self.handles.forget(ino, nlookup);
+        self.reclaim(ino)
+    }
+
+    /// Make an open file's writes durable
+    ///
+    /// Each write is its own committed transaction before it is answered, so by the time the
+    /// kernel asks (after writing back a shared mapping's dirty pages, for msync or fsync)
+    /// nothing is waiting to be committed. What's left is making the commits durable where
+    /// they may not be yet: blob files are synced, and in WAL mode with synchronous=NORMAL,
+    /// where commits only reach the disk at a checkpoint, a checkpoint is run. With
+    /// synchronous=OFF nothing SQLite does is durable, and neither is this. The exception to
+    /// all that is --write-buffer, whose writes for the file are stored here first; like
+    /// flush_basic, this reports an earlier failure storing them once. While writes are frozen
+    /// there's no checkpoint, and buffered writes can't be stored, which fails with EROFS.
+    fn fsync_basic(&mut self, req: &Caller, ino: u64, fh: u64) -> Fallible<()> {
+        self.getattr_basic(req, ino)?;
+        let stored = self.flush_writes(Some(ino));
+        let deferred = self.handles.take_deferred(fh);
+        stored?;
+        if let Some(errno) = deferred {
+            raise!(Error::Deferred{errno});
+        }
+        self.pages.sync(&self.conn, ino)?;
+        let journal_mode : String = self.conn.query_row("PRAGMA journal_mode", sql::NO_PARAMS, |row| row.get(0))?;
+        let synchronous : i64 = self.conn.query_row("PRAGMA synchronous", sql::NO_PARAMS, |row| row.get(0))?;
+        // A checkpoint writes to the database file, which holds still while writes are frozen
+        if journal_mode.eq_ignore_ascii_case("wal") && synchronous == 1 && !freeze::is_frozen() {
+            let busy : bool = self.conn.query_row("PRAGMA wal_checkpoint(FULL)", sql::NO_PARAMS, |row| row.get(0))?;
+            // Even blocked, the checkpoint synced the WAL first, and that's what makes it durable
+            if busy {
+                debug!("Another connection kept the fsync checkpoint of ino:{} from finishing", ino);
+            }
+        }
+        Ok(())
+    }
+
+    /// Make a directory's entries durable, for the fsync after a create or rename
+    ///
+    /// Like writes, every create, rename and unlink is committed before it's answered, so
+    /// there's never a pending transaction to finish; this is fsync's checkpoint, so the
+    /// entries survive a crash under synchronous=NORMAL too.
+    fn fsyncdir_basic(&mut self, req: &Caller, ino: u64, fh: u64) -> Fallible<()> {
+        self.fsync_basic(req, ino, fh)
+    }
+
+    /// Read some data from the pages overlapping the requested range
+    ///
+    /// `buf` is cleared and filled with exactly the bytes between `offset` and `offset + size`
+    /// that exist in the file, so one buffer can be reused across reads without reallocating.
+    fn read_basic(&mut self, _req: &Caller, ino: u64, fh: u64, offset: i64, size: u32, buf: &mut Vec<u8>) -> Fallible<()> {
+        if self.is_synthetic(ino) {
+            let dump = self.synthetic_dump(ino, false)?;
+            let start = (offset as usize).min(dump.len());
+            let end = (start + size as usize).min(dump.len());
+            buf.clear();
+            buf.extend_from_slice(&dump[start..end]);
+            return Ok(());
+        }
+        let file_size : i64 = self.conn.query_row(
+            "SELECT size FROM Inode WHERE inode = ?",
+            [ino as i64],
+            |row| row.get(0))?;
+        let file_size = file_size.max(self.write_buffer.extent(ino).map_or(0, |(end, _)| end));
+        if self.handles.get(fh).is_none_or(|handle| handle.flags & libc::O_NOATIME as u32 == 0) {
+            self.touch_atime(ino)?;
+        }
+        // Clamped to the file, so a short file gives a short reply and a read past the end an
+        // empty one, which is how the kernel learns where EOF is; saturating, since the
+        // kernel will pass along offsets right up to i64::MAX. --max-read-size clamps it
+        // further, so however much a caller asks for, no more than that is ever in memory.
+        let size = match self.options.max_read_size {
+            Some(max) => u64::from(size).min(max.max(1)),
+            None => u64::from(size)
+        };
+        let end = file_size.min(offset.saturating_add(size as i64));
+        self.handles.record_read(fh, offset, end.max(offset));
+        buf.clear();
+        if end <= offset {
+            return Ok(());
+        }
+        // Anything no page covers is a hole, and reads as zeros
+        buf.resize((end - offset) as usize, 0);
+        if !self.page_cache.is_empty() {
+            let version = self.data_version()?;
+            self.page_cache.revalidate(version);
+        }
+        if !self.page_cache.take(ino, offset, buf) {
+            layers::scan(&self.conn, &self.layers, &*self.pages, ino, offset, end, &mut |start, content| {
+                // The part of this page inside the requested range, as file offsets
+                let lo = start.max(offset);
+                let hi = (start + content.len() as i64).min(end);
+                if lo < hi {
+                    buf[(lo - offset) as usize .. (hi - offset) as usize]
+                        .copy_from_slice(&content[(lo - start) as usize .. (hi - start) as usize]);
+                }
+            })?;
+        }
+        self.write_buffer.overlay(ino, offset, buf);
+        Ok(())
+    }
+
+    /// Write data at any offset, returning how much was written
+    ///
+    /// A write that doesn't entirely fit, under --max-file-size or because the database is
+    /// full, writes what does and returns that short count, as write(2) does; the caller finds
+    /// out why when it tries the rest. EFBIG, EDQUOT or ENOSPC only come back when not even
+    /// the first byte fits. Room is found a page at a time: an attempt that overfills the
+    /// database is rolled back and tried again one page shorter.
+    ///
+    /// Under --write-buffer the write is only kept in memory and answered, unless it would
+    /// overfill the buffer: then everything buffered is stored first, and a write bigger
+    /// than the whole buffer is stored directly, after the file's buffered ones. A buffered
+    /// write can't be short, so when the database is full it's the write that has to store
+    /// the others that fails.
+    fn write_basic(&mut self, _req: &Caller, ino: u64, _fh: u64, offset: i64, data: &[u8]) -> Fallible<u32> {
+        self.ensure_writable(ino)?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let mut len = data.len() as i64;
+        if let Some(max) = self.options.max_file_size {
+            require!((offset as u64) < max, Error::FileTooBig);
+            len = (len as u64).min(max.saturating_sub(offset as u64)) as i64;
+        }
+        self.ensure_quota(ino, offset + len)?;
+        if let Some(limit) = self.options.write_buffer {
+            if self.write_buffer.bytes() as u64 + len as u64 > limit {
+                self.flush_writes(None)?;
+            }
+            if len as u64 <= limit {
+                self.attr_cache.forget(ino);
+                self.write_buffer.add(ino, offset, &data[..len as usize], time::get_time().sec);
+                return Ok(len as u32);
+            }
+        }
+        loop {
+            match self.write_span(ino, offset, &data[..len as usize]) {
+                Ok(()) => return Ok(len as u32),
+                Err(e) => {
+                    // The last page boundary inside the write, if it crosses one
+                    let boundary = (offset + len - 1) / PAGE_SIZE * PAGE_SIZE;
+                    if !errors::is_disk_full(&e) || boundary <= offset {
+                        return Err(e);
+                    }
+                    len = boundary - offset;
+                }
+            }
+        }
+    }
+
+    /// Where a symlink points, for readlink(2); anything else is EINVAL, as readlink says
+    fn readlink_basic(&mut self, req: &Caller, ino: u64) -> Fallible<Vec<u8>> {
+        require!(self.getattr_basic(req, ino)?.kind == FileType::Symlink, Error::InvalidArgument);
+        Ok(self.symlink_target(ino)?.into_os_string().into_vec())
+    }
+
+    /// Change an inode's mode, owner, size or times, all in one transaction
+    ///
+    /// Truncating deletes the pages past the new size and cuts the one straddling it short,
+    /// so no old bytes come back if the file grows again; growing leaves a hole. A new mode
+    /// is written into the access ACL too, if there is one, as chmod does. Only the columns
+    /// asked for are written, plus ctime, which POSIX has every change move: setting just
+    /// atime (`touch -a`) leaves mtime exactly as it was, and only a size change moves it.
+    fn setattr_basic(&mut self, req: &Caller, ino: u64, changes: AttrChanges) -> Fallible<FileAttr> {
+        self.ensure_writable(ino)?;
+        if let Some(size) = changes.size {
+            // Whatever's buffered past the new end need never be stored, which lets a
+            // truncate free the room whose lack is keeping the rest from being stored
+            self.write_buffer.truncate(ino, size.min(i64::MAX as u64) as i64);
+        }
+        self.flush_writes(Some(ino))?;
+        let attr = self.getattr_basic(req, ino)?;
+        if let Some(size) = changes.size {
+            require!(attr.kind != FileType::Directory, Error::IsADirectory);
+            require!(size <= i64::MAX as u64, Error::FileTooBig);
+            // Checked first, like in write_basic, so a rejected truncate changes nothing
+            if let Some(max) = self.options.max_file_size {
+                require!(size <= max, Error::FileTooBig);
+            }
+            self.ensure_quota(ino, size as i64)?;
+            self.page_cache.forget(ino);
+        }
+        let owners = (changes.uid.map(|id| self.stored_owner(id)), changes.gid.map(|id| self.stored_owner(id)));
+        let txn : sql::Transaction = self.conn.transaction()?;
+        if let Some(size) = changes.size {
+            let size = size as i64;
+            layers::copy_up_content(&txn, &self.layers, &*self.pages, ino)?;
+            // The page straddling the new end is cut short, and everything after it goes
+            let mut straddling = vec![];
+            self.pages.scan(&txn, ino, size, size + 1, &mut |start, content| {
+                if start < size {
+                    straddling.push((start, content[.. (size - start) as usize].to_vec()));
+                }
+            })?;
+            self.pages.delete(&txn, ino, size, i64::MAX)?;
+            if let Some((start, content)) = merge_pages(straddling) {
+                self.pages.put(&txn, ino, start, &content)?;
+            }
+            if size < mime::SNIFF_LEN as i64 {
+                txn.execute(
+                    "DELETE FROM Xattr WHERE inode = ? AND name = ?",
+                    &[ &(ino as i64) as &dyn sql::ToSql, &mime::XATTR ])?;
+            }
+            txn.execute(
+                "UPDATE Inode SET size = ?1, blocks = (?1 + 511) / 512, mtime = strftime('%s') WHERE inode = ?2",
+                [size, ino as i64])?;
+            if self.options.page_overlap_check {
+                check_page_overlaps(&txn, ino)?;
+            }
+        }
+        if let Some(mode) = changes.mode {
+            let perm = mode & 0o7777;
+            txn.execute("UPDATE Inode SET perm = ? WHERE inode = ?", [i64::from(perm), ino as i64])?;
+            let acl : Option<Vec<u8>> = txn.query_row(
+                "SELECT value FROM Xattr WHERE inode = ? AND name = ?",
+                &[ &(ino as i64) as &dyn sql::ToSql, &acl::ACCESS_XATTR ],
+                |row| row.get(0)).optional()?;
+            if let Some(acl) = acl {
+                txn.execute(
+                    "UPDATE Xattr SET value = ? WHERE inode = ? AND name = ?",
+                    &[ &acl::with_mode(&acl, perm as u16)? as &dyn sql::ToSql, &(ino as i64), &acl::ACCESS_XATTR ])?;
+            }
+        }
+        for &(column, id) in &[("uid", owners.0), ("gid", owners.1)] {
+            if let Some(id) = id {
+                txn.execute(&format!("UPDATE Inode SET {} = ? WHERE inode = ?", column), [i64::from(id), ino as i64])?;
+            }
+        }
+        for &(column, time) in &[("atime", changes.atime), ("mtime", changes.mtime)] {
+            if let Some(time) = time {
+                txn.execute(&format!("UPDATE Inode SET {} = ? WHERE inode = ?", column), [time.sec, ino as i64])?;
+            }
+        }
+        txn.execute("UPDATE Inode SET ctime = strftime('%s') WHERE inode = ?", [ino as i64])?;
+        txn.commit()?;
+        // Forgotten only now, since reading the attributes above cached them as they were
+        self.attr_cache.forget(ino);
+        self.pages.collect(&self.conn)?;
+        self.getattr_basic(req, ino)
+    }
+
+    /// List a directory's entries after the one given `offset`, in --readdir-order
+    ///
+    /// The kernel resumes from the offset of the last entry it took when a reply fills up
+    /// before the end. Each entry's offset is its Path rowid, and resuming carries on after
+    /// that entry's sort key with `>` and LIMIT, so every batch costs one indexed query
+    /// however far into the directory it starts. Every --readdir-order is a total one, ties
+    /// broken by name; it only shifts when the directory changes (or, for mtime, a file in
+    /// it), as it may on any filesystem. The synthetic entries at the root come last, with
+    /// offsets from SYNTHETIC_OFFSET, and the synthetic directories are listed whole.
+    fn readdir_basic(&mut self, _req: &Caller, ino: u64, _fh: u64, offset: i64) -> Fallible<Vec<DirectoryEntry>> {
+        if let Some(entries) = self.list_synthetic_dir(ino)? {
+            return Ok(entries.into_iter()
+                .enumerate()
+                .skip(offset.max(0) as usize)
+                .map(|(index, entry)| DirectoryEntry{offset: index as i64 + 1, ..entry})
+                .collect());
+        }
+        let mut entries = if offset < SYNTHETIC_OFFSET { self.list_dir(ino, offset)? } else { Vec::new() };
+        if ino == self.root && entries.len() < readdir_cursor::BATCH {
+            let synthetic = [
+                (self.options.expose_tables, TABLES_DIR_INODE, tables::TABLES_DIR_NAME),
+                (self.options.control_dir, CONTROL_DIR_INODE, control::CONTROL_DIR_NAME)
+            ];
+            for (index, &(shown, dir, name)) in synthetic.iter().enumerate() {
+                let at = SYNTHETIC_OFFSET + index as i64;
+                if !shown || at <= offset {
+                    continue;
+                }
+                entries.push(DirectoryEntry{
+                    ino: dir,
+                    offset: at,
+                    kind: FileType::Directory,
+                    name: name.into()
+                });
+            }
+        }
         Ok(entries)
     }
-    fn mkdir_basic(
+
+    /// Create a special file, including overlayfs-style whiteouts (0/0 char devices)
+    fn mknod_basic(
         &mut self, 
-        req: &Request, 
+        req: &Caller, 
         parent: u64, 
         name: &OsStr, 
-        mode: u32
+        mode: u32, 
+        rdev: u32
     ) -> Fallible<FileAttr> {
+        self.ensure_mutable(parent, name)?;
+        require!(!self.options.no_ds_store || name != macos::DS_STORE, Error::NotPermitted);
+        let name = &self.stored_name(parent, name)?;
+        self.attr_cache.clear();
         let txn : sql::Transaction = self.conn.transaction()?;
-        let maybe_inode = txn.query_row(
-            "SELECT inode FROM Path
-                WHERE parent = ? AND name = ?; ",
-            &[ &(parent as i64) as &dyn sql::ToSql, &name.to_string_lossy() ],
-            |row| row.get::<&str, i64>("is_there"))
-            .ok();
-        let definitely_inode = match maybe_inode {
-            Some(ino) => ino,
-            None => {
-                txn.execute(
-                    "INSERT OR IGNORE INTO Inode(perm) VALUES (?);",
-                    &[mode])?;
-                let new_inode = txn.last_insert_rowid();
-                txn.execute(
-                    "INSERT OR IGNORE INTO Path(inode, parent, name) VALUES (?,?,?);",
-                    &[
-                        &new_inode,
-                        &(parent as i64) as &dyn sql::ToSql,
-                        &name.to_string_lossy()
-                    ])?;
-                new_inode
+        layers::remove_whiteout(&txn, parent, name)?;
+        let (uid, gid, perm) = new_owner(&txn, req, parent, mode & 0o7777, false)?;
+        let new_inode = number_inode(&txn, self.options.stable_inodes, parent, name)?;
+        txn.execute(
+            "INSERT INTO Inode(inode, kind, perm, uid, gid, rdev) VALUES (?,?,?,?,?,?);",
+            &[
+                &new_inode as &dyn sql::ToSql,
+                &Elkridge::code_from_mode(mode, rdev),
+                &perm,
+                &uid,
+                &gid,
+                &rdev
+            ])?;
+        // No OR IGNORE here: an existing name must fail rather than orphan the new inode
+        txn.execute(
+            "INSERT INTO Path(inode, parent, name) VALUES (?,?,?);",
+            &[
+                &new_inode,
+                &(parent as i64) as &dyn sql::ToSql,
+                &name.to_string_lossy()
+            ])?;
+        txn.commit()?;
+        if self.options.compat_macos {
+            // An AppleDouble file that came before the file it belongs to, as tar extracts them
+            let double = OsString::from(format!("{}{}", macos::APPLE_DOUBLE_PREFIX, name.to_string_lossy()));
+            self.fold_apple_double(req, parent, &double);
+        }
+        self.getattr_basic(req, new_inode as u64)
+    }
+
+    /// Create a regular file and open it, for open(2) with O_CREAT, returning it with
+    /// open_basic's handle and open flags
+    ///
+    /// The kernel only asks when it has found nothing by that name, but another connection
+    /// to the database can make one in between; the name's uniqueness in Path is what
+    /// settles that race, so losing it shows up here as AlreadyExists. Without O_EXCL that's
+    /// the file POSIX says O_CREAT opens, truncated for O_TRUNC, just as if it had been there
+    /// all along; with it, it's EEXIST.
+    fn create_basic(&mut self, req: &Caller, parent: u64, name: &OsStr, mode: u32, flags: u32) -> Fallible<(FileAttr, u64, u32)> {
+        let attr = match self.mknod_basic(req, parent, name, mode, 0) {
+            Ok(attr) => attr,
+            Err(e) => {
+                let e = errors::classify(e);
+                match e.downcast_ref::<Error>() {
+                    Some(Error::AlreadyExists) if flags & libc::O_EXCL as u32 == 0 => {},
+                    _ => return Err(e)
+                }
+                let attr = self.lookup_basic(req, parent, name)?;
+                require!(attr.kind != FileType::Directory, Error::IsADirectory);
+                if flags & libc::O_TRUNC as u32 != 0 && attr.kind == FileType::RegularFile {
+                    self.setattr_basic(req, attr.ino, AttrChanges{size: Some(0), ..Default::default()})?
+                } else {
+                    attr
+                }
             }
         };
+        let (fh, open_flags) = self.open_basic(req, attr.ino, flags)?;
+        Ok((attr, fh, open_flags))
+    }
+
+    /// Create a directory
+    fn mkdir_basic(
+        &mut self, 
+        req: &Caller, 
+        parent: u64, 
+        name: &OsStr, 
+        mode: u32
+    ) -> Fallible<FileAttr> {
+        self.ensure_mutable(parent, name)?;
+        let name = &self.stored_name(parent, name)?;
+        self.attr_cache.clear();
+        let txn : sql::Transaction = self.conn.transaction()?;
+        layers::remove_whiteout(&txn, parent, name)?;
+        let (uid, gid, perm) = new_owner(&txn, req, parent, mode & 0o7777, true)?;
+        let new_inode = number_inode(&txn, self.options.stable_inodes, parent, name)?;
+        txn.execute(
+            "INSERT INTO Inode(inode, kind, perm, uid, gid) VALUES (?,3,?,?,?);",
+            &[ &new_inode as &dyn sql::ToSql, &perm, &uid, &gid ])?;
+        // As in mknod, an existing name fails (with EEXIST) rather than orphaning the inode
+        txn.execute(
+            "INSERT INTO Path(inode, parent, name) VALUES (?,?,?);",
+            &[
+                &new_inode,
+                &(parent as i64) as &dyn sql::ToSql,
+                &name.to_string_lossy()
+            ])?;
         txn.commit()?;
-        self.getattr_basic(req, definitely_inode as u64)
+        self.getattr_basic(req, new_inode as u64)
+    }
+
+    /// Create a symlink to `link`, stored as its content the way import stores one
+    ///
+    /// The link and its target go in separately, so if storing the target fails the new
+    /// link is taken away again rather than left pointing nowhere.
+    fn symlink_basic(&mut self, req: &Caller, parent: u64, name: &OsStr, link: &Path) -> Fallible<FileAttr> {
+        let attr = self.mknod_basic(req, parent, name, libc::S_IFLNK | 0o777, 0)?;
+        if let Err(e) = self.write_span(attr.ino, 0, link.as_os_str().as_bytes()) {
+            self.unlink_basic(req, parent, name)?;
+            return Err(e);
+        }
+        self.getattr_basic(req, attr.ino)
     }
     fn rmdir_basic(
         &mut self, 
-        _req: &Request, 
+        req: &Caller, 
+        parent: u64, 
+        name: &OsStr
+    ) -> Fallible<()> {
+        self.ensure_mutable(parent, name)?;
+        let name = &self.stored_name(parent, name)?;
+        self.attr_cache.clear();
+        self.merge_dir(parent, name)?;
+        let txn : sql::Transaction = self.conn.transaction()?;
+        let (inode, kind) = find_child(&txn, parent, name)?
+            .ok_or_else(|| sql::Error::QueryReturnedNoRows)?;
+        require!(kind == 3, Error::NotADirectory);
+        ensure_sticky_allows(&txn, req, parent, inode)?;
+        let child_count : i64 = txn.query_row(
+            "SELECT child_count FROM Inode WHERE inode = ?",
+            [inode],
+            |row| row.get(0))?;
+        require!(child_count == layers::clear_whiteouts(&txn, inode as u64)?, Error::NotEmpty);
+        txn.execute("DELETE FROM Path WHERE parent=? AND name = ?;",
+            &[
+                &(parent as i64) as &dyn sql::ToSql,
+                &name.to_string_lossy()
+            ])?;
+        layers::white_out(&txn, &self.layers, self.options.stable_inodes, parent, name)?;
+        txn.commit()?;
+        self.reclaim(inode as u64)
+    }
+
+    /// Remove a name, and the inode too once nothing else refers to it
+    fn unlink_basic(
+        &mut self, 
+        req: &Caller, 
         parent: u64, 
         name: &OsStr
     ) -> Fallible<()> {
-        self.conn.execute("DELETE FROM Path WHERE parent=? AND name = ?;",
+        self.ensure_mutable(parent, name)?;
+        let name = &self.stored_name(parent, name)?;
+        self.attr_cache.clear();
+        let txn : sql::Transaction = self.conn.transaction()?;
+        let (inode, kind) = find_child(&txn, parent, name)?
+            .ok_or_else(|| sql::Error::QueryReturnedNoRows)?;
+        require!(kind != 3, Error::IsADirectory);
+        ensure_sticky_allows(&txn, req, parent, inode)?;
+        txn.execute("DELETE FROM Path WHERE parent = ? AND name = ?;",
             &[
                 &(parent as i64) as &dyn sql::ToSql,
                 &name.to_string_lossy()
             ])?;
+        layers::white_out(&txn, &self.layers, self.options.stable_inodes, parent, name)?;
+        txn.commit()?;
+        self.reclaim(inode as u64)
+    }
+
+    /// Move an entry, honoring renameat2's NOREPLACE and EXCHANGE flags
+    ///
+    /// Only macOS's exchange passes a flag from the kernel; at the protocol the fuse crate
+    /// speaks Linux sends no RENAME2, and fails renameat2 with flags itself.
+    fn rename_basic(
+        &mut self, 
+        req: &Caller, 
+        parent: u64, 
+        name: &OsStr, 
+        newparent: u64, 
+        newname: &OsStr, 
+        flags: u32
+    ) -> Fallible<()> {
+        self.ensure_mutable(parent, name)?;
+        self.ensure_mutable(newparent, newname)?;
+        require!(!self.options.no_ds_store || newname != macos::DS_STORE, Error::NotPermitted);
+        let name = &self.stored_name(parent, name)?;
+        let newname = &self.stored_name(newparent, newname)?;
+        self.attr_cache.clear();
+        self.merge_dir(newparent, newname)?;
+        let buffered = self.buffered_growth()?;
+        let txn : sql::Transaction = self.conn.transaction()?;
+        // A whiteout in the way is no entry at all, as far as anyone else can see
+        layers::remove_whiteout(&txn, newparent, newname)?;
+        let (source, source_kind) = find_child(&txn, parent, name)?
+            .ok_or_else(|| sql::Error::QueryReturnedNoRows)?;
+        let target = find_child(&txn, newparent, newname)?;
+        let mut replaced = None;
+        // Moving an entry out of a sticky directory is removing it from there, and replacing
+        // (or exchanging with) one in a sticky directory is removing that one
+        ensure_sticky_allows(&txn, req, parent, source)?;
+        if let Some((existing, _)) = target {
+            ensure_sticky_allows(&txn, req, newparent, existing)?;
+        }
+        // A directory moved under itself would be cut off from the root in a loop
+        if source_kind == 3 {
+            require!(!is_within(&txn, newparent, source)?, Error::InvalidArgument);
+        }
+        if let (Some((existing, 3)), true) = (target, flags & RENAME_EXCHANGE != 0) {
+            require!(!is_within(&txn, parent, existing)?, Error::InvalidArgument);
+        }
+        quota::check_move(&txn, source as u64, parent, newparent, &buffered)?;
+        if let (Some((existing, _)), true) = (target, flags & RENAME_EXCHANGE != 0) {
+            quota::check_move(&txn, existing as u64, newparent, parent, &buffered)?;
+        }
+
+        if flags & RENAME_EXCHANGE != 0 {
+            target.ok_or_else(|| sql::Error::QueryReturnedNoRows)?;
+            // Both rows are moved rather than having their inodes swapped, since Path.inode is UNIQUE.
+            // The source is parked under a name containing '/', which no real entry can have,
+            // made from its own inode number, which no other row has, so the PRIMARY KEY never
+            // sees two rows with the same (parent, name).
+            let parked = OsString::from(format!("/{}", source));
+            move_path(&txn, parent, name, parent, &parked)?;
+            move_path(&txn, newparent, newname, parent, name)?;
+            move_path(&txn, parent, &parked, newparent, newname)?;
+        } else {
+            if let Some((existing, existing_kind)) = target {
+                require!(flags & RENAME_NOREPLACE == 0, Error::AlreadyExists);
+                if existing == source {
+                    // Renaming a name onto another name for the same inode does nothing
+                    return Ok(());
+                }
+                match (source_kind == 3, existing_kind == 3) {
+                    (true, false) => raise!(Error::NotADirectory),
+                    (false, true) => raise!(Error::IsADirectory),
+                    _ => ()
+                }
+                let child_count : i64 = txn.query_row(
+                    "SELECT child_count FROM Inode WHERE inode = ?",
+                    [existing],
+                    |row| row.get(0))?;
+                require!(child_count == layers::clear_whiteouts(&txn, existing as u64)?, Error::NotEmpty);
+                txn.execute("DELETE FROM Path WHERE parent = ? AND name = ?;",
+                    &[
+                        &(newparent as i64) as &dyn sql::ToSql,
+                        &newname.to_string_lossy()
+                    ])?;
+                replaced = Some(existing as u64);
+            }
+            move_path(&txn, parent, name, newparent, newname)?;
+            layers::white_out(&txn, &self.layers, self.options.stable_inodes, parent, name)?;
+        }
+        txn.commit()?;
+        match replaced {
+            Some(replaced) => self.reclaim(replaced),
+            None => Ok(())
+        }
+    }
+
+    /// Report usage, in PAGE_SIZE blocks so the kernel's idea of a block matches our pages
+    ///
+    /// There's no inode table to run out of, so free inodes are as many empty files as the
+    /// free space would hold, at INODE_BYTES each.
+    fn statfs_basic(&mut self, _req: &Caller, _ino: u64) -> Fallible<FilesystemStats> {
+        let (used, files) : (i64, i64) = self.conn.query_row(
+            "SELECT coalesce(sum(size), 0), count(*) FROM Inode",
+            sql::NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let used = (used as u64).div_ceil(PAGE_SIZE as u64);
+        // Whatever room is left on the disk holding the database is room left for files,
+        // unless --capacity leaves less, and so are the pages in SQLite's freelist, where a
+        // deleted file's pages go: new content reuses them before the file grows, until
+        // auto_vacuum hands them back to the disk
+        let reusable = self.freelist_bytes()?;
+        let mut free = self.host_free_bytes()?.unwrap_or(0) + reusable;
+        if let Some(capacity) = self.options.capacity {
+            free = free.min((capacity + reusable).saturating_sub(self.database_bytes()?));
+        }
+        let (free, free_inodes) = (free / PAGE_SIZE as u64, free / INODE_BYTES);
+        Ok(FilesystemStats{
+            blocks: used + free,
+            bfree: free,
+            bavail: free,
+            files: files as u64,
+            ffree: free_inodes,
+            bsize: PAGE_SIZE as u32,
+            namelen: 255,
+            frsize: PAGE_SIZE as u32
+        })
+    }
+
+    /// Leave the database clean at unmount
+    ///
+    /// Every operation commits its own transaction, so nothing is pending by now; all that's
+    /// left is folding the WAL (if there is one) back into the database and letting go of
+    /// what was cached in memory.
+    fn destroy_basic(&mut self, _req: &Caller) -> Fallible<()> {
+        if freeze::is_frozen() {
+            info!("Unmounting ends the freeze, so what's still buffered or unreclaimed is stored");
+            freeze::thaw();
+            self.reclaim_thawed();
+        }
+        self.flush_writes(None)?;
+        if let Some(lock) = self.mount_lock.take() {
+            lock.release(&self.conn)?;
+        }
+        self.handles = Handles::default();
+        self.page_cache = PageCache::default();
+        self.synthetic_dump = None;
+        // This is a no-op, not an error, when the database isn't in WAL mode
+        let busy : bool = self.conn.query_row(
+            "PRAGMA wal_checkpoint(FULL)",
+            sql::NO_PARAMS,
+            |row| row.get(0))?;
+        if busy {
+            warn!("Another connection kept the final checkpoint from finishing");
+        }
+        Ok(())
+    }
+
+    /// Set an extended attribute, honoring XATTR_CREATE and XATTR_REPLACE
+    ///
+    /// An access ACL also rewrites the permission bits, since the mode is just a summary of
+    /// the ACL's owner, group (or mask) and other entries; a default ACL only needs checking.
+    /// `position` is only for macOS's resource fork, and is ignored without --compat-macos.
+    fn setxattr_basic(&mut self, req: &Caller, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32) -> Fallible<()> {
+        self.ensure_writable(ino)?;
+        self.attr_cache.forget(ino);
+        require!(name != mime::XATTR, Error::PermissionDenied);
+        let name = name.to_string_lossy();
+        let stored = self.stored_xattr_name(&name);
+        let txn = self.conn.transaction()?;
+        let (kind, old) : (i8, Option<Vec<u8>>) = txn.query_row(
+            "SELECT kind, (SELECT value FROM Xattr WHERE Xattr.inode = Inode.inode AND Xattr.name = ?)
+            FROM Inode
+            WHERE inode = ?",
+            &[ &stored as &dyn sql::ToSql, &(ino as i64) ],
+            |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let exists = old.is_some();
+        require!(flags & libc::XATTR_CREATE as u32 == 0 || !exists, Error::AlreadyExists);
+        require!(flags & libc::XATTR_REPLACE as u32 == 0 || exists, Error::NoData);
+        let stored_value = if self.options.compat_macos {
+            macos::set_value(&name, old, value, position)?
+        } else {
+            Some(value.to_vec())
+        };
+        if name == acl::ACCESS_XATTR {
+            txn.execute(
+                "UPDATE Inode SET perm = (perm & ~511) | ? WHERE inode = ?",
+                [i64::from(acl::mode_of(value)?), ino as i64])?;
+        } else if name == acl::DEFAULT_XATTR {
+            // Only directories have children to pass a default on to
+            require!(kind == 3, Error::PermissionDenied);
+            acl::validate_default(value)?;
+        }
+        if name == quota::XATTR {
+            require!(req.uid() == 0, Error::NotPermitted);
+            require!(kind == 3, Error::NotADirectory);
+            quota::parse(value)?;
+            self.quotas_known = None;
+        }
+        match stored_value {
+            Some(value) => txn.execute(
+                "INSERT OR REPLACE INTO Xattr(inode, name, value) VALUES (?,?,?)",
+                &[ &(ino as i64) as &dyn sql::ToSql, &stored, &value ])?,
+            None => txn.execute(
+                "DELETE FROM Xattr WHERE inode = ? AND name = ?",
+                &[ &(ino as i64) as &dyn sql::ToSql, &stored ])?
+        };
+        txn.execute("UPDATE Inode SET ctime = strftime('%s') WHERE inode = ?", [ino as i64])?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Get the value of an extended attribute
+    fn getxattr_basic(&mut self, _req: &Caller, ino: u64, name: &OsStr) -> Fallible<Vec<u8>> {
+        if self.is_synthetic(ino) {
+            raise!(Error::NoData);
+        }
+        if name == mime::XATTR {
+            return self.mime_type(ino);
+        }
+        self.conn.query_row(
+            "SELECT value FROM Xattr WHERE inode = ? AND name = ?",
+            &[ &(ino as i64) as &dyn sql::ToSql, &self.stored_xattr_name(&name.to_string_lossy()) ],
+            |row| row.get(0)
+        ).optional()?
+        .ok_or_else(|| Error::NoData.into())
+    }
+
+    /// List the names of an inode's extended attributes, each followed by a NUL
+    fn listxattr_basic(&mut self, _req: &Caller, ino: u64) -> Fallible<Vec<u8>> {
+        let mut list = Vec::new();
+        if self.is_synthetic(ino) {
+            return Ok(list);
+        }
+        // Regular files always have a content type, whether or not --cache-mime has stored it
+        let mut stmt = self.conn.prepare(
+            "SELECT name FROM Xattr WHERE inode = ?1
+            UNION
+            SELECT ?2 FROM Inode WHERE inode = ?1 AND kind = 4
+            ORDER BY name")?;
+        let mut rows = stmt.query(&[ &(ino as i64) as &dyn sql::ToSql, &mime::XATTR ])?;
+        while let Some(row) = rows.next()? {
+            let name = row.get_raw(0).as_str()?;
+            let name = if self.options.compat_macos { macos::presented_xattr_name(name) } else { name };
+            list.extend_from_slice(name.as_bytes());
+            list.push(0);
+        }
+        Ok(list)
+    }
+
+    /// Remove an extended attribute
+    ///
+    /// Removing an access ACL leaves the permission bits as they are, which is what they
+    /// already summarized.
+    fn removexattr_basic(&mut self, req: &Caller, ino: u64, name: &OsStr) -> Fallible<()> {
+        self.ensure_writable(ino)?;
+        self.attr_cache.forget(ino);
+        require!(name != mime::XATTR, Error::PermissionDenied);
+        require!(name != quota::XATTR || req.uid() == 0, Error::NotPermitted);
+        let removed = self.conn.execute(
+            "DELETE FROM Xattr WHERE inode = ? AND name = ?",
+            &[ &(ino as i64) as &dyn sql::ToSql, &self.stored_xattr_name(&name.to_string_lossy()) ])?;
+        require!(removed > 0, Error::NoData);
+        if name == quota::XATTR {
+            self.quotas_known = None;
+        }
+        self.conn.execute("UPDATE Inode SET ctime = strftime('%s') WHERE inode = ?", [ino as i64])?;
         Ok(())
     }
 }
 
-/// Directory Entry, used as part of the return type of readdir()
-pub struct DirectoryEntry {
-    pub ino: u64,
-    pub offset: i64,
-    pub kind: FileType,
-    pub name: OsString
-}
\ No newline at end of file
+impl Elkridge {
+    /// Bytes available to unprivileged users on the filesystem holding the database
+    ///
+    /// In-memory and temporary databases have no such filesystem, and get None.
+    fn host_free_bytes(&self) -> Fallible<Option<u64>> {
+        let path : String = self.conn.query_row(
+            "SELECT file FROM pragma_database_list WHERE name = 'main'",
+            sql::NO_PARAMS,
+            |row| row.get(0))?;
+        if path.is_empty() {
+            return Ok(None);
+        }
+        let path = CString::new(path)?;
+        let mut stats : libc::statvfs = unsafe { mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+            return Err(::std::io::Error::last_os_error().into());
+        }
+        Ok(Some(stats.f_bavail as u64 * stats.f_frsize as u64))
+    }
+
+    /// How big the database currently is, in bytes
+    fn database_bytes(&self) -> Fallible<u64> {
+        Ok(self.conn.query_row(
+            "SELECT page_count * page_size FROM pragma_page_count, pragma_page_size",
+            sql::NO_PARAMS,
+            |row| row.get::<usize, i64>(0))? as u64)
+    }
+
+    /// Bytes in pages the database holds but no longer uses, waiting to be reused
+    fn freelist_bytes(&self) -> Fallible<u64> {
+        Ok(self.conn.query_row(
+            "SELECT freelist_count * page_size FROM pragma_freelist_count, pragma_page_size",
+            sql::NO_PARAMS,
+            |row| row.get::<usize, i64>(0))? as u64)
+    }
+
+    /// The UUID generated when this database was first opened
+    ///
+    /// The FUSE protocol has no way to report an fsid, so the kernel makes one up for each
+    /// mount; exporting over NFS with `fsid=` set to this keeps file handles valid across
+    /// remounts instead.
+    pub fn database_uuid(&self) -> sql::Result<String> {
+        self.conn.query_row("SELECT value FROM Meta WHERE key = 'uuid'", sql::NO_PARAMS, |row| row.get(0))
+    }
+
+    /// Prepare an error from a basic operation for logging and replying to the kernel
+    ///
+    /// SQLite reports both a full disk and reaching --capacity (its max_page_count) as
+    /// SQLITE_FULL; when the capacity left less room than the disk did, it was the quota
+    /// that ran out, and the caller gets EDQUOT instead of ENOSPC.
+    pub fn describe(&self, err: failure::Error) -> failure::Error {
+        let err = errors::classify(err);
+        if let Some(capacity) = self.options.capacity {
+            if errors::is_disk_full(&err) {
+                let room = capacity.saturating_sub(self.database_bytes().unwrap_or(0));
+                match self.host_free_bytes() {
+                    Ok(Some(free)) if free <= room => (),
+                    _ => return Error::QuotaExceeded.into()
+                }
+            }
+        }
+        err
+    }
+
+    /// Read the next --readahead pages into the page cache, if the handle is reading sequentially
+    ///
+    /// This runs after the read that prompted it has been answered, so the reader doesn't wait
+    /// for it; by the time the next read arrives its pages are already in memory. Pages that
+    /// don't sit on PAGE_SIZE boundaries (from a database written by something else) aren't
+    /// cached at all, and reads of them keep going to the database.
+    pub fn prefetch(&mut self, fh: u64) -> Fallible<()> {
+        let depth = i64::from(self.options.readahead);
+        let (ino, from) = match self.handles.get(fh) {
+            Some(handle) if depth > 0 && handle.sequential && !self.is_synthetic(handle.ino) => (handle.ino, handle.next_offset),
+            _ => return Ok(())
+        };
+        let file_size : i64 = self.conn.query_row(
+            "SELECT size FROM Inode WHERE inode = ?",
+            [ino as i64],
+            |row| row.get(0))?;
+        let window = from - from % PAGE_SIZE;
+        let end = file_size.min(window + depth * PAGE_SIZE);
+        let version = self.data_version()?;
+        self.page_cache.revalidate(version);
+        // Only go to the database when some page in the window isn't already cached
+        let first = match (window..end).step_by(PAGE_SIZE as usize).find(|&start| !self.page_cache.contains(ino, start)) {
+            Some(first) => first,
+            None => return Ok(())
+        };
+        let mut pages = HashMap::new();
+        let mut aligned = true;
+        layers::scan(&self.conn, &self.layers, &*self.pages, ino, first, end, &mut |start, content| {
+            aligned &= start % PAGE_SIZE == 0 && content.len() as i64 <= PAGE_SIZE;
+            pages.insert(start, content.to_vec());
+        })?;
+        if !aligned {
+            return Ok(());
+        }
+        for start in (first..end).step_by(PAGE_SIZE as usize) {
+            let content = pages.remove(&start).unwrap_or_default();
+            self.page_cache.insert(ino, start, content);
+        }
+        Ok(())
+    }
+
+    /// SQLite's count of commits made to the database by other connections
+    pub fn data_version(&self) -> sql::Result<i64> {
+        self.conn.prepare_cached("PRAGMA data_version")?.query_row(sql::NO_PARAMS, |row| row.get(0))
+    }
+
+    /// The user.mime_type of a regular file, detected from its first bytes
+    ///
+    /// Detection happens on every request unless --cache-mime is on, in which case the answer
+    /// is stored in Xattr like any other attribute until a write near the start removes it.
+    fn mime_type(&mut self, ino: u64) -> Fallible<Vec<u8>> {
+        let (kind, stored) : (i8, Option<Vec<u8>>) = self.conn.query_row(
+            "SELECT kind, (SELECT value FROM Xattr WHERE Xattr.inode = Inode.inode AND name = ?)
+            FROM Inode
+            WHERE inode = ?",
+            &[ &mime::XATTR as &dyn sql::ToSql, &(ino as i64) ],
+            |row| Ok((row.get(0)?, row.get(1)?)))?;
+        require!(kind == 4, Error::NoData);
+        if let Some(stored) = stored {
+            return Ok(stored);
+        }
+        let mut head = Vec::new();
+        self.flush_writes(Some(ino))?;
+        self.read_head(ino, &mut head)?;
+        let detected = mime::sniff(&head).as_bytes().to_vec();
+        if self.options.cache_mime && !self.refuses_changes() {
+            self.conn.execute(
+                "INSERT INTO Xattr(inode, name, value) VALUES (?,?,?)",
+                &[ &(ino as i64) as &dyn sql::ToSql, &mime::XATTR, &detected ])?;
+        }
+        Ok(detected)
+    }
+
+    /// The first SNIFF_LEN bytes of a file, without touching atime or any handle
+    fn read_head(&self, ino: u64, head: &mut Vec<u8>) -> Fallible<()> {
+        layers::scan(&self.conn, &self.layers, &*self.pages, ino, 0, mime::SNIFF_LEN as i64, &mut |start, content| {
+            let end = (start as usize + content.len()).min(mime::SNIFF_LEN);
+            if head.len() < end {
+                head.resize(end, 0);
+            }
+            head[start as usize .. end].copy_from_slice(&content[.. end - start as usize]);
+        })
+    }
+
+    /// Delete an inode that has no names left, unless the kernel still refers to it
+    ///
+    /// Until then it lives on with no Path rows, so open handles keep working; anything
+    /// left like that by a crash is cleared out the next time the database is opened. While
+    /// writes are frozen it waits for the thaw, see reclaim_thawed.
+    fn reclaim(&mut self, ino: u64) -> Fallible<()> {
+        // The root has no Path row, but it's never going anywhere
+        if ino == ROOT_INODE || self.handles.is_referenced(ino) {
+            return Ok(());
+        }
+        if freeze::is_frozen() {
+            if !self.unreclaimed.contains(&ino) {
+                self.unreclaimed.push(ino);
+            }
+            return Ok(());
+        }
+        let deleted = self.conn.execute(
+            "DELETE FROM Inode WHERE inode = ?1
+                AND NOT EXISTS(SELECT 1 FROM Path WHERE inode = ?1);",
+            [ino as i64])?;
+        if deleted > 0 {
+            self.write_buffer.take(Some(ino));
+            self.page_cache.forget(ino);
+            self.attr_cache.forget(ino);
+            self.pages.collect(&self.conn)?;
+        }
+        Ok(())
+    }
+
+    /// Store the bytes of one write, all or nothing
+    fn write_span(&mut self, ino: u64, offset: i64, data: &[u8]) -> Fallible<()> {
+        self.page_cache.forget(ino);
+        self.attr_cache.forget(ino);
+        let txn : sql::Transaction = self.conn.transaction()?;
+        put_span(&txn, &self.layers, &*self.pages, ino, offset, data)?;
+        if self.options.page_overlap_check {
+            check_page_overlaps(&txn, ino)?;
+        }
+        txn.commit()?;
+        self.pages.collect(&self.conn)?;
+        Ok(())
+    }
+
+    /// Store the writes --write-buffer is holding, only `ino`'s or everyone's, in one transaction
+    ///
+    /// Each file's writes are stored in the order they came, and its mtime and ctime are
+    /// set to when the latest came in rather than now. A file another connection deleted in
+    /// the meantime takes its writes with it. If storing fails (the database is full, say)
+    /// nothing is stored and the writes stay buffered, to be tried again, and every handle
+    /// open on their files keeps the error to report, since whoever called this may not be
+    /// the one whose writes they were.
+    pub fn flush_writes(&mut self, ino: Option<u64>) -> Fallible<()> {
+        let taken = self.write_buffer.take(ino);
+        if taken.is_empty() {
+            return Ok(());
+        }
+        if freeze::is_frozen() {
+            // Kept for after the thaw, without an error for the handles: nothing went wrong
+            self.write_buffer.restore(taken);
+            raise!(Error::ReadOnly);
+        }
+        let mut store = || -> Fallible<()> {
+            let txn : sql::Transaction = self.conn.transaction()?;
+            for (ino, dirty) in &taken {
+                self.page_cache.forget(*ino);
+                self.attr_cache.forget(*ino);
+                let exists = txn.query_row("SELECT 1 FROM Inode WHERE inode = ?", [*ino as i64], |_| Ok(())).optional()?;
+                if exists.is_none() {
+                    continue;
+                }
+                for (offset, data) in &dirty.writes {
+                    put_span(&txn, &self.layers, &*self.pages, *ino, *offset, data)?;
+                }
+                txn.execute("UPDATE Inode SET mtime = ?1, ctime = ?1 WHERE inode = ?2", [dirty.modified, *ino as i64])?;
+                if self.options.page_overlap_check {
+                    check_page_overlaps(&txn, *ino)?;
+                }
+            }
+            txn.commit()?;
+            self.pages.collect(&self.conn)?;
+            Ok(())
+        };
+        match store() {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let e = self.describe(e);
+                let errno = errors::errno_for(&e);
+                for &(ino, _) in &taken {
+                    self.handles.defer_error(ino, errno);
+                }
+                self.write_buffer.restore(taken);
+                Err(e)
+            }
+        }
+    }
+
+    /// Store buffered writes once the earliest has waited --write-buffer-delay
+    ///
+    /// This runs before every request, whatever file it's for, so a burst of writes is
+    /// stored soon after it ends as long as anything at all is using the mount. A failure
+    /// is only logged, as it's not the request's; the writes stay buffered, and the error
+    /// comes back from the next fsync or close on their files.
+    pub fn flush_aged_writes(&mut self) {
+        let delay = self.options.write_buffer_delay.unwrap_or(write_buffer::DEFAULT_WRITE_BUFFER_DELAY);
+        if self.write_buffer.age().is_some_and(|age| age >= delay) && !freeze::is_frozen() {
+            if let Err(e) = self.flush_writes(None) {
+                warn!("Couldn't store buffered writes yet: {}", e);
+            }
+        }
+    }
+
+    /// Reclaim the inodes that came due while writes were frozen, once they're thawed
+    ///
+    /// Like flush_aged_writes this runs before every request, and a failure is only logged.
+    pub fn reclaim_thawed(&mut self) {
+        if self.unreclaimed.is_empty() || freeze::is_frozen() {
+            return;
+        }
+        for ino in mem::take(&mut self.unreclaimed) {
+            if let Err(e) = self.reclaim(ino) {
+                warn!("Couldn't reclaim ino:{} after the thaw: {}", ino, e);
+            }
+        }
+    }
+
+    /// The name an xattr is stored under, which --compat-macos changes for com.apple ones
+    fn stored_xattr_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if self.options.compat_macos { macos::stored_xattr_name(name) } else { Cow::Borrowed(name) }
+    }
+
+    /// For --compat-macos, move the xattrs in the AppleDouble file `double` onto the file it
+    /// belongs to, and delete it
+    ///
+    /// macOS writes `._name` beside `name` where it can't set xattrs, and so do tools that
+    /// copy its files onto other systems. An AppleDouble file is left alone while it's open,
+    /// when the file it belongs to isn't there (yet: making that file folds it in too), and
+    /// when it isn't AppleDouble after all; the xattrs it holds replace any with the same
+    /// names. This is housekeeping on the way out of a close or a create, so a failure is
+    /// only logged.
+    fn fold_apple_double(&mut self, req: &Caller, parent: u64, double: &OsStr) {
+        let fold = |fs: &mut Elkridge| -> Fallible<bool> {
+            let target = match double.to_str().and_then(|name| name.strip_prefix(macos::APPLE_DOUBLE_PREFIX)) {
+                Some(target) if !target.is_empty() => OsString::from(target),
+                _ => return Ok(false)
+            };
+            let (double_ino, target_ino) = match (find_child(&fs.conn, parent, double)?, find_child(&fs.conn, parent, &target)?) {
+                (Some((double_ino, 4)), Some((target_ino, _))) if !fs.handles.is_open(double_ino as u64) => (double_ino, target_ino),
+                _ => return Ok(false)
+            };
+            let size : i64 = fs.conn.query_row("SELECT size FROM Inode WHERE inode = ?", [double_ino], |row| row.get(0))?;
+            let mut content = vec![0u8; size as usize];
+            layers::scan(&fs.conn, &fs.layers, &*fs.pages, double_ino as u64, 0, size, &mut |start, page| {
+                let end = (start + page.len() as i64).min(size);
+                if start < end {
+                    content[start as usize .. end as usize].copy_from_slice(&page[.. (end - start) as usize]);
+                }
+            })?;
+            let xattrs = match macos::apple_double_xattrs(&content) {
+                Some(xattrs) => xattrs,
+                None => return Ok(false)
+            };
+            let txn = fs.conn.transaction()?;
+            for (name, value) in &xattrs {
+                txn.execute(
+                    "INSERT OR REPLACE INTO Xattr(inode, name, value) VALUES (?,?,?)",
+                    &[ &target_ino as &dyn sql::ToSql, &macos::stored_xattr_name(name), value ])?;
+            }
+            txn.execute("UPDATE Inode SET ctime = strftime('%s') WHERE inode = ?", [target_ino])?;
+            txn.commit()?;
+            fs.attr_cache.forget(target_ino as u64);
+            // Whatever the AppleDouble file carried, a quota included
+            fs.quotas_known = None;
+            fs.unlink_basic(req, parent, double)?;
+            Ok(true)
+        };
+        match fold(self) {
+            Ok(true) => debug!("Folded {} into the xattrs of its file in ino:{}", double.to_string_lossy(), parent),
+            Ok(false) => {},
+            Err(e) => warn!("Couldn't fold {} in ino:{} into xattrs: {}", double.to_string_lossy(), parent, e)
+        }
+    }
+
+    /// The name an entry in `parent` is stored under, for a name as the kernel gave it
+    ///
+    /// Without --normalize that's the name itself. With it, names that are the same in the
+    /// --normalize form are the same name, so an entry made under any of them is found by
+    /// all of them; it keeps the bytes it was made with, unless --normalize-store stores new
+    /// names normalized. An ASCII name is its own normal form, so only stored names that
+    /// aren't have to be normalized and compared, one by one.
+    fn stored_name(&self, parent: u64, name: &OsStr) -> Fallible<OsString> {
+        let form = match self.options.normalize {
+            Some(form) => form,
+            None => return Ok(name.to_os_string())
+        };
+        let given = name.to_string_lossy();
+        let normal = form.apply(&given);
+        for candidate in &[&*given, normal.as_str()] {
+            if find_child(&self.conn, parent, OsStr::new(candidate))?.is_some() {
+                return Ok(OsString::from(candidate));
+            }
+        }
+        let mut others = self.conn.prepare_cached(
+            "SELECT name FROM Path WHERE parent = ? AND name GLOB '*[^ -~]*' AND inode != parent")?;
+        for other in others.query_map([parent as i64], |row| row.get::<usize, String>(0))? {
+            let other = other?;
+            if form.apply(&other) == normal {
+                return Ok(OsString::from(other));
+            }
+        }
+        Ok(if self.options.normalize_store { OsString::from(normal) } else { name.to_os_string() })
+    }
+
+    /// Copy up everything the lower layers have in the directory called `name` in `parent`,
+    /// if that's where one is, so what's being removed or replaced is known to be empty
+    fn merge_dir(&mut self, parent: u64, name: &OsStr) -> Fallible<()> {
+        if self.layers.is_empty() {
+            return Ok(());
+        }
+        if let Some((dir, 3)) = find_child(&self.conn, parent, name)? {
+            let txn : sql::Transaction = self.conn.transaction()?;
+            for name in layers::names_below(&txn, &self.layers, dir as u64)? {
+                layers::merge(&txn, &self.layers, self.options.stable_inodes, dir as u64, OsStr::new(&name))?;
+            }
+            txn.commit()?;
+        }
+        Ok(())
+    }
+
+    /// The entries of a synthetic directory, which are few enough to list whole every time
+    fn list_synthetic_dir(&self, ino: u64) -> Fallible<Option<Vec<DirectoryEntry>>> {
+        let files : Vec<(u64, String)> = if self.options.expose_tables && ino == TABLES_DIR_INODE {
+            tables::list(&self.conn)?
+        } else if self.options.control_dir && ino == CONTROL_DIR_INODE {
+            control::FILES.iter().map(|&(ino, name)| (ino, name.to_string())).collect()
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(files.into_iter()
+            .map(|(ino, name)| DirectoryEntry{
+                ino,
+                offset: 0,
+                kind: FileType::RegularFile,
+                name: name.into()
+            })
+            .collect()))
+    }
+
+    /// The next batch of a directory's entries after the one given `offset`, or from the
+    /// start for 0, in --readdir-order
+    fn list_dir(&mut self, ino: u64, offset: i64) -> Fallible<Vec<DirectoryEntry>> {
+        // Only as a listing starts, or every batch would go through the lower layers again
+        if offset == 0 && !self.layers.is_empty() && freeze::is_frozen() {
+            // As in lookup: listing only what's already up would leave names out
+            for name in layers::names_below(&self.conn, &self.layers, ino)? {
+                require!(!layers::unmerged(&self.conn, &self.layers, ino, OsStr::new(&name))?, Error::ReadOnly);
+            }
+        } else if offset == 0 && !self.layers.is_empty() {
+            // Everything the lower layers have here is copied up first, in one transaction
+            let txn : sql::Transaction = self.conn.transaction()?;
+            for name in layers::names_below(&txn, &self.layers, ino)? {
+                layers::merge(&txn, &self.layers, self.options.stable_inodes, ino, OsStr::new(&name))?;
+            }
+            txn.commit()?;
+        }
+        // Before the first entry in every order, names being at least one byte long
+        let mut after : SortKey = (i64::MIN, String::new());
+        if offset > 0 {
+            match self.readdir_key(ino, offset)? {
+                Some(key) => after = key,
+                None => debug!("Entry {} of ino:{} is gone and wasn't listed lately, listing it again from the start", offset, ino)
+            }
+        }
+        let (order, condition) = self.readdir_order();
+        // Whole rows, since the getattr of each entry is likely to follow (see AttrCache)
+        let version = self.data_version()?;
+        let mut stmt = self.conn.prepare(
+            &format!("SELECT *, {} AS nlink, Path.rowid AS entry
+            FROM Path
+            NATURAL JOIN Inode
+            -- The root is its own parent but not its own child
+            WHERE Path.parent = ?1 AND Path.inode != Path.parent {} AND {}
+            ORDER BY {}
+            LIMIT ?4", NLINK, self.hide_whiteouts(), condition, order))?;
+        let rows = stmt.query_map(
+            &[
+                &(ino as i64) as &dyn sql::ToSql,
+                &after.0,
+                &after.1,
+                &(readdir_cursor::BATCH as i64)
+            ],
+            |row| Ok((
+                self.generate_fileattr_from_row(row)?,
+                row.get::<&str, String>("name")?,
+                row.get::<&str, i64>("entry")?,
+                row.get::<&str, i64>("mtime")?
+            ))
+        )?.collect::<sql::Result<Vec<(FileAttr, String, i64, i64)>>>()?;
+        drop(stmt);
+        let keys : Vec<(i64, SortKey)> = rows.iter()
+            .map(|(attr, name, entry, mtime)| (*entry, self.sort_key(*entry, attr.ino, *mtime, name)))
+            .collect();
+        if rows.len() < readdir_cursor::BATCH {
+            self.readdir_cursors.finish(ino);
+        } else {
+            self.readdir_cursors.remember(ino, keys);
+        }
+        let mut entries : Vec<DirectoryEntry> = rows.iter()
+            .map(|(attr, name, entry, _)| DirectoryEntry{
+                ino: attr.ino,
+                offset: *entry,
+                kind: attr.kind,
+                name: name.into()
+            })
+            .collect();
+        self.attr_cache.revalidate(version);
+        self.attr_cache.fill(rows.into_iter().map(|(attr, ..)| attr));
+        if ino == self.root {
+            // A synthetic directory hides any real entry that happens to share its name
+            let synthetic = [
+                (self.options.expose_tables, tables::TABLES_DIR_NAME),
+                (self.options.control_dir, control::CONTROL_DIR_NAME)
+            ];
+            entries.retain(|entry| !synthetic.iter().any(|&(shown, name)| shown && entry.name == name));
+        }
+        Ok(entries)
+    }
+
+    /// The sort key readdir carries on after, from the entry given `offset`
+    ///
+    /// None if the entry is gone and wasn't in a recent batch either.
+    fn readdir_key(&self, dir: u64, offset: i64) -> Fallible<Option<SortKey>> {
+        if let Some(key) = self.readdir_cursors.find(dir, offset) {
+            return Ok(Some(key));
+        }
+        Ok(self.conn.query_row(
+            "SELECT Path.inode, mtime, name FROM Path NATURAL JOIN Inode WHERE Path.rowid = ? AND parent = ?",
+            [offset, dir as i64],
+            |row| Ok(self.sort_key(offset, row.get::<usize, i64>(0)? as u64, row.get(1)?, &row.get::<usize, String>(2)?))
+        ).optional()?)
+    }
+
+    /// Where an entry sorts under --readdir-order, by the columns readdir_order compares
+    fn sort_key(&self, entry: i64, ino: u64, mtime: i64, name: &str) -> SortKey {
+        match self.options.readdir_order {
+            ReaddirOrder::Natural => (entry, String::new()),
+            ReaddirOrder::Name => (0, name.to_string()),
+            ReaddirOrder::Mtime => (mtime, name.to_string()),
+            ReaddirOrder::Inode => (ino as i64, String::new())
+        }
+    }
+
+    /// SQL ordering for --readdir-order, by Path and Inode columns, and the condition for
+    /// coming after a sort key, whose number is ?2 and name ?3
+    fn readdir_order(&self) -> (&'static str, &'static str) {
+        match self.options.readdir_order {
+            ReaddirOrder::Natural => ("Path.rowid", "Path.rowid > ?2"),
+            ReaddirOrder::Name => ("Path.name", "Path.name > ?3"),
+            ReaddirOrder::Mtime => ("Inode.mtime, Path.name", "(Inode.mtime, Path.name) > (?2, ?3)"),
+            ReaddirOrder::Inode => ("Path.inode", "Path.inode > ?2")
+        }
+    }
+
+    /// SQL condition leaving out whiteouts, which over lower layers stand for nothing at all;
+    /// without any they're ordinary 0/0 character devices
+    fn hide_whiteouts(&self) -> &'static str {
+        if self.layers.is_empty() { "" } else { "AND kind != 7" }
+    }
+
+    /// Record a read in atime, as far as the --atime policy asks
+    fn touch_atime(&mut self, ino: u64) -> Fallible<()> {
+        if self.refuses_changes() {
+            return Ok(());
+        }
+        let strict = match self.options.atime {
+            AtimePolicy::Never => return Ok(()),
+            AtimePolicy::Relative => false,
+            AtimePolicy::Strict => true
+        };
+        // The relatime rule is in the WHERE clause, so reads that don't qualify write nothing
+        self.conn.prepare_cached(
+            "UPDATE Inode SET atime = strftime('%s')
+            WHERE inode = ?1
+                AND (?2
+                    OR atime <= mtime
+                    OR atime <= ctime
+                    OR atime <= strftime('%s') - 86400)")?
+            .execute(&[ &(ino as i64) as &dyn sql::ToSql, &strict ])?;
+        self.attr_cache.forget(ino);
+        Ok(())
+    }
+
+    /// Whether an inode is one of the synthetic ones, in the tables or control tree
+    fn is_synthetic(&self, ino: u64) -> bool {
+        (self.options.expose_tables && tables::is_synthetic(ino))
+            || (self.options.control_dir && control::is_control(ino))
+    }
+
+    /// The synthetic directory `name` in `parent` refers to, if it refers to one
+    fn synthetic_dir(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        if parent != self.root {
+            None
+        } else if self.options.expose_tables && name == tables::TABLES_DIR_NAME {
+            Some(TABLES_DIR_INODE)
+        } else if self.options.control_dir && name == control::CONTROL_DIR_NAME {
+            Some(CONTROL_DIR_INODE)
+        } else {
+            None
+        }
+    }
+
+    /// Find the inode and kind code at a path, starting from the real root
+    ///
+    /// This walks the stored tree only, so it never sees the synthetic tables directory.
+    /// Symlinks on the way are followed, with absolute targets taken from the real root, and
+    /// so is one at the end if `follow` is set, as stat does; otherwise the symlink itself is
+    /// found, as lstat does. Following more than MAX_SYMLINKS of them fails with ELOOP.
+    pub fn resolve_path(&self, path: &Path, follow: bool) -> Fallible<(u64, i8)> {
+        let mut found = (ROOT_INODE, 3);
+        // Names still to look up, last first, with ".." for a parent
+        let mut pending = Vec::new();
+        push_components(&mut pending, path)?;
+        let mut followed = 0;
+        while let Some(name) = pending.pop() {
+            require!(found.1 == 3, Error::NotADirectory);
+            if name == ".." {
+                let parent : i64 = self.conn.query_row(
+                    "SELECT parent FROM Path WHERE inode = ?", [found.0 as i64], |row| row.get(0))?;
+                found = (parent as u64, 3);
+                continue;
+            }
+            let (inode, kind) = find_child(&self.conn, found.0, &name)?
+                .ok_or(sql::Error::QueryReturnedNoRows)?;
+            if kind == 5 && (follow || !pending.is_empty()) {
+                followed += 1;
+                require!(followed <= MAX_SYMLINKS, Error::SymlinkLoop);
+                let target = self.symlink_target(inode as u64)?;
+                if target.has_root() {
+                    found = (ROOT_INODE, 3);
+                }
+                push_components(&mut pending, &target)?;
+                continue;
+            }
+            found = (inode as u64, kind);
+        }
+        Ok(found)
+    }
+
+    /// Where a symlink points, which is its content
+    fn symlink_target(&self, ino: u64) -> Fallible<PathBuf> {
+        let mut target = Vec::new();
+        layers::scan(&self.conn, &self.layers, &*self.pages, ino, 0, i64::MAX, &mut |start, bytes| {
+            let (start, end) = (start as usize, start as usize + bytes.len());
+            if target.len() < end {
+                target.resize(end, 0);
+            }
+            target[start..end].copy_from_slice(bytes);
+        })?;
+        Ok(PathBuf::from(OsString::from_vec(target)))
+    }
+
+    /// Fail with EDQUOT if growing a file to `end` bytes would take a directory it's under
+    /// past its user.elkridge.quota
+    fn ensure_quota(&mut self, ino: u64, end: i64) -> Fallible<()> {
+        if !self.any_quotas()? {
+            return Ok(());
+        }
+        let quotas = match quota::parent_of(&self.conn, ino)? {
+            Some(dir) => quota::over(&self.conn, dir)?,
+            None => return Ok(())
+        };
+        if quotas.is_empty() {
+            return Ok(());
+        }
+        let buffered = self.buffered_growth()?;
+        // Past what's stored and buffered already, which the quota's usage counts
+        let reached = buffered.iter().find(|&&(other, _)| other == ino).map_or(0, |&(_, bytes)| bytes as i64)
+            + self.conn.query_row("SELECT size FROM Inode WHERE inode = ?", [ino as i64], |row| row.get::<usize, i64>(0))?;
+        quota::check(&self.conn, &quotas, (end - reached).max(0) as u64, &buffered)
+    }
+
+    /// Whether any directory has a quota, so writes on a database without one skip the
+    /// lookups of ensure_quota
+    ///
+    /// The answer is kept until another connection commits or this one sets or removes a
+    /// quota. The lower layers' quotas count too, since a lookup can copy one up at any time.
+    fn any_quotas(&mut self) -> Fallible<bool> {
+        let version = self.data_version()?;
+        if let Some((known, any)) = self.quotas_known {
+            if known == version {
+                return Ok(any);
+            }
+        }
+        let mut any = false;
+        for schema in iter::once("main").chain(self.layers.iter().map(|layer| layer.schema.as_str())) {
+            any = any || self.conn.prepare_cached(&format!("SELECT EXISTS(SELECT 1 FROM {}.Xattr WHERE name = ?)", schema))?
+                .query_row([quota::XATTR], |row| row.get(0))?;
+        }
+        self.quotas_known = Some((version, any));
+        Ok(any)
+    }
+
+    /// How far each file with buffered writes has grown past its stored size, for quotas
+    fn buffered_growth(&self) -> Fallible<Vec<(u64, u64)>> {
+        let mut growth = Vec::new();
+        for (ino, end) in self.write_buffer.ends() {
+            let stored : i64 = self.conn.query_row("SELECT size FROM Inode WHERE inode = ?", [ino as i64], |row| row.get(0))?;
+            if end > stored {
+                growth.push((ino, (end - stored) as u64));
+            }
+        }
+        Ok(growth)
+    }
+
+    /// Refuse to change an inode's contents or xattrs under --read-only, or a synthetic one's
+    fn ensure_writable(&self, ino: u64) -> Fallible<()> {
+        require!(!self.refuses_changes() && !self.is_synthetic(ino), Error::ReadOnly);
+        Ok(())
+    }
+
+    /// Refuse to change anything under --read-only, or inside (or shadowed by) a synthetic
+    /// directory
+    fn ensure_mutable(&self, parent: u64, name: &OsStr) -> Fallible<()> {
+        let synthetic = self.is_synthetic(parent) || self.synthetic_dir(parent, name).is_some();
+        require!(!self.refuses_changes() && !synthetic, Error::ReadOnly);
+        Ok(())
+    }
+
+    /// Whether the database is to be left as it is, for --read-only or while writes are frozen
+    fn refuses_changes(&self) -> bool {
+        self.options.read_only || freeze::is_frozen()
+    }
+
+    /// Attributes of a synthetic inode, regenerating a file's content so its size is current
+    fn synthetic_attr(&mut self, ino: u64) -> Fallible<FileAttr> {
+        let is_dir = ino == TABLES_DIR_INODE || ino == CONTROL_DIR_INODE;
+        let size = if is_dir {
+            0
+        } else {
+            self.synthetic_dump(ino, true)?.len() as u64
+        };
+        Ok(tables::attr(ino, is_dir, size, self.mounted))
+    }
+
+    /// The content of a synthetic file: an exposed table's CSV dump, or a control file
+    ///
+    /// The last dump is kept so that reading a file in many chunks doesn't regenerate it
+    /// every time; lookup and getattr refresh it, which happens whenever the file is opened.
+    fn synthetic_dump(&mut self, ino: u64, refresh: bool) -> Fallible<&[u8]> {
+        let stale = match self.synthetic_dump {
+            Some((cached, _)) => refresh || cached != ino,
+            None => true
+        };
+        if stale {
+            let dump = if ino == control::HANDLES_INODE {
+                self.handles_dump()?
+            } else if ino == control::STATS_INODE {
+                self.stats_dump()?
+            } else {
+                let name = tables::name_of(&self.conn, ino)?;
+                tables::dump_csv(&self.conn, &name)?
+            };
+            self.synthetic_dump = Some((ino, dump));
+        }
+        Ok(self.synthetic_dump.as_ref().map(|(_, dump)| dump.as_slice()).unwrap_or(&[]))
+    }
+
+    /// The open handles as JSON, for /.elkridge/handles
+    fn handles_dump(&self) -> Fallible<Vec<u8>> {
+        let mut paths = HashMap::new();
+        for (_, handle) in self.handles.list() {
+            paths.insert(handle.ino, self.path_of(handle.ino)?);
+        }
+        Ok(control::handles_json(&self.handles, |ino| {
+            (self.inode_to_kernel(ino), paths.get(&ino).cloned().unwrap_or(None))
+        }))
+    }
+
+    /// The journal's settings and the WAL's state as JSON, for /.elkridge/stats
+    fn stats_dump(&self) -> Fallible<Vec<u8>> {
+        let journal_mode : String = self.conn.query_row("PRAGMA journal_mode", sql::NO_PARAMS, |row| row.get(0))?;
+        let autocheckpoint : i64 = self.conn.query_row("PRAGMA wal_autocheckpoint", sql::NO_PARAMS, |row| row.get(0))?;
+        let wal = checkpoint::wal_state(&self.conn)?;
+        Ok(control::stats_json(&journal_mode, autocheckpoint, wal.as_ref()))
+    }
+
+    /// Where an inode is under the presented root, like /a/b, or None if it has no name there
+    fn path_of(&self, ino: u64) -> Fallible<Option<String>> {
+        let mut names = Vec::new();
+        let mut at = ino;
+        while at != self.root {
+            let step : Option<(i64, String)> = self.conn.query_row(
+                "SELECT parent, name FROM Path WHERE inode = ?",
+                [at as i64],
+                |row| Ok((row.get(0)?, row.get(1)?))).optional()?;
+            match step {
+                Some((parent, name)) => {
+                    names.push(name);
+                    at = parent as u64;
+                },
+                None => return Ok(None)
+            }
+        }
+        names.reverse();
+        Ok(Some(format!("/{}", names.join("/"))))
+    }
+}
+
+/// Take the next inode number from the counter in Meta
+///
+/// Numbers only ever go up, so one that has been deleted is never handed out again,
+/// which would otherwise confuse NFS and anything else caching inode numbers. Call it
+/// inside the transaction that inserts the inode, so a rollback returns the number too.
+pub fn allocate_inode(conn: &sql::Connection) -> sql::Result<i64> {
+    loop {
+        conn.prepare_cached("UPDATE Meta SET value = value + 1 WHERE key = 'next_inode'")?
+            .execute(sql::NO_PARAMS)?;
+        let inode = conn.prepare_cached("SELECT value - 1 FROM Meta WHERE key = 'next_inode'")?
+            .query_row(sql::NO_PARAMS, |row| row.get(0))?;
+        // Stable inodes (see stable_inode) can be anywhere, including ahead of the counter
+        if !inode_exists(conn, inode)? {
+            return Ok(inode);
+        }
+    }
+}
+
+/// An inode number that depends only on the path the inode is created at, for --stable-inodes
+///
+/// The same tree built twice, in any database, gets the same numbers, which makes the
+/// archives and images made from it reproducible. The path is hashed into the range below
+/// the synthetic inodes, and a number already taken moves on to the next free one. Unlike
+/// allocate_inode's, a number comes back when a file is deleted and made again in its place.
+pub fn stable_inode(conn: &sql::Connection, path: &str) -> sql::Result<i64> {
+    // FNV-1a, which unlike std's hashers is fixed forever
+    let hash = path.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3));
+    // 0 is the stored root and 1 the kernel's name for it, so neither is ever handed out
+    let range = CONTROL_DIR_INODE - 2;
+    let mut inode = 2 + hash % range;
+    while inode_exists(conn, inode as i64)? {
+        inode = 2 + (inode - 1) % range;
+    }
+    Ok(inode as i64)
+}
+
+/// Number a new inode about to be named `name` in `parent`, as --stable-inodes says
+pub fn number_inode(conn: &sql::Connection, stable: bool, parent: u64, name: &OsStr) -> sql::Result<i64> {
+    if stable {
+        stable_inode(conn, &format!("{}/{}", database_path(conn, parent)?, name.to_string_lossy()))
+    } else {
+        allocate_inode(conn)
+    }
+}
+
+/// Where an inode is from the database's real root, like /a/b, or the empty string for the root
+pub fn database_path(conn: &sql::Connection, ino: u64) -> sql::Result<String> {
+    let mut names = Vec::new();
+    let mut at = ino as i64;
+    while at != ROOT_INODE as i64 {
+        let (parent, name) : (i64, String) = conn.prepare_cached("SELECT parent, name FROM Path WHERE inode = ?")?
+            .query_row([at], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        names.push(name);
+        at = parent;
+    }
+    Ok(names.iter().rev().map(|name| format!("/{}", name)).collect())
+}
+
+fn inode_exists(conn: &sql::Connection, inode: i64) -> sql::Result<bool> {
+    conn.prepare_cached("SELECT EXISTS(SELECT 1 FROM Inode WHERE inode = ?)")?
+        .query_row([inode], |row| row.get(0))
+}
+
+/// A random (version 4) UUID, in the usual hyphenated form
+pub fn generate_uuid() -> io::Result<String> {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex : Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(format!("{}-{}-{}-{}-{}",
+        hex[0..4].concat(), hex[4..6].concat(), hex[6..8].concat(), hex[8..10].concat(), hex[10..].concat()))
+}
+
+/// The uid, gid and permission bits for a new inode in `parent`
+///
+/// New inodes belong to whoever made them, except that in a setgid directory they take the
+/// directory's group instead, as POSIX has it, and new directories there stay setgid too.
+fn new_owner(conn: &sql::Connection, req: &Caller, parent: u64, perm: u32, is_dir: bool) -> sql::Result<(u32, u32, u32)> {
+    let (parent_perm, parent_gid) : (u32, u32) = conn.query_row(
+        "SELECT perm, gid FROM Inode WHERE inode = ?",
+        [parent as i64],
+        |row| Ok((row.get(0)?, row.get(1)?)))?;
+    if parent_perm & S_ISGID == 0 {
+        Ok((req.uid(), req.gid(), perm))
+    } else if is_dir {
+        Ok((req.uid(), parent_gid, perm | S_ISGID))
+    } else {
+        Ok((req.uid(), parent_gid, perm))
+    }
+}
+
+/// Whether the mode bits let this uid and gid access `attr` as `mask` says, access(2) style
+///
+/// Only the primary gid counts, since FUSE requests don't carry supplementary groups, and
+/// named ACL entries are ignored, as the kernel ignores them under default_permissions.
+fn mode_allows(attr: &FileAttr, uid: u32, gid: u32, mask: u32) -> bool {
+    let mask = mask & 0o7;
+    if uid == 0 {
+        // Root may read and write anything, but execute only what someone may execute
+        return mask & libc::X_OK as u32 == 0 || attr.kind == FileType::Directory || attr.perm & 0o111 != 0;
+    }
+    let bits = if uid == attr.uid {
+        attr.perm >> 6
+    } else if gid == attr.gid {
+        attr.perm >> 3
+    } else {
+        attr.perm
+    };
+    u32::from(bits) & mask == mask
+}
+
+/// Refuse to remove `inode` from `parent` if the parent is sticky, like /tmp, and the caller
+/// owns neither of them and isn't root
+///
+/// Unlike the rest of the mode bits this is checked with or without --enforce-permissions,
+/// since a shared sticky directory is only any use if it's always honored.
+fn ensure_sticky_allows(conn: &sql::Connection, req: &Caller, parent: u64, inode: i64) -> Fallible<()> {
+    let (parent_perm, parent_uid) : (u32, u32) = conn.query_row(
+        "SELECT perm, uid FROM Inode WHERE inode = ?",
+        [parent as i64],
+        |row| Ok((row.get(0)?, row.get(1)?)))?;
+    if parent_perm & S_ISVTX == 0 || req.uid() == 0 || req.uid() == parent_uid {
+        return Ok(());
+    }
+    let owner : u32 = conn.query_row("SELECT uid FROM Inode WHERE inode = ?", [inode], |row| row.get(0))?;
+    require!(req.uid() == owner, Error::PermissionDenied);
+    Ok(())
+}
+
+/// Find the inode and kind code of the entry called `name` in `parent`, if there is one
+fn find_child(conn: &sql::Connection, parent: u64, name: &OsStr) -> sql::Result<Option<(i64, i8)>> {
+    conn.query_row(
+        "SELECT inode, kind
+        FROM Path
+        NATURAL JOIN Inode
+        WHERE parent = ? AND name = ?",
+        &[
+            &(parent as i64) as &dyn sql::ToSql,
+            &name.to_string_lossy()
+        ],
+        |row| Ok((row.get("inode")?, row.get("kind")?))
+    ).optional()
+}
+
+/// Add a path's names to resolve_path's pending ones, to be looked up before them
+fn push_components(pending: &mut Vec<OsString>, path: &Path) -> Fallible<()> {
+    for component in path.components().rev() {
+        match component {
+            Component::RootDir | Component::CurDir => continue,
+            Component::Normal(name) => pending.push(name.to_os_string()),
+            Component::ParentDir => pending.push(OsString::from("..")),
+            Component::Prefix(_) => bail!("Unsupported path component in {}", path.display())
+        }
+    }
+    Ok(())
+}
+
+/// Whether `ino` is `dir` or anywhere under it, going up through the Path rows
+pub fn is_within(conn: &sql::Connection, ino: u64, dir: i64) -> sql::Result<bool> {
+    let mut at = ino as i64;
+    loop {
+        if at == dir {
+            return Ok(true);
+        }
+        if at == ROOT_INODE as i64 {
+            return Ok(false);
+        }
+        let parent : Option<i64> = conn.prepare_cached("SELECT parent FROM Path WHERE inode = ?")?
+            .query_row([at], |row| row.get(0))
+            .optional()?;
+        match parent {
+            Some(parent) => at = parent,
+            // Unlinked but still open, so under nothing
+            None => return Ok(false)
+        }
+    }
+}
+
+/// Give an existing Path row a new parent and name
+fn move_path(conn: &sql::Connection, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> sql::Result<()> {
+    conn.execute(
+        "UPDATE Path SET parent = ?, name = ? WHERE parent = ? AND name = ?;",
+        &[
+            &(newparent as i64) as &dyn sql::ToSql,
+            &newname.to_string_lossy(),
+            &(parent as i64),
+            &name.to_string_lossy()
+        ])?;
+    Ok(())
+}
+
+/// Store the bytes of one write in an open transaction
+///
+/// Pages stay aligned to PAGE_SIZE and never overlap: the aligned span around the write is
+/// read back, patched, and stored again, so small appends grow the last page rather than
+/// piling up tiny ones, and unaligned direct I/O writes need no special treatment. Any
+/// overlapping pages the span touches (left by some other writer) are merged on the way,
+/// the newest bytes winning, as they do for reads.
+fn put_span(txn: &sql::Transaction, layers: &[Layer], pages: &dyn PageStore, ino: u64, offset: i64, data: &[u8]) -> Fallible<()> {
+    let end = offset + data.len() as i64;
+    let span_start = offset - offset % PAGE_SIZE;
+    let span_end = (end + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+    let mut span = vec![0u8; (span_end - span_start) as usize];
+    // How much of the span will hold data, which is at least the write itself
+    let (mut lo, mut hi) = (offset, end);
+    // Parts of old pages that stick out of either end of the span and have to be kept
+    let mut before : Vec<(i64, Vec<u8>)> = vec![];
+    let mut after : Vec<(i64, Vec<u8>)> = vec![];
+
+    if offset < mime::SNIFF_LEN as i64 {
+        // A stored content type goes stale once the bytes it was detected from change
+        txn.execute(
+            "DELETE FROM Xattr WHERE inode = ? AND name = ?",
+            &[ &(ino as i64) as &dyn sql::ToSql, &mime::XATTR ])?;
+    }
+    layers::copy_up_content(txn, layers, pages, ino)?;
+    pages.scan(txn, ino, span_start, span_end, &mut |start, content| {
+        let finish = start + content.len() as i64;
+        if start < span_start {
+            before.push((start, content[.. (span_start - start) as usize].to_vec()));
+        }
+        if finish > span_end {
+            after.push((span_end, content[(span_end - start) as usize ..].to_vec()));
+        }
+        let (a, b) = (start.max(span_start), finish.min(span_end));
+        span[(a - span_start) as usize .. (b - span_start) as usize]
+            .copy_from_slice(&content[(a - start) as usize .. (b - start) as usize]);
+        lo = lo.min(a);
+        hi = hi.max(b);
+    })?;
+    span[(offset - span_start) as usize .. (end - span_start) as usize].copy_from_slice(data);
+    pages.delete(txn, ino, span_start, span_end)?;
+    for (start, content) in merge_pages(before).into_iter().chain(merge_pages(after)) {
+        pages.put(txn, ino, start, &content)?;
+    }
+    let mut page_start = lo;
+    while page_start < hi {
+        let page_end = (page_start / PAGE_SIZE + 1) * PAGE_SIZE;
+        let page_end = page_end.min(hi);
+        pages.put(txn, ino, page_start, &span[(page_start - span_start) as usize .. (page_end - span_start) as usize])?;
+        page_start = page_end;
+    }
+    txn.execute(
+        "UPDATE Inode SET
+            size = max(size, ?1),
+            blocks = (max(size, ?1) + 511) / 512,
+            mtime = strftime('%s'),
+            ctime = strftime('%s')
+        WHERE inode = ?2",
+        [end, ino as i64])?;
+    Ok(())
+}
+
+/// For --page-overlap-check, make sure a write or truncate left no pages of `ino` overlapping
+///
+/// Overlaps are logged as errors, and in debug builds they're a panic too, which fails the
+/// operation with EIO and rolls back the transaction it's in (see unpanicked).
+fn check_page_overlaps(conn: &sql::Connection, ino: u64) -> Fallible<()> {
+    let overlaps = fsck::check_page_overlaps(conn, Some(ino))?;
+    if let Some(overlap) = overlaps.first() {
+        error!("ino:{} has {} overlapping pages, the first at {}..{}; elkridge fsck --repair fixes them",
+            ino, overlaps.len(), overlap.start, overlap.finish);
+        debug_assert!(false, "Overlapping pages in ino:{}", ino);
+    }
+    Ok(())
+}
+
+/// Combine the parts of old pages left on one side of a write into a single page, later parts
+/// over earlier ones, as they come from PageStore::scan
+///
+/// Every part reaches the write's span, so together they cover one unbroken range.
+fn merge_pages(parts: Vec<(i64, Vec<u8>)>) -> Option<(i64, Vec<u8>)> {
+    let lo = parts.iter().map(|&(start, _)| start).min()?;
+    let hi = parts.iter().map(|&(start, ref content)| start + content.len() as i64).max()?;
+    let mut merged = vec![0u8; (hi - lo) as usize];
+    for (start, content) in parts {
+        merged[(start - lo) as usize ..][.. content.len()].copy_from_slice(&content);
+    }
+    Some((lo, merged))
+}
+
+/// Directory Entry, used as part of the return type of readdir()
+pub struct DirectoryEntry {
+    pub ino: u64,
+    pub offset: i64,
+    pub kind: FileType,
+    pub name: OsString
+}
+
+/// Filesystem-wide usage, the return type of statfs()
+///
+/// Block counts are in units of `frsize`.
+pub struct FilesystemStats {
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub bsize: u32,
+    pub namelen: u32,
+    pub frsize: u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use options::{NameForm, Options};
+
+    fn mounted(options: Options) -> Elkridge {
+        Elkridge::new(sql::Connection::open_in_memory().unwrap(), options).unwrap()
+    }
+
+    /// Truncate a file short and grow it again, checking no old bytes come back
+    fn regrow(mut fs: Elkridge) {
+        let root = Caller::new(0, 0, 0);
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        fs.write_basic(&root, file.ino, 0, 0, &[7; 10000]).unwrap();
+        fs.setattr_basic(&root, file.ino, AttrChanges{size: Some(10), ..Default::default()}).unwrap();
+        let grown = fs.setattr_basic(&root, file.ino, AttrChanges{size: Some(1000), ..Default::default()}).unwrap();
+        assert_eq!(grown.size, 1000);
+        let mut buf = vec![];
+        fs.read_basic(&root, file.ino, 0, 0, 20000, &mut buf).unwrap();
+        assert_eq!(buf.len(), 1000);
+        assert!(buf[..10].iter().all(|&byte| byte == 7));
+        assert!(buf[10..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn truncate_then_grow_reads_zeros() {
+        regrow(mounted(Options::default()));
+    }
+
+    #[test]
+    fn truncate_then_grow_reads_zeros_with_write_buffer() {
+        regrow(mounted(Options{write_buffer: Some(1 << 20), ..Options::default()}));
+    }
+
+    #[test]
+    fn short_files_give_short_reads() {
+        let mut fs = mounted(Options::default());
+        let root = Caller::new(0, 0, 0);
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        fs.write_basic(&root, file.ino, 0, 0, &[7; 100]).unwrap();
+        let (fh, _) = fs.open_basic(&root, file.ino, 0).unwrap();
+        let mut buf = vec![];
+        fs.read_basic(&root, file.ino, fh, 0, 4096, &mut buf).unwrap();
+        assert_eq!(buf, [7; 100]);
+        fs.read_basic(&root, file.ino, fh, 50, 4096, &mut buf).unwrap();
+        assert_eq!(buf.len(), 50);
+        fs.read_basic(&root, file.ino, fh, 200, 4096, &mut buf).unwrap();
+        assert!(buf.is_empty());
+        fs.read_basic(&root, file.ino, fh, i64::MAX - 10, 4096, &mut buf).unwrap();
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn setgid_directories_pass_on_their_group() {
+        let mut fs = mounted(Options::default());
+        let root = Caller::new(0, 0, 0);
+        let user = Caller::new(1000, 1000, 0);
+        let dir = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("shared"), 0o777).unwrap();
+        let changes = AttrChanges{mode: Some(libc::S_IFDIR | S_ISGID | 0o777), gid: Some(77), ..Default::default()};
+        fs.setattr_basic(&root, dir.ino, changes).unwrap();
+        let file = fs.mknod_basic(&user, dir.ino, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        assert_eq!((file.uid, file.gid), (1000, 77));
+        assert_eq!(u32::from(file.perm) & S_ISGID, 0);
+        let sub = fs.mkdir_basic(&user, dir.ino, OsStr::new("sub"), 0o755).unwrap();
+        assert_eq!(sub.gid, 77);
+        assert_ne!(u32::from(sub.perm) & S_ISGID, 0);
+        let plain = fs.mknod_basic(&user, ROOT_INODE, OsStr::new("plain"), libc::S_IFREG | 0o644, 0).unwrap();
+        assert_eq!(plain.gid, 1000);
+    }
+
+    #[test]
+    fn names_are_taken_literally() {
+        let mut fs = mounted(Options::default());
+        let root = Caller::new(0, 0, 0);
+        let names = [
+            "'; DROP TABLE Inode;--", "' OR '1'='1", "\"quoted\"", "semi;colon", "%_*?", "a\\b", "ünïcödé ☃",
+        ];
+        for name in &names {
+            let name = OsStr::new(name);
+            assert!(fs.lookup_basic(&root, ROOT_INODE, name).is_err());
+            let dir = fs.mkdir_basic(&root, ROOT_INODE, name, 0o755).unwrap();
+            assert_eq!(fs.lookup_basic(&root, ROOT_INODE, name).unwrap().ino, dir.ino);
+            let (file, _, _) = fs.create_basic(&root, dir.ino, name, libc::S_IFREG | 0o644, 0).unwrap();
+            assert_eq!(fs.lookup_basic(&root, dir.ino, name).unwrap().ino, file.ino);
+            let listed : Vec<OsString> = fs.readdir_basic(&root, dir.ino, 0, 0).unwrap()
+                .into_iter().map(|entry| entry.name).collect();
+            assert!(listed.iter().any(|listed| listed == name), "{:?} not in {:?}", name, listed);
+        }
+        let listed = fs.readdir_basic(&root, ROOT_INODE, 0, 0).unwrap();
+        for name in &names {
+            assert!(listed.iter().any(|entry| entry.name == OsStr::new(name)));
+        }
+        // No name from the kernel holds a NUL, and one can't be bound, so it's refused
+        let nul = OsStr::new("nul\0inside");
+        let refused = |fs: &Elkridge, result: Fallible<FileAttr>| errors::errno_for(&fs.describe(result.unwrap_err()));
+        let found = fs.lookup_basic(&root, ROOT_INODE, nul);
+        assert_eq!(refused(&fs, found), libc::EINVAL);
+        let made = fs.mkdir_basic(&root, ROOT_INODE, nul, 0o755);
+        assert_eq!(refused(&fs, made), libc::EINVAL);
+        let made = fs.create_basic(&root, ROOT_INODE, nul, libc::S_IFREG | 0o644, 0).map(|(attr, _, _)| attr);
+        assert_eq!(refused(&fs, made), libc::EINVAL);
+        let inodes : i64 = fs.conn.query_row("SELECT count(*) FROM Inode", sql::NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(inodes, 1 + 2 * names.len() as i64);
+    }
+
+    #[test]
+    fn setting_only_atime_leaves_mtime() {
+        let mut fs = mounted(Options::default());
+        let root = Caller::new(0, 0, 0);
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        // Long ago, so a change made now shows even at whole seconds
+        fs.conn.execute("UPDATE Inode SET atime = 1000, mtime = 2000, ctime = 3000 WHERE inode = ?", [file.ino as i64]).unwrap();
+        fs.attr_cache.forget(file.ino);
+        let changes = AttrChanges{atime: Some(Timespec::new(5000, 0)), ..Default::default()};
+        let attr = fs.setattr_basic(&root, file.ino, changes).unwrap();
+        assert_eq!(attr.atime.sec, 5000);
+        assert_eq!(attr.mtime, Timespec::new(2000, 0));
+        assert!(attr.ctime.sec > 3000);
+        assert_eq!(fs.getattr_basic(&root, file.ino).unwrap().mtime, Timespec::new(2000, 0));
+    }
+
+    #[test]
+    fn directories_cant_move_into_themselves() {
+        let mut fs = mounted(Options::default());
+        let root = Caller::new(0, 0, 0);
+        let a = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("a"), 0o755).unwrap();
+        let b = fs.mkdir_basic(&root, a.ino, OsStr::new("b"), 0o755).unwrap();
+        let c = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("c"), 0o755).unwrap();
+        let errno = |fs: &Elkridge, result: Fallible<()>| errors::errno_for(&fs.describe(result.unwrap_err()));
+        let moved = fs.rename_basic(&root, ROOT_INODE, OsStr::new("a"), b.ino, OsStr::new("a"), 0);
+        assert_eq!(errno(&fs, moved), libc::EINVAL);
+        let moved = fs.rename_basic(&root, ROOT_INODE, OsStr::new("a"), a.ino, OsStr::new("x"), 0);
+        assert_eq!(errno(&fs, moved), libc::EINVAL);
+        let swapped = fs.rename_basic(&root, a.ino, OsStr::new("b"), ROOT_INODE, OsStr::new("a"), RENAME_EXCHANGE);
+        assert_eq!(errno(&fs, swapped), libc::EINVAL);
+        assert_eq!(fs.lookup_basic(&root, a.ino, OsStr::new("b")).unwrap().ino, b.ino);
+
+        fs.rename_basic(&root, ROOT_INODE, OsStr::new("a"), c.ino, OsStr::new("a"), 0).unwrap();
+        assert_eq!(fs.lookup_basic(&root, c.ino, OsStr::new("a")).unwrap().ino, a.ino);
+        assert_eq!(fs.lookup_basic(&root, a.ino, OsStr::new("b")).unwrap().ino, b.ino);
+        assert!(fs.lookup_basic(&root, ROOT_INODE, OsStr::new("a")).is_err());
+        assert_eq!(fs.getattr_basic(&root, c.ino).unwrap().nlink, 3);
+    }
+
+    #[test]
+    fn quotas_count_from_when_they_are_set() {
+        let mut fs = mounted(Options::default());
+        let root = Caller::new(0, 0, 0);
+        let dir = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("dir"), 0o755).unwrap();
+        let file = fs.mknod_basic(&root, dir.ino, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        // Written while there are no quotas, so the answer that there are none is cached
+        fs.write_basic(&root, file.ino, 0, 0, &[7; 100]).unwrap();
+        let quota = OsStr::new(quota::XATTR);
+        fs.setxattr_basic(&root, dir.ino, quota, b"1000", 0, 0).unwrap();
+        let errno = |fs: &Elkridge, result: Fallible<u32>| errors::errno_for(&fs.describe(result.unwrap_err()));
+        let written = fs.write_basic(&root, file.ino, 0, 100, &[7; 1000]);
+        assert_eq!(errno(&fs, written), libc::EDQUOT);
+        fs.write_basic(&root, file.ino, 0, 100, &[7; 900]).unwrap();
+        fs.removexattr_basic(&root, dir.ino, quota).unwrap();
+        fs.write_basic(&root, file.ino, 0, 1000, &[7; 1000]).unwrap();
+    }
+
+    #[test]
+    fn link_counts_saturate() {
+        let mut fs = mounted(Options::default());
+        let root = Caller::new(0, 0, 0);
+        let dir = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("dir"), 0o755).unwrap();
+        let file = fs.mknod_basic(&root, dir.ino, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        assert_eq!(fs.getattr_basic(&root, file.ino).unwrap().nlink, 1);
+        fs.conn.execute("UPDATE Inode SET child_count = ? WHERE inode = ?", [1i64 << 40, dir.ino as i64]).unwrap();
+        assert_eq!(fs.getattr_basic(&root, dir.ino).unwrap().nlink, u32::MAX);
+    }
+
+    #[test]
+    fn rename_honours_noreplace_and_exchange() {
+        let mut fs = mounted(Options::default());
+        let root = Caller::new(0, 0, 0);
+        let dir = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("dir"), 0o755).unwrap();
+        let a = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("a"), libc::S_IFREG | 0o644, 0).unwrap();
+        let b = fs.mknod_basic(&root, dir.ino, OsStr::new("b"), libc::S_IFREG | 0o644, 0).unwrap();
+        let errno = |fs: &Elkridge, result: Fallible<()>| errors::errno_for(&fs.describe(result.unwrap_err()));
+        let moved = fs.rename_basic(&root, ROOT_INODE, OsStr::new("a"), dir.ino, OsStr::new("b"), RENAME_NOREPLACE);
+        assert_eq!(errno(&fs, moved), libc::EEXIST);
+        assert_eq!(fs.lookup_basic(&root, dir.ino, OsStr::new("b")).unwrap().ino, b.ino);
+        assert_eq!(fs.lookup_basic(&root, ROOT_INODE, OsStr::new("a")).unwrap().ino, a.ino);
+
+        fs.rename_basic(&root, ROOT_INODE, OsStr::new("a"), dir.ino, OsStr::new("b"), RENAME_EXCHANGE).unwrap();
+        assert_eq!(fs.lookup_basic(&root, dir.ino, OsStr::new("b")).unwrap().ino, a.ino);
+        assert_eq!(fs.lookup_basic(&root, ROOT_INODE, OsStr::new("a")).unwrap().ino, b.ino);
+        let parked : i64 = fs.conn.query_row("SELECT count(*) FROM Path WHERE name LIKE '/%'", sql::NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(parked, 0);
+        let swapped = fs.rename_basic(&root, ROOT_INODE, OsStr::new("a"), dir.ino, OsStr::new("missing"), RENAME_EXCHANGE);
+        assert_eq!(errno(&fs, swapped), libc::ENOENT);
+    }
+
+    /// Write through a handle after it's been released, as a writeback cache would
+    fn write_after_release(mut fs: Elkridge) {
+        let root = Caller::new(0, 0, 0);
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        let (fh, _) = fs.open_basic(&root, file.ino, libc::O_WRONLY as u32).unwrap();
+        fs.release_basic(&root, file.ino, fh).unwrap();
+        assert_eq!(fs.write_basic(&root, file.ino, fh, 0, b"late").unwrap(), 4);
+        fs.flush_writes(None).unwrap();
+        let pages : i64 = fs.conn.query_row("SELECT count(*) FROM Page WHERE inode = ?", [file.ino as i64], |row| row.get(0)).unwrap();
+        assert_eq!(pages, 1);
+        let mut buf = vec![];
+        fs.read_basic(&root, file.ino, 0, 0, 100, &mut buf).unwrap();
+        assert_eq!(buf, b"late");
+    }
+
+    #[test]
+    fn writes_after_release_are_stored() {
+        write_after_release(mounted(Options::default()));
+    }
+
+    #[test]
+    fn writes_after_release_are_stored_with_write_buffer() {
+        write_after_release(mounted(Options{write_buffer: Some(1 << 20), ..Options::default()}));
+    }
+
+    #[test]
+    fn fsync_and_release_store_buffered_writes() {
+        let mut fs = mounted(Options{write_buffer: Some(1 << 20), ..Options::default()});
+        let root = Caller::new(0, 0, 0);
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        let stored = |fs: &Elkridge| -> Vec<u8> {
+            let pages : Vec<Vec<u8>> = fs.conn.prepare("SELECT content FROM Page WHERE inode = ? ORDER BY start").unwrap()
+                .query_map([file.ino as i64], |row| row.get(0)).unwrap()
+                .collect::<sql::Result<_>>().unwrap();
+            pages.concat()
+        };
+        // The writes a shared mapping's dirty pages come back as, then msync's fsync
+        let (fh, _) = fs.open_basic(&root, file.ino, libc::O_RDWR as u32).unwrap();
+        fs.write_basic(&root, file.ino, fh, 0, b"mapped").unwrap();
+        assert!(stored(&fs).is_empty());
+        fs.fsync_basic(&root, file.ino, fh).unwrap();
+        assert_eq!(stored(&fs), b"mapped");
+        // And unmapping after close, which release comes after
+        fs.write_basic(&root, file.ino, fh, 0, b"MAPPED").unwrap();
+        fs.release_basic(&root, file.ino, fh).unwrap();
+        assert_eq!(stored(&fs), b"MAPPED");
+    }
+
+    #[test]
+    fn disk_images_round_trip_block_writes() {
+        let mut fs = mounted(Options::default());
+        let root = Caller::new(0, 0, 0);
+        let image = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("image"), libc::S_IFREG | 0o644, 0).unwrap();
+        const SIZE: usize = 1 << 20;
+        fs.setattr_basic(&root, image.ino, AttrChanges{size: Some(SIZE as u64), ..Default::default()}).unwrap();
+        let mut expected = vec![0u8; SIZE];
+        // A fixed sequence of 512 B to 8 KiB blocks at 512 B aligned offsets, so most cross pages
+        let mut seed = 1u64;
+        for _ in 0..200 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let len = 512 << ((seed >> 60) % 5);
+            let offset = (seed >> 20) as usize % (SIZE / 512) * 512;
+            let len = len.min(SIZE - offset);
+            let block : Vec<u8> = (0..len).map(|i| (seed >> 8) as u8 ^ i as u8).collect();
+            assert_eq!(fs.write_basic(&root, image.ino, 0, offset as i64, &block).unwrap() as usize, len);
+            expected[offset..offset + len].copy_from_slice(&block);
+        }
+        assert_eq!(fs.getattr_basic(&root, image.ino).unwrap().size, SIZE as u64);
+        let mut buf = vec![];
+        for offset in (0..SIZE).step_by(1 << 16) {
+            fs.read_basic(&root, image.ino, 0, offset as i64, 1 << 16, &mut buf).unwrap();
+            assert!(buf == expected[offset..offset + (1 << 16)], "differs in the 64 KiB at {}", offset);
+        }
+    }
+
+    #[test]
+    fn forgetting_reclaims_unlinked_inodes_once_unreferenced() {
+        let mut fs = mounted(Options::default());
+        let root = Caller::new(0, 0, 0);
+        let mut files = vec![];
+        for (name, lookups) in &[("a", 1), ("b", 2), ("c", 1)] {
+            let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new(name), libc::S_IFREG | 0o644, 0).unwrap();
+            // As the lookup callback counts each entry it answers with
+            for _ in 0..*lookups {
+                fs.handles.remember_lookup(file.ino);
+            }
+            fs.unlink_basic(&root, ROOT_INODE, OsStr::new(name)).unwrap();
+            files.push(file.ino);
+        }
+        let exists = |fs: &Elkridge, ino: u64| -> bool {
+            fs.conn.query_row("SELECT count(*) > 0 FROM Inode WHERE inode = ?", [ino as i64], |row| row.get(0)).unwrap()
+        };
+        assert!(files.iter().all(|&ino| exists(&fs, ino)));
+        for &ino in &files {
+            fs.forget_basic(&root, ino, 1).unwrap();
+        }
+        assert!(!exists(&fs, files[0]));
+        assert!(exists(&fs, files[1]));
+        assert!(!exists(&fs, files[2]));
+        fs.forget_basic(&root, files[1], 1).unwrap();
+        assert!(!exists(&fs, files[1]));
+    }
+
+    /// Every name in a directory, listed the way the kernel does: from the offset of the last
+    /// entry it took, which is `taken` of each batch
+    fn list_resumed(fs: &mut Elkridge, dir: u64, taken: usize) -> Vec<OsString> {
+        let root = Caller::new(0, 0, 0);
+        let (mut names, mut offset) = (vec![], 0);
+        loop {
+            let batch = fs.readdir_basic(&root, dir, 0, offset).unwrap();
+            if batch.is_empty() {
+                return names;
+            }
+            for entry in batch.into_iter().take(taken) {
+                offset = entry.offset;
+                names.push(entry.name);
+            }
+        }
+    }
+
+    #[test]
+    fn resumed_listings_keep_the_readdir_order() {
+        let orders = [ReaddirOrder::Natural, ReaddirOrder::Name, ReaddirOrder::Mtime, ReaddirOrder::Inode];
+        for &order in &orders {
+            let mut fs = mounted(Options{readdir_order: order, ..Options::default()});
+            let root = Caller::new(0, 0, 0);
+            let dir = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("dir"), 0o755).unwrap();
+            // Made out of name order, with mtimes out of both orders
+            let mut made = vec![];
+            for i in 0..300u32 {
+                let name = format!("{:03}", i * 7 % 300);
+                let file = fs.mknod_basic(&root, dir.ino, OsStr::new(&name), libc::S_IFREG | 0o644, 0).unwrap();
+                let mtime = Timespec::new(i64::from(i * 11 % 300) / 3, 0);
+                fs.setattr_basic(&root, file.ino, AttrChanges{mtime: Some(mtime), ..Default::default()}).unwrap();
+                made.push((name, file.ino, mtime.sec));
+            }
+            match order {
+                ReaddirOrder::Natural => (),
+                ReaddirOrder::Name => made.sort_by(|a, b| a.0.cmp(&b.0)),
+                ReaddirOrder::Mtime => made.sort_by(|a, b| (a.2, &a.0).cmp(&(b.2, &b.0))),
+                ReaddirOrder::Inode => made.sort_by_key(|entry| entry.1)
+            }
+            let expected : Vec<OsString> = made.into_iter().map(|(name, ..)| name.into()).collect();
+            assert_eq!(list_resumed(&mut fs, dir.ino, usize::MAX), expected, "{:?}", order);
+            // As when the kernel's reply fills up partway through a batch
+            assert_eq!(list_resumed(&mut fs, dir.ino, 50), expected, "{:?}", order);
+        }
+    }
+
+    #[test]
+    fn listings_carry_on_past_entries_deleted_since() {
+        let mut fs = mounted(Options{readdir_order: ReaddirOrder::Name, ..Options::default()});
+        let root = Caller::new(0, 0, 0);
+        let dir = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("dir"), 0o755).unwrap();
+        for i in 0..300 {
+            fs.mknod_basic(&root, dir.ino, OsStr::new(&format!("{:03}", i)), libc::S_IFREG | 0o644, 0).unwrap();
+        }
+        // As rm -r does, removing each batch before asking for the next
+        let mut offset = 0;
+        let mut removed = 0;
+        loop {
+            let batch = fs.readdir_basic(&root, dir.ino, 0, offset).unwrap();
+            match batch.last() {
+                Some(last) => offset = last.offset,
+                None => break
+            }
+            for entry in &batch {
+                fs.unlink_basic(&root, dir.ino, &entry.name).unwrap();
+                removed += 1;
+            }
+        }
+        assert_eq!(removed, 300);
+        fs.rmdir_basic(&root, ROOT_INODE, OsStr::new("dir")).unwrap();
+    }
+
+    #[test]
+    fn writes_past_max_file_size_are_refused() {
+        let mut fs = mounted(Options{max_file_size: Some(1000), ..Options::default()});
+        let root = Caller::new(0, 0, 0);
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        fs.write_basic(&root, file.ino, 0, 0, &[7; 100]).unwrap();
+        let errno = |fs: &Elkridge, result: Fallible<u32>| errors::errno_for(&fs.describe(result.unwrap_err()));
+        for &offset in &[1000, 5000, i64::MAX] {
+            let written = fs.write_basic(&root, file.ino, 0, offset, &[7; 10]);
+            assert_eq!(errno(&fs, written), libc::EFBIG);
+        }
+        assert_eq!(fs.getattr_basic(&root, file.ino).unwrap().size, 100);
+        // A limit past what i64 holds is no limit, not a negative one
+        let mut fs = mounted(Options{max_file_size: Some(u64::MAX), ..Options::default()});
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        assert_eq!(fs.write_basic(&root, file.ino, 0, 0, &[7; 10]).unwrap(), 10);
+    }
+
+    #[test]
+    fn writes_crossing_max_file_size_are_short() {
+        let mut fs = mounted(Options{max_file_size: Some(1000), ..Options::default()});
+        let root = Caller::new(0, 0, 0);
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        assert_eq!(fs.write_basic(&root, file.ino, 0, 900, &[7; 300]).unwrap(), 100);
+        assert_eq!(fs.getattr_basic(&root, file.ino).unwrap().size, 1000);
+        let mut buf = vec![];
+        fs.read_basic(&root, file.ino, 0, 0, 4096, &mut buf).unwrap();
+        assert_eq!(buf.len(), 1000);
+        assert!(buf[..900].iter().all(|&byte| byte == 0));
+        assert!(buf[900..].iter().all(|&byte| byte == 7));
+    }
+
+    #[test]
+    fn statfs_counts_pages_and_inodes_apart() {
+        let db = ::tests::Scratch::new("statfs");
+        let mut fs = Elkridge::new(db.open(), Options{capacity: Some(1 << 20), ..Options::default()}).unwrap();
+        let root = Caller::new(0, 0, 0);
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        fs.write_basic(&root, file.ino, 0, 0, &[7; 10000]).unwrap();
+        let (page_count, page_size, freelist) : (i64, i64, i64) = fs.conn.query_row(
+            "SELECT page_count, page_size, freelist_count FROM pragma_page_count, pragma_page_size, pragma_freelist_count",
+            sql::NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap();
+        // The disk has more room than --capacity leaves, so that's what's free
+        let free = (1 << 20) + (freelist - page_count) * page_size;
+        let stats = fs.statfs_basic(&root, ROOT_INODE).unwrap();
+        assert_eq!((stats.bsize, stats.frsize), (4096, 4096));
+        assert_eq!(stats.bfree, free as u64 / 4096);
+        assert_eq!(stats.blocks, 3 + stats.bfree);
+        assert_eq!(stats.files, 2);
+        assert_eq!(stats.ffree, free as u64 / INODE_BYTES);
+    }
+
+    #[test]
+    fn deleting_a_file_frees_its_blocks_at_once() {
+        let db = ::tests::Scratch::new("statfs-delete");
+        let mut fs = Elkridge::new(db.open(), Options{capacity: Some(4 << 20), ..Options::default()}).unwrap();
+        let root = Caller::new(0, 0, 0);
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        fs.write_basic(&root, file.ino, 0, 0, &[7; 1 << 20]).unwrap();
+        let before = fs.statfs_basic(&root, ROOT_INODE).unwrap();
+        let size = fs.database_bytes().unwrap();
+        fs.unlink_basic(&root, ROOT_INODE, OsStr::new("file")).unwrap();
+        let after = fs.statfs_basic(&root, ROOT_INODE).unwrap();
+        // Still the same size on disk, with the pages on the freelist
+        assert_eq!(fs.database_bytes().unwrap(), size);
+        assert!(after.bfree >= before.bfree + 250, "{} free blocks before, {} after", before.bfree, after.bfree);
+    }
+
+
+    #[test]
+    fn symlinks_read_back_and_resolve_with_or_without_following() {
+        let mut fs = mounted(Options::default());
+        let root = Caller::new(0, 0, 0);
+        let errno = |fs: &Elkridge, result: Fallible<Vec<u8>>| errors::errno_for(&fs.describe(result.unwrap_err()));
+        let dir = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("d"), 0o755).unwrap();
+        let file = fs.mknod_basic(&root, dir.ino, OsStr::new("e"), libc::S_IFREG | 0o644, 0).unwrap();
+        let link = fs.symlink_basic(&root, ROOT_INODE, OsStr::new("ed"), Path::new("d/e")).unwrap();
+        assert_eq!(link.kind, FileType::Symlink);
+        assert_eq!(link.size, 3);
+        assert_eq!(fs.readlink_basic(&root, link.ino).unwrap(), b"d/e");
+        let result = fs.readlink_basic(&root, file.ino);
+        assert_eq!(errno(&fs, result), libc::EINVAL);
+
+        assert_eq!(fs.resolve_path(Path::new("ed"), false).unwrap(), (link.ino, 5));
+        assert_eq!(fs.resolve_path(Path::new("ed"), true).unwrap(), (file.ino, 4));
+        fs.symlink_basic(&root, ROOT_INODE, OsStr::new("loop1"), Path::new("loop2")).unwrap();
+        fs.symlink_basic(&root, ROOT_INODE, OsStr::new("loop2"), Path::new("/loop1")).unwrap();
+        let looped = fs.resolve_path(Path::new("loop1"), true).unwrap_err();
+        assert_eq!(errors::errno_for(&fs.describe(looped)), libc::ELOOP);
+    }
+
+
+    #[test]
+    fn shared_chunks_are_stored_once_and_collected_with_their_last_page() {
+        let dir = std::env::temp_dir().join(format!("elkridge-test-{}-chunks", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut fs = mounted(Options{blob_dir: Some(dir.clone()), ..Options::default()});
+        let root = Caller::new(0, 0, 0);
+        let chunks = || -> usize {
+            std::fs::read_dir(&dir).unwrap()
+                .map(|shard| std::fs::read_dir(shard.unwrap().path()).unwrap().count())
+                .sum()
+        };
+        for name in &["a", "b"] {
+            let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new(name), libc::S_IFREG | 0o644, 0).unwrap();
+            fs.write_basic(&root, file.ino, 0, 0, &[7; PAGE_SIZE as usize]).unwrap();
+        }
+        assert_eq!(chunks(), 1);
+        let dead_chunks = |fs: &Elkridge| -> i64 {
+            fs.conn.query_row("SELECT count(*) FROM DeadChunk", sql::NO_PARAMS, |row| row.get(0)).unwrap()
+        };
+        fs.unlink_basic(&root, ROOT_INODE, OsStr::new("a")).unwrap();
+        assert_eq!(chunks(), 1);
+        // Still listed, since b shares it
+        assert_eq!(dead_chunks(&fs), 1);
+        let b = fs.lookup_basic(&root, ROOT_INODE, OsStr::new("b")).unwrap();
+        let mut buf = vec![];
+        fs.read_basic(&root, b.ino, 0, 0, PAGE_SIZE as u32, &mut buf).unwrap();
+        assert_eq!(buf, vec![7; PAGE_SIZE as usize]);
+        fs.unlink_basic(&root, ROOT_INODE, OsStr::new("b")).unwrap();
+        assert_eq!(chunks(), 0);
+        assert_eq!(dead_chunks(&fs), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+
+    #[test]
+    fn child_counts_follow_mkdir_unlink_and_rename() {
+        let mut fs = mounted(Options::default());
+        let root = Caller::new(0, 0, 0);
+        let errno = |fs: &Elkridge, result: Fallible<()>| errors::errno_for(&fs.describe(result.unwrap_err()));
+        let children = |fs: &Elkridge, dir: u64| -> i64 {
+            fs.conn.query_row("SELECT child_count FROM Inode WHERE inode = ?", [dir as i64], |row| row.get(0)).unwrap()
+        };
+        let a = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("a"), 0o755).unwrap();
+        let b = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("b"), 0o755).unwrap();
+        fs.mkdir_basic(&root, a.ino, OsStr::new("sub"), 0o755).unwrap();
+        fs.mknod_basic(&root, a.ino, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        assert_eq!(children(&fs, a.ino), 2);
+        assert_eq!(fs.getattr_basic(&root, a.ino).unwrap().nlink, 4);
+
+        fs.rename_basic(&root, a.ino, OsStr::new("file"), b.ino, OsStr::new("moved"), 0).unwrap();
+        assert_eq!((children(&fs, a.ino), children(&fs, b.ino)), (1, 1));
+        let result = fs.rmdir_basic(&root, ROOT_INODE, OsStr::new("b"));
+        assert_eq!(errno(&fs, result), libc::ENOTEMPTY);
+
+        fs.unlink_basic(&root, b.ino, OsStr::new("moved")).unwrap();
+        assert_eq!(children(&fs, b.ino), 0);
+        fs.rmdir_basic(&root, ROOT_INODE, OsStr::new("b")).unwrap();
+        let result = fs.rmdir_basic(&root, ROOT_INODE, OsStr::new("a"));
+        assert_eq!(errno(&fs, result), libc::ENOTEMPTY);
+        fs.rmdir_basic(&root, a.ino, OsStr::new("sub")).unwrap();
+        assert_eq!(children(&fs, a.ino), 0);
+        fs.rmdir_basic(&root, ROOT_INODE, OsStr::new("a")).unwrap();
+    }
+
+
+    /// A file's atime after one read under `policy`, with the times it had before
+    fn atime_after_read(policy: AtimePolicy, atime: i64, mtime: i64, ctime: i64) -> i64 {
+        let mut fs = mounted(Options{atime: policy, ..Options::default()});
+        let root = Caller::new(0, 0, 0);
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        fs.write_basic(&root, file.ino, 0, 0, b"content").unwrap();
+        fs.conn.execute("UPDATE Inode SET atime = ?, mtime = ?, ctime = ? WHERE inode = ?",
+            [atime, mtime, ctime, file.ino as i64]).unwrap();
+        let (fh, _) = fs.open_basic(&root, file.ino, 0).unwrap();
+        fs.read_basic(&root, file.ino, fh, 0, 100, &mut vec![]).unwrap();
+        fs.getattr_basic(&root, file.ino).unwrap().atime.sec
+    }
+
+    #[test]
+    fn reads_update_atime_as_the_policy_says() {
+        let now = time::get_time().sec;
+        let (recent, old) = (now - 100, now - 2 * 86400);
+        // Older than mtime
+        assert_eq!(atime_after_read(AtimePolicy::Never, old, recent, old), old);
+        assert!(atime_after_read(AtimePolicy::Relative, old, recent, old) >= now);
+        assert!(atime_after_read(AtimePolicy::Strict, old, recent, old) >= now);
+        // Newer than mtime and ctime, but more than a day old
+        assert_eq!(atime_after_read(AtimePolicy::Never, old, old - 10, old - 10), old);
+        assert!(atime_after_read(AtimePolicy::Relative, old, old - 10, old - 10) >= now);
+        // Newer than both and recent
+        assert_eq!(atime_after_read(AtimePolicy::Never, recent, old, old), recent);
+        assert_eq!(atime_after_read(AtimePolicy::Relative, recent, old, old), recent);
+        assert!(atime_after_read(AtimePolicy::Strict, recent, old, old) >= now);
+    }
+
+
+    #[test]
+    fn noatime_handles_leave_atime_alone() {
+        let mut fs = mounted(Options{atime: AtimePolicy::Strict, ..Options::default()});
+        let (root, other) = (Caller::new(0, 0, 0), Caller::new(1000, 1000, 0));
+        let errno = |fs: &Elkridge, result: Fallible<(u64, u32)>| errors::errno_for(&fs.describe(result.unwrap_err()));
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        fs.conn.execute("UPDATE Inode SET atime = 1000 WHERE inode = ?", [file.ino as i64]).unwrap();
+        fs.attr_cache.clear();
+        let (quiet, _) = fs.open_basic(&root, file.ino, libc::O_NOATIME as u32).unwrap();
+        fs.read_basic(&root, file.ino, quiet, 0, 100, &mut vec![]).unwrap();
+        assert_eq!(fs.getattr_basic(&root, file.ino).unwrap().atime.sec, 1000);
+        // Only the owner may ask
+        let result = fs.open_basic(&other, file.ino, libc::O_NOATIME as u32);
+        assert_eq!(errno(&fs, result), libc::EPERM);
+        let (loud, _) = fs.open_basic(&root, file.ino, 0).unwrap();
+        fs.read_basic(&root, file.ino, loud, 0, 100, &mut vec![]).unwrap();
+        assert!(fs.getattr_basic(&root, file.ino).unwrap().atime.sec > 1000);
+    }
+
+
+    /// The errno of the write that finds the database full, checking what did fit is kept
+    fn fill_up(mut fs: Elkridge) -> i32 {
+        let root = Caller::new(0, 0, 0);
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        let chunk = vec![7; 64 << 10];
+        let mut offset = 0;
+        let failed = loop {
+            assert!(offset < 1 << 20, "{} bytes fit in 256 KiB", offset);
+            match fs.write_basic(&root, file.ino, 0, offset, &chunk) {
+                Ok(written) => offset += i64::from(written),
+                Err(e) => break e
+            }
+        };
+        assert_eq!(fs.getattr_basic(&root, file.ino).unwrap().size, offset as u64);
+        errors::errno_for(&fs.describe(failed))
+    }
+
+    #[test]
+    fn a_full_database_answers_enospc() {
+        let conn = sql::Connection::open_in_memory().unwrap();
+        conn.query_row("PRAGMA max_page_count = 64", sql::NO_PARAMS, |row| row.get::<usize, i64>(0)).unwrap();
+        assert_eq!(fill_up(Elkridge::new(conn, Options::default()).unwrap()), libc::ENOSPC);
+        // Reaching --capacity first is the quota running out instead
+        assert_eq!(fill_up(mounted(Options{capacity: Some(256 << 10), ..Options::default()})), libc::EDQUOT);
+    }
+
+
+    #[test]
+    fn inode_numbers_are_not_reused_after_a_reopen() {
+        let db = ::tests::Scratch::new("inode-numbers");
+        let root = Caller::new(0, 0, 0);
+        let last = {
+            let mut fs = Elkridge::new(db.open(), Options::default()).unwrap();
+            let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+            fs.unlink_basic(&root, ROOT_INODE, OsStr::new("file")).unwrap();
+            file.ino
+        };
+        let mut fs = Elkridge::new(db.open(), Options::default()).unwrap();
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        assert!(file.ino > last, "{} came after {}", file.ino, last);
+        assert_ne!(file.ino, ROOT_INODE);
+    }
+
+
+    #[test]
+    fn a_png_header_gives_image_png() {
+        for &cache_mime in &[false, true] {
+            let mut fs = mounted(Options{cache_mime, ..Options::default()});
+            let root = Caller::new(0, 0, 0);
+            let mime = OsStr::new(mime::XATTR);
+            let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("image"), libc::S_IFREG | 0o644, 0).unwrap();
+            fs.write_basic(&root, file.ino, 0, 0, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+            assert_eq!(fs.getxattr_basic(&root, file.ino, mime).unwrap(), b"image/png");
+            assert!(fs.listxattr_basic(&root, file.ino).unwrap().starts_with(b"user.mime_type\0"));
+            let result = fs.setxattr_basic(&root, file.ino, mime, b"text/plain", 0, 0);
+            assert_eq!(errors::errno_for(&fs.describe(result.unwrap_err())), libc::EACCES);
+            // Writing over the start is detected again, cached or not
+            fs.write_basic(&root, file.ino, 0, 0, b"plain text, now").unwrap();
+            assert_eq!(fs.getxattr_basic(&root, file.ino, mime).unwrap(), b"text/plain");
+        }
+    }
+
+
+    #[test]
+    fn crtime_never_changes() {
+        let mut fs = mounted(Options::default());
+        let root = Caller::new(0, 0, 0);
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        fs.write_basic(&root, file.ino, 0, 0, b"content").unwrap();
+        let changes = AttrChanges{mode: Some(0o600), size: Some(3), mtime: Some(Timespec::new(5, 0)), ..Default::default()};
+        let attr = fs.setattr_basic(&root, file.ino, changes).unwrap();
+        assert_eq!(attr.crtime, file.crtime);
+        assert_eq!(attr.mtime.sec, 5);
+        let rewritten = fs.conn.execute("UPDATE Inode SET crtime = crtime + 1 WHERE inode = ?", [file.ino as i64]);
+        assert!(rewritten.is_err());
+        fs.attr_cache.clear();
+        assert_eq!(fs.getattr_basic(&root, file.ino).unwrap().crtime, file.crtime);
+    }
+
+
+    /// Write, overwrite, truncate and grow a file, checking every read against the same
+    /// operations done on a Vec
+    fn exercise_pages(mut fs: Elkridge) {
+        let root = Caller::new(0, 0, 0);
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        let mut model = Vec::new();
+        let check = |fs: &mut Elkridge, model: &[u8]| {
+            let mut buf = vec![];
+            fs.read_basic(&root, file.ino, 0, 0, 1 << 20, &mut buf).unwrap();
+            assert!(buf == model, "read {} bytes, expected {}", buf.len(), model.len());
+        };
+        let page = PAGE_SIZE as usize;
+        for &(offset, len, byte) in &[(0, 3 * page + 100, 1u8), (page - 10, 20, 2), (2 * page, page, 3), (5 * page, 7, 4)] {
+            fs.write_basic(&root, file.ino, 0, offset as i64, &vec![byte; len]).unwrap();
+            if model.len() < offset + len {
+                model.resize(offset + len, 0);
+            }
+            model[offset .. offset + len].iter_mut().for_each(|old| *old = byte);
+            check(&mut fs, &model);
+        }
+        for &size in &[page + 5, 0, 2 * page] {
+            fs.setattr_basic(&root, file.ino, AttrChanges{size: Some(size as u64), ..Default::default()}).unwrap();
+            model.resize(size, 0);
+            check(&mut fs, &model);
+        }
+    }
+
+    #[test]
+    fn both_page_stores_read_back_what_was_written() {
+        exercise_pages(mounted(Options::default()));
+        let dir = std::env::temp_dir().join(format!("elkridge-test-{}-blob-dir", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        exercise_pages(mounted(Options{blob_dir: Some(dir.clone()), ..Options::default()}));
+        // Kept there rather than in the database
+        assert!(std::fs::read_dir(&dir).unwrap().next().is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+
+    #[test]
+    fn overlapping_writes_read_back_the_newest_bytes() {
+        let mut fs = mounted(Options::default());
+        let root = Caller::new(0, 0, 0);
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        let mut model = vec![0u8; 3 * PAGE_SIZE as usize];
+        // At places picked by a fixed LCG, overlapping each other and page boundaries
+        let mut seed = 1u64;
+        for byte in 1..=40u8 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let offset = (seed >> 33) as usize % (2 * PAGE_SIZE as usize);
+            let len = 1 + (seed >> 17) as usize % PAGE_SIZE as usize;
+            fs.write_basic(&root, file.ino, 0, offset as i64, &vec![byte; len]).unwrap();
+            model[offset .. offset + len].iter_mut().for_each(|old| *old = byte);
+        }
+        let size = fs.getattr_basic(&root, file.ino).unwrap().size as usize;
+        let mut buf = vec![];
+        fs.read_basic(&root, file.ino, 0, 0, 1 << 20, &mut buf).unwrap();
+        assert_eq!(buf.len(), size);
+        if let Some(at) = (0..size).find(|&at| buf[at] != model[at]) {
+            panic!("byte {} is {}, but the last write there was {}", at, buf[at], model[at]);
+        }
+        // And no page overlaps another, which is what makes that so
+        check_page_overlaps(&fs.conn, file.ino).unwrap();
+    }
+
+
+    #[test]
+    fn sticky_directories_keep_others_files() {
+        let mut fs = mounted(Options::default());
+        let (root, alice, bob) = (Caller::new(0, 0, 0), Caller::new(1000, 1000, 0), Caller::new(1001, 1001, 0));
+        let errno = |fs: &Elkridge, result: Fallible<()>| errors::errno_for(&fs.describe(result.unwrap_err()));
+        let tmp = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("tmp"), 0o1777).unwrap();
+        fs.mknod_basic(&alice, tmp.ino, OsStr::new("hers"), libc::S_IFREG | 0o666, 0).unwrap();
+        fs.mknod_basic(&bob, tmp.ino, OsStr::new("his"), libc::S_IFREG | 0o666, 0).unwrap();
+
+        let result = fs.unlink_basic(&bob, tmp.ino, OsStr::new("hers"));
+        assert_eq!(errno(&fs, result), libc::EACCES);
+        let result = fs.rename_basic(&bob, tmp.ino, OsStr::new("hers"), ROOT_INODE, OsStr::new("taken"), 0);
+        assert_eq!(errno(&fs, result), libc::EACCES);
+        // Replacing hers is removing it too
+        let result = fs.rename_basic(&bob, tmp.ino, OsStr::new("his"), tmp.ino, OsStr::new("hers"), 0);
+        assert_eq!(errno(&fs, result), libc::EACCES);
+        assert!(fs.lookup_basic(&root, tmp.ino, OsStr::new("hers")).is_ok());
+
+        // Owners and root may
+        fs.rename_basic(&bob, tmp.ino, OsStr::new("his"), tmp.ino, OsStr::new("still his"), 0).unwrap();
+        fs.unlink_basic(&bob, tmp.ino, OsStr::new("still his")).unwrap();
+        fs.unlink_basic(&alice, tmp.ino, OsStr::new("hers")).unwrap();
+        fs.mknod_basic(&alice, tmp.ino, OsStr::new("hers"), libc::S_IFREG | 0o666, 0).unwrap();
+        fs.unlink_basic(&root, tmp.ino, OsStr::new("hers")).unwrap();
+    }
+
+
+    #[test]
+    fn writes_copy_lower_files_up_and_leave_the_lower_alone() {
+        let lower = ::tests::Scratch::new("lower-layer");
+        let root = Caller::new(0, 0, 0);
+        let read_all = |fs: &mut Elkridge, ino: u64| {
+            let mut buf = vec![];
+            fs.read_basic(&root, ino, 0, 0, 1 << 20, &mut buf).unwrap();
+            buf
+        };
+        {
+            let mut fs = Elkridge::new(lower.open(), Options::default()).unwrap();
+            let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+            fs.write_basic(&root, file.ino, 0, 0, b"from below").unwrap();
+        }
+        let mut fs = mounted(Options{lowers: vec![PathBuf::from(lower.path())], ..Options::default()});
+        let file = fs.lookup_basic(&root, ROOT_INODE, OsStr::new("file")).unwrap();
+        assert_eq!(read_all(&mut fs, file.ino), b"from below");
+        fs.write_basic(&root, file.ino, 0, 0, b"FROM").unwrap();
+        assert_eq!(read_all(&mut fs, file.ino), b"FROM below");
+        let origins : i64 = fs.conn.query_row("SELECT count(*) FROM Origin WHERE inode = ?", [file.ino as i64], |row| row.get(0)).unwrap();
+        assert_eq!(origins, 0);
+
+        let mut below = Elkridge::new(lower.open(), Options{read_only: true, ..Options::default()}).unwrap();
+        let original = below.lookup_basic(&root, ROOT_INODE, OsStr::new("file")).unwrap();
+        assert_eq!(read_all(&mut below, original.ino), b"from below");
+    }
+
+
+    #[test]
+    fn frozen_mounts_refuse_changes_until_thawed() {
+        let lower = ::tests::Scratch::new("frozen-lower");
+        let root = Caller::new(0, 0, 0);
+        let errno = |fs: &Elkridge, result: Fallible<FileAttr>| errors::errno_for(&fs.describe(result.unwrap_err()));
+        {
+            let mut fs = Elkridge::new(lower.open(), Options::default()).unwrap();
+            fs.mknod_basic(&root, ROOT_INODE, OsStr::new("below"), libc::S_IFREG | 0o644, 0).unwrap();
+        }
+        let mut fs = mounted(Options{lowers: vec![PathBuf::from(lower.path())], ..Options::default()});
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        freeze::freeze();
+        let result = fs.write_basic(&root, file.ino, 0, 0, b"content");
+        assert_eq!(errors::errno_for(&fs.describe(result.unwrap_err())), libc::EROFS);
+        let result = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("new"), libc::S_IFREG | 0o644, 0);
+        assert_eq!(errno(&fs, result), libc::EROFS);
+        // Looking up what's only below would copy it up, so that's refused too
+        let result = fs.lookup_basic(&root, ROOT_INODE, OsStr::new("below"));
+        assert_eq!(errno(&fs, result), libc::EROFS);
+        let copied : i64 = fs.conn.query_row("SELECT count(*) FROM Origin", sql::NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(copied, 0);
+        // What's already up can still be read
+        assert_eq!(fs.getattr_basic(&root, file.ino).unwrap().size, 0);
+
+        freeze::thaw();
+        assert_eq!(fs.write_basic(&root, file.ino, 0, 0, b"content").unwrap(), 7);
+        fs.lookup_basic(&root, ROOT_INODE, OsStr::new("below")).unwrap();
+        fs.mknod_basic(&root, ROOT_INODE, OsStr::new("new"), libc::S_IFREG | 0o644, 0).unwrap();
+    }
+
+
+    #[test]
+    fn normalized_names_find_each_other() {
+        let (nfd, nfc) = ("cafe\u{301}", "caf\u{e9}");
+        let root = Caller::new(0, 0, 0);
+        // Without --normalize they're two names
+        let mut fs = mounted(Options::default());
+        fs.mknod_basic(&root, ROOT_INODE, OsStr::new(nfd), libc::S_IFREG | 0o644, 0).unwrap();
+        assert!(fs.lookup_basic(&root, ROOT_INODE, OsStr::new(nfc)).is_err());
+
+        for &normalize_store in &[false, true] {
+            let mut fs = mounted(Options{normalize: Some(NameForm::Nfc), normalize_store, ..Options::default()});
+            let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new(nfd), libc::S_IFREG | 0o644, 0).unwrap();
+            assert_eq!(fs.lookup_basic(&root, ROOT_INODE, OsStr::new(nfc)).unwrap().ino, file.ino);
+            assert_eq!(fs.lookup_basic(&root, ROOT_INODE, OsStr::new(nfd)).unwrap().ino, file.ino);
+            let result = fs.mknod_basic(&root, ROOT_INODE, OsStr::new(nfc), libc::S_IFREG | 0o644, 0);
+            assert_eq!(errors::errno_for(&fs.describe(result.unwrap_err())), libc::EEXIST);
+            // The name as given, unless --normalize-store asks for the normal form
+            let listed = fs.readdir_basic(&root, ROOT_INODE, 0, 0).unwrap();
+            let stored = if normalize_store { nfc } else { nfd };
+            assert!(listed.iter().any(|entry| entry.name == OsStr::new(stored)));
+        }
+    }
+
+
+    #[test]
+    fn alloc_size_rounds_blocks_up_to_whole_units() {
+        let mut fs = mounted(Options{alloc_size: Some(4096), ..Options::default()});
+        let root = Caller::new(0, 0, 0);
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        assert_eq!(fs.getattr_basic(&root, file.ino).unwrap().blocks, 0);
+        for &(size, blocks) in &[(1, 8), (4096, 8), (4097, 16)] {
+            fs.setattr_basic(&root, file.ino, AttrChanges{size: Some(size), ..Default::default()}).unwrap();
+            let attr = fs.getattr_basic(&root, file.ino).unwrap();
+            assert_eq!((attr.size, attr.blocks), (size, blocks));
+        }
+    }
+
+
+    /// What each of `threads` connections got from creating the same name at once
+    fn race_to_create(db: &::tests::Scratch, name: &str, flags: u32, threads: usize) -> Vec<Result<u64, i32>> {
+        let barrier = std::sync::Arc::new(std::sync::Barrier::new(threads));
+        let racers : Vec<_> = (0..threads).map(|_| {
+            let (path, name, barrier) = (db.path().to_string(), name.to_string(), barrier.clone());
+            std::thread::spawn(move || {
+                let mut fs = Elkridge::new(sql::Connection::open(path).unwrap(), Options::default()).unwrap();
+                let root = Caller::new(0, 0, 0);
+                barrier.wait();
+                match fs.create_basic(&root, ROOT_INODE, OsStr::new(&name), libc::S_IFREG | 0o644, flags) {
+                    Ok((attr, _, _)) => Ok(attr.ino),
+                    Err(e) => Err(errors::errno_for(&fs.describe(e)))
+                }
+            })
+        }).collect();
+        racers.into_iter().map(|racer| racer.join().unwrap()).collect()
+    }
+
+    #[test]
+    fn racing_creates_have_one_winner() {
+        let db = ::tests::Scratch::new("racing-creates");
+        Elkridge::new(db.open(), Options::default()).unwrap();
+        let excl = (libc::O_CREAT | libc::O_EXCL | libc::O_WRONLY) as u32;
+        let results = race_to_create(&db, "exclusive", excl, 8);
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1, "{:?}", results);
+        assert!(results.iter().all(|&result| result.is_ok() || result == Err(libc::EEXIST)), "{:?}", results);
+        // Without O_EXCL whoever loses opens the winner's file
+        let results = race_to_create(&db, "shared", (libc::O_CREAT | libc::O_WRONLY) as u32, 8);
+        let first = results[0].unwrap();
+        assert!(results.iter().all(|&result| result == Ok(first)), "{:?}", results);
+    }
+
+
+    #[test]
+    fn exposed_tables_read_as_their_rows() {
+        let mut fs = mounted(Options{expose_tables: true, ..Options::default()});
+        let root = Caller::new(0, 0, 0);
+        fs.conn.execute_batch("
+            CREATE TABLE notes(id INTEGER, note TEXT, data BLOB);
+            INSERT INTO notes VALUES (1, 'plain', x'00ff'), (2, 'with, a \"comma\"', NULL);
+        ").unwrap();
+        let dir = fs.lookup_basic(&root, ROOT_INODE, OsStr::new(tables::TABLES_DIR_NAME)).unwrap();
+        assert_eq!(dir.kind, FileType::Directory);
+        // Only tables of one's own, not the filesystem's
+        let names : Vec<OsString> = fs.readdir_basic(&root, dir.ino, 0, 0).unwrap().into_iter()
+            .map(|entry| entry.name)
+            .filter(|name| name != "." && name != "..")
+            .collect();
+        assert_eq!(names, vec![OsString::from("notes")]);
+        let notes = fs.lookup_basic(&root, dir.ino, OsStr::new("notes")).unwrap();
+        let mut buf = vec![];
+        fs.read_basic(&root, notes.ino, 0, 0, 4096, &mut buf).unwrap();
+        let expected = "id,note,data\n1,plain,00ff\n2,\"with, a \"\"comma\"\"\",\n";
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+        assert_eq!(notes.size, expected.len() as u64);
+
+        let result = fs.write_basic(&root, notes.ino, 0, 0, b"3,new,");
+        assert_eq!(errors::errno_for(&fs.describe(result.unwrap_err())), libc::EROFS);
+        let result = fs.mknod_basic(&root, dir.ino, OsStr::new("more"), libc::S_IFREG | 0o644, 0);
+        assert_eq!(errors::errno_for(&fs.describe(result.unwrap_err())), libc::EROFS);
+        let result = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new(tables::TABLES_DIR_NAME), 0o755);
+        assert_eq!(errors::errno_for(&fs.describe(result.unwrap_err())), libc::EROFS);
+    }
+
+
+    #[test]
+    fn root_path_mounts_only_that_subtree() {
+        let db = ::tests::Scratch::new("root-path");
+        let root = Caller::new(0, 0, 0);
+        let sub = {
+            let mut fs = Elkridge::new(db.open(), Options::default()).unwrap();
+            let sub = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("sub"), 0o755).unwrap();
+            fs.mknod_basic(&root, sub.ino, OsStr::new("inside"), libc::S_IFREG | 0o644, 0).unwrap();
+            fs.mknod_basic(&root, ROOT_INODE, OsStr::new("outside"), libc::S_IFREG | 0o644, 0).unwrap();
+            fs.symlink_basic(&root, ROOT_INODE, OsStr::new("via"), Path::new("sub")).unwrap();
+            sub.ino
+        };
+        let open = |root_path: &str| Elkridge::new(db.open(), Options{root_path: Some(root_path.to_string()), ..Options::default()});
+        for root_path in &["/sub", "sub/../sub", "via"] {
+            let mut fs = open(root_path).unwrap();
+            let top = fs.inode_from_kernel(fuse::FUSE_ROOT_ID);
+            assert_eq!(top, sub);
+            assert_eq!(fs.inode_to_kernel(sub), fuse::FUSE_ROOT_ID);
+            assert!(fs.lookup_basic(&root, top, OsStr::new("inside")).is_ok());
+            let result = fs.lookup_basic(&root, top, OsStr::new("outside"));
+            assert_eq!(errors::errno_for(&fs.describe(result.unwrap_err())), libc::ENOENT);
+            let names : Vec<OsString> = fs.readdir_basic(&root, top, 0, 0).unwrap().into_iter().map(|entry| entry.name).collect();
+            assert_eq!(names, vec![OsString::from("inside")]);
+        }
+        // Only a directory will do
+        for root_path in &["/outside", "/sub/inside", "/missing"] {
+            assert!(open(root_path).is_err(), "{}", root_path);
+        }
+    }
+
+
+    #[test]
+    fn sequential_reads_prefetch_and_random_ones_dont() {
+        let mut fs = mounted(Options{readahead: 4, ..Options::default()});
+        let root = Caller::new(0, 0, 0);
+        let page = PAGE_SIZE;
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        let content : Vec<u8> = (0..10 * page).map(|at| (at / page) as u8).collect();
+        fs.write_basic(&root, file.ino, 0, 0, &content).unwrap();
+        let cached = |fs: &Elkridge| -> Vec<i64> {
+            (0..12).filter(|&n| fs.page_cache.contains(file.ino, n * page)).collect()
+        };
+
+        let (fh, _) = fs.open_basic(&root, file.ino, 0).unwrap();
+        let mut buf = vec![];
+        fs.read_basic(&root, file.ino, fh, 0, page as u32, &mut buf).unwrap();
+        fs.prefetch(fh).unwrap();
+        assert_eq!(cached(&fs), vec![1, 2, 3, 4]);
+        // The next read is served from what was fetched, and the window moves on
+        fs.read_basic(&root, file.ino, fh, page, page as u32, &mut buf).unwrap();
+        assert_eq!(buf, &content[page as usize .. 2 * page as usize]);
+        fs.prefetch(fh).unwrap();
+        assert_eq!(cached(&fs), vec![2, 3, 4, 5]);
+        // Never past the end of the file
+        fs.read_basic(&root, file.ino, fh, 2 * page, 6 * page as u32, &mut buf).unwrap();
+        fs.prefetch(fh).unwrap();
+        assert!(cached(&fs).ends_with(&[8, 9]), "{:?}", cached(&fs));
+        fs.release_basic(&root, file.ino, fh).unwrap();
+        assert!(fs.page_cache.is_empty());
+
+        let (fh, _) = fs.open_basic(&root, file.ino, 0).unwrap();
+        for &at in &[7, 2, 5] {
+            fs.read_basic(&root, file.ino, fh, at * page, page as u32, &mut buf).unwrap();
+            fs.prefetch(fh).unwrap();
+            assert!(fs.page_cache.is_empty(), "prefetched {:?} after a read at page {}", cached(&fs), at);
+        }
+    }
+
+
+    thread_local! {
+        /// Statements run on this thread's connections, apart from PRAGMA data_version
+        static QUERIES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    fn count_query(statement: &str) {
+        if !statement.starts_with("PRAGMA data_version") {
+            QUERIES.with(|queries| queries.set(queries.get() + 1));
+        }
+    }
+
+    #[test]
+    fn getattrs_after_readdir_cost_no_queries() {
+        let mut fs = mounted(Options::default());
+        let root = Caller::new(0, 0, 0);
+        for n in 0..10 {
+            fs.mknod_basic(&root, ROOT_INODE, OsStr::new(&format!("file{}", n)), libc::S_IFREG | 0o644, 0).unwrap();
+        }
+        fs.attr_cache.clear();
+        fs.conn.trace(Some(count_query));
+        let listed = fs.readdir_basic(&root, ROOT_INODE, 0, 0).unwrap();
+        assert_eq!(listed.len(), 10);
+        QUERIES.with(|queries| queries.set(0));
+        for entry in &listed {
+            assert_eq!(fs.getattr_basic(&root, entry.ino).unwrap().ino, entry.ino);
+        }
+        assert_eq!(QUERIES.with(|queries| queries.get()), 0);
+
+        // A change replaces what it touched with what it answers, rather than leaving it stale
+        let first = listed[0].ino;
+        fs.setattr_basic(&root, first, AttrChanges{mode: Some(0o600), ..Default::default()}).unwrap();
+        QUERIES.with(|queries| queries.set(0));
+        assert_eq!(fs.getattr_basic(&root, first).unwrap().perm & 0o777, 0o600);
+        assert_eq!(fs.getattr_basic(&root, listed[1].ino).unwrap().ino, listed[1].ino);
+        fs.conn.trace(None);
+        assert_eq!(QUERIES.with(|queries| queries.get()), 0);
+    }
+
+
+    #[test]
+    fn owner_map_shows_and_stores_through_the_mapping() {
+        let mut fs = mounted(Options{owner_map: vec![(0, 1000)], ..Options::default()});
+        let (root, other) = (Caller::new(0, 0, 0), Caller::new(2000, 2000, 0));
+        let stored = |fs: &Elkridge, ino: u64| -> (u32, u32) {
+            fs.conn.query_row("SELECT uid, gid FROM Inode WHERE inode = ?", [ino as i64], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+        };
+        let roots = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("root's"), libc::S_IFREG | 0o644, 0).unwrap();
+        assert_eq!((roots.uid, roots.gid), (1000, 1000));
+        assert_eq!(stored(&fs, roots.ino), (0, 0));
+        let others = fs.mknod_basic(&other, ROOT_INODE, OsStr::new("other's"), libc::S_IFREG | 0o644, 0).unwrap();
+        assert_eq!((others.uid, others.gid), (2000, 2000));
+
+        // Given to the shown id, it's stored as the one that stands for
+        let changes = AttrChanges{uid: Some(1000), gid: Some(1000), ..Default::default()};
+        let chowned = fs.setattr_basic(&root, others.ino, changes).unwrap();
+        assert_eq!((chowned.uid, chowned.gid), (1000, 1000));
+        assert_eq!(stored(&fs, others.ino), (0, 0));
+        let chowned = fs.setattr_basic(&root, others.ino, AttrChanges{uid: Some(3000), ..Default::default()}).unwrap();
+        assert_eq!(chowned.uid, 3000);
+        assert_eq!(stored(&fs, others.ino), (3000, 0));
+    }
+
+
+    #[test]
+    fn getattr_cache_follows_writes_setattr_and_other_connections() {
+        let db = ::tests::Scratch::new("attr-cache");
+        let mut fs = Elkridge::new(db.open(), Options::default()).unwrap();
+        let root = Caller::new(0, 0, 0);
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        assert_eq!(fs.getattr_basic(&root, file.ino).unwrap().size, 0);
+        assert!(fs.attr_cache.get(file.ino).is_some());
+
+        fs.write_basic(&root, file.ino, 0, 0, b"hello").unwrap();
+        assert_eq!(fs.getattr_basic(&root, file.ino).unwrap().size, 5);
+        fs.setattr_basic(&root, file.ino, AttrChanges{mode: Some(0o600), ..Default::default()}).unwrap();
+        assert_eq!(fs.getattr_basic(&root, file.ino).unwrap().perm, 0o600);
+
+        // A commit on another connection moves data_version, which drops the whole cache
+        let other = db.open();
+        other.execute("UPDATE Inode SET perm = ? WHERE inode = ?", [0o640, file.ino as i64]).unwrap();
+        assert_eq!(fs.getattr_basic(&root, file.ino).unwrap().perm, 0o640);
+    }
+}
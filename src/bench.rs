@@ -0,0 +1,187 @@
+use basic::{BasicFilesystem, Caller};
+use failure::Fallible;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+use {Elkridge, PAGE_SIZE, ROOT_INODE};
+
+/// The size of the standard workload `elkridge bench` runs
+#[derive(Debug, Clone, Copy)]
+pub struct Workload {
+    /// How many files to create, write, read and delete
+    pub files: u32,
+    /// How many bytes to write into each one
+    pub file_size: u32
+}
+
+impl Default for Workload {
+    fn default() -> Workload {
+        Workload{files: 1000, file_size: 64 * 1024}
+    }
+}
+
+/// The latencies of every call of one kind of operation
+pub struct OpStats {
+    pub op: &'static str,
+    pub latencies: Vec<Duration>
+}
+
+impl OpStats {
+    /// Calls per second of time spent in them
+    pub fn throughput(&self) -> f64 {
+        let total : Duration = self.latencies.iter().sum();
+        if total == Duration::default() {
+            return 0.0;
+        }
+        self.latencies.len() as f64 / total.as_secs_f64()
+    }
+
+    /// The latency `fraction` of the way up from the fastest call, nearest rank
+    pub fn percentile(&self, fraction: f64) -> Duration {
+        let mut sorted = self.latencies.clone();
+        sorted.sort();
+        match sorted.len() {
+            0 => Duration::default(),
+            len => sorted[((len as f64 * fraction).ceil() as usize).clamp(1, len) - 1]
+        }
+    }
+}
+
+/// A fixed xorshift sequence, so every run does the same work in the same order
+struct Random(u64);
+
+impl Random {
+    fn below(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound.max(1)
+    }
+}
+
+/// Time one call, adding it to the stats for its kind
+fn timed<T, F: FnOnce() -> Fallible<T>>(stats: &mut OpStats, call: F) -> Fallible<T> {
+    let started = Instant::now();
+    let result = call()?;
+    stats.latencies.push(started.elapsed());
+    Ok(result)
+}
+
+/// Run the standard workload through the basic layer, as the kernel's requests would
+///
+/// The files are spread over a directory per hundred, then: each is created and written
+/// start to end a page at a time, random ranges are overwritten and read back, every
+/// directory is listed and every file looked up, and finally all of it is deleted again. Reads and writes go through open handles, and the
+/// database is left as it was found.
+pub fn run(fs: &mut Elkridge, caller: &Caller, workload: Workload) -> Fallible<Vec<OpStats>> {
+    let ops = ["mkdir", "create", "write", "overwrite", "read", "readdir", "lookup", "unlink", "rmdir"];
+    let mut stats : Vec<OpStats> = ops.iter().map(|&op| OpStats{op, latencies: Vec::new()}).collect();
+    let mut random = Random(0x2545_f491_4f6c_dd1d);
+    let size = u64::from(workload.file_size);
+    let page = vec![0xa5u8; PAGE_SIZE as usize];
+    let mut buf = Vec::new();
+
+    let dir_count = (workload.files / 100).max(1);
+    let mut dirs = Vec::new();
+    for d in 0..dir_count {
+        let name = OsString::from(format!("bench-{}", d));
+        dirs.push((timed(&mut stats[0], || fs.mkdir_basic(caller, ROOT_INODE, &name, 0o755))?.ino, name));
+    }
+    let mut files = Vec::new();
+    for f in 0..workload.files {
+        let dir = dirs[(f % dir_count) as usize].0;
+        let name = OsString::from(format!("file-{}", f));
+        let ino = timed(&mut stats[1], || fs.mknod_basic(caller, dir, &name, libc::S_IFREG | 0o644, 0))?.ino;
+        let (fh, _) = fs.open_basic(caller, ino, libc::O_RDWR as u32)?;
+        files.push((dir, name, ino, fh));
+    }
+    for &(_, _, ino, fh) in &files {
+        let mut offset = 0;
+        while offset < size {
+            let len = (size - offset).min(PAGE_SIZE as u64) as usize;
+            timed(&mut stats[2], || fs.write_basic(caller, ino, fh, offset as i64, &page[..len]))?;
+            offset += len as u64;
+        }
+    }
+    for _ in 0..workload.files * 2 {
+        let (_, _, ino, fh) = files[random.below(files.len() as u64) as usize];
+        let offset = random.below(size) as i64;
+        let len = 1 + random.below(PAGE_SIZE as u64) as usize;
+        timed(&mut stats[3], || fs.write_basic(caller, ino, fh, offset, &page[..len]))?;
+    }
+    for _ in 0..workload.files * 4 {
+        let (_, _, ino, fh) = files[random.below(files.len() as u64) as usize];
+        let offset = random.below(size) as i64;
+        let len = 1 + random.below(2 * PAGE_SIZE as u64) as u32;
+        timed(&mut stats[4], || fs.read_basic(caller, ino, fh, offset, len, &mut buf))?;
+    }
+    for _ in 0..10 {
+        for &(dir, _) in &dirs {
+            timed(&mut stats[5], || fs.readdir_basic(caller, dir, 0, 0))?;
+        }
+    }
+    for (dir, name, _, _) in &files {
+        timed(&mut stats[6], || fs.lookup_basic(caller, *dir, name))?;
+    }
+    for (dir, name, ino, fh) in &files {
+        fs.release_basic(caller, *ino, *fh)?;
+        timed(&mut stats[7], || fs.unlink_basic(caller, *dir, name))?;
+    }
+    for (_, name) in &dirs {
+        timed(&mut stats[8], || fs.rmdir_basic(caller, ROOT_INODE, name))?;
+    }
+    Ok(stats)
+}
+
+/// Print one line per kind of operation: how many, calls per second, and latency percentiles
+pub fn report<W: Write>(mut out: W, stats: &[OpStats]) -> io::Result<()> {
+    writeln!(out, "{:<10} {:>8} {:>12} {:>10} {:>10} {:>10} {:>10}", "op", "count", "ops/s", "p50 us", "p90 us", "p99 us", "max us")?;
+    for op in stats {
+        let micros = |d: Duration| d.as_micros();
+        writeln!(out, "{:<10} {:>8} {:>12.0} {:>10} {:>10} {:>10} {:>10}",
+            op.op,
+            op.latencies.len(),
+            op.throughput(),
+            micros(op.percentile(0.5)),
+            micros(op.percentile(0.9)),
+            micros(op.percentile(0.99)),
+            micros(op.percentile(1.0)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use options::Options;
+    use rusqlite as sql;
+
+    #[test]
+    fn a_tiny_workload_runs_and_cleans_up() {
+        let mut fs = Elkridge::new(sql::Connection::open_in_memory().unwrap(), Options::default()).unwrap();
+        let stats = run(&mut fs, &Caller::new(0, 0, 0), Workload{files: 20, file_size: 10000}).unwrap();
+        let counts : Vec<(&str, usize)> = stats.iter().map(|op| (op.op, op.latencies.len())).collect();
+        assert_eq!(counts, vec![
+            ("mkdir", 1), ("create", 20), ("write", 60), ("overwrite", 40), ("read", 80),
+            ("readdir", 10), ("lookup", 20), ("unlink", 20), ("rmdir", 1)
+        ]);
+        let inodes : i64 = fs.conn.query_row("SELECT count(*) FROM Inode", sql::NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(inodes, 1);
+
+        let mut out = Vec::new();
+        report(&mut out, &stats).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 1 + stats.len());
+        assert!(out.lines().nth(2).unwrap().starts_with("create           20 "), "{}", out);
+    }
+
+    #[test]
+    fn percentiles_are_nearest_rank() {
+        let op = OpStats{op: "test", latencies: (1..=10).rev().map(Duration::from_millis).collect()};
+        assert_eq!(op.percentile(0.5), Duration::from_millis(5));
+        assert_eq!(op.percentile(0.9), Duration::from_millis(9));
+        assert_eq!(op.percentile(0.0), Duration::from_millis(1));
+        assert_eq!(op.percentile(1.0), Duration::from_millis(10));
+        assert_eq!(OpStats{op: "none", latencies: vec![]}.percentile(0.5), Duration::default());
+    }
+}
@@ -0,0 +1,117 @@
+use failure::Fallible;
+use freeze;
+use rusqlite as sql;
+use rusqlite::NO_PARAMS;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often the WAL's size is looked at when only --wal-limit is given
+const POLL: Duration = Duration::from_secs(1);
+
+/// A thread that checkpoints the WAL in the background, so it can't grow without bound
+///
+/// It has its own connection, so checkpoints never wait on a FUSE request and the
+/// filesystem's connection never waits on a checkpoint, apart from the brief exclusive
+/// lock a TRUNCATE checkpoint needs at the end. Databases not in WAL mode are unaffected.
+pub struct Checkpointer {
+    stop: Sender<()>,
+    thread: JoinHandle<()>
+}
+
+impl Checkpointer {
+    /// Start checkpointing every `interval`, and whenever the WAL grows past `wal_limit` bytes
+    ///
+    /// Interval checkpoints are PASSIVE, so they never hold up writers. Going over the limit
+    /// calls for a TRUNCATE checkpoint instead, since only that actually shrinks the file.
+    pub fn start(sqlite_path: &str, interval: Option<Duration>, wal_limit: Option<u64>) -> Fallible<Checkpointer> {
+        let conn = sql::Connection::open(sqlite_path)?;
+        conn.busy_timeout(Duration::from_millis(100))?;
+        let wal_path = format!("{}-wal", sqlite_path);
+        let tick = interval.map_or(POLL, |interval| interval.min(POLL));
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut last = Instant::now();
+            // Any message, or the other end going away, means stop
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(tick) {
+                // A checkpoint writes to the database file, which a freeze holds still
+                if freeze::is_frozen() {
+                    continue;
+                }
+                let wal_size = fs::metadata(&wal_path).map(|meta| meta.len()).unwrap_or(0);
+                let mode = if wal_limit.is_some_and(|limit| wal_size > limit) {
+                    "TRUNCATE"
+                } else if interval.is_some_and(|interval| last.elapsed() >= interval) {
+                    "PASSIVE"
+                } else {
+                    continue;
+                };
+                last = Instant::now();
+                match checkpoint(&conn, mode) {
+                    Ok((busy, logged, done)) => debug!(
+                        "{} checkpoint of {} byte WAL: {} of {} frames{}",
+                        mode, wal_size, done, logged, if busy { ", blocked" } else { "" }),
+                    Err(e) => warn!("{} checkpoint failed: {}", mode, e)
+                }
+            }
+        });
+        Ok(Checkpointer{stop, thread})
+    }
+
+    /// Stop the thread and wait for any checkpoint in progress to finish
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+}
+
+/// Where a database's WAL stands, for /.elkridge/stats
+pub struct WalState {
+    /// Length of the -wal file, which only shrinks when a checkpoint truncates it
+    pub bytes: u64,
+    /// Frames committed to the WAL since it last started over from the beginning
+    pub frames: u32,
+    /// How many of those a checkpoint has copied back into the database
+    pub checkpointed: u32
+}
+
+/// Where the main database's WAL stands, or None if it isn't in WAL mode or isn't a file
+///
+/// SQLite doesn't answer this without running a checkpoint, so the counts come from the
+/// wal-index header in the -shm file, as described in SQLite's walformat.html. It's read
+/// without taking SQLite's locks, so a commit at the same moment can make it a frame behind.
+pub fn wal_state(conn: &sql::Connection) -> Fallible<Option<WalState>> {
+    let journal_mode : String = conn.query_row("PRAGMA journal_mode", NO_PARAMS, |row| row.get(0))?;
+    let path : String = conn.query_row(
+        "SELECT file FROM pragma_database_list WHERE name = 'main'",
+        NO_PARAMS,
+        |row| row.get(0))?;
+    if !journal_mode.eq_ignore_ascii_case("wal") || path.is_empty() {
+        return Ok(None);
+    }
+    let bytes = fs::metadata(format!("{}-wal", path)).map(|meta| meta.len()).unwrap_or(0);
+    // The header, then its copy, then the checkpoint info, whose first field is nBackfill
+    let mut index = [0u8; 100];
+    let read = match File::open(format!("{}-shm", path)) {
+        Ok(mut shm) => shm.read_exact(&mut index),
+        Err(e) => Err(e)
+    };
+    let field = |at: usize| u32::from_ne_bytes([index[at], index[at + 1], index[at + 2], index[at + 3]]);
+    match read {
+        Ok(()) => Ok(Some(WalState{bytes, frames: field(16), checkpointed: field(96)})),
+        // Before the first connection sets it up there's nothing in the WAL
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound || e.kind() == io::ErrorKind::UnexpectedEof =>
+            Ok(Some(WalState{bytes, frames: 0, checkpointed: 0})),
+        Err(e) => Err(e.into())
+    }
+}
+
+/// Run one checkpoint, returning whether it was blocked and its WAL and checkpointed frame counts
+fn checkpoint(conn: &sql::Connection, mode: &str) -> sql::Result<(bool, i64, i64)> {
+    conn.query_row(
+        &format!("PRAGMA wal_checkpoint({})", mode),
+        NO_PARAMS,
+        |row| Ok((row.get::<usize, i64>(0)? != 0, row.get(1)?, row.get(2)?)))
+}
@@ -0,0 +1,85 @@
+use checkpoint::WalState;
+use handles::Handles;
+use tables::TABLES_DIR_INODE;
+
+/// Name of the synthetic directory of diagnostics, directly under the root
+pub const CONTROL_DIR_NAME: &str = ".elkridge";
+
+/// Inode of the synthetic directory, below the tables range but still far above any real inode
+pub const CONTROL_DIR_INODE: u64 = 1 << 61;
+
+/// The file listing open handles, as JSON
+pub const HANDLES_INODE: u64 = CONTROL_DIR_INODE + 1;
+
+/// The file of database statistics, as JSON
+pub const STATS_INODE: u64 = CONTROL_DIR_INODE + 2;
+
+/// The files in the control directory as (inode, name)
+pub const FILES: &[(u64, &str)] = &[(HANDLES_INODE, "handles"), (STATS_INODE, "stats")];
+
+/// Whether this inode belongs to the synthetic control tree
+pub fn is_control(ino: u64) -> bool {
+    (CONTROL_DIR_INODE..TABLES_DIR_INODE).contains(&ino)
+}
+
+/// Find a control file's inode by name
+pub fn find(name: &str) -> Option<u64> {
+    FILES.iter().find(|&&(_, file)| file == name).map(|&(ino, _)| ino)
+}
+
+/// Describe every open handle, in fh order, as a JSON array of objects
+///
+/// `ino` is the number `stat` shows and `path` is where the file is now, or null once it has
+/// been unlinked (or isn't under the mounted root); `flags`, `uid` and `pid` are as they came
+/// with the open, and `offset` is where the last read on the handle ended.
+pub fn handles_json<F>(handles: &Handles, mut describe: F) -> Vec<u8>
+where F: FnMut(u64) -> (u64, Option<String>) {
+    let objects : Vec<String> = handles.list().into_iter()
+        .map(|(fh, handle)| {
+            let (ino, path) = describe(handle.ino);
+            format!(
+                "  {{\"fh\": {}, \"ino\": {}, \"path\": {}, \"flags\": {}, \"uid\": {}, \"pid\": {}, \"offset\": {}}}",
+                fh, ino, path.map_or("null".to_string(), |path| json_string(&path)),
+                handle.flags, handle.uid, handle.pid, handle.next_offset)
+        })
+        .collect();
+    if objects.is_empty() {
+        b"[]\n".to_vec()
+    } else {
+        format!("[\n{}\n]\n", objects.join(",\n")).into_bytes()
+    }
+}
+
+/// Describe the database's journal as a JSON object
+///
+/// `journal_mode` and `wal_autocheckpoint` are what those pragmas answer. Outside WAL mode
+/// the `wal_` fields are null; in it, `wal_bytes` is the size of the -wal file, `wal_frames`
+/// how many frames it holds since it last started over, and `wal_checkpointed` how many of
+/// those a checkpoint has copied back, so the difference is what the next one has to do.
+pub fn stats_json(journal_mode: &str, wal_autocheckpoint: i64, wal: Option<&WalState>) -> Vec<u8> {
+    let field = |value: Option<u64>| value.map_or("null".to_string(), |value| value.to_string());
+    format!(
+        "{{\n  \"journal_mode\": {},\n  \"wal_autocheckpoint\": {},\n  \"wal_bytes\": {},\n  \"wal_frames\": {},\n  \"wal_checkpointed\": {}\n}}\n",
+        json_string(journal_mode),
+        wal_autocheckpoint,
+        field(wal.map(|wal| wal.bytes)),
+        field(wal.map(|wal| u64::from(wal.frames))),
+        field(wal.map(|wal| u64::from(wal.checkpointed))))
+        .into_bytes()
+}
+
+/// Quote a string for JSON
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+    out
+}
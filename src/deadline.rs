@@ -0,0 +1,70 @@
+use libc::{c_int, c_void};
+use rusqlite as sql;
+use std::cell::Cell;
+use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How many SQLite virtual machine instructions run between looks at the clock
+const PROGRESS_STEPS: c_int = 1000;
+
+/// How long to sleep between tries while another connection holds the lock
+const BUSY_SLEEP: Duration = Duration::from_millis(10);
+
+/// How long to keep trying while no operation is running, as SQLite's default busy_timeout
+const IDLE_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+thread_local! {
+    /// When the operation running on this thread has to give up, for --op-timeout
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Make a connection give up on any statement that outlives the operation's budget
+///
+/// SQLite calls the progress handler every PROGRESS_STEPS instructions, and a statement
+/// whose operation is past its deadline is interrupted there, failing with SQLITE_INTERRUPT.
+/// Waiting for a lock runs no instructions, so the busy handler is replaced too: it keeps
+/// trying until the deadline instead of for a fixed time, and outside any operation (while
+/// mounting, say) for IDLE_BUSY_TIMEOUT. That replaces any busy_timeout set before.
+pub fn install(conn: &sql::Connection) -> sql::Result<()> {
+    unsafe {
+        sql::ffi::sqlite3_progress_handler(conn.handle(), PROGRESS_STEPS, Some(progress), ptr::null_mut());
+    }
+    conn.busy_handler(Some(busy))
+}
+
+/// Run one operation with `budget` to finish its statements in, or as long as it takes
+pub fn within<T, F: FnOnce() -> T>(budget: Option<Duration>, call: F) -> T {
+    /// Clears the deadline however the operation ends, panics included
+    struct Clear;
+    impl Drop for Clear {
+        fn drop(&mut self) {
+            DEADLINE.with(|deadline| deadline.set(None));
+        }
+    }
+    let _clear = Clear;
+    DEADLINE.with(|deadline| deadline.set(budget.map(|budget| Instant::now() + budget)));
+    call()
+}
+
+/// Whether the operation running on this thread is out of time
+fn expired() -> bool {
+    DEADLINE.with(|deadline| deadline.get().is_some_and(|deadline| Instant::now() >= deadline))
+}
+
+/// Progress handler: nonzero interrupts the statement
+extern "C" fn progress(_: *mut c_void) -> c_int {
+    expired() as c_int
+}
+
+/// Busy handler: whether to try for the lock again, after a short sleep
+fn busy(tries: i32) -> bool {
+    let give_up = DEADLINE.with(|deadline| match deadline.get() {
+        Some(deadline) => Instant::now() >= deadline,
+        None => BUSY_SLEEP * tries.max(0) as u32 >= IDLE_BUSY_TIMEOUT
+    });
+    if !give_up {
+        thread::sleep(BUSY_SLEEP);
+    }
+    !give_up
+}
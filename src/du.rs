@@ -0,0 +1,193 @@
+use failure::Fallible;
+use rusqlite as sql;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// What one directory and everything under it take up, for `elkridge du`
+#[derive(Debug)]
+pub struct Usage {
+    pub path: PathBuf,
+    /// The sizes of the files in it added up, holes and all, as ls and stat give them
+    pub logical: u64,
+    /// The bytes of content actually stored for them, each piece counted once
+    pub physical: u64
+}
+
+/// A piece of stored content, which may be shared by more than one page
+#[derive(PartialEq, Eq, Hash)]
+enum Stored {
+    /// A chunk file in the blob directory, shared by every page with the same content
+    Chunk(String),
+    /// A numbered blob file, from before chunks
+    Blob(i64),
+    /// Content in the Page row itself, by its rowid
+    Row(i64)
+}
+
+/// The files under a directory and the content stored for them, each once
+#[derive(Default)]
+struct Totals {
+    inodes: HashMap<i64, u64>,
+    stored: HashMap<Stored, u64>
+}
+
+impl Totals {
+    fn merge(&mut self, other: Totals) {
+        self.inodes.extend(other.inodes);
+        self.stored.extend(other.stored);
+    }
+
+    fn usage(&self, path: PathBuf) -> Usage {
+        Usage {
+            path,
+            logical: self.inodes.values().sum(),
+            physical: self.stored.values().sum()
+        }
+    }
+}
+
+/// The usage of `root`, shown as `path`, and of every directory under it, children first
+///
+/// Logical size is what the files would take up written out plainly; physical is what the
+/// pages hold. Holes take up no pages, and under a blob directory pages with the same
+/// content share one chunk, so physical can be much less; a shared chunk is only counted
+/// once in any directory's total, however many files below it use it. Directories count
+/// for nothing themselves, and neither size includes what SQLite spends on indexes, free
+/// pages and the like, or content still in a lower layer. A path that isn't a directory
+/// gives only its own usage.
+pub fn usage(conn: &sql::Connection, root: u64, path: &Path) -> Fallible<Vec<Usage>> {
+    let mut children = conn.prepare(
+        "SELECT Path.name, Inode.inode, kind, size
+        FROM Path
+        NATURAL JOIN Inode
+        WHERE parent = ? AND Path.inode != Path.parent
+        ORDER BY Path.name")?;
+    let mut pages = conn.prepare(
+        "SELECT rowid, finish - start, length(content), blob, chunk FROM Page WHERE inode = ?")?;
+    let (kind, size) : (i8, i64) = conn.query_row(
+        "SELECT kind, size FROM Inode WHERE inode = ?", [root as i64], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    if kind != 3 {
+        let mut totals = Totals::default();
+        add_file(&mut pages, &mut totals, root as i64, size)?;
+        return Ok(vec![totals.usage(path.to_path_buf())]);
+    }
+
+    // Every directory, each after its parent, with the index of its parent
+    let mut dirs : Vec<(PathBuf, Option<usize>, Totals)> = vec![];
+    let mut pending = vec![(path.to_path_buf(), root as i64, None)];
+    while let Some((path, inode, parent)) = pending.pop() {
+        let mut totals = Totals::default();
+        let index = dirs.len();
+        // Pushed in name order, so they pop off last name first, and the reversed list
+        // below comes out in name order with each directory after what's in it, as du's does
+        let rows = children.query_map([inode], |row| Ok((
+            row.get::<usize, String>(0)?,
+            row.get::<usize, i64>(1)?,
+            row.get::<usize, i8>(2)?,
+            row.get::<usize, i64>(3)?)))?;
+        for row in rows {
+            let (name, child, kind, size) = row?;
+            if kind == 3 {
+                pending.push((path.join(name), child, Some(index)));
+            } else {
+                add_file(&mut pages, &mut totals, child, size)?;
+            }
+        }
+        dirs.push((path, parent, totals));
+    }
+
+    let mut usages = Vec::with_capacity(dirs.len());
+    while let Some((path, parent, totals)) = dirs.pop() {
+        usages.push(totals.usage(path));
+        if let Some(parent) = parent {
+            dirs[parent].2.merge(totals);
+        }
+    }
+    Ok(usages)
+}
+
+/// Count a file and its pages into `totals`
+fn add_file(pages: &mut sql::Statement, totals: &mut Totals, inode: i64, size: i64) -> Fallible<()> {
+    totals.inodes.insert(inode, size as u64);
+    let rows = pages.query_map([inode], |row| Ok((
+        row.get::<usize, i64>(0)?,
+        row.get::<usize, i64>(1)?,
+        row.get::<usize, i64>(2)?,
+        row.get::<usize, Option<i64>>(3)?,
+        row.get::<usize, Option<String>>(4)?)))?;
+    for row in rows {
+        // A chunk or blob file holds the page's whole span; a row holds its own content
+        let (rowid, span, length, blob, chunk) = row?;
+        let (stored, bytes) = match (chunk, blob) {
+            (Some(chunk), _) => (Stored::Chunk(chunk), span),
+            (None, Some(blob)) => (Stored::Blob(blob), span),
+            (None, None) => (Stored::Row(rowid), length)
+        };
+        totals.stored.insert(stored, bytes as u64);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use basic::{AttrChanges, BasicFilesystem, Caller};
+    use options::Options;
+    use std::ffi::OsStr;
+    use {Elkridge, PAGE_SIZE, ROOT_INODE};
+
+    #[test]
+    fn totals_add_up_children_first() {
+        let root = Caller::new(0, 0, 0);
+        let mut fs = Elkridge::new(sql::Connection::open_in_memory().unwrap(), Options::default()).unwrap();
+        let file = |fs: &mut Elkridge, parent, name| {
+            fs.mknod_basic(&root, parent, OsStr::new(name), libc::S_IFREG | 0o644, 0).unwrap().ino
+        };
+        let a = file(&mut fs, ROOT_INODE, "a");
+        fs.write_basic(&root, a, 0, 0, &[1; 5000]).unwrap();
+        let dir = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("dir"), 0o755).unwrap().ino;
+        fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("empty"), 0o755).unwrap();
+        let sub = fs.mkdir_basic(&root, dir, OsStr::new("sub"), 0o755).unwrap().ino;
+        // Mostly a hole, which counts for its size but takes up nothing
+        let sparse = file(&mut fs, dir, "sparse");
+        fs.write_basic(&root, sparse, 0, 0, b"0123456789").unwrap();
+        fs.setattr_basic(&root, sparse, AttrChanges{size: Some(1 << 20), ..Default::default()}).unwrap();
+        let b = file(&mut fs, sub, "b");
+        fs.write_basic(&root, b, 0, 0, &[2; 100]).unwrap();
+
+        let usages = usage(&fs.conn, ROOT_INODE, Path::new("/")).unwrap();
+        let got : Vec<(&str, u64, u64)> = usages.iter()
+            .map(|u| (u.path.to_str().unwrap(), u.logical, u.physical))
+            .collect();
+        assert_eq!(got, vec![
+            ("/dir/sub", 100, 100),
+            ("/dir", 100 + (1 << 20), 110),
+            ("/empty", 0, 0),
+            ("/", 5100 + (1 << 20), 5110)
+        ]);
+
+        let alone = usage(&fs.conn, a, Path::new("/a")).unwrap();
+        assert_eq!((alone.len(), alone[0].logical, alone[0].physical), (1, 5000, 5000));
+    }
+
+    #[test]
+    fn shared_chunks_count_once_in_each_total() {
+        let dir = ::std::env::temp_dir().join(format!("elkridge-test-{}-du-chunks", ::std::process::id()));
+        let _ = ::std::fs::remove_dir_all(&dir);
+        let options = Options{blob_dir: Some(dir.clone()), ..Options::default()};
+        let mut fs = Elkridge::new(sql::Connection::open_in_memory().unwrap(), options).unwrap();
+        let root = Caller::new(0, 0, 0);
+        let left = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("left"), 0o755).unwrap().ino;
+        let right = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("right"), 0o755).unwrap().ino;
+        for &parent in &[left, right] {
+            let file = fs.mknod_basic(&root, parent, OsStr::new("same"), libc::S_IFREG | 0o644, 0).unwrap();
+            fs.write_basic(&root, file.ino, 0, 0, &[7; PAGE_SIZE as usize]).unwrap();
+        }
+        let page = PAGE_SIZE as u64;
+        let got : Vec<(u64, u64)> = usage(&fs.conn, ROOT_INODE, Path::new("/")).unwrap().iter()
+            .map(|u| (u.logical, u.physical))
+            .collect();
+        assert_eq!(got, vec![(page, page), (page, page), (2 * page, page)]);
+        ::std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,12 +1,269 @@
+use failure;
+use libc;
+use libc::{c_int, EACCES, EDQUOT, EEXIST, EFBIG, EINVAL, EIO, EISDIR, ELOOP, ENODATA, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, EPERM, EROFS};
 use rusqlite as sql;
 
+/// Extended result codes that libsqlite3-sys doesn't define, from sqlite3.h
+const SQLITE_CONSTRAINT_PRIMARYKEY: c_int = sql::ffi::SQLITE_CONSTRAINT | (6 << 8);
+const SQLITE_CONSTRAINT_UNIQUE: c_int = sql::ffi::SQLITE_CONSTRAINT | (8 << 8);
+
+/// Return early with one of our errors, like failure's bail!
+///
+/// bail! and ensure! pass their argument through err_msg, which keeps only its message and
+/// so hides it from errno_for; these keep the Error itself.
+macro_rules! raise {
+    ($err:expr) => {
+        return Err($err.into())
+    };
+}
+
+/// Return early with one of our errors unless the condition holds, like failure's ensure!
+macro_rules! require {
+    ($cond:expr, $err:expr) => {
+        if !($cond) {
+            raise!($err);
+        }
+    };
+}
+
+/// Why a basic operation failed, by the errno it should be answered with
+///
+/// SQLite failures that mean one of these (see the From impl) are classified into it, so
+/// errno_for only ever has to look at the variant; SQLError is whatever is left. More
+/// variants may come as more failures are told apart, hence non_exhaustive.
 #[derive(Debug, Fail)]
-enum Error {
-    #[fail(display = "SQLite error: {}", err)]
-    SQLError{err: sql::Error}
+#[allow(clippy::enum_variant_names)]
+#[non_exhaustive]
+pub enum Error {
+    #[fail(display = "SQLite error: {} (extended code {})", err, extended_code)]
+    SQLError{err: sql::Error, extended_code: c_int},
+    #[fail(display = "No such entry")]
+    NotFound,
+    #[fail(display = "An entry with that name already exists")]
+    AlreadyExists,
+    #[fail(display = "Directory is not empty")]
+    NotEmpty,
+    #[fail(display = "Is a directory")]
+    IsADirectory,
+    #[fail(display = "Not a directory")]
+    NotADirectory,
+    #[fail(display = "Read-only file system")]
+    ReadOnly,
+    #[fail(display = "No such attribute")]
+    NoData,
+    #[fail(display = "Invalid argument")]
+    InvalidArgument,
+    #[fail(display = "Permission denied")]
+    PermissionDenied,
+    /// Refused for not being the owner (or root), where EPERM rather than EACCES is the rule
+    #[fail(display = "Operation not permitted")]
+    NotPermitted,
+    #[fail(display = "File too large")]
+    FileTooBig,
+    #[fail(display = "Disk quota exceeded")]
+    QuotaExceeded,
+    #[fail(display = "Too many levels of symbolic links")]
+    SymlinkLoop,
+    #[fail(display = "Timed out waiting for the database")]
+    TimedOut,
+    /// Storing buffered writes failed earlier, with this errno, when there was no one to tell
+    #[fail(display = "Storing earlier writes failed with errno {}", errno)]
+    Deferred{errno: c_int},
+    /// A bug, caught on its way out of a callback so the request is still answered
+    #[fail(display = "Panicked: {}", message)]
+    Panicked{message: String}
 }
 impl From<rusqlite::Error> for Error {
+    /// Classify an SQLite error, keeping its extended code
+    ///
+    /// No row means nothing by that name or number, a primary key or UNIQUE constraint
+    /// means the name (or attribute) is taken, and a read-only database is EROFS. A lock
+    /// never released or a statement interrupted (by --op-timeout, see deadline) timed out. SQLite's
+    /// own message for those is only logged, at debug level, since the variant says it all.
+    /// rusqlite refuses to bind text with a NUL in it, which no name from the kernel has, so
+    /// that's an invalid argument.
+    ///
+    /// That message comes with the error rather than from a trace of every statement, which
+    /// would cost each one a copy with its parameters expanded. It names the constraint or
+    /// table that failed; --trace-sql logs the statements leading up to it.
     fn from(err: sql::Error) -> Self {
-        Error::SQLError{err}
+        let extended_code = match err {
+            sql::Error::QueryReturnedNoRows => return Error::NotFound,
+            sql::Error::NulError(_) => return Error::InvalidArgument,
+            sql::Error::SqliteFailure(ffi_err, _) => ffi_err.extended_code,
+            _ => 0
+        };
+        let classified = match extended_code {
+            SQLITE_CONSTRAINT_PRIMARYKEY | SQLITE_CONSTRAINT_UNIQUE => Error::AlreadyExists,
+            code if code & 0xff == sql::ffi::SQLITE_READONLY => Error::ReadOnly,
+            code if code & 0xff == sql::ffi::SQLITE_BUSY || code == sql::ffi::SQLITE_INTERRUPT => Error::TimedOut,
+            _ => return Error::SQLError{err, extended_code}
+        };
+        debug!("{} (extended code {})", err, extended_code);
+        classified
+    }
+}
+
+/// Classify a bare SQLite error into one of ours, leaving any other error alone
+pub fn classify(err: failure::Error) -> failure::Error {
+    match err.downcast::<sql::Error>() {
+        Ok(err) => Error::from(err).into(),
+        Err(err) => err
+    }
+}
+
+/// Choose the errno to reply with when a basic operation fails
+///
+/// Anything we don't specifically recognize stays ENOENT, as it always has been.
+pub fn errno_for(err: &failure::Error) -> c_int {
+    match err.downcast_ref::<Error>() {
+        Some(Error::NotFound) => ENOENT,
+        Some(Error::AlreadyExists) => EEXIST,
+        Some(Error::NotEmpty) => ENOTEMPTY,
+        Some(Error::IsADirectory) => EISDIR,
+        Some(Error::NotADirectory) => ENOTDIR,
+        Some(Error::ReadOnly) => EROFS,
+        Some(Error::NoData) => ENODATA,
+        Some(Error::InvalidArgument) => EINVAL,
+        Some(Error::PermissionDenied) => EACCES,
+        Some(Error::NotPermitted) => EPERM,
+        Some(Error::FileTooBig) => EFBIG,
+        Some(Error::QuotaExceeded) => EDQUOT,
+        Some(Error::SymlinkLoop) => ELOOP,
+        Some(Error::TimedOut) => EIO,
+        Some(&Error::Deferred{errno}) => errno,
+        Some(Error::Panicked{..}) => EIO,
+        _ if is_disk_full(err) => ENOSPC,
+        _ => ENOENT
+    }
+}
+
+/// The symbolic name of an errno, for logs
+pub fn errno_name(errno: c_int) -> &'static str {
+    match errno {
+        libc::EPERM => "EPERM",
+        libc::ENOENT => "ENOENT",
+        libc::EIO => "EIO",
+        libc::EBADF => "EBADF",
+        libc::EACCES => "EACCES",
+        libc::EBUSY => "EBUSY",
+        libc::EEXIST => "EEXIST",
+        libc::EXDEV => "EXDEV",
+        libc::ENOTDIR => "ENOTDIR",
+        libc::EISDIR => "EISDIR",
+        libc::EINVAL => "EINVAL",
+        libc::EFBIG => "EFBIG",
+        libc::ENOSPC => "ENOSPC",
+        libc::EROFS => "EROFS",
+        libc::ERANGE => "ERANGE",
+        libc::ENAMETOOLONG => "ENAMETOOLONG",
+        libc::ENOSYS => "ENOSYS",
+        libc::ENOTEMPTY => "ENOTEMPTY",
+        libc::ELOOP => "ELOOP",
+        libc::ENODATA => "ENODATA",
+        libc::ENOTSUP => "ENOTSUP",
+        libc::EDQUOT => "EDQUOT",
+        _ => "an unnamed errno"
+    }
+}
+
+/// Whether SQLite failed because it couldn't grow the database, whether wrapped or not
+pub fn is_disk_full(err: &failure::Error) -> bool {
+    let err = match err.downcast_ref::<Error>() {
+        Some(Error::SQLError{err, ..}) => err,
+        _ => match err.downcast_ref::<sql::Error>() {
+            Some(err) => err,
+            None => return false
+        }
+    };
+    match err {
+        sql::Error::SqliteFailure(ffi_err, _) => ffi_err.code == sql::ErrorCode::DiskFull,
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failing(sql: &str) -> Error {
+        let conn = sql::Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE T(x NOT NULL UNIQUE)").unwrap();
+        conn.execute_batch("INSERT INTO T VALUES (1)").unwrap();
+        conn.execute_batch(sql).unwrap_err().into()
+    }
+
+    #[test]
+    fn sqlite_errors_name_the_constraint() {
+        let err = failing("INSERT INTO T VALUES (NULL)");
+        match err {
+            Error::SQLError{extended_code, ..} => assert_eq!(extended_code & 0xff, sql::ffi::SQLITE_CONSTRAINT),
+            ref other => panic!("Expected an SQLError, got {:?}", other)
+        }
+        let message = err.to_string();
+        assert!(message.contains("NOT NULL constraint failed: T.x"), "{}", message);
+    }
+
+    #[test]
+    fn unique_constraints_are_already_exists() {
+        assert!(matches!(failing("INSERT INTO T VALUES (1)"), Error::AlreadyExists));
+    }
+
+    #[test]
+    fn names_the_common_errnos() {
+        assert_eq!(errno_name(ENOENT), "ENOENT");
+        assert_eq!(errno_name(EEXIST), "EEXIST");
+        assert_eq!(errno_name(ENOTEMPTY), "ENOTEMPTY");
+        assert_eq!(errno_name(EROFS), "EROFS");
+        assert_eq!(errno_name(EIO), "EIO");
+        assert_eq!(errno_name(-1), "an unnamed errno");
+    }
+
+    /// The errno for an SQLite failure with this extended result code
+    fn errno_for_code(code: c_int) -> c_int {
+        let err = sql::Error::SqliteFailure(sql::ffi::Error::new(code), None);
+        errno_for(&Error::from(err).into())
+    }
+
+    #[test]
+    fn classifies_sqlite_errors() {
+        assert_eq!(errno_for(&Error::from(sql::Error::QueryReturnedNoRows).into()), ENOENT);
+        assert_eq!(errno_for_code(SQLITE_CONSTRAINT_UNIQUE), EEXIST);
+        assert_eq!(errno_for_code(SQLITE_CONSTRAINT_PRIMARYKEY), EEXIST);
+        assert_eq!(errno_for_code(sql::ffi::SQLITE_READONLY), EROFS);
+        assert_eq!(errno_for_code(sql::ffi::SQLITE_BUSY), EIO);
+        assert_eq!(errno_for_code(sql::ffi::SQLITE_INTERRUPT), EIO);
+        assert_eq!(errno_for_code(sql::ffi::SQLITE_FULL), ENOSPC);
+        // NOT NULL is left unclassified, and so gets the ENOENT anything unrecognized does
+        assert_eq!(errno_for_code(sql::ffi::SQLITE_CONSTRAINT | (5 << 8)), ENOENT);
+        // Bare, as operations return them before describe classifies them
+        let full = sql::Error::SqliteFailure(sql::ffi::Error::new(sql::ffi::SQLITE_FULL), None);
+        assert_eq!(errno_for(&full.into()), ENOSPC);
+    }
+
+    #[test]
+    fn maps_each_variant_to_its_errno() {
+        let cases = vec![
+            (Error::NotFound, ENOENT),
+            (Error::AlreadyExists, EEXIST),
+            (Error::NotEmpty, ENOTEMPTY),
+            (Error::IsADirectory, EISDIR),
+            (Error::NotADirectory, ENOTDIR),
+            (Error::ReadOnly, EROFS),
+            (Error::NoData, ENODATA),
+            (Error::InvalidArgument, EINVAL),
+            (Error::PermissionDenied, EACCES),
+            (Error::NotPermitted, EPERM),
+            (Error::FileTooBig, EFBIG),
+            (Error::QuotaExceeded, EDQUOT),
+            (Error::SymlinkLoop, ELOOP),
+            (Error::TimedOut, EIO),
+            (Error::Deferred{errno: ENOSPC}, ENOSPC),
+            (Error::Panicked{message: "oops".to_string()}, EIO),
+        ];
+        for (err, errno) in cases {
+            assert_eq!(errno_for(&err.into()), errno);
+        }
+        assert_eq!(errno_for(&format_err!("Something else")), ENOENT);
     }
 }
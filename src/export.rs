@@ -0,0 +1,330 @@
+use failure::Fallible;
+use libc;
+use rusqlite as sql;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use tar::{Builder, EntryType, Header};
+use owners::{self, IdMapping, Kind};
+use pages::{self, PageStore};
+use PAGE_SIZE;
+
+/// How much of a file PageReader fetches at a time
+const WINDOW: i64 = 64 * PAGE_SIZE;
+
+/// What an export wrote out
+#[derive(Debug, Default)]
+pub struct ExportStats {
+    pub entries: u64,
+    pub bytes: u64
+}
+
+/// Write everything under `root` to `out` as a tar archive
+///
+/// Entries come out parent first and in name order, so the same database always gives the
+/// same archive. Each file's pages are streamed into its entry, holes as zeros, without
+/// holding the whole file in memory. With IdMapping::Names, entries also carry the owner
+/// names recorded on import, which tar prefers over the numbers when extracting; sockets
+/// and whiteouts have no tar equivalent and are left out. With `prune`, so are directories
+/// with nothing left in them.
+///
+/// Nothing in the archive can reach outside the directory it's extracted into, whatever a
+/// database says: names that aren't a single path component are left out, along with
+/// everything under them, and symlink targets that could climb out are rewritten to point
+/// where they would inside the tree, see confine_target.
+pub fn export_tar<W: Write>(conn: &sql::Connection, root: u64, out: W, ids: IdMapping, prune: bool) -> Fallible<ExportStats> {
+    let mut stats = ExportStats::default();
+    let store = pages::open(conn, None, false)?;
+    let mut builder = Builder::new(out);
+    // Where each inode with more than one name was first written, for hard links
+    let mut written : HashMap<i64, PathBuf> = HashMap::new();
+    let mut names : HashMap<(Kind, u32), Option<String>> = HashMap::new();
+    let mut children = conn.prepare(
+        "SELECT Path.name, Inode.inode, kind, perm, uid, gid, size, mtime, rdev,
+            (SELECT count(*) FROM Path AS Other WHERE Other.inode = Inode.inode) AS links
+        FROM Path
+        NATURAL JOIN Inode
+        WHERE parent = ? AND Path.inode != Path.parent
+        ORDER BY Path.name DESC")?;
+
+    // Walk with an explicit stack, like import_tree; children are pushed last name first
+    let mut pending : Vec<(PathBuf, Entry)> = vec![];
+    push_children(&mut children, &mut pending, Path::new(""), root as i64)?;
+    // Directories not written yet under `prune`, outermost first, each inside the one before,
+    // waiting to find out if anything goes in them
+    let mut deferred : Vec<(PathBuf, Header)> = vec![];
+    while let Some((path, entry)) = pending.pop() {
+        // Entries pop off depth first, so once one isn't inside a deferred directory, nothing
+        // else will be either, and it stays out
+        while deferred.last().is_some_and(|(dir, _)| !path.starts_with(dir)) {
+            deferred.pop();
+        }
+        let mut header = Header::new_gnu();
+        header.set_mode(entry.perm);
+        header.set_uid(u64::from(entry.uid));
+        header.set_gid(u64::from(entry.gid));
+        // Epoch seconds, as tar stores them too; the tar crate can't write times before 1970
+        header.set_mtime(entry.mtime.max(0) as u64);
+        header.set_size(0);
+        if ids == IdMapping::Names {
+            for &(kind, id) in &[(Kind::User, entry.uid), (Kind::Group, entry.gid)] {
+                let name = match names.get(&(kind, id)) {
+                    Some(name) => name.clone(),
+                    None => {
+                        let name = owners::database_name(conn, kind, id)?;
+                        names.insert((kind, id), name.clone());
+                        name
+                    }
+                };
+                if let Some(name) = name {
+                    match kind {
+                        Kind::User => header.set_username(&name)?,
+                        Kind::Group => header.set_groupname(&name)?
+                    }
+                }
+            }
+        }
+        if prune && entry.kind == 3 {
+            deferred.push((path.clone(), header));
+            push_children(&mut children, &mut pending, &path, entry.inode)?;
+            continue;
+        }
+        // Only entries that are written get here, so every directory around them goes first
+        for (dir, mut dir_header) in deferred.drain(..) {
+            dir_header.set_entry_type(EntryType::Directory);
+            builder.append_data(&mut dir_header, &dir, io::empty())?;
+            stats.entries += 1;
+        }
+        if entry.links > 1 {
+            if let Some(first) = written.get(&entry.inode) {
+                header.set_entry_type(EntryType::Link);
+                builder.append_link(&mut header, &path, first)?;
+                stats.entries += 1;
+                continue;
+            }
+            written.insert(entry.inode, path.clone());
+        }
+        match entry.kind {
+            3 => {
+                header.set_entry_type(EntryType::Directory);
+                builder.append_data(&mut header, &path, io::empty())?;
+                push_children(&mut children, &mut pending, &path, entry.inode)?;
+            },
+            4 => {
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(entry.size as u64);
+                builder.append_data(&mut header, &path, PageReader::new(conn, &*store, entry.inode, entry.size))?;
+                stats.bytes += entry.size as u64;
+            },
+            5 => {
+                let mut target = Vec::new();
+                PageReader::new(conn, &*store, entry.inode, entry.size).read_to_end(&mut target)?;
+                let target = String::from_utf8_lossy(&target);
+                let confined = confine_target(&path, Path::new(&*target));
+                if confined != Path::new(&*target) {
+                    warn!("Exporting symlink {} to {} as {}, to keep it inside the archive",
+                        path.display(), target, confined.display());
+                }
+                header.set_entry_type(EntryType::Symlink);
+                builder.append_link(&mut header, &path, &confined)?;
+            },
+            0..=2 => {
+                header.set_entry_type(match entry.kind {
+                    0 => EntryType::Fifo,
+                    1 => EntryType::Char,
+                    _ => EntryType::Block
+                });
+                let rdev = entry.rdev as libc::dev_t;
+                let (major, minor) = (libc::major(rdev), libc::minor(rdev));
+                header.set_device_major(major)?;
+                header.set_device_minor(minor)?;
+                builder.append_data(&mut header, &path, io::empty())?;
+            },
+            kind => {
+                warn!("Leaving {} out of the archive, as tar can't store kind {}", path.display(), kind);
+                continue;
+            }
+        }
+        stats.entries += 1;
+    }
+    builder.into_inner()?.flush()?;
+    Ok(stats)
+}
+
+/// The columns of one Path and Inode row that go into a tar header
+struct Entry {
+    inode: i64,
+    kind: i8,
+    perm: u32,
+    uid: u32,
+    gid: u32,
+    size: i64,
+    mtime: i64,
+    rdev: i64,
+    links: i64
+}
+
+/// Where a symlink at `link` to `target` should point so it can't leave the archive's tree
+///
+/// Relative targets with no `..` are left alone; they can only go down from the link's own
+/// directory, through symlinks that are confined themselves. Anything else is resolved as if
+/// the tree were chrooted, so `/` is its root and `..` goes no higher, and then given relative
+/// to the link with every `..` at the front, where it climbs only real directories. A `..`
+/// after a name could undo a symlink that name turns out to be, so none are kept.
+fn confine_target(link: &Path, target: &Path) -> PathBuf {
+    let climbs = target.components().any(|c| c == Component::ParentDir);
+    if !target.has_root() && !climbs {
+        return target.to_path_buf();
+    }
+    let dir : Vec<Component> = link.parent().map_or(vec![], |dir| dir.components().collect());
+    let mut resolved : Vec<Component> = if target.has_root() { vec![] } else { dir.clone() };
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => resolved.push(component),
+            Component::ParentDir => { resolved.pop(); },
+            _ => ()
+        }
+    }
+    let shared = dir.iter().zip(&resolved).take_while(|(a, b)| a == b).count();
+    let mut confined : PathBuf = dir[shared..].iter().map(|_| Component::ParentDir).collect();
+    confined.extend(&resolved[shared..]);
+    if confined.as_os_str().is_empty() {
+        confined.push(".");
+    }
+    confined
+}
+
+/// Queue the entries of a directory so they pop off in name order
+///
+/// Names that aren't one ordinary path component, like `..` or anything containing `/`,
+/// can't be written safely and are left out with a warning, along with what's under them.
+fn push_children(
+    children: &mut sql::Statement,
+    pending: &mut Vec<(PathBuf, Entry)>,
+    dir: &Path,
+    inode: i64
+) -> sql::Result<()> {
+    let mut rows = children.query([inode])?;
+    while let Some(row) = rows.next()? {
+        let name : String = row.get("name")?;
+        let mut components = Path::new(&name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(part)), None) if part == &*name => (),
+            _ => {
+                warn!("Leaving {:?} in /{} out of the archive, as it isn't a safe file name", name, dir.display());
+                continue;
+            }
+        }
+        pending.push((dir.join(name), Entry {
+            inode: row.get("inode")?,
+            kind: row.get("kind")?,
+            perm: row.get("perm")?,
+            uid: row.get("uid")?,
+            gid: row.get("gid")?,
+            size: row.get("size")?,
+            mtime: row.get("mtime")?,
+            rdev: row.get("rdev")?,
+            links: row.get("links")?
+        }));
+    }
+    Ok(())
+}
+
+/// A file's contents read page by page, with holes as zeros, up to its recorded size
+struct PageReader<'a> {
+    conn: &'a sql::Connection,
+    store: &'a dyn PageStore,
+    inode: i64,
+    size: i64,
+    position: i64,
+    /// The file's bytes from `window_start`, up to WINDOW of them
+    window: Vec<u8>,
+    window_start: i64
+}
+
+impl<'a> PageReader<'a> {
+    fn new(conn: &'a sql::Connection, store: &'a dyn PageStore, inode: i64, size: i64) -> PageReader<'a> {
+        PageReader{conn, store, inode, size, position: 0, window: Vec::new(), window_start: 0}
+    }
+
+    /// Read the next WINDOW of the file in once `position` has reached the end of the last
+    fn fill(&mut self) -> Fallible<()> {
+        if self.position < self.window_start + self.window.len() as i64 {
+            return Ok(());
+        }
+        let (lo, hi) = (self.position, self.size.min(self.position + WINDOW));
+        let window = &mut self.window;
+        window.clear();
+        window.resize((hi - lo).max(0) as usize, 0);
+        // Painted in scan order, so where pages overlap the newest wins, as in read_basic
+        self.store.scan(self.conn, self.inode as u64, lo, hi, &mut |start, content| {
+            let (a, b) = (start.max(lo), (start + content.len() as i64).min(hi));
+            if a < b {
+                window[(a - lo) as usize .. (b - lo) as usize]
+                    .copy_from_slice(&content[(a - start) as usize .. (b - start) as usize]);
+            }
+        })?;
+        self.window_start = lo;
+        Ok(())
+    }
+}
+
+impl<'a> Read for PageReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fill().map_err(|e| io::Error::other(e.compat()))?;
+        let available = &self.window[(self.position - self.window_start) as usize ..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.position += len as i64;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use basic::{BasicFilesystem, Caller};
+    use options::Options;
+    use std::ffi::OsStr;
+    use tar::Archive;
+    use {Elkridge, ROOT_INODE};
+
+    #[test]
+    fn symlink_targets_stay_inside_the_tree() {
+        let confined = |link: &str, target: &str| confine_target(Path::new(link), Path::new(target));
+        assert_eq!(confined("d/link", "e/f"), Path::new("e/f"));
+        assert_eq!(confined("d/link", "../e"), Path::new("../e"));
+        assert_eq!(confined("d/link", "../../../etc/passwd"), Path::new("../etc/passwd"));
+        assert_eq!(confined("d/link", "/etc/passwd"), Path::new("../etc/passwd"));
+        assert_eq!(confined("link", "/"), Path::new("."));
+    }
+
+    #[test]
+    fn names_that_climb_out_are_left_out() {
+        let mut fs = Elkridge::new(sql::Connection::open_in_memory().unwrap(), Options::default()).unwrap();
+        let root = Caller::new(0, 0, 0);
+        let dir = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("d"), 0o755).unwrap();
+        fs.mknod_basic(&root, dir.ino, OsStr::new("kept"), libc::S_IFREG | 0o644, 0).unwrap();
+        fs.symlink_basic(&root, dir.ino, OsStr::new("link"), Path::new("/etc/passwd")).unwrap();
+        // No name from the kernel looks like these, but a database can have them anyway
+        for (parent, name) in &[(dir.ino, "../../etc/passwd"), (ROOT_INODE, ".."), (dir.ino, "a/b")] {
+            let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("crafted"), libc::S_IFREG | 0o644, 0).unwrap();
+            fs.conn.execute("UPDATE Path SET parent = ?, name = ? WHERE inode = ?",
+                &[&(*parent as i64) as &dyn sql::ToSql, name, &(file.ino as i64)]).unwrap();
+        }
+
+        let mut archive = vec![];
+        export_tar(&fs.conn, ROOT_INODE, &mut archive, IdMapping::Numeric, false).unwrap();
+        let mut entries = vec![];
+        for entry in Archive::new(&archive[..]).entries().unwrap() {
+            let entry = entry.unwrap();
+            let link = entry.link_name().unwrap().map(|link| link.into_owned());
+            entries.push((entry.path().unwrap().into_owned(), link));
+        }
+        assert_eq!(entries, vec![
+            (PathBuf::from("d"), None),
+            (PathBuf::from("d/kept"), None),
+            (PathBuf::from("d/link"), Some(PathBuf::from("../etc/passwd")))
+        ]);
+    }
+}
@@ -0,0 +1,107 @@
+use failure::Fallible;
+use libc::{self, c_int};
+#[cfg(test)]
+use std::cell::Cell;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use time;
+
+/// Whether writes are frozen right now
+static FROZEN: AtomicBool = AtomicBool::new(false);
+
+#[cfg(test)]
+thread_local! {
+    /// What FROZEN is in tests, which each freeze only themselves, not the others running alongside
+    static FROZEN_HERE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// What is_frozen last logged, so each change is logged once, from outside the handler
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+/// When --read-only-after freezes writes, in seconds since the epoch, or NEVER
+static SCHEDULED: AtomicI64 = AtomicI64::new(NEVER);
+
+const NEVER: i64 = i64::MAX;
+
+/// Freeze writes for the rest of the mount on SIGUSR1, and thaw them on SIGUSR2
+///
+/// While frozen every change is refused with EROFS, as under --read-only, and nothing in
+/// the background writes either, so the database files hold still for a backup to copy.
+/// The handler only flips an atomic, which is all a signal handler may safely do; the
+/// first request or background tick after it is what notices.
+pub fn install() -> Fallible<()> {
+    for &signal in &[libc::SIGUSR1, libc::SIGUSR2] {
+        unsafe {
+            let mut action : libc::sigaction = mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(c_int) as libc::sighandler_t;
+            // So the session's blocking read of /dev/fuse carries on rather than failing
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signal, &action, ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+    }
+    Ok(())
+}
+
+extern "C" fn on_signal(signal: c_int) {
+    FROZEN.store(signal == libc::SIGUSR1, Ordering::SeqCst);
+}
+
+/// Freeze writes once the clock reaches `at`, in seconds since the epoch, for --read-only-after
+pub fn schedule(at: i64) {
+    SCHEDULED.store(at, Ordering::SeqCst);
+}
+
+/// Freeze writes now, as SIGUSR1 does
+pub fn freeze() {
+    set_frozen(true);
+}
+
+/// Let writes through again, as SIGUSR2 does
+pub fn thaw() {
+    set_frozen(false);
+}
+
+#[cfg(not(test))]
+fn set_frozen(frozen: bool) {
+    FROZEN.store(frozen, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+fn set_frozen(frozen: bool) {
+    FROZEN_HERE.with(|here| here.set(frozen));
+}
+
+#[cfg(not(test))]
+fn frozen() -> bool {
+    FROZEN.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+fn frozen() -> bool {
+    FROZEN_HERE.with(Cell::get)
+}
+
+/// Whether changes are to be refused, freezing first if the --read-only-after time has come
+///
+/// The scheduled freeze only happens once, so a thaw after it lasts.
+pub fn is_frozen() -> bool {
+    let at = SCHEDULED.load(Ordering::SeqCst);
+    if at != NEVER && time::get_time().sec >= at
+        && SCHEDULED.compare_exchange(at, NEVER, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+        freeze();
+    }
+    let frozen = frozen();
+    if REPORTED.swap(frozen, Ordering::SeqCst) != frozen {
+        if frozen {
+            info!("Writes are frozen; the database won't change until SIGUSR2 or unmounting");
+        } else {
+            info!("Writes are thawed");
+        }
+    }
+    frozen
+}
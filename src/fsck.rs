@@ -0,0 +1,213 @@
+use basic;
+use failure::Fallible;
+use pages::PageStore;
+use rusqlite as sql;
+use rusqlite::{OptionalExtension, NO_PARAMS};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use {PAGE_SIZE, ROOT_INODE};
+
+/// A directory whose maintained child_count disagrees with its Path rows
+#[derive(Debug)]
+pub struct ChildCountMismatch {
+    pub inode: i64,
+    pub stored: i64,
+    pub actual: i64
+}
+
+/// Find every inode whose child_count doesn't match the number of entries naming it as parent
+pub fn check_child_counts(conn: &sql::Connection) -> Fallible<Vec<ChildCountMismatch>> {
+    let mut stmt = conn.prepare(
+        "SELECT inode, child_count,
+            (SELECT count(*) FROM Path
+                WHERE Path.parent = Inode.inode AND Path.inode != Path.parent) AS actual
+        FROM Inode
+        WHERE child_count != actual")?;
+    let mismatches = stmt.query_map(
+        NO_PARAMS,
+        |row| Ok(ChildCountMismatch{
+            inode: row.get("inode")?,
+            stored: row.get("child_count")?,
+            actual: row.get("actual")?
+        })
+    )?.collect::<sql::Result<_>>()?;
+    Ok(mismatches)
+}
+
+/// Recompute child_count from the Path table, returning how many inodes were corrected
+pub fn repair_child_counts(conn: &sql::Connection) -> Fallible<usize> {
+    Ok(conn.execute(
+        "UPDATE Inode SET child_count = (
+            SELECT count(*) FROM Path
+                WHERE Path.parent = Inode.inode AND Path.inode != Path.parent)
+        WHERE child_count != (
+            SELECT count(*) FROM Path
+                WHERE Path.parent = Inode.inode AND Path.inode != Path.parent)",
+        NO_PARAMS)?)
+}
+
+/// A page that overlaps one starting before it in the same file
+#[derive(Debug)]
+pub struct PageOverlap {
+    pub inode: i64,
+    pub start: i64,
+    pub finish: i64
+}
+
+/// Find every page overlapping an earlier one of the same file, of one inode or of all
+///
+/// Writes replace whatever they cover, so pages never overlap unless something is wrong.
+/// Reads would still be right, since later pages are laid over earlier ones as they're
+/// read, but the content hidden underneath is wasted. Sorting the pages by start and
+/// keeping the furthest finish so far finds overlaps without comparing every pair.
+pub fn check_page_overlaps(conn: &sql::Connection, inode: Option<u64>) -> Fallible<Vec<PageOverlap>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT inode, start, finish FROM (
+            SELECT inode, start, finish, max(finish) OVER (
+                PARTITION BY inode ORDER BY start, finish ROWS BETWEEN UNBOUNDED PRECEDING AND 1 PRECEDING
+            ) AS reach
+            FROM Page
+            WHERE finish > start AND (?1 IS NULL OR inode = ?1)
+        )
+        WHERE start < reach")?;
+    let overlaps = stmt.query_map(
+        [inode.map(|inode| inode as i64)],
+        |row| Ok(PageOverlap{inode: row.get(0)?, start: row.get(1)?, finish: row.get(2)?})
+    )?.collect::<sql::Result<_>>()?;
+    Ok(overlaps)
+}
+
+/// Rewrite the pages of every file in `overlaps` so none overlap, returning how many files
+///
+/// Each file's content is read back as a read sees it, later pages over earlier ones, and
+/// written again a page at a time, keeping its holes; nothing anyone could read changes.
+/// Call PageStore::collect once this is committed.
+pub fn repair_page_overlaps(conn: &sql::Connection, pages: &dyn PageStore, overlaps: &[PageOverlap]) -> Fallible<usize> {
+    let mut inodes : Vec<u64> = overlaps.iter().map(|overlap| overlap.inode as u64).collect();
+    inodes.dedup();
+    for &inode in &inodes {
+        // Each page's window, and the part of that window any old page covered
+        let mut windows : BTreeMap<i64, (Vec<u8>, i64, i64)> = BTreeMap::new();
+        pages.scan(conn, inode, 0, i64::MAX, &mut |start, content| {
+            let finish = start + content.len() as i64;
+            let mut at = start;
+            while at < finish {
+                let window = at - at % PAGE_SIZE;
+                let end = finish.min(window + PAGE_SIZE);
+                let (bytes, lo, hi) = windows.entry(window).or_insert_with(|| (vec![0; PAGE_SIZE as usize], PAGE_SIZE, 0));
+                bytes[(at - window) as usize .. (end - window) as usize]
+                    .copy_from_slice(&content[(at - start) as usize .. (end - start) as usize]);
+                *lo = (*lo).min(at - window);
+                *hi = (*hi).max(end - window);
+                at = end;
+            }
+        })?;
+        pages.delete(conn, inode, 0, i64::MAX)?;
+        for (window, (bytes, lo, hi)) in windows {
+            pages.put(conn, inode, window + lo, &bytes[lo as usize .. hi as usize])?;
+        }
+    }
+    Ok(inodes.len())
+}
+
+/// Name of the directory recover links unreachable inodes into, directly under the root
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/// An inode recover linked into lost+found
+#[derive(Debug)]
+pub struct Recovered {
+    pub inode: i64,
+    pub kind: i8,
+    /// Its name in lost+found
+    pub name: String,
+    /// Whether it still had a Path row, in a loop of directories cut off from the root,
+    /// which has been moved rather than added
+    pub moved: bool
+}
+
+/// Link every inode the root can't reach into /lost+found, named by its inode number
+///
+/// Mostly these are inodes with no Path row, whose names a crash or a bug lost; a
+/// directory among them brings everything still under it back along with it. What's left
+/// unreachable after that is in loops of directories that name each other as parent, and
+/// one directory of each loop is moved. lost+found is made (rwx for root only, as e2fsck
+/// makes it) if the root doesn't have it. Whiteouts only matter by their names, so any
+/// without one are left alone. Open files that have been unlinked look just the same,
+/// so this is only for a database nothing has mounted, and it has to come before the next
+/// mount, which deletes whatever has no name and nothing under it.
+pub fn recover_lost(conn: &sql::Connection) -> Fallible<Vec<Recovered>> {
+    let mut recovered = Vec::new();
+    let unnamed = unreachable(conn, "NOT EXISTS(SELECT 1 FROM Path WHERE Path.inode = Inode.inode)")?;
+    let mut lost_dir = None;
+    for (inode, kind) in unnamed {
+        let dir = lost_and_found(conn, &mut lost_dir)?;
+        let name = free_name(conn, dir, inode)?;
+        conn.execute(
+            "INSERT INTO Path(inode, parent, name) VALUES (?,?,?)",
+            &[&inode as &dyn sql::ToSql, &dir, &name])?;
+        recovered.push(Recovered{inode, kind, name, moved: false});
+    }
+    // Moving one directory out of a loop reconnects the rest of it, so look again each time
+    while let Some(&(inode, kind)) = unreachable(conn, "kind = 3")?.first() {
+        let dir = lost_and_found(conn, &mut lost_dir)?;
+        let name = free_name(conn, dir, inode)?;
+        conn.execute(
+            "UPDATE Path SET parent = ?, name = ? WHERE inode = ?",
+            &[&dir as &dyn sql::ToSql, &name, &inode])?;
+        recovered.push(Recovered{inode, kind, name, moved: true});
+    }
+    Ok(recovered)
+}
+
+/// Every inode, apart from whiteouts, not under the root and matching `condition`, as
+/// (inode, kind)
+fn unreachable(conn: &sql::Connection, condition: &str) -> sql::Result<Vec<(i64, i8)>> {
+    let mut stmt = conn.prepare(&format!(
+        "WITH RECURSIVE Reached(inode) AS (
+            SELECT 0
+            UNION SELECT Path.inode FROM Path JOIN Reached ON Path.parent = Reached.inode
+        )
+        SELECT inode, kind FROM Inode
+        WHERE inode NOT IN Reached AND kind != 7 AND {}
+        ORDER BY inode", condition))?;
+    let found = stmt.query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<sql::Result<_>>()?;
+    Ok(found)
+}
+
+/// The inode of /lost+found, made the first time it's needed
+fn lost_and_found(conn: &sql::Connection, found: &mut Option<i64>) -> Fallible<i64> {
+    if let Some(dir) = *found {
+        return Ok(dir);
+    }
+    let existing : Option<(i64, i8)> = conn.query_row(
+        "SELECT inode, kind FROM Path NATURAL JOIN Inode WHERE parent = 0 AND name = ? AND inode != 0",
+        [LOST_AND_FOUND],
+        |row| Ok((row.get(0)?, row.get(1)?))).optional()?;
+    let dir = match existing {
+        Some((dir, 3)) => dir,
+        Some(_) => bail!("/{} exists but isn't a directory; move it aside first", LOST_AND_FOUND),
+        None => {
+            let dir = basic::number_inode(conn, false, ROOT_INODE, OsStr::new(LOST_AND_FOUND))?;
+            conn.execute("INSERT INTO Inode(inode, kind, perm, uid, gid) VALUES (?, 3, 448, 0, 0)", [dir])?;
+            conn.execute(
+                "INSERT INTO Path(inode, parent, name) VALUES (?, 0, ?)",
+                &[&dir as &dyn sql::ToSql, &LOST_AND_FOUND])?;
+            dir
+        }
+    };
+    *found = Some(dir);
+    Ok(dir)
+}
+
+/// The inode's number as a name in `dir`, with a suffix if something already has it
+fn free_name(conn: &sql::Connection, dir: i64, inode: i64) -> sql::Result<String> {
+    let mut taken = conn.prepare_cached("SELECT EXISTS(SELECT 1 FROM Path WHERE parent = ? AND name = ?)")?;
+    let mut name = inode.to_string();
+    let mut tries = 1;
+    while taken.query_row(&[&dir as &dyn sql::ToSql, &name], |row| row.get::<usize, bool>(0))? {
+        name = format!("{}.{}", inode, tries);
+        tries += 1;
+    }
+    Ok(name)
+}
@@ -0,0 +1,147 @@
+use failure::Fallible;
+use libc;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use is_mount_point;
+
+/// Name mount(8) runs us by for filesystems of type elkridge in /etc/fstab
+pub const HELPER_NAME: &str = "mount.elkridge";
+
+/// How long mount(8) is kept waiting for the mount to appear before giving up on it
+const MOUNT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Options mount(8) passes along that are either its own business or the kernel's defaults
+/// for FUSE anyway, and so mean nothing to us
+const IGNORED: &[&str] = &[
+    "defaults", "rw", "auto", "noauto", "user", "nouser", "users", "owner", "group", "nofail",
+    "_netdev", "dev", "nodev", "suid", "nosuid", "exec", "noexec", "async", "atime", "silent", "loud",
+];
+
+/// Whether we were started as the mount helper, judging by the name we were run as
+pub fn is_helper(argv: &[OsString]) -> bool {
+    argv.first()
+        .and_then(|arg0| Path::new(arg0).file_name())
+        .is_some_and(|name| name == HELPER_NAME)
+}
+
+/// Rewrite a helper command line, `mount.elkridge <db> <mountpoint> [-sfnv] [-o options]`,
+/// into the ordinary one
+pub fn helper_args(argv: &[OsString]) -> Fallible<Vec<OsString>> {
+    let mut args = vec![OsString::from(HELPER_NAME)];
+    let mut rest = argv.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        match arg.to_str() {
+            Some("-o") => {
+                let options = rest.next().ok_or_else(|| format_err!("-o needs a list of options"))?;
+                let options = options.to_str().ok_or_else(|| format_err!("Options are not valid UTF-8"))?;
+                args.extend(parse_options(options)?.into_iter().map(OsString::from));
+            },
+            // Sloppy, fake, no mtab and verbose: none of them change what we'd do
+            Some("-s") | Some("-f") | Some("-n") | Some("-v") => {},
+            Some("-t") => {
+                rest.next();
+            },
+            _ => args.push(arg.clone())
+        }
+    }
+    Ok(args)
+}
+
+/// Turn an fstab options field like `ro,noatime,cache-size=1024K` into command line arguments
+///
+/// Our own options are written like the long flags without their dashes, taking values after
+/// an `=`; underscores may stand in for the dashes inside them.
+pub fn parse_options(options: &str) -> Fallible<Vec<String>> {
+    let mut args = Vec::new();
+    for option in options.split(',').filter(|option| !option.is_empty()) {
+        if IGNORED.contains(&option) || option.starts_with("x-") || option.starts_with("comment=") {
+            continue;
+        }
+        match option {
+            "ro" => args.push("--read-only".to_string()),
+            "default_permissions" => args.push("--enforce-permissions".to_string()),
+            "noatime" | "relatime" | "strictatime" => args.push(format!("--atime={}", option)),
+            _ => {
+                let (key, value) = match option.find('=') {
+                    Some(at) => (&option[..at], Some(&option[at + 1..])),
+                    None => (option, None)
+                };
+                ensure!(!key.is_empty() && !key.starts_with('-'), "Invalid mount option: {}", option);
+                let key = key.replace('_', "-");
+                args.push(match value {
+                    Some(value) => format!("--{}={}", key, value),
+                    None => format!("--{}", key)
+                });
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Carry on in the background, returning in a child process while this one waits for the
+/// mount to appear and then exits, which is when mount(8) considers the mount done
+///
+/// If the child exits first, mounting failed (it will have said why) and so does this.
+pub fn daemonize(mount_path: &OsStr) -> Fallible<()> {
+    let mount_path = Path::new(mount_path);
+    match unsafe { libc::fork() } {
+        -1 => bail!("Cannot fork: {}", ::std::io::Error::last_os_error()),
+        0 => {
+            unsafe { libc::setsid() };
+            Ok(())
+        },
+        child => {
+            let started = Instant::now();
+            while started.elapsed() < MOUNT_TIMEOUT {
+                let mut status = 0;
+                if unsafe { libc::waitpid(child, &mut status, libc::WNOHANG) } == child {
+                    bail!("Mounting {} failed", mount_path.display());
+                }
+                if is_mount_point(mount_path).unwrap_or(false) {
+                    process::exit(0);
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+            bail!("{} was not mounted after {}s", mount_path.display(), MOUNT_TIMEOUT.as_secs());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<OsString> {
+        list.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn translates_fstab_options() {
+        assert_eq!(parse_options("ro,noatime,cache_size=1024K,expose-tables").unwrap(),
+            ["--read-only", "--atime=noatime", "--cache-size=1024K", "--expose-tables"]);
+        assert_eq!(parse_options("default_permissions").unwrap(), ["--enforce-permissions"]);
+    }
+
+    #[test]
+    fn skips_what_means_nothing_to_us() {
+        assert!(parse_options("defaults,rw,nofail,_netdev,x-systemd.automount,comment=backup,,").unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_options_that_arent_options() {
+        assert!(parse_options("=1").is_err());
+        assert!(parse_options("--read-only").is_err());
+    }
+
+    #[test]
+    fn rewrites_helper_command_lines() {
+        let argv = args(&["/sbin/mount.elkridge", "db.sqlite", "/mnt", "-n", "-o", "ro,file_mask=0444", "-t", "elkridge"]);
+        assert!(is_helper(&argv));
+        assert_eq!(helper_args(&argv).unwrap(),
+            args(&[HELPER_NAME, "db.sqlite", "/mnt", "--read-only", "--file-mask=0444"]));
+        assert!(!is_helper(&args(&["elkridge", "db.sqlite", "/mnt"])));
+    }
+}
@@ -0,0 +1,134 @@
+use libc::c_int;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// Inodes the kernel still has references to, shared with the background sweeper
+pub type Pinned = Arc<Mutex<HashSet<u64>>>;
+
+/// What we keep about one open file between open and release
+pub struct Handle {
+    pub ino: u64,
+    /// The open flags, and who opened it
+    pub flags: u32,
+    pub uid: u32,
+    pub pid: u32,
+    /// Where the last read on this handle ended
+    pub next_offset: i64,
+    /// Whether the last read started where the one before it ended
+    pub sequential: bool,
+    /// The errno of a failure storing the file's buffered writes, until this handle reports it
+    pub deferred: Option<c_int>
+}
+
+/// The kernel's references to our inodes: open file handles, and names it has looked up
+///
+/// An inode that has lost its last name stays in the database until both are gone, so a
+/// file unlinked while open can still be read and written through its handles.
+#[derive(Default)]
+pub struct Handles {
+    /// Open handles, by the fh number handed to the kernel
+    open: HashMap<u64, Handle>,
+    last: u64,
+    /// How many entry replies the kernel has had for each inode, less those it has forgotten
+    lookups: HashMap<u64, u64>,
+    /// Every inode that is_referenced, for threads that can't ask
+    pinned: Pinned
+}
+
+impl Handles {
+    /// Record a newly opened file, returning its fh
+    ///
+    /// Numbers start at 1 and aren't reused, so a stale fh can never find someone else's handle.
+    pub fn open(&mut self, ino: u64, flags: u32, uid: u32, pid: u32) -> u64 {
+        self.last += 1;
+        self.open.insert(self.last, Handle{ino, flags, uid, pid, next_offset: 0, sequential: true, deferred: None});
+        self.update_pin(ino);
+        self.last
+    }
+
+    pub fn get(&self, fh: u64) -> Option<&Handle> {
+        self.open.get(&fh)
+    }
+
+    /// Every open handle with its fh, oldest first
+    pub fn list(&self) -> Vec<(u64, &Handle)> {
+        let mut list : Vec<(u64, &Handle)> = self.open.iter().map(|(&fh, handle)| (fh, handle)).collect();
+        list.sort_by_key(|&(fh, _)| fh);
+        list
+    }
+
+    /// Note a read of `offset..end` on a handle, tracking whether its reads are sequential
+    pub fn record_read(&mut self, fh: u64, offset: i64, end: i64) {
+        if let Some(handle) = self.open.get_mut(&fh) {
+            handle.sequential = offset == handle.next_offset;
+            handle.next_offset = end;
+        }
+    }
+
+    /// Keep a failure storing an inode's writes on every handle open on it, for each to report
+    ///
+    /// A handle that already has one keeps that, the first, as Linux reports the first
+    /// writeback error a descriptor hasn't seen.
+    pub fn defer_error(&mut self, ino: u64, errno: c_int) {
+        for handle in self.open.values_mut().filter(|handle| handle.ino == ino) {
+            handle.deferred.get_or_insert(errno);
+        }
+    }
+
+    /// The failure a handle has yet to report, if any, which it has then reported
+    pub fn take_deferred(&mut self, fh: u64) -> Option<c_int> {
+        self.open.get_mut(&fh).and_then(|handle| handle.deferred.take())
+    }
+
+    /// Forget a handle once the kernel releases it
+    pub fn release(&mut self, fh: u64) -> Option<Handle> {
+        let handle = self.open.remove(&fh);
+        if let Some(ref handle) = handle {
+            self.update_pin(handle.ino);
+        }
+        handle
+    }
+
+    /// Whether any handle is still open on this inode
+    pub fn is_open(&self, ino: u64) -> bool {
+        self.open.values().any(|handle| handle.ino == ino)
+    }
+
+    /// Count an entry reply (from lookup, mknod and the like) for this inode
+    pub fn remember_lookup(&mut self, ino: u64) {
+        *self.lookups.entry(ino).or_insert(0) += 1;
+        self.update_pin(ino);
+    }
+
+    /// Take back lookups the kernel has forgotten
+    pub fn forget(&mut self, ino: u64, count: u64) {
+        if let Some(lookups) = self.lookups.get_mut(&ino) {
+            *lookups = lookups.saturating_sub(count);
+            if *lookups == 0 {
+                self.lookups.remove(&ino);
+            }
+        }
+        self.update_pin(ino);
+    }
+
+    /// Whether the kernel could still ask about this inode
+    pub fn is_referenced(&self, ino: u64) -> bool {
+        self.lookups.contains_key(&ino) || self.is_open(ino)
+    }
+
+    /// The set of referenced inodes, kept up to date as references come and go
+    pub fn pinned(&self) -> Pinned {
+        self.pinned.clone()
+    }
+
+    fn update_pin(&self, ino: u64) {
+        let referenced = self.is_referenced(ino);
+        if let Ok(mut pinned) = self.pinned.lock() {
+            if referenced {
+                pinned.insert(ino);
+            } else {
+                pinned.remove(&ino);
+            }
+        }
+    }
+}
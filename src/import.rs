@@ -0,0 +1,478 @@
+use failure::Fallible;
+use rusqlite as sql;
+use libc;
+use rusqlite::OptionalExtension;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File, Metadata};
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tar::{Archive, EntryType};
+use basic::number_inode;
+use errors::Error;
+use owners::{IdMapping, Kind, OwnerMap};
+use {Elkridge, PAGE_SIZE};
+
+/// What an import added to the database
+#[derive(Debug, Default)]
+pub struct ImportStats {
+    /// Inodes added, or with import_tree's incremental, added or brought up to date
+    pub inodes: u64,
+    /// Bytes of content written
+    pub bytes: u64,
+    /// Pages of file content written
+    pub pages: u64,
+    /// Files an incremental import found unchanged and left alone
+    pub unchanged: u64
+}
+
+/// Copy a directory tree from the host into the database under `parent`
+///
+/// Everything happens in the caller's transaction, which the caller commits (or, for a dry
+/// run, rolls back), with a handful of prepared statements reused for every row. That's what
+/// makes importing many small files fast: the per-statement and per-commit overheads are
+/// paid once rather than once per file. Hard links on the host
+/// are imported as separate copies. With IdMapping::Names, owners are matched up with the
+/// names already in the database rather than stored by number. `stable` numbers inodes by
+/// their path, as --stable-inodes does when mounted.
+///
+/// `incremental` brings an earlier import of the same tree up to date instead of adding to
+/// it, for backups. A file whose size, mtime and ctime are as stored is left alone
+/// entirely; anything else already there keeps its inode and gets its attributes updated,
+/// and a changed file has only the pages whose content differs rewritten. An entry whose type
+/// changed is removed, with everything under it, and imported afresh. Entries the source no
+/// longer has are kept, as rsync does without --delete.
+pub fn import_tree(
+    txn: &sql::Transaction,
+    source: &Path,
+    parent: u64,
+    ids: IdMapping,
+    stable: bool,
+    incremental: bool
+) -> Fallible<ImportStats> {
+    let mut stats = ImportStats::default();
+    let mut owners = OwnerMap::default();
+    {
+        let mut insert_inode = txn.prepare_cached(
+            "INSERT INTO Inode(inode, kind, perm, uid, gid, size, blocks, atime, mtime, ctime, crtime, rdev)
+            VALUES (?,?,?,?,?,?,?,?,?,?,?,?)")?;
+        let mut update_inode = txn.prepare_cached(
+            "UPDATE Inode SET perm = ?2, uid = ?3, gid = ?4, size = ?5, blocks = ?6, atime = ?7, mtime = ?8, ctime = ?9, rdev = ?10
+            WHERE inode = ?1")?;
+        let mut insert_path = txn.prepare_cached(
+            "INSERT INTO Path(inode, parent, name) VALUES (?,?,?)")?;
+        let mut insert_page = txn.prepare_cached(
+            "INSERT INTO Page(inode, start, finish, content) VALUES (?,?,?,?)")?;
+        let mut find = txn.prepare_cached(
+            "SELECT inode, kind, size, mtime, ctime FROM Path NATURAL JOIN Inode WHERE parent = ? AND name = ?")?;
+        let mut same_page = txn.prepare_cached(
+            "SELECT EXISTS(SELECT 1 FROM Page WHERE inode = ? AND start = ? AND finish = ? AND blob IS NULL AND chunk IS NULL AND content = ?)")?;
+        let mut delete_pages = txn.prepare_cached(
+            "DELETE FROM Page WHERE inode = ? AND start < ? AND finish > ?")?;
+        let mut chunk = vec![0u8; PAGE_SIZE as usize];
+
+        // Walk with an explicit stack so deep trees can't overflow ours
+        let mut pending : Vec<(PathBuf, i64)> = vec![(source.to_path_buf(), parent as i64)];
+        while let Some((dir, dir_inode)) = pending.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let meta = entry.path().symlink_metadata()?;
+                let kind_code = Elkridge::code_from_mode(meta.mode(), meta.rdev() as u32);
+                let (uid, gid) = match ids {
+                    IdMapping::Numeric => (meta.uid(), meta.gid()),
+                    IdMapping::Names => (
+                        owners.database_id(txn, Kind::User, meta.uid())?,
+                        owners.database_id(txn, Kind::Group, meta.gid())?)
+                };
+                let existing : Option<(i64, i8, i64, i64, i64)> = if incremental {
+                    find.query_row(
+                        &[&dir_inode as &dyn sql::ToSql, &entry.file_name().to_string_lossy()],
+                        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))).optional()?
+                } else {
+                    None
+                };
+                // Whether the inode was already there, so its content is compared before writing
+                let (inode, compare) = match existing {
+                    Some((_, 4, size, mtime, ctime))
+                        if kind_code == 4 && (size, mtime, ctime) == (meta.size() as i64, meta.mtime(), meta.ctime()) => {
+                        stats.unchanged += 1;
+                        continue;
+                    },
+                    Some((inode, kind, ..)) if kind == kind_code => {
+                        update_inode.execute(&[
+                            &inode as &dyn sql::ToSql,
+                            &(meta.mode() & 0o7777),
+                            &uid,
+                            &gid,
+                            &(meta.size() as i64),
+                            &(meta.blocks() as i64),
+                            &meta.atime(),
+                            &meta.mtime(),
+                            &meta.ctime(),
+                            &(meta.rdev() as i64)
+                        ])?;
+                        (inode, true)
+                    },
+                    existing => {
+                        if let Some((inode, ..)) = existing {
+                            remove_tree(txn, inode)?;
+                        }
+                        let inode = number_inode(txn, stable, dir_inode as u64, &entry.file_name())?;
+                        insert_inode.execute(&[
+                            &inode as &dyn sql::ToSql,
+                            &kind_code,
+                            &(meta.mode() & 0o7777),
+                            &uid,
+                            &gid,
+                            &(meta.size() as i64),
+                            &(meta.blocks() as i64),
+                            &meta.atime(),
+                            &meta.mtime(),
+                            &meta.ctime(),
+                            &birth_time(&meta),
+                            &(meta.rdev() as i64)
+                        ])?;
+                        insert_path.execute(&[
+                            &inode as &dyn sql::ToSql,
+                            &dir_inode,
+                            &entry.file_name().to_string_lossy()
+                        ])?;
+                        (inode, false)
+                    }
+                };
+                stats.inodes += 1;
+
+                // Write a page unless an identical one is already there, replacing whatever
+                // overlaps it (since pages never overlap, an identical one is all that's there),
+                // returning whether it was written
+                let mut put_page = |start: i64, content: &[u8]| -> sql::Result<bool> {
+                    let finish = start + content.len() as i64;
+                    if compare {
+                        let same : bool = same_page.query_row(
+                            &[&inode as &dyn sql::ToSql, &start, &finish, &content],
+                            |row| row.get(0))?;
+                        if same {
+                            return Ok(false);
+                        }
+                        delete_pages.execute([inode, finish, start])?;
+                    }
+                    insert_page.execute(&[&inode as &dyn sql::ToSql, &start, &finish, &content])?;
+                    Ok(true)
+                };
+                let kind = meta.file_type();
+                if kind.is_dir() {
+                    pending.push((entry.path(), inode));
+                } else if kind.is_file() {
+                    let mut file = File::open(entry.path())?;
+                    let mut start = 0i64;
+                    loop {
+                        let len = fill(&mut file, &mut chunk)?;
+                        if len == 0 {
+                            break;
+                        }
+                        if put_page(start, &chunk[..len])? {
+                            stats.pages += 1;
+                            stats.bytes += len as u64;
+                        }
+                        start += len as i64;
+                    }
+                    if compare {
+                        txn.execute("DELETE FROM Page WHERE inode = ? AND start >= ?", [inode, start])?;
+                    }
+                } else if kind.is_symlink() {
+                    // A symlink's content is its target, like a very small file
+                    let target = fs::read_link(entry.path())?;
+                    let target = target.to_string_lossy();
+                    put_page(0, target.as_bytes())?;
+                    if compare {
+                        txn.execute("DELETE FROM Page WHERE inode = ? AND start >= ?", [inode, target.len() as i64])?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Delete an inode along with everything under it, if it's a directory
+///
+/// Children's Path rows go before any Inode row, since a directory can't be deleted while
+/// anything names it as parent.
+fn remove_tree(conn: &sql::Connection, inode: i64) -> sql::Result<()> {
+    let tree : Vec<i64> = conn.prepare_cached(
+        "WITH RECURSIVE Tree(inode) AS (
+            SELECT ?
+            UNION SELECT Path.inode FROM Path JOIN Tree ON Path.parent = Tree.inode WHERE Path.inode != Path.parent
+        )
+        SELECT inode FROM Tree")?
+        .query_map([inode], |row| row.get(0))?
+        .collect::<sql::Result<_>>()?;
+    for &inode in &tree {
+        conn.execute("DELETE FROM Path WHERE inode = ?", [inode])?;
+    }
+    for &inode in &tree {
+        conn.execute("DELETE FROM Inode WHERE inode = ?", [inode])?;
+    }
+    Ok(())
+}
+
+/// Copy a tar archive into the database under `parent`, like import_tree does a directory
+///
+/// Directories missing from the archive are made as needed, and ones that already exist
+/// (including `.`) are merged into rather than replaced. Long names and PAX headers are
+/// handled by the tar crate. Hard links become copies of the file they point to, which must
+/// come earlier in the archive. With IdMapping::Names, the owner names in each header decide
+/// the ids stored, as recorded in the Owner table. `stable` and the transaction are as for
+/// import_tree.
+pub fn import_tar<R: Read>(txn: &sql::Transaction, source: R, parent: u64, ids: IdMapping, stable: bool) -> Fallible<ImportStats> {
+    let mut stats = ImportStats::default();
+    let mut owners = OwnerMap::default();
+    let mut archive = Archive::new(source);
+    {
+        let mut insert_inode = txn.prepare_cached(
+            "INSERT INTO Inode(inode, kind, perm, uid, gid, size, blocks, atime, mtime, ctime, rdev)
+            VALUES (?1,?2,?3,?4,?5,?6,?7,?8,?8,?8,?9)")?;
+        let mut insert_path = txn.prepare_cached(
+            "INSERT INTO Path(inode, parent, name) VALUES (?,?,?)")?;
+        let mut insert_page = txn.prepare_cached(
+            "INSERT INTO Page(inode, start, finish, content) VALUES (?,?,?,?)")?;
+        let mut find = txn.prepare_cached(
+            "SELECT inode, kind FROM Path NATURAL JOIN Inode WHERE parent = ? AND name = ?")?;
+        let mut chunk = vec![0u8; PAGE_SIZE as usize];
+        // Inodes already stored in this import, by their path in the archive
+        let mut stored : HashMap<PathBuf, i64> = HashMap::new();
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = archive_path(&entry.path()?)?;
+            let kind = match entry.header().entry_type() {
+                EntryType::Directory => 3,
+                EntryType::Regular | EntryType::Continuous | EntryType::Link => 4,
+                EntryType::Symlink => 5,
+                EntryType::Fifo => 0,
+                EntryType::Char => 1,
+                EntryType::Block => 2,
+                other => {
+                    warn!("Skipping {}, a {:?} entry", path.display(), other);
+                    continue;
+                }
+            };
+            let (name, dir) = match (path.file_name(), path.parent()) {
+                (Some(name), Some(dir)) => (name.to_string_lossy().into_owned(), dir.to_path_buf()),
+                // The archive's own top directory, which is `parent` itself
+                _ => continue
+            };
+
+            // Find (or make) every directory on the way down
+            let mut dir_inode = parent as i64;
+            let mut walked = PathBuf::new();
+            for component in dir.iter() {
+                walked.push(component);
+                dir_inode = match stored.get(&walked) {
+                    Some(&inode) => inode,
+                    None => {
+                        let component = component.to_string_lossy();
+                        let existing : Option<(i64, i8)> = find.query_row(
+                            &[&dir_inode as &dyn sql::ToSql, &component],
+                            |row| Ok((row.get(0)?, row.get(1)?))).optional()?;
+                        let inode = match existing {
+                            Some((inode, 3)) => inode,
+                            Some(_) => raise!(Error::NotADirectory),
+                            None => {
+                                let inode = number_inode(txn, stable, dir_inode as u64, OsStr::new(component.as_ref()))?;
+                                txn.execute("INSERT INTO Inode(inode, kind, perm) VALUES (?, 3, 493)", [inode])?;
+                                insert_path.execute(&[&inode as &dyn sql::ToSql, &dir_inode, &component])?;
+                                stats.inodes += 1;
+                                inode
+                            }
+                        };
+                        stored.insert(walked.clone(), inode);
+                        inode
+                    }
+                };
+            }
+            if kind == 3 {
+                let existing : Option<(i64, i8)> = find.query_row(
+                    &[&dir_inode as &dyn sql::ToSql, &name],
+                    |row| Ok((row.get(0)?, row.get(1)?))).optional()?;
+                if let Some((inode, 3)) = existing {
+                    stored.insert(path, inode);
+                    continue;
+                }
+            }
+
+            let header = entry.header();
+            let (mut uid, mut gid) = (header.uid()? as u32, header.gid()? as u32);
+            if ids == IdMapping::Names {
+                if let Some(name) = header.username()? {
+                    uid = owners.database_id_named(txn, Kind::User, uid, name)?;
+                }
+                if let Some(name) = header.groupname()? {
+                    gid = owners.database_id_named(txn, Kind::Group, gid, name)?;
+                }
+            }
+            // Other entries may leave the device fields blank rather than zero
+            let rdev = match kind {
+                1 | 2 => match (header.device_major()?, header.device_minor()?) {
+                    (Some(major), Some(minor)) => libc::makedev(major, minor) as i64,
+                    _ => 0
+                },
+                _ => 0
+            };
+            let mode = header.mode()? & 0o7777;
+            let mtime = header.mtime()? as i64;
+            let link = entry.link_name()?.map(|link| link.into_owned());
+            let kind = if kind == 1 {
+                Elkridge::code_from_mode(libc::S_IFCHR | mode, rdev as u32)
+            } else {
+                kind
+            };
+            let inode = number_inode(txn, stable, dir_inode as u64, OsStr::new(&name))?;
+            insert_inode.execute(&[
+                &inode as &dyn sql::ToSql,
+                &kind,
+                &mode,
+                &uid,
+                &gid,
+                &0i64,
+                &0i64,
+                &mtime,
+                &rdev
+            ])?;
+            insert_path.execute(&[&inode as &dyn sql::ToSql, &dir_inode, &name])?;
+            stats.inodes += 1;
+
+            let mut size = 0i64;
+            match (entry.header().entry_type(), link) {
+                (EntryType::Link, Some(target)) => {
+                    let target = archive_path(&target)?;
+                    let &original = stored.get(&target)
+                        .ok_or_else(|| format_err!("{} links to {}, which isn't earlier in the archive", path.display(), target.display()))?;
+                    stats.pages += txn.execute(
+                        "INSERT INTO Page(inode, start, finish, content)
+                        SELECT ?, start, finish, content FROM Page WHERE inode = ?",
+                        [inode, original])? as u64;
+                    size = txn.query_row("SELECT size FROM Inode WHERE inode = ?", [original], |row| row.get(0))?;
+                    stats.bytes += size as u64;
+                },
+                (EntryType::Symlink, Some(target)) => {
+                    let target = target.to_string_lossy();
+                    insert_page.execute(&[&inode as &dyn sql::ToSql, &0i64, &(target.len() as i64), &target.as_bytes()])?;
+                    size = target.len() as i64;
+                },
+                _ if kind == 4 => loop {
+                    let len = fill(&mut entry, &mut chunk)?;
+                    if len == 0 {
+                        break;
+                    }
+                    insert_page.execute(&[&inode as &dyn sql::ToSql, &size, &(size + len as i64), &&chunk[..len]])?;
+                    size += len as i64;
+                    stats.bytes += len as u64;
+                    stats.pages += 1;
+                },
+                _ => {}
+            }
+            if size > 0 {
+                txn.execute(
+                    "UPDATE Inode SET size = ?1, blocks = (?1 + 511) / 512 WHERE inode = ?2",
+                    [size, inode])?;
+            }
+            stored.insert(path, inode);
+        }
+    }
+    Ok(stats)
+}
+
+/// A path from an archive made relative, refusing any that would climb out of the import
+fn archive_path(path: &Path) -> Fallible<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {},
+            Component::ParentDir => bail!("Refusing to import {}, which leads outside the archive", path.display())
+        }
+    }
+    Ok(relative)
+}
+
+/// Read until the buffer is full or the input ends, so every page but the last is full sized
+pub fn fill<R: Read>(file: &mut R, buf: &mut [u8]) -> Fallible<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n
+        }
+    }
+    Ok(len)
+}
+
+/// The creation time where the host filesystem records one, otherwise the mtime
+fn birth_time(meta: &Metadata) -> i64 {
+    meta.created().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_else(|| meta.mtime())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use basic::{BasicFilesystem, Caller};
+    use options::Options;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use ROOT_INODE;
+
+    fn import(fs: &mut Elkridge, source: &Path) -> ImportStats {
+        let txn = fs.conn.transaction().unwrap();
+        let stats = import_tree(&txn, source, ROOT_INODE, IdMapping::Numeric, false, true).unwrap();
+        txn.commit().unwrap();
+        stats
+    }
+
+    /// Give a file an mtime of its own, since a rewrite within the same second might not move it
+    fn set_mtime(path: &Path, mtime: i64) {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let times = [libc::timeval{tv_sec: mtime, tv_usec: 0}; 2];
+        assert_eq!(unsafe { libc::utimes(path.as_ptr(), times.as_ptr()) }, 0);
+    }
+
+    #[test]
+    fn incremental_imports_rewrite_only_what_changed() {
+        let source = ::std::env::temp_dir().join(format!("elkridge-test-{}-incremental", ::std::process::id()));
+        let _ = fs::remove_dir_all(&source);
+        fs::create_dir_all(source.join("sub")).unwrap();
+        let mut content = vec![1u8; 3 * PAGE_SIZE as usize];
+        fs::write(source.join("a"), b"small").unwrap();
+        fs::write(source.join("b"), &content).unwrap();
+        fs::write(source.join("sub/c"), b"nested").unwrap();
+        let mut fs = Elkridge::new(sql::Connection::open_in_memory().unwrap(), Options::default()).unwrap();
+        let first = import(&mut fs, &source);
+        assert_eq!((first.inodes, first.pages, first.unchanged), (4, 5, 0));
+
+        let again = import(&mut fs, &source);
+        assert_eq!((again.pages, again.bytes, again.unchanged), (0, 0, 3));
+
+        content[PAGE_SIZE as usize + 7] = 2;
+        fs::write(source.join("b"), &content).unwrap();
+        set_mtime(&source.join("b"), 1_000_000);
+        fs::write(source.join("d"), b"new").unwrap();
+        fs::remove_file(source.join("a")).unwrap();
+        let changed = import(&mut fs, &source);
+        assert_eq!((changed.pages, changed.bytes, changed.unchanged), (2, PAGE_SIZE as u64 + 3, 1));
+
+        let root = Caller::new(0, 0, 0);
+        let b = fs.lookup_basic(&root, ROOT_INODE, OsStr::new("b")).unwrap();
+        assert_eq!(b.mtime.sec, 1_000_000);
+        let mut buf = vec![];
+        fs.read_basic(&root, b.ino, 0, 0, 1 << 20, &mut buf).unwrap();
+        assert!(buf == content);
+        // Gone from the source but kept, as rsync keeps it without --delete
+        assert!(fs.lookup_basic(&root, ROOT_INODE, OsStr::new("a")).is_ok());
+        fs::remove_dir_all(&source).unwrap();
+    }
+}
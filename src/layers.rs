@@ -0,0 +1,258 @@
+use basic;
+use failure::Fallible;
+use pages::{self, PageStore};
+use rusqlite as sql;
+use rusqlite::OptionalExtension;
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use ROOT_INODE;
+
+/// Kind code of a whiteout, a 0/0 character device, as Elkridge::code_from_mode makes it
+pub const WHITEOUT: i8 = 7;
+
+/// A read-only database layered under the mounted one, for --lower
+///
+/// The mounted database is the upper layer, and everything is changed there. A lower entry
+/// is copied up, without its content, the first time it's looked up or listed: the upper
+/// gets an Inode row like the lower's, a Path row, its xattrs, and an Origin row saying
+/// where it came from. A file's content is read from its origin until something changes it,
+/// which copies the content up too and drops the Origin row. A directory keeps its Origin
+/// rows, one for each layer it's merged from, which is how its lower entries are found
+/// wherever it's been renamed to. Removing an entry that a lower layer also has leaves a
+/// whiteout in its place, which hides it from then on, as overlayfs does.
+pub struct Layer {
+    /// Name the database is attached under
+    pub schema: String,
+    /// Its Meta uuid, which is how Origin rows name it, so the order of --lower can change
+    pub uuid: String,
+    pub pages: Box<dyn PageStore>
+}
+
+/// What the lower layers have under some name
+pub struct Below {
+    /// Index in the layers and inode there of the topmost entry, whose attributes are used
+    pub layer: usize,
+    pub inode: i64,
+    /// Everywhere the entry is merged from, topmost first: just the topmost for anything
+    /// but a directory, and for a directory every directory under it in lower layers
+    pub origins: Vec<(usize, i64)>
+}
+
+/// Attach each lower database read-only, topmost first
+pub fn attach(conn: &sql::Connection, lowers: &[PathBuf]) -> Fallible<Vec<Layer>> {
+    let mut layers = Vec::new();
+    for (i, path) in lowers.iter().enumerate() {
+        ensure!(path.exists(), "Lower database {} doesn't exist", path.display());
+        let path = path.canonicalize()?;
+        let path = path.to_str().ok_or_else(|| format_err!("Lower database {} is not valid UTF-8", path.display()))?;
+        let schema = format!("lower{}", i + 1);
+        // A URI, so the layer is opened read-only whatever the main database is
+        let uri = format!("file:{}?mode=ro", path.replace('%', "%25").replace('?', "%3f").replace('#', "%23"));
+        conn.execute(&format!("ATTACH DATABASE ? AS {}", schema), [uri])?;
+        let uuid : Option<String> = conn.query_row(
+            &format!("SELECT value FROM {}.Meta WHERE key = 'uuid'", schema),
+            sql::NO_PARAMS,
+            |row| row.get(0)).optional()?;
+        let uuid = uuid.ok_or_else(|| format_err!("{} hasn't been opened by Elkridge, mount or import into it first", path))?;
+        let pages = pages::open_attached(conn, &schema)?;
+        layers.push(Layer{schema, uuid, pages});
+    }
+    Ok(layers)
+}
+
+/// The lower directories an upper one is merged from, topmost first
+///
+/// The root is always merged with every lower root.
+pub fn lower_dirs(conn: &sql::Connection, layers: &[Layer], dir: u64) -> sql::Result<Vec<(usize, i64)>> {
+    if dir == ROOT_INODE {
+        return Ok((0..layers.len()).map(|layer| (layer, ROOT_INODE as i64)).collect());
+    }
+    origins(conn, layers, dir)
+}
+
+/// Where an upper inode was copied up from, as (layer index, lower inode), topmost first
+///
+/// Origins in layers that aren't attached this time are left out.
+fn origins(conn: &sql::Connection, layers: &[Layer], ino: u64) -> sql::Result<Vec<(usize, i64)>> {
+    let rows : Vec<(String, i64)> = conn.prepare_cached("SELECT layer, lower FROM Origin WHERE inode = ?")?
+        .query_map([ino as i64], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<sql::Result<_>>()?;
+    let mut found : Vec<(usize, i64)> = rows.into_iter()
+        .filter_map(|(uuid, lower)| layers.iter().position(|layer| layer.uuid == uuid).map(|layer| (layer, lower)))
+        .collect();
+    found.sort();
+    Ok(found)
+}
+
+/// Look for `name` under the upper directory `dir` in the lower layers
+///
+/// A whiteout in a layer hides the name in every layer under it, and so does anything but a
+/// directory when looking for more of a directory.
+pub fn find_below(conn: &sql::Connection, layers: &[Layer], dir: u64, name: &OsStr) -> sql::Result<Option<Below>> {
+    let mut below : Option<Below> = None;
+    for (layer, lower_dir) in lower_dirs(conn, layers, dir)? {
+        let found : Option<(i64, i8)> = conn.prepare_cached(&format!(
+            "SELECT inode, kind FROM {0}.Path NATURAL JOIN {0}.Inode
+            WHERE parent = ? AND name = ? AND inode != parent", layers[layer].schema))?
+            .query_row(&[&lower_dir as &dyn sql::ToSql, &name.to_string_lossy()], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()?;
+        match (&mut below, found) {
+            (_, None) => continue,
+            (None, Some((_, WHITEOUT))) => return Ok(None),
+            (None, Some((inode, kind))) => {
+                below = Some(Below{layer, inode, origins: vec![(layer, inode)]});
+                if kind != 3 {
+                    break;
+                }
+            },
+            (Some(below), Some((inode, 3))) => below.origins.push((layer, inode)),
+            (Some(_), Some(_)) => break
+        }
+    }
+    Ok(below)
+}
+
+/// The names in every lower directory merged into `dir`, whiteouts included
+pub fn names_below(conn: &sql::Connection, layers: &[Layer], dir: u64) -> sql::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for (layer, lower_dir) in lower_dirs(conn, layers, dir)? {
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT name FROM {}.Path WHERE parent = ? AND inode != parent", layers[layer].schema))?;
+        let found = stmt.query_map([lower_dir], |row| row.get::<usize, String>(0))?;
+        for name in found {
+            names.push(name?);
+        }
+    }
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+/// Whether the upper directory `dir` has anything under `name`, a whiteout included
+fn taken(conn: &sql::Connection, dir: u64, name: &OsStr) -> sql::Result<bool> {
+    conn.prepare_cached("SELECT EXISTS(SELECT 1 FROM Path WHERE parent = ? AND name = ?)")?
+        .query_row(&[&(dir as i64) as &dyn sql::ToSql, &name.to_string_lossy()], |row| row.get(0))
+}
+
+/// Whether merge would copy anything up for `name` in `dir`, without copying it
+pub fn unmerged(conn: &sql::Connection, layers: &[Layer], dir: u64, name: &OsStr) -> sql::Result<bool> {
+    Ok(!taken(conn, dir, name)? && find_below(conn, layers, dir, name)?.is_some())
+}
+
+/// Copy up what the lower layers have under `name` in `dir`, unless the upper already has
+/// something there (a whiteout included), returning whether there was anything to copy
+pub fn merge(conn: &sql::Connection, layers: &[Layer], stable: bool, dir: u64, name: &OsStr) -> sql::Result<bool> {
+    if taken(conn, dir, name)? {
+        return Ok(false);
+    }
+    match find_below(conn, layers, dir, name)? {
+        Some(below) => {
+            let inode = basic::number_inode(conn, stable, dir, name)?;
+            copy_up(conn, layers, &below, dir, name, inode)?;
+            Ok(true)
+        },
+        None => Ok(false)
+    }
+}
+
+/// Copy an entry found below up into `dir` as `inode`, leaving its content where it is
+fn copy_up(conn: &sql::Connection, layers: &[Layer], below: &Below, dir: u64, name: &OsStr, inode: i64) -> sql::Result<()> {
+    let schema = &layers[below.layer].schema;
+    conn.execute(
+        &format!(
+            "INSERT INTO Inode(inode, kind, perm, uid, gid, size, blocks, atime, mtime, ctime, crtime, rdev)
+            SELECT ?, kind, perm, uid, gid, size, blocks, atime, mtime, ctime, crtime, rdev
+            FROM {}.Inode WHERE inode = ?", schema),
+        [inode, below.inode])?;
+    conn.execute(
+        "INSERT INTO Path(inode, parent, name) VALUES (?,?,?)",
+        &[&inode as &dyn sql::ToSql, &(dir as i64), &name.to_string_lossy()])?;
+    conn.execute(
+        &format!("INSERT INTO Xattr(inode, name, value) SELECT ?, name, value FROM {}.Xattr WHERE inode = ?", schema),
+        [inode, below.inode])?;
+    for &(layer, lower) in &below.origins {
+        conn.execute(
+            "INSERT INTO Origin(inode, layer, lower) VALUES (?,?,?)",
+            &[&inode as &dyn sql::ToSql, &layers[layer].uuid, &lower])?;
+    }
+    Ok(())
+}
+
+/// The layer a file's content is still read from, if it hasn't been copied up, and its
+/// inode there
+pub fn content_origin(conn: &sql::Connection, layers: &[Layer], ino: u64) -> Fallible<Option<(usize, i64)>> {
+    if layers.is_empty() {
+        return Ok(None);
+    }
+    let origin : Option<(String, i64)> = conn.prepare_cached(
+        "SELECT layer, lower FROM Origin NATURAL JOIN Inode WHERE inode = ? AND kind != 3")?
+        .query_row([ino as i64], |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()?;
+    match origin {
+        None => Ok(None),
+        Some((uuid, lower)) => match layers.iter().position(|layer| layer.uuid == uuid) {
+            Some(layer) => Ok(Some((layer, lower))),
+            None => bail!("The content of inode {} is in lower database {}, which isn't mounted", ino, uuid)
+        }
+    }
+}
+
+/// Copy a file's content up from its origin, before anything changes it
+pub fn copy_up_content(conn: &sql::Connection, layers: &[Layer], upper: &dyn PageStore, ino: u64) -> Fallible<()> {
+    if let Some((layer, lower)) = content_origin(conn, layers, ino)? {
+        let mut content = Vec::new();
+        layers[layer].pages.scan(conn, lower as u64, 0, i64::MAX, &mut |start, bytes| content.push((start, bytes.to_vec())))?;
+        for (start, bytes) in content {
+            upper.put(conn, ino, start, &bytes)?;
+        }
+        conn.execute("DELETE FROM Origin WHERE inode = ?", [ino as i64])?;
+    }
+    Ok(())
+}
+
+/// Scan a file's content wherever it is, in its origin or in the upper layer's `upper`,
+/// as PageStore::scan does
+pub fn scan(
+    conn: &sql::Connection,
+    layers: &[Layer],
+    upper: &dyn PageStore,
+    ino: u64,
+    lo: i64,
+    hi: i64,
+    each: &mut dyn FnMut(i64, &[u8])
+) -> Fallible<()> {
+    match content_origin(conn, layers, ino)? {
+        Some((layer, lower)) => layers[layer].pages.scan(conn, lower as u64, lo, hi, each),
+        None => upper.scan(conn, ino, lo, hi, each)
+    }
+}
+
+/// Leave a whiteout called `name` in `dir` if a lower layer has something by that name,
+/// once the upper entry is gone
+pub fn white_out(conn: &sql::Connection, layers: &[Layer], stable: bool, dir: u64, name: &OsStr) -> sql::Result<()> {
+    if layers.is_empty() || find_below(conn, layers, dir, name)?.is_none() {
+        return Ok(());
+    }
+    let inode = basic::number_inode(conn, stable, dir, name)?;
+    conn.execute("INSERT INTO Inode(inode, kind, perm) VALUES (?, ?, 0)", &[&inode as &dyn sql::ToSql, &WHITEOUT])?;
+    conn.execute(
+        "INSERT INTO Path(inode, parent, name) VALUES (?,?,?)",
+        &[&inode as &dyn sql::ToSql, &(dir as i64), &name.to_string_lossy()])?;
+    Ok(())
+}
+
+/// Remove the whiteout called `name` in `dir`, if there is one, so something can be made there
+pub fn remove_whiteout(conn: &sql::Connection, dir: u64, name: &OsStr) -> sql::Result<()> {
+    conn.execute(
+        "DELETE FROM Inode WHERE kind = ? AND inode = (SELECT inode FROM Path WHERE parent = ? AND name = ?)",
+        &[&WHITEOUT as &dyn sql::ToSql, &(dir as i64), &name.to_string_lossy()])?;
+    Ok(())
+}
+
+/// Remove every whiteout in `dir`, which is about to be removed, returning how many there were
+pub fn clear_whiteouts(conn: &sql::Connection, dir: u64) -> sql::Result<i64> {
+    let cleared = conn.execute(
+        "DELETE FROM Inode WHERE kind = ? AND inode IN (SELECT inode FROM Path WHERE parent = ?)",
+        [i64::from(WHITEOUT), dir as i64])?;
+    Ok(cleared as i64)
+}
@@ -0,0 +1,128 @@
+use errors::Error;
+use failure::Fallible;
+use std::borrow::Cow;
+use std::convert::TryInto;
+
+/// The Finder's flags, type, creator and icon position for a file, always 32 bytes
+pub const FINDER_INFO: &str = "com.apple.FinderInfo";
+/// A file's resource fork, which macOS reads and writes as an xattr in pieces
+pub const RESOURCE_FORK: &str = "com.apple.ResourceFork";
+/// The Finder's per-directory view settings, which it rewrites on every look
+pub const DS_STORE: &str = ".DS_Store";
+/// What an AppleDouble file's name starts with, before that of the file it belongs to
+pub const APPLE_DOUBLE_PREFIX: &str = "._";
+
+/// What macOS names its own xattrs with, which no Linux namespace covers
+const APPLE_PREFIX: &str = "com.apple.";
+
+const APPLE_DOUBLE_MAGIC: u32 = 0x0005_1607;
+const ENTRY_RESOURCE_FORK: u32 = 2;
+const ENTRY_FINDER_INFO: u32 = 9;
+/// What begins the xattrs macOS packs into the FinderInfo entry, after the 32 bytes and 2 of padding
+const ATTR_MAGIC: &[u8] = b"ATTR";
+
+/// The name to store an xattr under, for --compat-macos
+///
+/// Linux only has the user, trusted, security and system namespaces, so macOS's com.apple
+/// xattrs go into user as user.com.apple.*, where Linux tools (getfattr, rsync -X, cp -a)
+/// see and copy them like any other. Every other name is stored as it's given.
+pub fn stored_xattr_name(name: &str) -> Cow<'_, str> {
+    if name.starts_with(APPLE_PREFIX) {
+        Cow::Owned(format!("user.{}", name))
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+/// The name to list a stored xattr under, undoing stored_xattr_name
+pub fn presented_xattr_name(stored: &str) -> &str {
+    match stored.strip_prefix("user.") {
+        Some(name) if name.starts_with(APPLE_PREFIX) => name,
+        _ => stored
+    }
+}
+
+/// The stored value of a com.apple xattr after a setxattr at `position`, or None to remove it
+///
+/// As on HFS+ and APFS, FinderInfo is exactly 32 bytes (anything else is EINVAL) and
+/// setting it to all zeros is the same as having none, which is how the Finder clears it.
+/// The resource fork is written in pieces at increasing positions, each one replacing the
+/// fork from there on; position 0 starts it over. Other xattrs take no position.
+pub fn set_value(name: &str, old: Option<Vec<u8>>, value: &[u8], position: u32) -> Fallible<Option<Vec<u8>>> {
+    match name {
+        FINDER_INFO => {
+            require!(value.len() == 32 && position == 0, Error::InvalidArgument);
+            Ok(if value.iter().all(|&b| b == 0) { None } else { Some(value.to_vec()) })
+        },
+        RESOURCE_FORK => {
+            let mut fork = if position == 0 { Vec::new() } else { old.unwrap_or_default() };
+            fork.resize(position as usize, 0);
+            fork.extend_from_slice(value);
+            Ok(Some(fork))
+        },
+        _ => {
+            require!(position == 0, Error::InvalidArgument);
+            Ok(Some(value.to_vec()))
+        }
+    }
+}
+
+/// The xattrs in an AppleDouble file, as macOS writes them beside a file on a volume that
+/// can't keep xattrs itself, or None if it isn't one
+///
+/// That's the FinderInfo entry's 32 bytes (if they aren't all zero) and the resource fork
+/// (if it isn't empty), and any other xattrs, which macOS packs into an ATTR block after
+/// the FinderInfo in the same entry. Entries it doesn't know are skipped, and an entry
+/// or xattr that runs past the end of the file makes the whole file not AppleDouble, so
+/// nothing is taken from a damaged one.
+pub fn apple_double_xattrs(file: &[u8]) -> Option<Vec<(String, Vec<u8>)>> {
+    if be32(file, 0)? != APPLE_DOUBLE_MAGIC {
+        return None;
+    }
+    let mut xattrs = Vec::new();
+    let entries = be16(file, 24)?;
+    for entry in 0..usize::from(entries) {
+        let header = 26 + entry * 12;
+        let (id, offset, length) = (be32(file, header)?, be32(file, header + 4)? as usize, be32(file, header + 8)? as usize);
+        let content = file.get(offset .. offset.checked_add(length)?)?;
+        match id {
+            ENTRY_FINDER_INFO if content.len() >= 32 => {
+                if content[..32].iter().any(|&b| b != 0) {
+                    xattrs.push((FINDER_INFO.to_string(), content[..32].to_vec()));
+                }
+                if content.get(34..38) == Some(ATTR_MAGIC) {
+                    xattrs.extend(attr_block(file, offset + 34)?);
+                }
+            },
+            ENTRY_RESOURCE_FORK if !content.is_empty() => xattrs.push((RESOURCE_FORK.to_string(), content.to_vec())),
+            _ => {}
+        }
+    }
+    Some(xattrs)
+}
+
+/// The xattrs in the ATTR block at `start`, whose entries' offsets are from the start of the file
+fn attr_block(file: &[u8], start: usize) -> Option<Vec<(String, Vec<u8>)>> {
+    let count = be16(file, start + 34)?;
+    let mut xattrs = Vec::new();
+    let mut entry = start + 36;
+    for _ in 0..count {
+        let (offset, length) = (be32(file, entry)? as usize, be32(file, entry + 4)? as usize);
+        let name_len = usize::from(*file.get(entry + 10)?);
+        // The name's length counts its terminating NUL
+        let name = file.get(entry + 11 .. entry + 11 + name_len)?;
+        let name = String::from_utf8_lossy(name.strip_suffix(b"\0").unwrap_or(name)).into_owned();
+        xattrs.push((name, file.get(offset .. offset.checked_add(length)?)?.to_vec()));
+        // Entries are padded to 4 bytes
+        entry = (entry + 11 + name_len + 3) & !3;
+    }
+    Some(xattrs)
+}
+
+fn be32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(at .. at + 4)?.try_into().ok()?))
+}
+
+fn be16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(bytes.get(at .. at + 2)?.try_into().ok()?))
+}
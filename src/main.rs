@@ -1,50 +1,910 @@
+// failure_derive predates the non_local_definitions lint and trips it on every derive
+#![allow(non_local_definitions)]
 extern crate fuse;
 extern crate rusqlite;
 #[macro_use] extern crate clap;
 #[macro_use] extern crate failure;
 extern crate libc;
 extern crate time;
+extern crate env_logger;
+extern crate flate2;
+extern crate tar;
+extern crate unicode_normalization;
+extern crate sha2;
+// No macro_use: its event macros would clash with log's
+extern crate tracing;
+extern crate tracing_subscriber;
+#[macro_use] extern crate log;
 use failure::Fallible;
 
-use std::ffi::OsStr;
+use libc::c_int;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::panic;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
 use time::Timespec;
-use libc::ENOENT;
-use fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory, ReplyEmpty};
+use fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory, ReplyEmpty, ReplyOpen, ReplyCreate, ReplyWrite, ReplyStatfs, ReplyXattr};
 use rusqlite as sql;
-use basic::BasicFilesystem;
+use rusqlite::NO_PARAMS;
+use clap::ArgMatches;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use attr_cache::AttrCache;
+use basic::{AttrChanges, BasicFilesystem};
+use checkpoint::Checkpointer;
+use sweep::Sweeper;
+use vacuum::Vacuumer;
+use write_buffer::WriteBuffer;
+use errors::errno_for;
+use handles::Handles;
+use layers::Layer;
+use mount_lock::MountLock;
+use options::{AutoVacuum, JournalMode, Options};
+use owners::IdMapping;
+use pages::PageStore;
+use readahead::PageCache;
+use readdir_cursor::ReaddirCursors;
 
-mod errors;
+#[macro_use] mod errors;
+mod acl;
+mod attr_cache;
 mod basic;
+mod bench;
+mod checkpoint;
+mod control;
+mod deadline;
+mod du;
+mod export;
+mod freeze;
+mod fsck;
+mod fstab;
+mod handles;
+mod import;
+mod layers;
+mod macos;
+mod mime;
+mod mount_lock;
+mod options;
+mod owners;
+mod pages;
+mod quota;
+mod readahead;
+mod readdir_cursor;
+mod roundtrip;
+mod sweep;
+mod tables;
+mod vacuum;
+mod write_buffer;
 const TTL: Timespec = Timespec {sec: 1, nsec: 0};
 
+/// Enter a span for one request with these fields, for --tracing, and an outcome to fill in
+///
+/// That's "ok" once unpanicked succeeds, or the errno replied with (see choose_errno).
+/// Without --tracing no subscriber is interested, and a span costs a check of the callsite.
+macro_rules! request_span {
+    ($op:expr) => {
+        tracing::info_span!($op, outcome = tracing::field::Empty).entered()
+    };
+    ($op:expr, $($fields:tt)+) => {
+        tracing::info_span!($op, $($fields)+, outcome = tracing::field::Empty).entered()
+    };
+}
+/// For what the kernel mustn't keep at all, see Elkridge::ttl_for
+const NO_TTL: Timespec = Timespec {sec: 0, nsec: 0};
+/// Inode of the root directory in the Inode table
+const ROOT_INODE: u64 = 0;
+/// Largest span of a file stored in one Page row, and the alignment of page boundaries
+///
+/// The kernel ABI this version of fuse speaks has no big writes, so writes arrive at most
+/// 4 KiB at a time; matching that means an aligned write never rewrites a neighbor.
+const PAGE_SIZE: i64 = 4096;
+
 fn main() {
-    main_inner().unwrap();
+    env_logger::init();
+    if let Err(e) = main_inner() {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
 }
 
 fn main_inner() -> Fallible<()> {
-    let args = clap_app!(app =>
-        (about: "Mount an SQLite database as a FUSE filesystem")
+    let mut argv : Vec<OsString> = env::args_os().collect();
+    let helper = fstab::is_helper(&argv);
+    if helper {
+        argv = fstab::helper_args(&argv)?;
+    }
+    let args = arguments().get_matches_from(argv);
+    match args.subcommand() {
+        ("fsck", Some(sub)) => fsck_main(sub),
+        ("recover", Some(sub)) => recover_main(sub),
+        ("import", Some(sub)) => import_main(sub),
+        ("export", Some(sub)) => export_main(sub),
+        ("sql", Some(sub)) => sql_main(sub),
+        ("dump-schema", Some(sub)) => dump_schema_main(sub),
+        ("bench", Some(sub)) => bench_main(sub),
+        ("verify-roundtrip", Some(sub)) => verify_roundtrip_main(sub),
+        ("du", Some(sub)) => du_main(sub),
+        _ => {
+            if helper {
+                fstab::daemonize(args.value_of_os("mount_path").unwrap_or_default())?;
+            }
+            mount_main(&args)
+        }
+    }
+}
+
+/// The command line, every subcommand and option
+fn arguments<'a, 'b>() -> clap::App<'a, 'b> {
+    clap_app!(app =>
+    (about: "Mount an SQLite database as a FUSE filesystem")
+    (@setting SubcommandsNegateReqs)
+    (@arg sqlite_path: +required "Path to the SQLite database")
+    (@arg mount_path: +required "Where to mount the new filesystem")
+    (@arg file_mask: --("file-mask") [MODE] "Present every non-directory with this octal permission (e.g. 0444)")
+    (@arg dir_mask: --("dir-mask") [MODE] "Present every directory with this octal permission (e.g. 0555)")
+    (@arg owner_map: --("owner-map") [IDS]... number_of_values(1) "Present files stored with uid or gid STORED as owned by SHOWN, given as STORED:SHOWN (repeatable)")
+    (@arg alloc_size: --("alloc-size") [BYTES] "Report blocks as if files took whole allocation units of this many bytes (a multiple of 512)")
+    (@arg normalize: --normalize [FORM] possible_value[none nfc nfd] "Treat names that are the same in this Unicode normal form as the same name (default none)")
+    (@arg normalize_store: --("normalize-store") requires[normalize] "Store new names in the --normalize form, rather than as given")
+    (@arg expose_tables: --("expose-tables") "Show the database's other tables as read-only CSV files under /.tables")
+    (@arg control_dir: --("control-dir") "Show diagnostics, like the open file handles, as read-only files under /.elkridge")
+    (@arg direct_io: --("direct-io") "Bypass the kernel page cache for every file, as if opened with O_DIRECT")
+    (@arg trace_sql: --("trace-sql") "Log every SQL statement and how long it took (needs RUST_LOG=debug)")
+    (@arg atime: --atime [POLICY] "When reads update atime: noatime, relatime (the default) or strictatime")
+    (@arg readdir_order: --("readdir-order") [ORDER] possible_value[natural name mtime inode] "List directories in the order their names were made (the default), or by name, mtime or inode")
+    (@arg capacity: --capacity [BYTES] "Let the database grow to at most this size; writes past it fail with EDQUOT")
+    (@arg cache_size: --("cache-size") [SIZE] "SQLite page cache, in pages or in KiB with a K suffix (default 65536K)")
+    (@arg journal_mode: --("journal-mode") [MODE] possible_value[delete truncate persist memory wal off] conflicts_with[read_only] "Switch the database to this PRAGMA journal_mode (by default it keeps the one it has)")
+    (@arg auto_vacuum: --("auto-vacuum") [MODE] possible_value[none full incremental] conflicts_with[read_only] "Set PRAGMA auto_vacuum, which a new database takes at once and an existing one only after VACUUM")
+    (@arg vacuum_interval: --("vacuum-interval") [SECONDS] conflicts_with[read_only] "Under auto_vacuum incremental, give free pages back to the disk this often")
+    (@arg journal_size_limit: --("journal-size-limit") [BYTES] "Cut the journal or WAL back to this size after use (default 64 MiB)")
+    (@arg max_file_size: --("max-file-size") [BYTES] "Refuse with EFBIG any write that would make a file larger than this")
+    (@arg write_buffer: --("write-buffer") [BYTES] conflicts_with[read_only] "Answer writes from memory, storing them together once this many bytes are waiting (lost in a crash until stored)")
+    (@arg write_buffer_delay: --("write-buffer-delay") [SECONDS] requires[write_buffer] "Store buffered writes at the first request after the earliest has waited this long (default 1); an idle mount keeps holding them")
+    (@arg max_read_size: --("max-read-size") [BYTES] "Answer any read asking for more than this with a short read of this much")
+    (@arg checkpoint_interval: --("checkpoint-interval") [SECONDS] "Checkpoint the WAL in the background this often")
+    (@arg wal_limit: --("wal-limit") [BYTES] "Checkpoint and truncate the WAL in the background once it grows past this")
+    (@arg wal_autocheckpoint: --("wal-autocheckpoint") [PAGES] "Have a commit checkpoint the WAL once it holds this many pages, 0 for never (default 1000)")
+    (@arg cache_mime: --("cache-mime") "Store each file's detected user.mime_type rather than detecting it on every getxattr")
+    (@arg tracing: --tracing "Write a span for every request to stderr as it finishes, with its fields, outcome and timings")
+    (@arg verbose_errno: --("verbose-errno") "Log the name of every errno returned to the kernel, at warn level")
+    (@arg readahead: --readahead [PAGES] "Read this many pages ahead of each sequential reader (default 0)")
+    (@arg mkdir: --mkdir "Create the mount point if it doesn't exist")
+    (@arg mount_timeout: --("mount-timeout") [SECONDS] "Fail if the mount isn't ready this long after starting, instead of waiting forever")
+    (@arg root_path: --("root-path") [PATH] "Mount only this directory of the database, as if it were the root")
+    (@arg single_threaded: --("single-threaded") "Debugging aid: start no background threads, so operations run strictly in order")
+    (@arg max_age: --("max-age") [SECONDS] "Delete files in the background once they haven't been read (see --age-by) for this long")
+    (@arg age_by: --("age-by") [TIME] possible_value[atime mtime] "Which time --max-age goes by: atime (the default) or mtime")
+    (@arg evict: --evict requires[capacity] "Delete the least recently read files in the background once the database outgrows --capacity")
+    (@arg sweep_interval: --("sweep-interval") [SECONDS] "Look for files to delete for --max-age and --evict this often (default 60)")
+    (@arg lower: --lower [DB]... number_of_values(1) conflicts_with[read_only] "Layer the database over this read-only one, overlayfs-style (repeatable, topmost first)")
+    (@arg blob_dir: --("blob-dir") [DIR] "Keep new file content as files in this directory, remembered by the database from then on")
+    (@arg allow_other: --("allow-other") "Let users other than the one mounting use the mount (FUSE's allow_other)")
+    (@arg per_user_root: --("per-user-root") requires[allow_other] conflicts_with[read_only] "Give each user their own root: the directory named for their uid under the real one")
+    (@arg compat_macos: --("compat-macos") "Store macOS's com.apple xattrs as user.com.apple.*, and fold ._ AppleDouble files into the xattrs of their files")
+    (@arg no_ds_store: --("no-ds-store") "Refuse to create .DS_Store files (with EPERM), so the Finder stops rewriting them")
+    (@arg enforce_permissions: --("enforce-permissions") "Have the kernel check the mode bits on every operation (FUSE's default_permissions)")
+    (@arg stable_inodes: --("stable-inodes") "Number new inodes by a hash of their path, so the same tree always gets the same numbers")
+    (@arg op_timeout: --("op-timeout") [SECONDS] "Fail any operation still waiting on SQLite after this long with EIO, rather than letting it hang")
+    (@arg verify_on_mount: --("verify-on-mount") "Refuse to mount unless PRAGMA integrity_check passes (slow on large databases)")
+    (@arg page_overlap_check: --("page-overlap-check") "Debugging aid: after every write, check that none of the file's pages overlap")
+    (@arg force: --force conflicts_with[read_only] "Mount even if another elkridge seems to have the database mounted already")
+    (@arg read_only_after: --("read-only-after") [TIME] conflicts_with[read_only] "Refuse every change from this time on (seconds since the epoch, or YYYY-MM-DDTHH:MM:SSZ), until SIGUSR2")
+    (@arg read_only: --("read-only") "Mount read-only, leaving the database exactly as it was (not even atime changes)")
+    (@subcommand fsck =>
+        (about: "Check the database for inconsistencies")
+        (@arg sqlite_path: +required "Path to the SQLite database")
+        (@arg repair: --repair "Fix the problems found instead of only reporting them")
+        (@arg dry_run: --("dry-run") requires[repair] "Report what --repair would fix and remove, then roll it all back")
+    )
+    (@subcommand recover =>
+        (about: "Link inodes that can't be reached from the root into /lost+found")
+        (@arg sqlite_path: +required "Path to the SQLite database")
+        (@arg dry_run: --("dry-run") "Report what would be linked, then roll it all back")
+    )
+    (@subcommand import =>
+        (about: "Copy a directory tree or tar archive into the root of the database")
+        (@arg sqlite_path: +required "Path to the SQLite database")
+        (@arg source_dir: +required "Directory (or with --format tar, archive) to import, or - for standard input")
+        (@arg format: --format [FORMAT] possible_value[dir tar] "What the source is: a directory (the default) or a tar archive")
+        (@arg numeric_ids: --("numeric-ids") conflicts_with[map_ids] "Store uid and gid numbers as they are (the default)")
+        (@arg map_ids: --("map-ids") "Store owners by user and group name, so they keep their meaning on other hosts")
+        (@arg stable_inodes: --("stable-inodes") "Number inodes by a hash of their path, as when mounted with --stable-inodes")
+        (@arg incremental: --incremental "Bring an earlier import of the same directory up to date, rewriting only what changed")
+        (@arg dry_run: --("dry-run") "Report what the import would write, then roll it all back")
+    )
+    (@subcommand sql =>
+        (about: "Run one SQL statement against the database, printing any rows as CSV")
+        (@arg sqlite_path: +required "Path to the SQLite database")
+        (@arg query: +required "The statement to run")
+        (@arg write: --write "Open the database for writing, so the statement may change it")
+    )
+    (@subcommand dump_schema =>
+        (name: "dump-schema")
+        (about: "Print the SQL that creates the database's tables, indexes and triggers")
         (@arg sqlite_path: +required "Path to the SQLite database")
-        (@arg mount_path: +required "Where to mount the new filesystem")
-    ).get_matches();
+    )
+    (@subcommand export =>
+        (about: "Write the whole database out as an archive")
+        (@arg sqlite_path: +required "Path to the SQLite database")
+        (@arg destination: +required "File to write the archive to, or - for standard output")
+        (@arg format: --format [FORMAT] possible_value[tar] "Archive format (only tar, the default, so far)")
+        (@arg gzip: --gzip "Compress the archive with gzip")
+        (@arg numeric_ids: --("numeric-ids") conflicts_with[map_ids] "Give owners only as uid and gid numbers (the default)")
+        (@arg map_ids: --("map-ids") "Also give owners by the names recorded when importing with --map-ids")
+        (@arg prune_empty_dirs: --("prune-empty-dirs") "Leave out directories that would end up with nothing in them")
+    )
+    (@subcommand du =>
+        (about: "Show how much each directory's files add up to, and how much of that is actually stored")
+        (@arg sqlite_path: +required "Path to the SQLite database")
+        (@arg path: "Directory inside the database to start from (by default the root)")
+    )
+    (@subcommand verify_roundtrip =>
+        (name: "verify-roundtrip")
+        (about: "Import a directory into a scratch database, export it again, and report everything that came back different")
+        (@arg source_dir: +required "Directory to try")
+    )
+    (@subcommand bench =>
+        (about: "Time a standard mix of operations against a scratch database, without mounting")
+        (@arg sqlite_path: "Scratch database to create, and remove afterwards (by default one in memory)")
+        (@arg files: --files [N] "How many files the workload creates (default 1000)")
+        (@arg file_size: --("file-size") [BYTES] "How much it writes into each (default 65536)")
+        (@arg cache_size: --("cache-size") [SIZE] "SQLite page cache, as for mounting")
+        (@arg journal_mode: --("journal-mode") [MODE] possible_value[delete truncate persist memory wal off] "Journal mode, as for mounting")
+    )
+
+    )
+}
+
+/// Write every request's span to stderr as it closes, for --tracing
+///
+/// Each line is the span's name and fields, outcome included, and time.busy, how long the
+/// callback took. It goes to stderr alongside the log, which stays with env_logger and
+/// RUST_LOG.
+fn install_tracing() -> Fallible<()> {
+    let subscriber = tracing_subscriber::fmt()
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .with_writer(io::stderr)
+        .with_target(false)
+        .finish();
+    tracing::subscriber::set_global_default(subscriber)?;
+    Ok(())
+}
+
+/// Mount the database, blocking until it is unmounted
+fn mount_main(args: &ArgMatches) -> Fallible<()> {
     let sqlite_path = value_t!(args, "sqlite_path", String)?;
     let mount_path = value_t!(args, "mount_path", String)?;
+    let options = Options::from_args(args)?;
+    match options.journal_mode {
+        // Straight to stderr rather than the log, so it's seen whatever RUST_LOG says
+        Some(JournalMode::Off) => eprintln!(
+            "Warning: with --journal-mode off, a crash or a failed change can leave {} \
+            corrupt; only use it for a database you can afford to throw away", sqlite_path),
+        Some(JournalMode::Memory) => warn!(
+            "With --journal-mode memory, a crash in the middle of any change can leave {} corrupt",
+            sqlite_path),
+        _ => {}
+    }
+    if options.tracing {
+        install_tracing()?;
+    }
+    check_mount_path(Path::new(&mount_path), args.is_present("mkdir"))?;
     let conn = sql::Connection::open(&sqlite_path)?;
-    fuse::mount(Elkridge::new(conn)?, &mount_path, &[])?;
+    let checkpointer = match (options.checkpoint_interval, options.wal_limit) {
+        (None, None) => None,
+        _ if options.single_threaded => {
+            warn!("Not checkpointing in the background under --single-threaded");
+            None
+        },
+        (interval, limit) => {
+            // The checkpointer's locks are brief, so wait them out rather than failing requests
+            conn.busy_timeout(Duration::from_secs(1))?;
+            Some(Checkpointer::start(&sqlite_path, interval, limit)?)
+        }
+    };
+    let mut kernel_options = Vec::new();
+    if options.read_only {
+        kernel_options.push("ro");
+    }
+    if options.enforce_permissions {
+        kernel_options.push("default_permissions");
+    }
+    if options.allow_other {
+        kernel_options.push("allow_other");
+    }
+    let kernel_options = kernel_options.join(",");
+    let mount_timeout = options.mount_timeout;
+    let policy = sweep::Policy {
+        max_age: options.max_age,
+        age_by: options.age_by,
+        capacity: if options.evict { options.capacity } else { None }
+    };
+    let sweep = options.max_age.is_some() || options.evict;
+    let interval = options.sweep_interval.unwrap_or(sweep::DEFAULT_SWEEP_INTERVAL);
+    let skip_sweep = if options.read_only { Some("--read-only") }
+        else if options.single_threaded { Some("--single-threaded") }
+        else { None };
+    let fs = Elkridge::mount(conn, options, &sqlite_path)?;
+    if !fs.options.read_only {
+        freeze::install()?;
+        if let Some(at) = fs.options.read_only_after {
+            freeze::schedule(at);
+        }
+    }
+    let sweeper = match skip_sweep {
+        _ if !sweep => None,
+        Some(flag) => {
+            warn!("Not deleting files for --max-age or --evict under {}", flag);
+            None
+        },
+        None => {
+            fs.conn.busy_timeout(Duration::from_secs(1))?;
+            Some(Sweeper::start(&sqlite_path, interval, policy, fs.handles.pinned())?)
+        }
+    };
+    let vacuumer = match fs.options.vacuum_interval {
+        None => None,
+        Some(_) if fs.options.single_threaded => {
+            warn!("Not vacuuming in the background under --single-threaded");
+            None
+        },
+        Some(interval) => {
+            let mode : i64 = fs.conn.query_row("PRAGMA auto_vacuum", NO_PARAMS, |row| row.get(0))?;
+            if mode == AutoVacuum::Incremental.number() {
+                fs.conn.busy_timeout(Duration::from_secs(1))?;
+                Some(Vacuumer::start(&sqlite_path, interval)?)
+            } else {
+                warn!("Not vacuuming for --vacuum-interval, since the database isn't in auto_vacuum incremental");
+                None
+            }
+        }
+    };
+    info!("Mounting database {} as {}; for a stable NFS export of it use fsid={}",
+        sqlite_path, mount_path, fs.database_uuid()?);
+    let mount_options : &[&OsStr] = if kernel_options.is_empty() {
+        &[]
+    } else {
+        &[OsStr::new("-o"), OsStr::new(&kernel_options)]
+    };
+    let mounted = match mount_timeout {
+        Some(timeout) => mount_within(fs, &mount_path, mount_options, timeout),
+        None => fuse::mount(fs, &mount_path, mount_options).map_err(failure::Error::from)
+    };
+    if let Some(checkpointer) = checkpointer {
+        checkpointer.stop();
+    }
+    if let Some(sweeper) = sweeper {
+        sweeper.stop();
+    }
+    if let Some(vacuumer) = vacuumer {
+        vacuumer.stop();
+    }
+    mounted?;
+    Ok(())
+}
+
+/// Mount like fuse::mount, but give up if the mount hasn't appeared within `timeout`
+///
+/// A missing fuse module or an unusable /dev/fuse can leave mounting stuck rather than
+/// failing, so the session runs on a thread of its own while this one watches the mount
+/// point. Once the mount appears, this waits for the session to end, as fuse::mount would.
+fn mount_within(fs: Elkridge, mount_path: &str, options: &[&OsStr], timeout: Duration) -> Fallible<()> {
+    let path = mount_path.to_string();
+    let options : Vec<OsString> = options.iter().map(|&option| option.to_owned()).collect();
+    let session = thread::spawn(move || {
+        let options : Vec<&OsStr> = options.iter().map(OsString::as_os_str).collect();
+        fuse::mount(fs, &path, &options)
+    });
+    let started = Instant::now();
+    while !session.is_finished() && !is_mount_point(Path::new(mount_path)).unwrap_or(false) {
+        if started.elapsed() >= timeout {
+            // Returning ends the process, and the stuck thread with it
+            bail!("{} was not mounted within {}s; check that the fuse module is loaded and /dev/fuse is usable",
+                mount_path, timeout.as_secs());
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    match session.join() {
+        Ok(mounted) => Ok(mounted?),
+        Err(_) => bail!("The filesystem session panicked")
+    }
+}
+
+/// Make sure the mount point is an empty directory that isn't already mounted
+///
+/// fuse::mount fails on all of these too, but with errors that don't say what to do next.
+fn check_mount_path(path: &Path, create: bool) -> Fallible<()> {
+    let meta = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound && create => {
+            fs::create_dir_all(path)?;
+            return Ok(());
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+            bail!("Mount point {} does not exist; create it or pass --mkdir", path.display()),
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOTCONN) =>
+            bail!("Mount point {} is a stale mount; unmount it with `fusermount -u {}`", path.display(), path.display()),
+        Err(e) => bail!("Cannot use mount point {}: {}", path.display(), e)
+    };
+    ensure!(meta.is_dir(), "Mount point {} is not a directory", path.display());
+    if is_mount_point(path)? {
+        bail!("Mount point {} is already mounted; unmount it first with `fusermount -u {}`", path.display(), path.display());
+    }
+    ensure!(fs::read_dir(path)?.next().is_none(), "Mount point {} is not empty", path.display());
     Ok(())
 }
 
+/// Whether something is mounted at this directory, judging by it being on a different
+/// device than its parent
+fn is_mount_point(path: &Path) -> io::Result<bool> {
+    let parent = path.canonicalize()?.join("..");
+    Ok(fs::metadata(&parent)?.dev() != fs::metadata(path)?.dev())
+}
+
+/// Copy a directory from the host into the database
+fn import_main(args: &ArgMatches) -> Fallible<()> {
+    let sqlite_path = value_t!(args, "sqlite_path", String)?;
+    let source_dir = value_t!(args, "source_dir", String)?;
+    let mut fs = Elkridge::new(sql::Connection::open(&sqlite_path)?, Options::default())?;
+    let started = time::precise_time_s();
+    let ids = id_mapping(args);
+    let stable = args.is_present("stable_inodes");
+    let incremental = args.is_present("incremental");
+    ensure!(!incremental || args.value_of("format") != Some("tar"), "--incremental only works on a directory, not a tar archive");
+    let dry_run = args.is_present("dry_run");
+    let txn = fs.conn.transaction()?;
+    let stats = match (args.value_of("format"), source_dir.as_str()) {
+        (Some("tar"), "-") => import::import_tar(&txn, io::stdin().lock(), ROOT_INODE, ids, stable)?,
+        (Some("tar"), path) => import::import_tar(&txn, fs::File::open(path)?, ROOT_INODE, ids, stable)?,
+        _ => import::import_tree(&txn, Path::new(&source_dir), ROOT_INODE, ids, stable, incremental)?
+    };
+    if dry_run {
+        txn.rollback()?;
+    } else {
+        txn.commit()?;
+        // Rewritten pages may have had their content in the blob directory
+        fs.pages.collect(&fs.conn)?;
+    }
+    let verb = if dry_run { "Would import" } else { "Imported" };
+    if incremental {
+        println!("{} {} inodes ({} bytes in {} pages), {} files unchanged, in {:.2}s",
+            verb, stats.inodes, stats.bytes, stats.pages, stats.unchanged, time::precise_time_s() - started);
+    } else {
+        println!("{} {} inodes ({} bytes) in {:.2}s",
+            verb, stats.inodes, stats.bytes, time::precise_time_s() - started);
+    }
+    Ok(())
+}
+
+/// Write the database out as a tar archive, to a file or standard output
+fn export_main(args: &ArgMatches) -> Fallible<()> {
+    let sqlite_path = value_t!(args, "sqlite_path", String)?;
+    let destination = value_t!(args, "destination", String)?;
+    let fs = Elkridge::new(sql::Connection::open(&sqlite_path)?, Options::default())?;
+    let out : Box<dyn Write> = if destination == "-" {
+        Box::new(BufWriter::new(io::stdout()))
+    } else {
+        Box::new(BufWriter::new(fs::File::create(&destination)?))
+    };
+    let prune = args.is_present("prune_empty_dirs");
+    let started = time::precise_time_s();
+    let stats = if args.is_present("gzip") {
+        let mut gzip = GzEncoder::new(out, Compression::default());
+        let stats = export::export_tar(&fs.conn, ROOT_INODE, &mut gzip, id_mapping(args), prune)?;
+        gzip.finish()?.flush()?;
+        stats
+    } else {
+        export::export_tar(&fs.conn, ROOT_INODE, out, id_mapping(args), prune)?
+    };
+    // The archive itself may be on standard output
+    eprintln!("Exported {} entries ({} bytes) in {:.2}s",
+        stats.entries, stats.bytes, time::precise_time_s() - started);
+    Ok(())
+}
+
+/// Print the logical and physical size of a directory and everything under it, du-style
+///
+/// One line per directory, innermost first, with the total last, and how much the stored
+/// content saves on stderr, after everything else.
+fn du_main(args: &ArgMatches) -> Fallible<()> {
+    let sqlite_path = value_t!(args, "sqlite_path", String)?;
+    let fs = Elkridge::new(sql::Connection::open(&sqlite_path)?, Options::default())?;
+    let path = Path::new(args.value_of_os("path").unwrap_or_else(|| OsStr::new("/")));
+    let (ino, _) = fs.resolve_path(path, true)
+        .map_err(|e| format_err!("Cannot find {} in {}: {}", path.display(), sqlite_path, e))?;
+    let usages = du::usage(&fs.conn, ino, path)?;
+    // Written rather than printed, as in dump_schema_main
+    let mut out = io::stdout().lock();
+    writeln!(out, "logical\tphysical\tpath")?;
+    for usage in &usages {
+        writeln!(out, "{}\t{}\t{}", usage.logical, usage.physical, usage.path.display())?;
+    }
+    if let Some(total) = usages.last() {
+        let saved = total.logical.saturating_sub(total.physical);
+        eprintln!("Stored {} bytes for {} bytes of files, saving {} ({:.1}%)",
+            total.physical, total.logical, saved,
+            if total.logical == 0 { 0.0 } else { 100.0 * saved as f64 / total.logical as f64 });
+    }
+    Ok(())
+}
+
+/// Time the standard workload against a scratch database and print what it took
+///
+/// A database path is only taken if nothing is there yet, so no real database is ever
+/// benchmarked over, and it's removed again afterwards, WAL and all.
+fn bench_main(args: &ArgMatches) -> Fallible<()> {
+    let defaults = bench::Workload::default();
+    let workload = bench::Workload {
+        files: args.value_of("files").map(|text| text.parse().map_err(|_| format_err!("Invalid number of files: {}", text)))
+            .transpose()?
+            .unwrap_or(defaults.files),
+        file_size: args.value_of("file_size").map(|text| text.parse().map_err(|_| format_err!("Invalid size in bytes: {}", text)))
+            .transpose()?
+            .unwrap_or(defaults.file_size)
+    };
+    let path = args.value_of_os("sqlite_path").map(Path::new);
+    let conn = match path {
+        Some(path) => {
+            ensure!(!path.exists(), "{} already exists; bench only runs against a database it creates", path.display());
+            sql::Connection::open(path)?
+        },
+        None => sql::Connection::open_in_memory()?
+    };
+    let mut fs = Elkridge::new(conn, Options::from_args(args)?)?;
+    let caller = basic::Caller::new(unsafe { libc::getuid() }, unsafe { libc::getgid() }, process::id());
+    let started = time::precise_time_s();
+    let stats = bench::run(&mut fs, &caller, workload);
+    drop(fs);
+    if let Some(path) = path {
+        remove_database(path)?;
+    }
+    bench::report(io::stdout().lock(), &stats?)?;
+    eprintln!("Ran {} files of {} bytes in {:.2}s", workload.files, workload.file_size, time::precise_time_s() - started);
+    Ok(())
+}
+
+/// Import a directory into a scratch database and export it again, printing each way what
+/// came back differs from what went in, and failing if anything does
+///
+/// The database is made in the temporary directory and removed afterwards, whatever happens.
+fn verify_roundtrip_main(args: &ArgMatches) -> Fallible<()> {
+    let source_dir = PathBuf::from(args.value_of_os("source_dir").unwrap_or_default());
+    ensure!(source_dir.is_dir(), "{} is not a directory", source_dir.display());
+    let scratch = env::temp_dir().join(format!("elkridge-roundtrip-{}.sqlite", process::id()));
+    ensure!(!scratch.exists(), "{} already exists; remove it if no verify-roundtrip is using it", scratch.display());
+    let started = time::precise_time_s();
+    let report = roundtrip::verify(&source_dir, &scratch);
+    // Whatever went wrong with the round trip matters more than a scratch file left behind
+    if let Err(err) = remove_database(&scratch) {
+        warn!("Couldn't remove the scratch database {}: {}", scratch.display(), err);
+    }
+    let report = report?;
+    for difference in &report.differences {
+        println!("{}: {}", difference.path.display(), difference.what);
+    }
+    eprintln!("Compared {} entries in {:.2}s", report.entries, time::precise_time_s() - started);
+    ensure!(report.differences.is_empty(), "Found {} differences between {} and its round trip",
+        report.differences.len(), source_dir.display());
+    println!("No differences");
+    Ok(())
+}
+
+/// Remove a database file along with any journal, WAL and shared memory files beside it
+fn remove_database(path: &Path) -> io::Result<()> {
+    for suffix in &["", "-wal", "-shm", "-journal"] {
+        let mut file = path.as_os_str().to_owned();
+        file.push(suffix);
+        if Path::new(&file).exists() {
+            fs::remove_file(&file)?;
+        }
+    }
+    Ok(())
+}
+
+/// Run a statement given on the command line, for looking into (or with --write, fixing) a
+/// database by hand
+///
+/// The database is opened as it is, without the schema checks and migrations a mount or
+/// import would run, and read-only unless --write is given.
+fn sql_main(args: &ArgMatches) -> Fallible<()> {
+    let sqlite_path = value_t!(args, "sqlite_path", String)?;
+    let query = value_t!(args, "query", String)?;
+    let conn = if args.is_present("write") {
+        let conn = sql::Connection::open(&sqlite_path)?;
+        // So deletions cascade as they would when mounted
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        conn
+    } else {
+        sql::Connection::open_with_flags(&sqlite_path, sql::OpenFlags::SQLITE_OPEN_READ_ONLY)?
+    };
+    if conn.prepare(&query)?.column_count() == 0 {
+        let changed = conn.execute(&query, NO_PARAMS)?;
+        eprintln!("Changed {} rows", changed);
+    } else {
+        io::stdout().write_all(&tables::query_csv(&conn, &query)?)?;
+    }
+    Ok(())
+}
+
+/// Print the statements that create every table, index and trigger, in the order they were made
+fn dump_schema_main(args: &ArgMatches) -> Fallible<()> {
+    let sqlite_path = value_t!(args, "sqlite_path", String)?;
+    let conn = sql::Connection::open_with_flags(&sqlite_path, sql::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut stmt = conn.prepare("SELECT sql FROM sqlite_master WHERE sql IS NOT NULL ORDER BY rowid")?;
+    let statements = stmt.query_map(NO_PARAMS, |row| row.get::<usize, String>(0))?;
+    // Written rather than printed, so piping into head fails quietly instead of panicking
+    let mut out = io::stdout().lock();
+    for statement in statements {
+        writeln!(out, "{};", statement?)?;
+    }
+    Ok(())
+}
+
+/// How import and export should treat owners, from --numeric-ids and --map-ids
+fn id_mapping(args: &ArgMatches) -> IdMapping {
+    if args.is_present("map_ids") {
+        IdMapping::Names
+    } else {
+        IdMapping::Numeric
+    }
+}
+
+/// Report (and optionally repair) inconsistencies in a database
+///
+/// With --dry-run the repairs are made in a transaction that's rolled back, and no files are
+/// removed, so what's reported is exactly what --repair alone would do.
+fn fsck_main(args: &ArgMatches) -> Fallible<()> {
+    let sqlite_path = value_t!(args, "sqlite_path", String)?;
+    let mut fs = Elkridge::new(sql::Connection::open(&sqlite_path)?, Options::default())?;
+    let dry_run = args.is_present("dry_run");
+    let mismatches = fsck::check_child_counts(&fs.conn)?;
+    for m in &mismatches {
+        println!("inode {}: child_count is {} but it has {} entries", m.inode, m.stored, m.actual);
+    }
+    let overlaps = fsck::check_page_overlaps(&fs.conn, None)?;
+    for o in &overlaps {
+        println!("inode {}: page {}..{} overlaps an earlier one", o.inode, o.start, o.finish);
+    }
+    // Without it, content whose pages are gone is reported below as orphaned instead
+    if !dry_run {
+        fs.pages.collect(&fs.conn)?;
+    }
+    let orphans = fs.pages.orphans(&fs.conn)?;
+    let mut orphan_bytes = 0;
+    for orphan in &orphans {
+        orphan_bytes += fs::metadata(orphan)?.len();
+        println!("{}: no page refers to it", orphan.display());
+    }
+    if dry_run {
+        let txn = fs.conn.transaction()?;
+        let fixed = fsck::repair_child_counts(&txn)?;
+        txn.rollback()?;
+        // Not rewritten even to roll back, since new content might land in the blob directory
+        let mut files : Vec<i64> = overlaps.iter().map(|o| o.inode).collect();
+        files.dedup();
+        println!("Would repair {} child counts", fixed);
+        println!("Would rewrite the overlapping pages of {} files", files.len());
+        println!("Would remove {} orphaned content files ({} bytes)", orphans.len(), orphan_bytes);
+    } else if args.is_present("repair") {
+        let fixed = fsck::repair_child_counts(&fs.conn)?;
+        println!("Repaired {} child counts", fixed);
+        let txn = fs.conn.transaction()?;
+        let files = fsck::repair_page_overlaps(&txn, &*fs.pages, &overlaps)?;
+        txn.commit()?;
+        fs.pages.collect(&fs.conn)?;
+        println!("Rewrote the overlapping pages of {} files", files);
+        for orphan in &orphans {
+            fs::remove_file(orphan)?;
+        }
+        println!("Removed {} orphaned content files ({} bytes)", orphans.len(), orphan_bytes);
+    } else if !mismatches.is_empty() || !overlaps.is_empty() || !orphans.is_empty() {
+        bail!("Found {} problems, rerun with --repair to fix them", mismatches.len() + overlaps.len() + orphans.len());
+    }
+    Ok(())
+}
+
+/// Give everything the root can't reach a name in /lost+found
+///
+/// The database is opened as it is, not the way a mount opens it, since that deletes
+/// nameless inodes as the leftovers of files unlinked while open, which is exactly what
+/// there may be to recover.
+fn recover_main(args: &ArgMatches) -> Fallible<()> {
+    let sqlite_path = value_t!(args, "sqlite_path", String)?;
+    let mut conn = open_to_recover(&sqlite_path)?;
+    let dry_run = args.is_present("dry_run");
+    let txn = conn.transaction()?;
+    let recovered = fsck::recover_lost(&txn)?;
+    for r in &recovered {
+        let kind = match r.kind {
+            3 => "directory",
+            4 => "file",
+            5 => "symlink",
+            _ => "special file"
+        };
+        println!("inode {}: {} {} /{}/{}",
+            r.inode, kind, if r.moved { "cut off in a loop, moved to" } else { "with no name, linked as" },
+            fsck::LOST_AND_FOUND, r.name);
+    }
+    let verb = if dry_run { "Would recover" } else { "Recovered" };
+    if dry_run {
+        txn.rollback()?;
+    } else {
+        txn.commit()?;
+    }
+    println!("{} {} inodes", verb, recovered.len());
+    Ok(())
+}
+
+/// Open a database as it is for recover, with only the foreign keys switched on
+fn open_to_recover(sqlite_path: &str) -> Fallible<sql::Connection> {
+    ensure!(Path::new(sqlite_path).exists(), "Database {} doesn't exist", sqlite_path);
+    let conn = sql::Connection::open(sqlite_path)?;
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    Ok(conn)
+}
+
 struct Elkridge {
-    conn: sql::Connection
+    conn: sql::Connection,
+    /// Where page content is kept
+    pages: Box<dyn PageStore>,
+    options: Options,
+    /// Scratch space reused by every read, see read_basic
+    read_buffer: Vec<u8>,
+    /// When this filesystem was created, used for the times of synthetic entries
+    mounted: Timespec,
+    /// The most recently generated content of a synthetic file (a table dump for
+    /// --expose-tables or a --control-dir file), by inode
+    synthetic_dump: Option<(u64, Vec<u8>)>,
+    /// Inode presented to the kernel as the root, normally ROOT_INODE unless --root-path is given
+    root: u64,
+    /// The root before --per-user-root, which switches `root` to the requester's directory in it
+    shared_root: u64,
+    /// Files currently open
+    handles: Handles,
+    /// Pages read ahead for sequential readers, see prefetch
+    page_cache: PageCache,
+    /// Attributes read by readdir, for the getattrs that follow it
+    attr_cache: AttrCache,
+    /// Where recent readdirs got to, for the next batch of each listing
+    readdir_cursors: ReaddirCursors,
+    /// Writes not stored yet, for --write-buffer
+    write_buffer: WriteBuffer,
+    /// Read-only databases under this one, topmost first, for --lower
+    layers: Vec<Layer>,
+    /// This mount's claim on the database, released at unmount; None when not mounted read-write
+    mount_lock: Option<MountLock>,
+    /// Inodes whose reclaim came while writes were frozen, see reclaim_thawed
+    unreclaimed: Vec<u64>,
+    /// Whether any directory has a quota, as of a data_version, see any_quotas
+    quotas_known: Option<(i64, bool)>,
+    /// Each uid's directory for --per-user-root, as of a data_version, see user_root
+    user_roots: (i64, HashMap<u32, u64>)
 }
 impl Elkridge {
-    fn new(conn: sql::Connection) -> Fallible<Elkridge> {
+    /// Open a database for a subcommand or a test, which never claims it like a mount does
+    fn new(conn: sql::Connection, options: Options) -> Fallible<Elkridge> {
+        Elkridge::open(conn, options, None)
+    }
+
+    /// Open the database at `sqlite_path` to mount it
+    ///
+    /// A read-write mount claims it (see MountLock) before anything writes to it, so a second
+    /// mount is refused as it was found rather than after migrating it under the first.
+    fn mount(conn: sql::Connection, options: Options, sqlite_path: &str) -> Fallible<Elkridge> {
+        Elkridge::open(conn, options, Some(sqlite_path))
+    }
+
+    fn open(mut conn: sql::Connection, options: Options, mounting: Option<&str>) -> Fallible<Elkridge> {
+        if options.trace_sql {
+            conn.profile(Some(log_statement));
+        }
+        if options.op_timeout.is_some() {
+            deadline::install(&conn)?;
+        }
+        // Before anything below writes to it, so a corrupt database is refused as it was found
+        if options.verify_on_mount {
+            verify_integrity(&conn)?;
+        }
+        // A read-only mount changes nothing, so it can share the database with anyone
+        let mount_lock = match mounting {
+            Some(sqlite_path) if !options.read_only => Some(MountLock::acquire(&conn, sqlite_path, options.force)?),
+            _ => None
+        };
+        if options.read_only {
+            check_schema(&conn)?;
+        } else {
+            Elkridge::set_up(&conn, &options, mount_lock.as_ref())?;
+        }
+        // Without this the CASCADE and RESTRICT clauses of the schema are only decoration
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        conn.execute_batch(&format!(
+            "PRAGMA cache_size = {}; PRAGMA journal_size_limit = {};",
+            options.cache_size.unwrap_or(options::DEFAULT_CACHE_SIZE),
+            options.journal_size_limit.unwrap_or(options::DEFAULT_JOURNAL_SIZE_LIMIT)))?;
+        if let Some(mode) = options.journal_mode {
+            // Like max_page_count this answers, with the mode the database ended up in
+            let now : String = conn.query_row(&format!("PRAGMA journal_mode = {}", mode.name()), NO_PARAMS, |row| row.get(0))?;
+            if !now.eq_ignore_ascii_case(mode.name()) {
+                warn!("Asked for journal mode {} but the database is in {}", mode.name(), now);
+            }
+        }
+        if let Some(mode) = options.auto_vacuum {
+            // Between full and incremental it changes at once; to or from none takes a VACUUM
+            let now : i64 = conn.query_row("PRAGMA auto_vacuum", NO_PARAMS, |row| row.get(0))?;
+            if now != mode.number() {
+                warn!("Asked for auto_vacuum {} but the database has {}; it changes once the database is vacuumed",
+                    mode.name(), now);
+            }
+        }
+        if let Some(pages) = options.wal_autocheckpoint {
+            // This one answers too, with the new setting
+            conn.query_row(&format!("PRAGMA wal_autocheckpoint = {}", pages), NO_PARAMS, |row| row.get::<usize, i64>(0))?;
+        }
+        if let Some(capacity) = options.capacity {
+            let page_size : i64 = conn.query_row("PRAGMA page_size", NO_PARAMS, |row| row.get(0))?;
+            let pages = (capacity / page_size as u64).max(1) as i64;
+            // This pragma answers with the new limit, so it can't go through execute
+            conn.query_row(&format!("PRAGMA max_page_count = {}", pages), NO_PARAMS, |row| row.get::<usize, i64>(0))?;
+        }
+        let pages = pages::open(&conn, options.blob_dir.as_deref(), !options.read_only)?;
+        if !options.read_only {
+            pages.collect(&conn)?;
+        }
+        let layers = layers::attach(&conn, &options.lowers)?;
+        let mut fs = Elkridge{
+            conn,
+            pages,
+            options,
+            read_buffer: Vec::new(),
+            mounted: time::get_time(),
+            synthetic_dump: None,
+            root: ROOT_INODE,
+            shared_root: ROOT_INODE,
+            handles: Handles::default(),
+            page_cache: PageCache::default(),
+            attr_cache: AttrCache::default(),
+            readdir_cursors: ReaddirCursors::default(),
+            write_buffer: WriteBuffer::default(),
+            layers,
+            mount_lock,
+            unreclaimed: Vec::new(),
+            quotas_known: None,
+            user_roots: (0, HashMap::new())
+        };
+        if let Some(root_path) = fs.options.root_path.clone() {
+            let (inode, kind) = fs.resolve_path(Path::new(&root_path), true)?;
+            ensure!(kind == 3, "Root path is not a directory: {}", root_path);
+            fs.root = inode;
+            fs.shared_root = inode;
+        }
+        Ok(fs)
+    }
+
+    /// Create the schema of a database opened read-write, or bring an older one's up to date
+    ///
+    /// `mount_lock` is the mount's claim on the database, if it's being mounted.
+    fn set_up(conn: &sql::Connection, options: &Options, mount_lock: Option<&MountLock>) -> Fallible<()> {
+        // Before the tables, since a new database only takes it before its first one
+        if let Some(mode) = options.auto_vacuum {
+            conn.execute_batch(&format!("PRAGMA auto_vacuum = {}", mode.name()))?;
+        }
         // Check that the connection is sane
         conn.execute_batch("
         CREATE TABLE IF NOT EXISTS Inode(
             inode   INTEGER PRIMARY KEY,
             size    INTEGER NOT NULL DEFAULT 0 CHECK ( size >= 0 ) ,
             blocks  INTEGER NOT NULL DEFAULT 0 CHECK ( blocks >= 0 ),
+                -- Times are whole seconds since the Unix epoch, which is UTC by definition, so
+                -- they never depend on TZ; strftime('%s') and st_mtime both count this way
             atime   INTEGER NOT NULL DEFAULT ( strftime('%s') ),
             mtime   INTEGER NOT NULL DEFAULT ( strftime('%s') ),
             ctime   INTEGER NOT NULL DEFAULT ( strftime('%s') ),
@@ -57,12 +917,15 @@ impl Elkridge {
                 -- 4: RegularFile, -- the default
                 -- 5: Symlink,
                 -- 6: Socket,
+                -- 7: Whiteout, -- overlayfs-style, presented as a 0/0 CharDevice
             kind    INTEGER NOT NULL DEFAULT 4, 
             perm    INTEGER NOT NULL DEFAULT 420, -- in decimal; aka rwxr--r-- aka 0644
             uid     INTEGER NOT NULL DEFAULT 0, -- root
             gid     INTEGER NOT NULL DEFAULT 0, -- root
             rdev    INTEGER NOT NULL DEFAULT 0,
-            flags   INTEGER NOT NULL DEFAULT 0
+            flags   INTEGER NOT NULL DEFAULT 0,
+                -- Number of Path rows with this inode as parent, kept up to date by triggers
+            child_count INTEGER NOT NULL DEFAULT 0 CHECK ( child_count >= 0 )
         );
         CREATE TABLE IF NOT EXISTS Path(
             inode   INTEGER NOT NULL UNIQUE REFERENCES Inode(inode) ON DELETE CASCADE ON UPDATE CASCADE,
@@ -74,51 +937,377 @@ impl Elkridge {
             inode   INTEGER NOT NULL REFERENCES Inode(inode) ON DELETE CASCADE ON UPDATE CASCADE,
             start  INTEGER NOT NULL DEFAULT 0 CHECK (start >= 0),
             finish INTEGER NOT NULL DEFAULT 0 CHECK (finish >= 0),
-            content BLOB NOT NULL
+            content BLOB NOT NULL,
+                -- The file in the blob directory holding the content instead, see pages.rs:
+                -- a chunk named by the content's hash, or in older rows a numbered blob
+            blob    INTEGER,
+            chunk   TEXT
         );
         CREATE INDEX IF NOT EXISTS Page__inode ON Page(inode);
+        CREATE TABLE IF NOT EXISTS Meta(
+            key     TEXT PRIMARY KEY,
+            value   NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS Xattr(
+            inode   INTEGER NOT NULL REFERENCES Inode(inode) ON DELETE CASCADE ON UPDATE CASCADE,
+            name    TEXT NOT NULL CHECK ( length(name) > 0 ),
+            value   BLOB NOT NULL,
+            PRIMARY KEY (inode, name)
+        );
+            -- Blob files whose pages are gone, waiting to be removed, see PageStore::collect
+        CREATE TABLE IF NOT EXISTS DeadBlob(
+            blob    INTEGER PRIMARY KEY
+        );
+            -- Chunks that pages have stopped referring to, which collect removes once none do
+        CREATE TABLE IF NOT EXISTS DeadChunk(
+            chunk   TEXT PRIMARY KEY
+        );
+            -- The name each stored uid or gid stood for where it was imported, see owners.rs
+        CREATE TABLE IF NOT EXISTS Owner(
+            kind    TEXT NOT NULL CHECK ( kind IN ('user', 'group') ),
+            id      INTEGER NOT NULL,
+            name    TEXT NOT NULL,
+            PRIMARY KEY (kind, id),
+            UNIQUE (kind, name)
+        );
+            -- The lower inode an inode was copied up from, by the layer's Meta uuid, see layers.rs
+        CREATE TABLE IF NOT EXISTS Origin(
+            inode   INTEGER NOT NULL REFERENCES Inode(inode) ON DELETE CASCADE ON UPDATE CASCADE,
+            layer   TEXT NOT NULL,
+            lower   INTEGER NOT NULL,
+            PRIMARY KEY (inode, layer)
+        );
         -- Create a root node
         INSERT OR IGNORE INTO Inode(inode, kind) VALUES (0, 3);
         -- Create a root path
         INSERT OR IGNORE INTO Path(inode, parent, name) VALUES (0, 0, '');
         ")?;
-        Ok(Elkridge{conn})
+        if let Some(lock) = mount_lock {
+            lock.record(conn)?;
+        }
+        // Databases created before child_count existed need it added and filled in
+        let has_child_count : bool = conn.query_row(
+            "SELECT count(*) > 0 FROM pragma_table_info('Inode') WHERE name = 'child_count'",
+            NO_PARAMS,
+            |row| row.get(0))?;
+        if !has_child_count {
+            conn.execute_batch("ALTER TABLE Inode ADD COLUMN child_count INTEGER NOT NULL DEFAULT 0;")?;
+            fsck::repair_child_counts(conn)?;
+        }
+        // Databases created before blob directories existed need somewhere to say so
+        let has_blob : bool = conn.query_row(
+            "SELECT count(*) > 0 FROM pragma_table_info('Page') WHERE name = 'blob'",
+            NO_PARAMS,
+            |row| row.get(0))?;
+        if !has_blob {
+            conn.execute_batch("ALTER TABLE Page ADD COLUMN blob INTEGER;")?;
+        }
+        // And before content-addressed chunks, somewhere to name them
+        let has_chunk : bool = conn.query_row(
+            "SELECT count(*) > 0 FROM pragma_table_info('Page') WHERE name = 'chunk'",
+            NO_PARAMS,
+            |row| row.get(0))?;
+        if !has_chunk {
+            conn.execute_batch("ALTER TABLE Page ADD COLUMN chunk TEXT;")?;
+        }
+        // mkdir used to leave directories with the default kind, a regular file; any that have
+        // entries in them give themselves away
+        conn.execute(
+            "UPDATE Inode SET kind = 3
+            WHERE kind != 3 AND EXISTS(SELECT 1 FROM Path WHERE Path.parent = Inode.inode AND Path.inode != Path.parent)",
+            NO_PARAMS)?;
+        // Created before anything below can delete pages, cascades included
+        conn.execute_batch("
+        CREATE TRIGGER IF NOT EXISTS Page__blob_delete AFTER DELETE ON Page
+            WHEN OLD.blob IS NOT NULL
+        BEGIN
+            INSERT OR IGNORE INTO DeadBlob(blob) VALUES (OLD.blob);
+        END;
+        CREATE TRIGGER IF NOT EXISTS Page__chunk_delete AFTER DELETE ON Page
+            WHEN OLD.chunk IS NOT NULL
+        BEGIN
+            INSERT OR IGNORE INTO DeadChunk(chunk) VALUES (OLD.chunk);
+        END;
+            -- For collect, which has to know whether any page still shares a chunk
+        CREATE INDEX IF NOT EXISTS Page__chunk ON Page(chunk) WHERE chunk IS NOT NULL;
+        ")?;
+        // Inodes kept alive only for the kernel's sake (see reclaim) outlive it no longer.
+        // Only a mount holding the lock knows no other mount still has them open, and a
+        // subcommand may be looking at a database to recover exactly these (see recover_main).
+        // The root has no Path row either (its empty name fails the CHECK), so it's spared.
+        if mount_lock.is_some() {
+            conn.execute(
+                "DELETE FROM Inode
+                WHERE inode != ?
+                    AND NOT EXISTS(SELECT 1 FROM Path WHERE Path.inode = Inode.inode)
+                    AND NOT EXISTS(SELECT 1 FROM Path WHERE Path.parent = Inode.inode)",
+                [ROOT_INODE as i64])?;
+        }
+        // Databases created before the inode counter existed may have let SQLite give out 1,
+        // which is what the kernel calls the root; that inode moves to the end first
+        let has_counter : bool = conn.query_row(
+            "SELECT count(*) > 0 FROM Meta WHERE key = 'next_inode'",
+            NO_PARAMS,
+            |row| row.get(0))?;
+        if !has_counter {
+            let moved = conn.execute(
+                "UPDATE Inode SET inode = (SELECT max(inode) + 1 FROM Inode) WHERE inode = 1",
+                NO_PARAMS)?;
+            if moved > 0 {
+                // The cascade to Path.parent runs the child_count trigger against both numbers
+                fsck::repair_child_counts(conn)?;
+            }
+            conn.execute(
+                "INSERT INTO Meta(key, value) SELECT 'next_inode', max(2, max(inode) + 1) FROM Inode",
+                NO_PARAMS)?;
+        }
+        // An identity for the database that survives moving or copying it, see database_uuid
+        conn.execute(
+            "INSERT OR IGNORE INTO Meta(key, value) VALUES ('uuid', ?)",
+            &[basic::generate_uuid()?])?;
+        conn.execute_batch("
+        -- The root is its own parent, which doesn't count as a child
+        CREATE TRIGGER IF NOT EXISTS Path__child_count_insert AFTER INSERT ON Path
+            WHEN NEW.inode != NEW.parent
+        BEGIN
+            UPDATE Inode SET child_count = child_count + 1 WHERE inode = NEW.parent;
+        END;
+        CREATE TRIGGER IF NOT EXISTS Path__child_count_delete AFTER DELETE ON Path
+            WHEN OLD.inode != OLD.parent
+        BEGIN
+            UPDATE Inode SET child_count = child_count - 1 WHERE inode = OLD.parent;
+        END;
+        CREATE TRIGGER IF NOT EXISTS Path__child_count_update AFTER UPDATE OF parent ON Path
+            WHEN OLD.parent != NEW.parent
+        BEGIN
+            UPDATE Inode SET child_count = child_count - 1 WHERE inode = OLD.parent;
+            UPDATE Inode SET child_count = child_count + 1 WHERE inode = NEW.parent;
+        END;
+        -- crtime is when the inode was born; nothing after its insert gets to change that
+        CREATE TRIGGER IF NOT EXISTS Inode__crtime_fixed BEFORE UPDATE OF crtime ON Inode
+            WHEN OLD.crtime != NEW.crtime
+        BEGIN
+            SELECT RAISE(ABORT, 'crtime is the birth time and never changes');
+        END;
+        ")?;
+        Ok(())
+    }
+
+    /// Translate an inode number from the kernel into one in the Inode table
+    ///
+    /// The kernel always calls the root FUSE_ROOT_ID, whichever directory we present there.
+    fn inode_from_kernel(&self, ino: u64) -> u64 {
+        if ino == fuse::FUSE_ROOT_ID { self.root } else { ino }
+    }
+
+    /// Translate an inode number from the Inode table into one for the kernel
+    fn inode_to_kernel(&self, ino: u64) -> u64 {
+        if ino == self.root { fuse::FUSE_ROOT_ID } else { ino }
+    }
+
+    /// How long the kernel may keep the attributes of kernel inode `ino`, or the entries in it
+    ///
+    /// Under --per-user-root the kernel's one root is a different directory for each user,
+    /// but it caches the root's attributes and names as if everyone saw the same ones. So
+    /// it keeps none of those, and asks again on every request that needs them. Everything
+    /// below is a distinct inode for each user already, and cached as usual.
+    fn ttl_for(&self, ino: u64) -> &'static Timespec {
+        if self.options.per_user_root && ino == fuse::FUSE_ROOT_ID { &NO_TTL } else { &TTL }
+    }
+
+    /// The requester's directory for --per-user-root, made for them if they haven't got one
+    ///
+    /// It's named for their uid, right in the shared root, and made like any mkdir of theirs
+    /// with mode 0700, so other users can't look in by way of the real root either. Every
+    /// request needs it, so it's only looked up again once another connection has committed,
+    /// which is the only way it can move: through the mount, users only reach their own.
+    fn user_root(&mut self, req: &basic::Caller) -> Fallible<u64> {
+        let version = self.data_version()?;
+        if self.user_roots.0 != version {
+            self.user_roots = (version, HashMap::new());
+        }
+        if let Some(&root) = self.user_roots.1.get(&req.uid()) {
+            return Ok(root);
+        }
+        let name = OsString::from(req.uid().to_string());
+        let attr = match self.lookup_basic(req, self.shared_root, &name).map_err(errors::classify) {
+            Err(ref e) if matches!(e.downcast_ref::<errors::Error>(), Some(errors::Error::NotFound)) =>
+                match self.mkdir_basic(req, self.shared_root, &name, 0o700).map_err(errors::classify) {
+                    // Another connection made it first
+                    Err(ref e) if matches!(e.downcast_ref::<errors::Error>(), Some(errors::Error::AlreadyExists)) =>
+                        self.lookup_basic(req, self.shared_root, &name)?,
+                    found => found?
+                },
+            found => found?
+        };
+        require!(attr.kind == FileType::Directory, errors::Error::NotADirectory);
+        self.user_roots.1.insert(req.uid(), attr.ino);
+        Ok(attr.ino)
+    }
+
+    /// Present the requester's own directory as the root under --per-user-root
+    fn switch_root(&mut self, req: &basic::Caller) -> Fallible<()> {
+        if self.options.per_user_root {
+            self.root = self.user_root(req)?;
+        }
+        Ok(())
+    }
+
+    /// Translate the inode number in a file attribute for the kernel
+    fn attr_to_kernel(&self, attr: FileAttr) -> FileAttr {
+        FileAttr { ino: self.inode_to_kernel(attr.ino), ..attr }
     }
 
     /// Generate a file attribute for a table
     fn generate_fileattr_from_row(&self, row: &sql::Row) -> sql::Result<FileAttr> {
+        let kind = Elkridge::filetype_from_code(row.get("kind")?);
+        // The masks only change what we report, the stored perm is left alone
+        let mask = match kind {
+            FileType::Directory => self.options.dir_mask,
+            _ => self.options.file_mask
+        };
+        let ino = row.get::<&str, i64>("inode")? as u64;
+        let mut size = row.get::<&str, i64>("size")? as u64;
+        let mut blocks = row.get::<&str, i64>("blocks")? as u64;
+        let (mut mtime, mut ctime) = (row.get("mtime")?, row.get("ctime")?);
+        // Writes still in --write-buffer count as made, just as they read back
+        if let Some((end, modified)) = self.write_buffer.extent(ino) {
+            if end as u64 > size {
+                size = end as u64;
+                blocks = size.div_ceil(512);
+            }
+            mtime = modified;
+            ctime = modified;
+        }
         Ok(FileAttr {
             // These three are fussy because technically we are straing an unsigned int as a signed int in sqlite
             // It's a no-op to convert between them and it's lossless but beware this when using the sqlite tables
             // Negative inodes, sizes, and blocks are possible for this reason, which is why we have the CHECKs in the DDL
             // Removing the CHECKs will still work with this code but may confuse you
-            ino:    row.get::<&str, i64>("inode")? as u64,
-            size:   row.get::<&str, i64>("size")? as u64,
-            blocks: row.get::<&str, i64>("blocks")? as u64,
+            ino,
+            size,
+            blocks: self.present_blocks(blocks, size),
             atime:  Timespec::new(row.get("atime")?, 0),
-            mtime:  Timespec::new(row.get("mtime")?, 0),
-            ctime:  Timespec::new(row.get("ctime")?, 0),
+            mtime:  Timespec::new(mtime, 0),
+            ctime:  Timespec::new(ctime, 0),
             crtime: Timespec::new(row.get("crtime")?, 0),
-            kind:   match row.get("kind")? {
-                // Convert codes back to enum
-                0 => FileType::NamedPipe,
-                1 => FileType::CharDevice,
-                2 => FileType::BlockDevice,
-                3 => FileType::Directory,
-                4 => FileType::RegularFile,
-                5 => FileType::Symlink,
-                6 => FileType::Socket,
-                _ => FileType::RegularFile
+            kind,
+            perm:   match mask {
+                Some(perm) => perm,
+                None => row.get("perm")?
             },
-            perm:   row.get("perm")?,
-            nlink:  row.get("nlink")?,
-            uid:    row.get("uid")?,
-            gid:    row.get("gid")?,
-            rdev:   0, // Not sure about these, for safety let's leave these alone
+            // Counted in SQL, so a directory with more than u32::MAX entries could outgrow the
+            // field; saturating keeps it a large count rather than a wrapped small one
+            nlink:  row.get::<&str, i64>("nlink")?.clamp(0, i64::from(u32::MAX)) as u32,
+            uid:    self.present_owner(row.get("uid")?),
+            gid:    self.present_owner(row.get("gid")?),
+            rdev:   row.get("rdev")?,
             flags:  0, // Not sure about these, for safety let's leave these alone
         })
     }
 
+    /// The uid or gid to report for one stored as `id`, after --owner-map
+    ///
+    /// Like the masks, this only changes what we report: the stored ids are left alone, and
+    /// a new file stores the ids it's given.
+    fn present_owner(&self, id: u32) -> u32 {
+        match self.options.owner_map.iter().find(|&&(stored, _)| stored == id) {
+            Some(&(_, shown)) => shown,
+            None => id
+        }
+    }
+
+    /// The uid or gid a chown to `id` stores, undoing --owner-map
+    ///
+    /// So a file given to an id that's shown for another comes out stored like the files
+    /// already shown that way, and the database stays in its own host's numbering.
+    fn stored_owner(&self, id: u32) -> u32 {
+        match self.options.owner_map.iter().find(|&&(_, shown)| shown == id) {
+            Some(&(stored, _)) => stored,
+            None => id
+        }
+    }
+
+    /// The 512 byte blocks to report for a file stored with `blocks`, after --alloc-size
+    ///
+    /// With it, every file takes whole allocation units, as on a filesystem with blocks that
+    /// size, so `du` adds up what the same files would take there. The size is left exact.
+    fn present_blocks(&self, blocks: u64, size: u64) -> u64 {
+        match self.options.alloc_size {
+            Some(unit) => size.div_ceil(unit) * (unit / 512),
+            None => blocks
+        }
+    }
+
+    /// Run one basic operation for a callback, turning a panic in it into Error::Panicked
+    ///
+    /// Every callback answers its request exactly once, with either its reply or an errno,
+    /// and so must a bug: unwinding out of the callback would drop the reply (which fuse
+    /// answers with EIO) and then end the whole session. A transaction the panic interrupts
+    /// is rolled back as it unwinds, but the caches may be ahead of what's committed, so
+    /// they're emptied. The operation gets --op-timeout to finish in, see deadline.
+    ///
+    /// Under --per-user-root the root is switched to the requester's first; `req` is None
+    /// only for requests that never see an inode, which can't make anyone's directory.
+    /// Then any buffered writes that have waited long enough are stored, see flush_aged_writes,
+    /// and after a freeze, inodes that came due during it are reclaimed, see reclaim_thawed.
+    fn unpanicked<T, F: FnOnce(&mut Elkridge) -> Fallible<T>>(&mut self, req: Option<&Request>, call: F) -> Fallible<T> {
+        let budget = self.options.op_timeout;
+        let run = |fs: &mut Elkridge| {
+            if let Some(req) = req {
+                fs.switch_root(&req.into())?;
+            }
+            fs.flush_aged_writes();
+            fs.reclaim_thawed();
+            call(fs)
+        };
+        match deadline::within(budget, || panic::catch_unwind(panic::AssertUnwindSafe(|| run(self)))) {
+            Ok(result) => {
+                if result.is_ok() {
+                    tracing::Span::current().record("outcome", "ok");
+                }
+                result
+            },
+            Err(payload) => {
+                self.page_cache = PageCache::default();
+                self.attr_cache.clear();
+                self.synthetic_dump = None;
+                let message = match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+                    (Some(message), _) => message.to_string(),
+                    (_, Some(message)) => message.clone(),
+                    _ => "no message".to_string()
+                };
+                raise!(errors::Error::Panicked{message})
+            }
+        }
+    }
+
+    /// Choose the errno to reply with for a failed operation, logging it under --verbose-errno
+    fn choose_errno(&self, op: &str, ino: u64, err: &failure::Error) -> c_int {
+        let errno = errno_for(err);
+        self.log_errno(op, ino, errno);
+        tracing::Span::current().record("outcome", errors::errno_name(errno));
+        errno
+    }
+
+    fn log_errno(&self, op: &str, ino: u64, errno: c_int) {
+        if self.options.verbose_errno {
+            warn!("{} on ino:{} returned {}", op, ino, errors::errno_name(errno));
+        }
+    }
+
+    /// Answer getxattr or listxattr, which ask only for the length when `size` is 0
+    fn reply_xattr(&self, op: &str, ino: u64, reply: ReplyXattr, size: u32, value: &[u8]) {
+        if size == 0 {
+            reply.size(value.len() as u32);
+        } else if value.len() > size as usize {
+            self.log_errno(op, ino, libc::ERANGE);
+            reply.error(libc::ERANGE);
+        } else {
+            reply.data(value);
+        }
+    }
+
     /// Convert a file type from its corresponding code
     /// (remember that the inverse can be done trivially using "as")
     fn filetype_from_code(code: i8) -> FileType {
@@ -131,57 +1320,341 @@ impl Elkridge {
             4 => FileType::RegularFile,
             5 => FileType::Symlink,
             6 => FileType::Socket,
+            // Whiteouts are stored distinctly but the kernel only knows them as 0/0 char devices
+            7 => FileType::CharDevice,
             _ => FileType::RegularFile
         }
     }
+
+    /// Convert the file type bits of a mode (as passed to mknod) into a kind code
+    ///
+    /// A character device numbered 0/0 is how overlayfs marks a whiteout,
+    /// so it gets its own code rather than being mixed up with real devices.
+    fn code_from_mode(mode: u32, rdev: u32) -> i8 {
+        match mode & libc::S_IFMT {
+            libc::S_IFIFO => 0,
+            libc::S_IFCHR if rdev == 0 => 7,
+            libc::S_IFCHR => 1,
+            libc::S_IFBLK => 2,
+            libc::S_IFDIR => 3,
+            libc::S_IFLNK => 5,
+            libc::S_IFSOCK => 6,
+            _ => 4
+        }
+    }
+}
+
+/// Most problems PRAGMA integrity_check lists for --verify-on-mount
+const INTEGRITY_PROBLEMS: i64 = 10;
+
+/// Refuse a database that fails PRAGMA integrity_check, for --verify-on-mount
+///
+/// Unlike quick_check, this also checks every index against its table, so it reads the whole
+/// database; that's why it's optional. Damage bad enough can stop the check partway, and
+/// then that error is the problem listed.
+fn verify_integrity(conn: &sql::Connection) -> Fallible<()> {
+    let check = || -> sql::Result<Vec<String>> {
+        conn.prepare(&format!("PRAGMA integrity_check({})", INTEGRITY_PROBLEMS))?
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect()
+    };
+    let problems = check().unwrap_or_else(|e| vec![e.to_string()]);
+    if problems.len() == 1 && problems[0] == "ok" {
+        return Ok(());
+    }
+    bail!("The database failed PRAGMA integrity_check, so it wasn't mounted. This is damage to SQLite's \
+        own structures, not the kind `elkridge fsck` repairs. Problems found (at most {}):\n{}",
+        INTEGRITY_PROBLEMS, problems.join("\n"))
+}
+
+/// Refuse to mount read-only a database that set_up would change, to leave it as it was
+///
+/// That's one made by an older elkridge, or not by elkridge at all; mounting it read-write
+/// once brings it up to date.
+fn check_schema(conn: &sql::Connection) -> Fallible<()> {
+    let tables : i64 = conn.query_row(
+        "SELECT count(*) FROM sqlite_master WHERE type = 'table'
+            AND name IN ('Inode', 'Path', 'Page', 'Meta', 'Xattr', 'DeadBlob', 'DeadChunk', 'Owner', 'Origin')",
+        NO_PARAMS,
+        |row| row.get(0))?;
+    let current = tables == 9 && conn.query_row(
+        "SELECT
+            EXISTS(SELECT 1 FROM pragma_table_info('Inode') WHERE name = 'child_count')
+            AND EXISTS(SELECT 1 FROM pragma_table_info('Page') WHERE name = 'blob')
+            AND EXISTS(SELECT 1 FROM pragma_table_info('Page') WHERE name = 'chunk')
+            AND (SELECT count(*) FROM Meta WHERE key IN ('next_inode', 'uuid')) = 2
+            AND NOT EXISTS(SELECT 1 FROM Inode WHERE kind != 3
+                AND EXISTS(SELECT 1 FROM Path WHERE Path.parent = Inode.inode AND Path.inode != Path.parent))",
+        NO_PARAMS,
+        |row| row.get(0))?;
+    ensure!(current, "The database needs setting up or upgrading, which --read-only won't do; mount it read-write once first");
+    Ok(())
+}
+
+/// Log a finished statement with how long it took, for --trace-sql
+///
+/// SQLite calls this after every statement, so it bails out before doing any formatting
+/// unless debug logging is actually enabled.
+fn log_statement(sql: &str, took: Duration) {
+    if log_enabled!(log::Level::Debug) {
+        debug!("{:8.3}ms {}",
+            took.as_secs_f64() * 1000.0,
+            sql.split_whitespace().collect::<Vec<_>>().join(" "));
+    }
 }
 
 impl Filesystem for Elkridge {
     /// Search for an inode by parent and name (e.g. using the path)
     fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        match self.lookup_basic(req, parent, name) {
-            Ok(res) => reply.entry(&TTL, &res, 0),
+        let _span = request_span!("lookup", parent, name = %name.to_string_lossy());
+        match self.unpanicked(Some(req), |fs| fs.lookup_basic(&req.into(), fs.inode_from_kernel(parent), name)) {
+            Ok(res) => {
+                self.handles.remember_lookup(res.ino);
+                reply.entry(self.ttl_for(parent), &self.attr_to_kernel(res), 0)
+            },
             Err(e) => {
+                let e = self.describe(e);
                 println!("Error: Failed to find {} {:?}.", name.to_str().unwrap_or("[Invalid name]"), e);
-                reply.error(ENOENT);
+                reply.error(self.choose_errno("lookup", parent, &e));
             }
         }
     }
 
+    /// The kernel has dropped some of its references to an inode
+    fn forget(&mut self, req: &Request, ino: u64, nlookup: u64) {
+        let _span = request_span!("forget", ino, nlookup);
+        if let Err(e) = self.unpanicked(Some(req), |fs| fs.forget_basic(&req.into(), fs.inode_from_kernel(ino), nlookup)) {
+            let e = self.describe(e);
+            println!("Error: Performing forget on ino:{} {:?}.", ino, e);
+        }
+    }
+
     /// Directly retrieve the info for an inode
     fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
-        match self.getattr_basic(req, ino) {
-            Ok(res) => reply.attr(&TTL, &res),
+        let _span = request_span!("getattr", ino);
+        match self.unpanicked(Some(req), |fs| fs.getattr_basic(&req.into(), fs.inode_from_kernel(ino))) {
+            Ok(res) => reply.attr(self.ttl_for(ino), &self.attr_to_kernel(res)),
             Err(e) => {
+                let e = self.describe(e);
                 println!("Error: Failed to find inode {} {:?}.", ino, e);
-                reply.error(ENOENT);
+                reply.error(self.choose_errno("getattr", ino, &e));
+            }
+        }
+    }
+
+    /// Check access permissions for access(2)
+    fn access(&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        let _span = request_span!("access", ino, mask);
+        match self.unpanicked(Some(req), |fs| fs.access_basic(&req.into(), fs.inode_from_kernel(ino), mask)) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing access on ino:{} {:?}.", ino, e);
+                reply.error(self.choose_errno("access", ino, &e));
+            }
+        }
+    }
+
+    /// Open a file
+    fn open(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        let _span = request_span!("open", ino, flags);
+        match self.unpanicked(Some(req), |fs| fs.open_basic(&req.into(), fs.inode_from_kernel(ino), flags)) {
+            Ok((fh, open_flags)) => reply.opened(fh, open_flags),
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing open on ino:{} {:?}.", ino, e);
+                reply.error(self.choose_errno("open", ino, &e));
             }
         }
     }
 
     /// Read some data from a page
     fn read(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
-        match self.read_basic(req, ino, fh, offset, size) {
-            Ok(buf) => reply.data(&buf),
+        let _span = request_span!("read", ino, fh, offset, size);
+        // Borrow the shared buffer so its allocation survives from one read to the next
+        let mut buf = mem::take(&mut self.read_buffer);
+        match self.unpanicked(Some(req), |fs| fs.read_basic(&req.into(), fs.inode_from_kernel(ino), fh, offset, size, &mut buf)) {
+            Ok(()) => {
+                reply.data(&buf);
+                // The reader already has its data, so reading ahead now costs it nothing
+                if let Err(e) = self.prefetch(fh) {
+                    warn!("Reading ahead on ino:{} {:?}", ino, self.describe(e));
+                }
+            },
             Err(e) => {
+                let e = self.describe(e);
                 println!("Error: Performing read on ino:{} {:?}.", ino, e);
-                reply.error(ENOENT);
+                reply.error(self.choose_errno("read", ino, &e));
+            }
+        }
+        self.read_buffer = buf;
+    }
+
+    /// Close a file
+    fn release(&mut self, req: &Request, ino: u64, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
+        let _span = request_span!("release", ino, fh);
+        match self.unpanicked(Some(req), |fs| fs.release_basic(&req.into(), fs.inode_from_kernel(ino), fh)) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing release on ino:{} {:?}.", ino, e);
+                reply.error(self.choose_errno("release", ino, &e));
+            }
+        }
+    }
+
+    /// Store a file's buffered writes as it's closed, reporting any failure to close(2)
+    fn flush(&mut self, req: &Request, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        let _span = request_span!("flush", ino, fh);
+        match self.unpanicked(Some(req), |fs| fs.flush_basic(&req.into(), fs.inode_from_kernel(ino), fh)) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing flush on ino:{} {:?}.", ino, e);
+                reply.error(self.choose_errno("flush", ino, &e));
+            }
+        }
+    }
+
+    /// Flush a file's writes to disk
+    fn fsync(&mut self, req: &Request, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let _span = request_span!("fsync", ino, fh);
+        match self.unpanicked(Some(req), |fs| fs.fsync_basic(&req.into(), fs.inode_from_kernel(ino), fh)) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing fsync on ino:{} {:?}.", ino, e);
+                reply.error(self.choose_errno("fsync", ino, &e));
+            }
+        }
+    }
+
+    /// Make a directory's entries durable
+    fn fsyncdir(&mut self, req: &Request, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let _span = request_span!("fsyncdir", ino, fh);
+        match self.unpanicked(Some(req), |fs| fs.fsyncdir_basic(&req.into(), fs.inode_from_kernel(ino), fh)) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing fsyncdir on ino:{} {:?}.", ino, e);
+                reply.error(self.choose_errno("fsyncdir", ino, &e));
+            }
+        }
+    }
+
+    /// Write some data into pages
+    fn write(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+        let _span = request_span!("write", ino, fh, offset, size = data.len());
+        match self.unpanicked(Some(req), |fs| fs.write_basic(&req.into(), fs.inode_from_kernel(ino), fh, offset, data)) {
+            Ok(written) => reply.written(written),
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing write on ino:{} {:?}.", ino, e);
+                reply.error(self.choose_errno("write", ino, &e));
+            }
+        }
+    }
+
+    /// Change attributes, which is how truncate, chmod, chown and utimens all arrive
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<Timespec>,
+        mtime: Option<Timespec>,
+        _fh: Option<u64>,
+        _crtime: Option<Timespec>,
+        _chgtime: Option<Timespec>,
+        _bkuptime: Option<Timespec>,
+        _flags: Option<u32>,
+        reply: ReplyAttr
+    ) {
+        let _span = request_span!("setattr", ino, size = ?size);
+        let changes = AttrChanges{mode, uid, gid, size, atime, mtime};
+        match self.unpanicked(Some(req), |fs| fs.setattr_basic(&req.into(), fs.inode_from_kernel(ino), changes)) {
+            Ok(attr) => reply.attr(self.ttl_for(ino), &self.attr_to_kernel(attr)),
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing setattr on ino:{} {:?}.", ino, e);
+                reply.error(self.choose_errno("setattr", ino, &e));
             }
         }
     }
 
     /// Get the list of children in a directory
     fn readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) {
-        match self.readdir_basic(req, ino, fh, offset) {
+        let _span = request_span!("readdir", ino, fh, offset);
+        match self.unpanicked(Some(req), |fs| fs.readdir_basic(&req.into(), fs.inode_from_kernel(ino), fh, offset)) {
             Ok(entries) => {
                 for entry in entries {
-                    reply.add(entry.ino, entry.offset, entry.kind, &entry.name);
+                    // Full: the kernel asks again from the last entry's offset for the rest
+                    if reply.add(self.inode_to_kernel(entry.ino), entry.offset, entry.kind, &entry.name) {
+                        break;
+                    }
                 }
                 reply.ok()
             },
             Err(e) => {
+                let e = self.describe(e);
                 println!("Error: Performing readdir on ino:{} {:?}.", ino, e);
-                reply.error(ENOENT);
+                reply.error(self.choose_errno("readdir", ino, &e));
+            }
+        }
+    }
+
+    /// Read where a symlink points
+    fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
+        let _span = request_span!("readlink", ino);
+        match self.unpanicked(Some(req), |fs| fs.readlink_basic(&req.into(), fs.inode_from_kernel(ino))) {
+            Ok(target) => reply.data(&target),
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing readlink on ino:{} {:?}.", ino, e);
+                reply.error(self.choose_errno("readlink", ino, &e));
+            }
+        }
+    }
+
+    /// Create a special file (device, FIFO, socket, or whiteout)
+    fn mknod(
+        &mut self, 
+        req: &Request, 
+        parent: u64, 
+        name: &OsStr, 
+        mode: u32, 
+        rdev: u32, 
+        reply: ReplyEntry
+    ) {
+        let _span = request_span!("mknod", parent, name = %name.to_string_lossy(), mode);
+        match self.unpanicked(Some(req), |fs| fs.mknod_basic(&req.into(), fs.inode_from_kernel(parent), name, mode, rdev)) {
+            Ok(attr) => {
+                self.handles.remember_lookup(attr.ino);
+                reply.entry(self.ttl_for(parent), &self.attr_to_kernel(attr), 0)
+            },
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing mknod on parent:{} name:{} {:?}.", parent, name.to_string_lossy(), e);
+                reply.error(self.choose_errno("mknod", parent, &e));
+            }
+        }
+    }
+
+    /// Create and open a regular file, for open(2) with O_CREAT
+    fn create(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32, reply: ReplyCreate) {
+        let _span = request_span!("create", parent, name = %name.to_string_lossy(), mode, flags);
+        match self.unpanicked(Some(req), |fs| fs.create_basic(&req.into(), fs.inode_from_kernel(parent), name, mode, flags)) {
+            Ok((attr, fh, open_flags)) => {
+                self.handles.remember_lookup(attr.ino);
+                reply.created(self.ttl_for(parent), &self.attr_to_kernel(attr), 0, fh, open_flags)
+            },
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing create on parent:{} name:{} {:?}.", parent, name.to_string_lossy(), e);
+                reply.error(self.choose_errno("create", parent, &e));
             }
         }
     }
@@ -195,11 +1668,32 @@ impl Filesystem for Elkridge {
         mode: u32, 
         reply: ReplyEntry
     ) {
-        match self.mkdir_basic(req, parent, name, mode) {
-            Ok(attr) => reply.entry(&TTL, &attr, 0),
+        let _span = request_span!("mkdir", parent, name = %name.to_string_lossy(), mode);
+        match self.unpanicked(Some(req), |fs| fs.mkdir_basic(&req.into(), fs.inode_from_kernel(parent), name, mode)) {
+            Ok(attr) => {
+                self.handles.remember_lookup(attr.ino);
+                reply.entry(self.ttl_for(parent), &self.attr_to_kernel(attr), 0)
+            },
             Err(e) => {
+                let e = self.describe(e);
                 println!("Error: Performing mkdir on parent:{} name:{} {:?}.", parent, name.to_string_lossy(), e);
-                reply.error(ENOENT);
+                reply.error(self.choose_errno("mkdir", parent, &e));
+            }
+        }
+    }
+
+    /// Create a symlink
+    fn symlink(&mut self, req: &Request, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
+        let _span = request_span!("symlink", parent, name = %name.to_string_lossy());
+        match self.unpanicked(Some(req), |fs| fs.symlink_basic(&req.into(), fs.inode_from_kernel(parent), name, link)) {
+            Ok(attr) => {
+                self.handles.remember_lookup(attr.ino);
+                reply.entry(self.ttl_for(parent), &self.attr_to_kernel(attr), 0)
+            },
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing symlink on parent:{} name:{} {:?}.", parent, name.to_string_lossy(), e);
+                reply.error(self.choose_errno("symlink", parent, &e));
             }
         }
     }
@@ -212,12 +1706,385 @@ impl Filesystem for Elkridge {
         name: &OsStr, 
         reply: ReplyEmpty
     ) {
-        match self.rmdir_basic(req, parent, name) {
+        let _span = request_span!("rmdir", parent, name = %name.to_string_lossy());
+        match self.unpanicked(Some(req), |fs| fs.rmdir_basic(&req.into(), fs.inode_from_kernel(parent), name)) {
             Ok(_) => reply.ok(),
             Err(e) => {
+                let e = self.describe(e);
                 println!("Error: Performing rmdir on parent:{} name:{} {:?}.", parent, name.to_string_lossy(), e);
-                reply.error(ENOENT);
+                reply.error(self.choose_errno("rmdir", parent, &e));
+            }
+        }
+    }
+
+    /// Remove a file
+    fn unlink(
+        &mut self, 
+        req: &Request, 
+        parent: u64, 
+        name: &OsStr, 
+        reply: ReplyEmpty
+    ) {
+        let _span = request_span!("unlink", parent, name = %name.to_string_lossy());
+        match self.unpanicked(Some(req), |fs| fs.unlink_basic(&req.into(), fs.inode_from_kernel(parent), name)) {
+            Ok(_) => reply.ok(),
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing unlink on parent:{} name:{} {:?}.", parent, name.to_string_lossy(), e);
+                reply.error(self.choose_errno("unlink", parent, &e));
             }
         }
     }
+
+    /// Refuse to make a hard link, since Path.inode is UNIQUE and so every inode has one name
+    ///
+    /// EPERM is what link(2) gives for a filesystem that doesn't support hard links, which says
+    /// more than the ENOSYS the crate would answer otherwise. With no second name there is no
+    /// link count to cap either.
+    fn link(&mut self, _req: &Request, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        let _span = request_span!("link", ino, newparent, newname = %newname.to_string_lossy());
+        let e = errors::Error::NotPermitted.into();
+        reply.error(self.choose_errno("link", ino, &e));
+    }
+
+    /// Move or rename an entry
+    fn rename(
+        &mut self, 
+        req: &Request, 
+        parent: u64, 
+        name: &OsStr, 
+        newparent: u64, 
+        newname: &OsStr, 
+        reply: ReplyEmpty
+    ) {
+        let _span = request_span!("rename", parent, name = %name.to_string_lossy(), newparent, newname = %newname.to_string_lossy());
+        // The kernel ABI spoken by this version of fuse has no RENAME2, so no flags reach us
+        // here: the kernel fails renameat2 with any flag with EINVAL before asking
+        match self.unpanicked(Some(req), |fs| fs.rename_basic(&req.into(), fs.inode_from_kernel(parent), name, fs.inode_from_kernel(newparent), newname, 0)) {
+            Ok(_) => reply.ok(),
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing rename on parent:{} name:{} {:?}.", parent, name.to_string_lossy(), e);
+                reply.error(self.choose_errno("rename", parent, &e));
+            }
+        }
+    }
+
+    /// macOS only: atomically swap two entries
+    #[cfg(target_os = "macos")]
+    fn exchange(
+        &mut self, 
+        req: &Request, 
+        parent: u64, 
+        name: &OsStr, 
+        newparent: u64, 
+        newname: &OsStr, 
+        _options: u64, 
+        reply: ReplyEmpty
+    ) {
+        let _span = request_span!("exchange", parent, name = %name.to_string_lossy(), newparent, newname = %newname.to_string_lossy());
+        match self.unpanicked(Some(req), |fs| fs.rename_basic(&req.into(), fs.inode_from_kernel(parent), name, fs.inode_from_kernel(newparent), newname, basic::RENAME_EXCHANGE)) {
+            Ok(_) => reply.ok(),
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing exchange on parent:{} name:{} {:?}.", parent, name.to_string_lossy(), e);
+                reply.error(self.choose_errno("exchange", parent, &e));
+            }
+        }
+    }
+
+    /// Report usage and block size for the whole filesystem
+    fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
+        let _span = request_span!("statfs", ino);
+        match self.unpanicked(Some(req), |fs| fs.statfs_basic(&req.into(), fs.inode_from_kernel(ino))) {
+            Ok(st) => reply.statfs(st.blocks, st.bfree, st.bavail, st.files, st.ffree, st.bsize, st.namelen, st.frsize),
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing statfs {:?}.", e);
+                reply.error(self.choose_errno("statfs", ino, &e));
+            }
+        }
+    }
+
+    /// Clean up at unmount
+    fn destroy(&mut self, req: &Request) {
+        let _span = request_span!("destroy");
+        if let Err(e) = self.unpanicked(None, |fs| fs.destroy_basic(&req.into())) {
+            let e = self.describe(e);
+            println!("Error: Performing destroy {:?}.", e);
+        }
+    }
+
+    /// Set an extended attribute
+    fn setxattr(&mut self, req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32, reply: ReplyEmpty) {
+        let _span = request_span!("setxattr", ino, name = %name.to_string_lossy(), size = value.len());
+        match self.unpanicked(Some(req), |fs| fs.setxattr_basic(&req.into(), fs.inode_from_kernel(ino), name, value, flags, position)) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing setxattr on ino:{} name:{} {:?}.", ino, name.to_string_lossy(), e);
+                reply.error(self.choose_errno("setxattr", ino, &e));
+            }
+        }
+    }
+
+    /// Get an extended attribute
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let _span = request_span!("getxattr", ino, name = %name.to_string_lossy(), size);
+        match self.unpanicked(Some(req), |fs| fs.getxattr_basic(&req.into(), fs.inode_from_kernel(ino), name)) {
+            Ok(value) => self.reply_xattr("getxattr", ino, reply, size, &value),
+            Err(e) => {
+                let e = self.describe(e);
+                // Asking for an attribute that isn't set is routine, so don't log that
+                if errno_for(&e) != libc::ENODATA {
+                    println!("Error: Performing getxattr on ino:{} name:{} {:?}.", ino, name.to_string_lossy(), e);
+                }
+                reply.error(self.choose_errno("getxattr", ino, &e));
+            }
+        }
+    }
+
+    /// List the names of the extended attributes
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        let _span = request_span!("listxattr", ino, size);
+        match self.unpanicked(Some(req), |fs| fs.listxattr_basic(&req.into(), fs.inode_from_kernel(ino))) {
+            Ok(list) => self.reply_xattr("listxattr", ino, reply, size, &list),
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing listxattr on ino:{} {:?}.", ino, e);
+                reply.error(self.choose_errno("listxattr", ino, &e));
+            }
+        }
+    }
+
+    /// Remove an extended attribute
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _span = request_span!("removexattr", ino, name = %name.to_string_lossy());
+        match self.unpanicked(Some(req), |fs| fs.removexattr_basic(&req.into(), fs.inode_from_kernel(ino), name)) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                let e = self.describe(e);
+                println!("Error: Performing removexattr on ino:{} name:{} {:?}.", ino, name.to_string_lossy(), e);
+                reply.error(self.choose_errno("removexattr", ino, &e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database file in the temporary directory, for tests a connection in memory won't do
+    ///
+    /// Removed when dropped, with its journal, WAL and lock file.
+    pub struct Scratch(PathBuf);
+
+    impl Scratch {
+        pub fn new(name: &str) -> Scratch {
+            let path = env::temp_dir().join(format!("elkridge-test-{}-{}.sqlite", process::id(), name));
+            remove_database(&path).unwrap();
+            Scratch(path)
+        }
+
+        pub fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+
+        pub fn open(&self) -> sql::Connection {
+            sql::Connection::open(&self.0).unwrap()
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = remove_database(&self.0);
+            let _ = fs::remove_file(format!("{}.lock", self.path()));
+        }
+    }
+
+    #[test]
+    fn a_refused_mount_writes_nothing() {
+        let db = Scratch::new("refused-mount");
+        let _first = MountLock::acquire(&db.open(), db.path(), false).unwrap();
+        assert!(Elkridge::mount(db.open(), Options::default(), db.path()).is_err());
+        let tables : i64 = db.open().query_row("SELECT count(*) FROM sqlite_master", NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(tables, 0);
+    }
+
+    #[test]
+    fn files_unlinked_while_open_are_reclaimed_by_the_next_mount() {
+        let db = Scratch::new("unlinked-open");
+        let root = basic::Caller::new(0, 0, 0);
+        let mut fs = Elkridge::mount(db.open(), Options::default(), db.path()).unwrap();
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        let (fh, _) = fs.open_basic(&root, file.ino, libc::O_RDWR as u32).unwrap();
+        fs.write_basic(&root, file.ino, fh, 0, b"still here").unwrap();
+        fs.unlink_basic(&root, ROOT_INODE, OsStr::new("file")).unwrap();
+        let mut buf = vec![];
+        fs.read_basic(&root, file.ino, fh, 0, 100, &mut buf).unwrap();
+        assert_eq!(buf, b"still here");
+        // Gone without a release, as when the mount crashes
+        drop(fs);
+        let exists = |fs: &Elkridge| -> bool {
+            fs.conn.query_row("SELECT count(*) > 0 FROM Inode WHERE inode = ?", [file.ino as i64], |row| row.get(0)).unwrap()
+        };
+        assert!(exists(&Elkridge::new(db.open(), Options::default()).unwrap()));
+        assert!(exists(&Elkridge::mount(db.open(), Options{read_only: true, ..Options::default()}, db.path()).unwrap()));
+        assert!(!exists(&Elkridge::mount(db.open(), Options::default(), db.path()).unwrap()));
+    }
+
+    #[test]
+    fn read_only_mounts_leave_the_database_as_it_was() {
+        let db = Scratch::new("read-only");
+        let root = basic::Caller::new(0, 0, 0);
+        let read_only = || Options{read_only: true, ..Options::default()};
+        assert!(Elkridge::mount(db.open(), read_only(), db.path()).is_err());
+        let mut fs = Elkridge::new(db.open(), Options::default()).unwrap();
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        fs.write_basic(&root, file.ino, 0, 0, b"content").unwrap();
+        drop(fs);
+        let before = fs::read(&db.0).unwrap();
+        let mut fs = Elkridge::mount(db.open(), read_only(), db.path()).unwrap();
+        let (fh, _) = fs.open_basic(&root, file.ino, libc::O_RDONLY as u32).unwrap();
+        let mut buf = vec![];
+        fs.read_basic(&root, file.ino, fh, 0, 100, &mut buf).unwrap();
+        assert_eq!(buf, b"content");
+        fs.release_basic(&root, file.ino, fh).unwrap();
+        drop(fs);
+        assert!(fs::read(&db.0).unwrap() == before);
+    }
+
+    #[test]
+    fn recover_finds_what_a_crash_left_nameless() {
+        let db = Scratch::new("recover");
+        let root = basic::Caller::new(0, 0, 0);
+        let mut fs = Elkridge::mount(db.open(), Options::default(), db.path()).unwrap();
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        let (fh, _) = fs.open_basic(&root, file.ino, libc::O_RDWR as u32).unwrap();
+        fs.write_basic(&root, file.ino, fh, 0, b"orphaned").unwrap();
+        fs.unlink_basic(&root, ROOT_INODE, OsStr::new("file")).unwrap();
+        drop(fs);
+        let recovered = fsck::recover_lost(&open_to_recover(db.path()).unwrap()).unwrap();
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered[0].inode as u64, file.ino);
+        let mut fs = Elkridge::mount(db.open(), Options::default(), db.path()).unwrap();
+        let lost = fs.lookup_basic(&root, ROOT_INODE, OsStr::new(fsck::LOST_AND_FOUND)).unwrap();
+        let found = fs.lookup_basic(&root, lost.ino, OsStr::new(&recovered[0].name)).unwrap();
+        assert_eq!(found.ino, file.ino);
+        let mut buf = vec![];
+        fs.read_basic(&root, found.ino, 0, 0, 100, &mut buf).unwrap();
+        assert_eq!(buf, b"orphaned");
+    }
+
+    #[test]
+    fn each_user_gets_their_own_root() {
+        let options = Options{allow_other: true, per_user_root: true, ..Options::default()};
+        let mut fs = Elkridge::new(sql::Connection::open_in_memory().unwrap(), options).unwrap();
+        let (alice, bob) = (basic::Caller::new(1000, 1000, 0), basic::Caller::new(1001, 1001, 0));
+        fs.switch_root(&alice).unwrap();
+        let alices = fs.root;
+        fs.mknod_basic(&alice, fs.root, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        fs.switch_root(&bob).unwrap();
+        assert_ne!(fs.root, alices);
+        assert!(fs.lookup_basic(&bob, fs.root, OsStr::new("file")).is_err());
+        let root = fs.getattr_basic(&bob, fs.root).unwrap();
+        assert_eq!((root.uid, root.perm), (1001, 0o700));
+        fs.switch_root(&alice).unwrap();
+        assert_eq!(fs.root, alices);
+        assert!(fs.lookup_basic(&alice, fs.root, OsStr::new("file")).is_ok());
+        let shared = basic::Caller::new(0, 0, 0);
+        assert_eq!(fs.lookup_basic(&shared, ROOT_INODE, OsStr::new("1000")).unwrap().ino, alices);
+    }
+
+    #[test]
+    fn allow_other_alone_shares_the_root() {
+        let options = Options{allow_other: true, ..Options::default()};
+        let mut fs = Elkridge::new(sql::Connection::open_in_memory().unwrap(), options).unwrap();
+        let alice = basic::Caller::new(1000, 1000, 0);
+        fs.switch_root(&alice).unwrap();
+        assert_eq!(fs.root, ROOT_INODE);
+        assert!(fs.readdir_basic(&alice, ROOT_INODE, 0, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn kind_codes_round_trip() {
+        let kinds = [
+            (libc::S_IFIFO, FileType::NamedPipe),
+            (libc::S_IFBLK, FileType::BlockDevice),
+            (libc::S_IFDIR, FileType::Directory),
+            (libc::S_IFREG, FileType::RegularFile),
+            (libc::S_IFLNK, FileType::Symlink),
+            (libc::S_IFSOCK, FileType::Socket),
+        ];
+        for &(format, kind) in &kinds {
+            let code = Elkridge::code_from_mode(format | 0o644, 0x0801);
+            assert_eq!(Elkridge::filetype_from_code(code), kind);
+        }
+    }
+
+    #[test]
+    fn whiteouts_are_char_devices_numbered_zero() {
+        assert_eq!(Elkridge::code_from_mode(libc::S_IFCHR, 0), 7);
+        assert_eq!(Elkridge::code_from_mode(libc::S_IFCHR | 0o600, 0x0501), 1);
+        assert_eq!(Elkridge::filetype_from_code(7), FileType::CharDevice);
+        assert_eq!(Elkridge::filetype_from_code(1), FileType::CharDevice);
+    }
+
+
+    /// Run a subcommand as the command line would
+    fn run(argv: &[&str], subcommand: fn(&ArgMatches) -> Fallible<()>) -> Fallible<()> {
+        let args = arguments().get_matches_from(["elkridge"].iter().chain(argv));
+        subcommand(args.subcommand_matches(argv[0]).unwrap())
+    }
+
+    #[test]
+    fn dry_runs_leave_the_database_byte_identical() {
+        let db = Scratch::new("dry-run");
+        let root = basic::Caller::new(0, 0, 0);
+        let mut fs = Elkridge::new(db.open(), Options::default()).unwrap();
+        let dir = fs.mkdir_basic(&root, ROOT_INODE, OsStr::new("dir"), 0o755).unwrap();
+        fs.conn.execute("UPDATE Inode SET child_count = 5 WHERE inode = ?", [dir.ino as i64]).unwrap();
+        drop(fs);
+        let source = env::temp_dir().join(format!("elkridge-test-{}-dry-run", process::id()));
+        let _ = fs::remove_dir_all(&source);
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("sub/file"), b"content").unwrap();
+        let before = fs::read(&db.0).unwrap();
+
+        run(&["import", db.path(), source.to_str().unwrap(), "--dry-run"], import_main).unwrap();
+        assert!(fs::read(&db.0).unwrap() == before);
+        run(&["import", db.path(), source.to_str().unwrap(), "--incremental", "--dry-run"], import_main).unwrap();
+        assert!(fs::read(&db.0).unwrap() == before);
+        run(&["fsck", db.path(), "--repair", "--dry-run"], fsck_main).unwrap();
+        assert!(fs::read(&db.0).unwrap() == before);
+
+        // Without --dry-run the repair does change it
+        run(&["fsck", db.path(), "--repair"], fsck_main).unwrap();
+        assert!(fs::read(&db.0).unwrap() != before);
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+
+    #[test]
+    fn page_overlap_check_catches_a_crafted_overlap() {
+        let root = basic::Caller::new(0, 0, 0);
+        let options = Options{page_overlap_check: true, ..Options::default()};
+        let mut fs = Elkridge::new(sql::Connection::open_in_memory().unwrap(), options).unwrap();
+        let file = fs.mknod_basic(&root, ROOT_INODE, OsStr::new("file"), libc::S_IFREG | 0o644, 0).unwrap();
+        fs.write_basic(&root, file.ino, 0, 0, &[1; 8192]).unwrap();
+        fs.conn.execute("INSERT INTO Page(inode, start, finish, content) VALUES (?, 100, 200, zeroblob(100))",
+            [file.ino as i64]).unwrap();
+        // A write elsewhere in the file finds it, panicking in a debug build as a test is
+        let result = fs.unpanicked(None, |fs| fs.write_basic(&root, file.ino, 0, 20000, b"more"));
+        if cfg!(debug_assertions) {
+            let message = format!("{}", result.unwrap_err());
+            assert!(message.contains(&format!("Overlapping pages in ino:{}", file.ino)), "{}", message);
+            // And the write is rolled back with the rest of its transaction
+            assert_eq!(fs.getattr_basic(&root, file.ino).unwrap().size, 8192);
+        } else {
+            result.unwrap();
+        }
+        assert_eq!(fsck::check_page_overlaps(&fs.conn, Some(file.ino)).unwrap().len(), 1);
+    }
 }
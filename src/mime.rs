@@ -0,0 +1,53 @@
+/// The computed xattr holding a regular file's detected content type
+pub const XATTR: &str = "user.mime_type";
+
+/// How much of the start of a file detection looks at (all of it fits in the first page)
+pub const SNIFF_LEN: usize = 512;
+
+/// Signatures as (offset, magic bytes, type), checked in order
+const MAGIC: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"BZh", "application/x-bzip2"),
+    (0, b"\xfd7zXZ\x00", "application/x-xz"),
+    (0, b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (0, b"\x7fELF", "application/x-executable"),
+    (0, b"SQLite format 3\x00", "application/vnd.sqlite3"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"fLaC", "audio/flac"),
+    (0, b"ID3", "audio/mpeg"),
+    (4, b"ftyp", "video/mp4"),
+    (8, b"WEBP", "image/webp"),
+    (8, b"WAVE", "audio/wav"),
+    (257, b"ustar", "application/x-tar"),
+];
+
+/// Guess a content type from the first bytes of a file, libmagic style
+///
+/// Known signatures win; otherwise anything that is valid UTF-8 without NULs is text.
+/// A prefix cut off in the middle of a character still counts as text.
+pub fn sniff(head: &[u8]) -> &'static str {
+    if head.is_empty() {
+        return "application/x-empty";
+    }
+    for &(offset, magic, mime) in MAGIC {
+        if head.len() >= offset + magic.len() && &head[offset .. offset + magic.len()] == magic {
+            return mime;
+        }
+    }
+    let text = match ::std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none() && head.len() == SNIFF_LEN
+    };
+    if text && !head.contains(&0) {
+        "text/plain"
+    } else {
+        "application/octet-stream"
+    }
+}
@@ -0,0 +1,140 @@
+use failure::Fallible;
+use libc;
+use rusqlite as sql;
+use rusqlite::{OptionalExtension, NO_PARAMS};
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::process;
+
+/// A read-write mount's claim on its database, so a second elkridge mounting it is refused
+///
+/// There are two parts. The sidecar `<db>.lock` file is flocked for as long as the mount
+/// lasts; the kernel lets go of that however the process ends, so on one host it alone says
+/// whether someone has the database mounted. flock can't be relied on across hosts (on NFS
+/// it may not work at all), so the mount also records itself in Meta as `mounted_by`, pid
+/// at hostname. A record left behind on this host by a mount that crashed is stale whenever
+/// the flock is free, and is taken over; one from another host can't be checked, so it's
+/// refused like a live one.
+pub struct MountLock {
+    /// Held open for the flock, which closing it releases
+    _file: File,
+    /// What was recorded in Meta, to remove at unmount only if it's still ours
+    holder: String
+}
+
+impl MountLock {
+    /// Claim `sqlite_path` for this process, or fail saying who has it, unless `force`
+    ///
+    /// This only takes the flock and reads Meta, which a new database doesn't have yet; the
+    /// mount is recorded there by record, once the tables exist.
+    pub fn acquire(conn: &sql::Connection, sqlite_path: &str, force: bool) -> Fallible<MountLock> {
+        let path = format!("{}.lock", sqlite_path);
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(&path)
+            .map_err(|e| format_err!("Cannot open lock file {}: {}", path, e))?;
+        let locked = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0;
+        if !locked {
+            let e = io::Error::last_os_error();
+            ensure!(e.raw_os_error() == Some(libc::EWOULDBLOCK), "Cannot lock {}: {}", path, e);
+        }
+        let host = hostname()?;
+        let holder = format!("{}@{}", process::id(), host);
+        let has_meta : bool = conn.query_row(
+            "SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'Meta'",
+            NO_PARAMS,
+            |row| row.get(0))?;
+        let recorded : Option<String> = if has_meta {
+            conn.query_row(
+                "SELECT value FROM Meta WHERE key = 'mounted_by'",
+                NO_PARAMS,
+                |row| row.get(0)).optional()?
+        } else {
+            None
+        };
+        let other = match (&recorded, locked) {
+            (_, false) => Some(recorded.clone().unwrap_or_else(|| "another process".to_string())),
+            (Some(recorded), true) => match recorded.split_once('@') {
+                Some((pid, recorded_host)) if recorded_host == host => {
+                    info!("Taking over the stale mount record of pid {}, which {}", pid,
+                        if pid.parse().is_ok_and(is_alive) { "no longer holds the lock" } else { "is gone" });
+                    None
+                },
+                _ => Some(recorded.clone())
+            },
+            (None, true) => None
+        };
+        if let Some(other) = other {
+            ensure!(force,
+                "{} is already mounted by elkridge ({}); unmount it first, or mount with --force if that mount is gone",
+                sqlite_path, other);
+            warn!("Mounting {} although {} seems to have it mounted, because of --force", sqlite_path, other);
+        }
+        Ok(MountLock{_file: file, holder})
+    }
+
+    /// Record the mount in Meta, for mounts on other hosts to find
+    pub fn record(&self, conn: &sql::Connection) -> Fallible<()> {
+        conn.execute("INSERT OR REPLACE INTO Meta(key, value) VALUES ('mounted_by', ?)", [&self.holder])?;
+        Ok(())
+    }
+
+    /// Remove the record in Meta, if no forced mount has replaced it, and let go of the flock
+    pub fn release(self, conn: &sql::Connection) -> Fallible<()> {
+        conn.execute("DELETE FROM Meta WHERE key = 'mounted_by' AND value = ?", [&self.holder])?;
+        Ok(())
+    }
+}
+
+/// Whether a process with this pid exists on this host, whoever it belongs to
+fn is_alive(pid: i32) -> bool {
+    let signalled = unsafe { libc::kill(pid, 0) } == 0;
+    signalled || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// This host's name, as gethostname(2) gives it
+fn hostname() -> Fallible<String> {
+    let mut name = [0u8; 256];
+    if unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // Truncated names aren't promised a NUL, so the last byte is kept as one
+    name[255] = 0;
+    Ok(CStr::from_bytes_until_nul(&name)?.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::Scratch;
+
+    #[test]
+    fn a_second_mount_is_refused() {
+        let db = Scratch::new("second-mount");
+        let conn = db.open();
+        let first = MountLock::acquire(&conn, db.path(), false).unwrap();
+        let err = MountLock::acquire(&conn, db.path(), false).err().unwrap();
+        assert!(err.to_string().contains("already mounted"), "{}", err);
+        assert!(MountLock::acquire(&conn, db.path(), true).is_ok());
+        drop(first);
+        assert!(MountLock::acquire(&conn, db.path(), false).is_ok());
+    }
+
+    #[test]
+    fn stale_records_on_this_host_are_taken_over() {
+        let db = Scratch::new("stale-mount");
+        let conn = db.open();
+        conn.execute_batch("CREATE TABLE Meta(key TEXT PRIMARY KEY, value NOT NULL)").unwrap();
+        // No pid is this large, so nothing holds the lock for it
+        let stale = format!("{}@{}", i32::MAX, hostname().unwrap());
+        conn.execute("INSERT INTO Meta(key, value) VALUES ('mounted_by', ?)", [&stale]).unwrap();
+        let lock = MountLock::acquire(&conn, db.path(), false).unwrap();
+        lock.record(&conn).unwrap();
+        let recorded : String = conn.query_row("SELECT value FROM Meta WHERE key = 'mounted_by'", NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(recorded, lock.holder);
+        // Somewhere else's can't be checked, so it stands
+        lock.release(&conn).unwrap();
+        conn.execute("INSERT INTO Meta(key, value) VALUES ('mounted_by', '1@elsewhere.invalid')", NO_PARAMS).unwrap();
+        assert!(MountLock::acquire(&conn, db.path(), false).is_err());
+    }
+}
@@ -0,0 +1,449 @@
+use clap::ArgMatches;
+use failure::Fallible;
+use std::path::PathBuf;
+use std::time::Duration;
+use time;
+use unicode_normalization::UnicodeNormalization;
+
+/// Settings that change how the database is presented, as opposed to what is in it
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    /// Permission reported for every non-directory, regardless of the stored perm
+    pub file_mask: Option<u16>,
+    /// Permission reported for every directory, regardless of the stored perm
+    pub dir_mask: Option<u16>,
+    /// Stored uids and gids to report as others, as (stored, reported) pairs
+    pub owner_map: Vec<(u32, u32)>,
+    /// Allocation unit in bytes, a multiple of 512, that reported blocks are rounded up to
+    pub alloc_size: Option<u64>,
+    /// Unicode normal form in which names are compared, or None to compare them byte for byte
+    pub normalize: Option<NameForm>,
+    /// Store new names in the normalize form rather than as they were given
+    pub normalize_store: bool,
+    /// Show the database's other tables as CSV files under /.tables
+    pub expose_tables: bool,
+    /// Show diagnostics as files under /.elkridge
+    pub control_dir: bool,
+    /// Bypass the kernel's page cache for every open file, as if each were opened with O_DIRECT
+    pub direct_io: bool,
+    /// Log each SQL statement and its run time at debug level
+    pub trace_sql: bool,
+    /// Directory in the database to present as the root of the mount, instead of the real root
+    pub root_path: Option<String>,
+    /// When reads update atime
+    pub atime: AtimePolicy,
+    /// What order readdir lists a directory's entries in
+    pub readdir_order: ReaddirOrder,
+    /// Most bytes one read returns, however many it asks for
+    pub max_read_size: Option<u64>,
+    /// Largest size in bytes any one file may grow to
+    pub max_file_size: Option<u64>,
+    /// Most bytes of writes to hold in memory before storing them, turning the buffer on
+    pub write_buffer: Option<u64>,
+    /// How long a buffered write waits before the next request stores it
+    pub write_buffer_delay: Option<Duration>,
+    /// How often to checkpoint the WAL in the background
+    pub checkpoint_interval: Option<Duration>,
+    /// WAL size in bytes past which a background checkpoint truncates it
+    pub wal_limit: Option<u64>,
+    /// PRAGMA wal_autocheckpoint, in pages, or None for SQLite's default of 1000
+    pub wal_autocheckpoint: Option<u32>,
+    /// How many pages to read ahead of a sequential reader, 0 for none
+    pub readahead: u32,
+    /// Largest size in bytes the database may grow to, enforced with max_page_count
+    pub capacity: Option<u64>,
+    /// SQLite's page cache, in PRAGMA cache_size's terms: pages if positive, KiB if negative
+    pub cache_size: Option<i64>,
+    /// Size in bytes the journal or WAL is cut back to after a transaction or checkpoint
+    pub journal_size_limit: Option<i64>,
+    /// Log the name of every errno replied with
+    pub verbose_errno: bool,
+    /// Trace every request as a span, written to stderr
+    pub tracing: bool,
+    /// Store each detected user.mime_type in Xattr instead of detecting it on every request
+    pub cache_mime: bool,
+    /// How long one operation may spend in SQLite, waiting for locks included, before failing
+    pub op_timeout: Option<Duration>,
+    /// Run PRAGMA integrity_check before mounting, and refuse to mount if it finds anything
+    pub verify_on_mount: bool,
+    /// Check after every write and truncate that no two of the file's pages overlap
+    pub page_overlap_check: bool,
+    /// Refuse every change, down to atime, and mount read-only in the kernel too
+    pub read_only: bool,
+    /// Mount even if another elkridge seems to have the database mounted, see mount_lock
+    pub force: bool,
+    /// When to freeze writes, in seconds since the epoch, see freeze
+    pub read_only_after: Option<i64>,
+    /// Run nothing in the background, so every operation happens in the order it arrives
+    pub single_threaded: bool,
+    /// Delete files in the background once they are this old
+    pub max_age: Option<Duration>,
+    /// Which time max_age is measured from
+    pub age_by: AgeBy,
+    /// Delete the least recently read files in the background when the database outgrows capacity
+    pub evict: bool,
+    /// How often to look for files to delete for max_age and evict
+    pub sweep_interval: Option<Duration>,
+    /// Directory to keep new page content in as files, rather than in the database
+    pub blob_dir: Option<PathBuf>,
+    /// Read-only databases to layer this one over, topmost first, see layers
+    pub lowers: Vec<PathBuf>,
+    /// How long to wait for the kernel to finish mounting before giving up
+    pub mount_timeout: Option<Duration>,
+    /// Number new inodes by their path rather than in order, see basic::stable_inode
+    pub stable_inodes: bool,
+    /// Mount with default_permissions, so the kernel checks the mode bits on every operation
+    pub enforce_permissions: bool,
+    /// Keep macOS's xattrs the way Linux can, and fold AppleDouble files into xattrs
+    pub compat_macos: bool,
+    /// Refuse to create .DS_Store files
+    pub no_ds_store: bool,
+    /// Let users other than the one mounting use the mount (FUSE's allow_other)
+    pub allow_other: bool,
+    /// Present each requester's own directory, named for their uid, as the root
+    pub per_user_root: bool,
+    /// PRAGMA journal_mode to switch the database to, or None to keep the one it has
+    pub journal_mode: Option<JournalMode>,
+    /// PRAGMA auto_vacuum to set, which a database only takes when new or after a VACUUM
+    pub auto_vacuum: Option<AutoVacuum>,
+    /// How often to give free pages back to the disk, if the database is in incremental auto_vacuum
+    pub vacuum_interval: Option<Duration>,
+}
+
+/// Page cache used unless --cache-size says otherwise: 64 MiB, against SQLite's own 2 MB,
+/// since a filesystem rereads the same directories and pages constantly
+pub const DEFAULT_CACHE_SIZE: i64 = -64 * 1024;
+
+/// Journal size limit used unless --journal-size-limit says otherwise: 64 MiB, rather than
+/// SQLite's default of keeping however much the largest transaction needed
+pub const DEFAULT_JOURNAL_SIZE_LIMIT: i64 = 64 << 20;
+
+/// When reading a file records the access in its atime, like the mount options of the same names
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AtimePolicy {
+    /// Never update atime, so reads never write to the database
+    Never,
+    /// Update atime only if it is older than mtime or ctime, or more than a day old
+    #[default]
+    Relative,
+    /// Update atime on every read
+    Strict,
+}
+
+/// What order readdir lists entries in, for --readdir-order
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ReaddirOrder {
+    /// The order the names were made in, by Path's rowid
+    #[default]
+    Natural,
+    /// By name, byte for byte
+    Name,
+    /// Least recently modified first
+    Mtime,
+    /// By inode number
+    Inode,
+}
+
+/// Which of a file's times --max-age is measured from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AgeBy {
+    /// The last read, as far as the --atime policy records reads
+    #[default]
+    Atime,
+    /// The last write
+    Mtime,
+}
+
+/// Unicode normal forms names can be compared in, for --normalize
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameForm {
+    /// Composed, as Linux and Windows programs usually write names
+    Nfc,
+    /// Decomposed, as macOS's HFS+ stored them
+    Nfd,
+}
+
+impl NameForm {
+    /// A name in this form
+    pub fn apply(self, name: &str) -> String {
+        match self {
+            NameForm::Nfc => name.nfc().collect(),
+            NameForm::Nfd => name.nfd().collect(),
+        }
+    }
+}
+
+/// What SQLite does with the pages a deletion frees, as set by PRAGMA auto_vacuum
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AutoVacuum {
+    /// Keep them in the freelist for reuse, so the file never shrinks (SQLite's default)
+    None,
+    /// Give them back at every commit, moving pages around to do it
+    Full,
+    /// Keep them in the freelist until PRAGMA incremental_vacuum gives them back
+    Incremental,
+}
+
+impl AutoVacuum {
+    /// The name PRAGMA auto_vacuum takes
+    pub fn name(self) -> &'static str {
+        match self {
+            AutoVacuum::None => "none",
+            AutoVacuum::Full => "full",
+            AutoVacuum::Incremental => "incremental",
+        }
+    }
+
+    /// The number PRAGMA auto_vacuum answers with
+    pub fn number(self) -> i64 {
+        match self {
+            AutoVacuum::None => 0,
+            AutoVacuum::Full => 1,
+            AutoVacuum::Incremental => 2,
+        }
+    }
+}
+
+/// SQLite's journal modes, as set by PRAGMA journal_mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    /// The journal is kept in memory, so a crash mid-transaction can corrupt the database
+    Memory,
+    Wal,
+    /// No journal at all, so neither rollback nor a crash mid-transaction is safe
+    Off,
+}
+
+impl JournalMode {
+    /// The name PRAGMA journal_mode takes and answers with
+    pub fn name(self) -> &'static str {
+        match self {
+            JournalMode::Delete => "delete",
+            JournalMode::Truncate => "truncate",
+            JournalMode::Persist => "persist",
+            JournalMode::Memory => "memory",
+            JournalMode::Wal => "wal",
+            JournalMode::Off => "off",
+        }
+    }
+}
+
+impl Options {
+    /// Read the options out of the parsed command line
+    pub fn from_args(args: &ArgMatches) -> Fallible<Options> {
+        Ok(Options {
+            file_mask: args.value_of("file_mask").map(parse_mode).transpose()?,
+            dir_mask: args.value_of("dir_mask").map(parse_mode).transpose()?,
+            owner_map: args.values_of("owner_map").into_iter().flatten().map(parse_owner_mapping).collect::<Fallible<_>>()?,
+            alloc_size: args.value_of("alloc_size").map(parse_alloc_size).transpose()?,
+            normalize: args.value_of("normalize").map(parse_name_form).transpose()?.flatten(),
+            normalize_store: args.is_present("normalize_store"),
+            expose_tables: args.is_present("expose_tables"),
+            control_dir: args.is_present("control_dir"),
+            direct_io: args.is_present("direct_io"),
+            trace_sql: args.is_present("trace_sql"),
+            root_path: args.value_of("root_path").map(String::from),
+            atime: args.value_of("atime").map(parse_atime).transpose()?.unwrap_or_default(),
+            readdir_order: args.value_of("readdir_order").map(parse_readdir_order).transpose()?.unwrap_or_default(),
+            max_file_size: args.value_of("max_file_size").map(parse_bytes).transpose()?,
+            write_buffer: args.value_of("write_buffer").map(parse_bytes).transpose()?,
+            write_buffer_delay: args.value_of("write_buffer_delay").map(parse_seconds).transpose()?,
+            max_read_size: args.value_of("max_read_size").map(parse_bytes).transpose()?,
+            checkpoint_interval: args.value_of("checkpoint_interval").map(parse_seconds).transpose()?,
+            wal_limit: args.value_of("wal_limit").map(parse_bytes).transpose()?,
+            wal_autocheckpoint: args.value_of("wal_autocheckpoint")
+                .map(|text| text.parse().map_err(|_| format_err!("Invalid number of pages: {}", text)))
+                .transpose()?,
+            readahead: args.value_of("readahead")
+                .map(|text| text.parse().map_err(|_| format_err!("Invalid number of pages: {}", text)))
+                .transpose()?
+                .unwrap_or(0),
+            capacity: args.value_of("capacity").map(parse_bytes).transpose()?,
+            cache_size: args.value_of("cache_size").map(parse_cache_size).transpose()?,
+            verbose_errno: args.is_present("verbose_errno"),
+            tracing: args.is_present("tracing"),
+            cache_mime: args.is_present("cache_mime"),
+            op_timeout: args.value_of("op_timeout").map(parse_seconds).transpose()?,
+            verify_on_mount: args.is_present("verify_on_mount"),
+            page_overlap_check: args.is_present("page_overlap_check"),
+            read_only: args.is_present("read_only"),
+            force: args.is_present("force"),
+            read_only_after: args.value_of("read_only_after").map(parse_time).transpose()?,
+            single_threaded: args.is_present("single_threaded"),
+            max_age: args.value_of("max_age").map(parse_seconds).transpose()?,
+            age_by: args.value_of("age_by").map(parse_age_by).transpose()?.unwrap_or_default(),
+            evict: args.is_present("evict"),
+            sweep_interval: args.value_of("sweep_interval").map(parse_seconds).transpose()?,
+            blob_dir: args.value_of_os("blob_dir").map(PathBuf::from),
+            lowers: args.values_of_os("lower").into_iter().flatten().map(PathBuf::from).collect(),
+            mount_timeout: args.value_of("mount_timeout").map(parse_seconds).transpose()?,
+            stable_inodes: args.is_present("stable_inodes"),
+            enforce_permissions: args.is_present("enforce_permissions"),
+            compat_macos: args.is_present("compat_macos"),
+            no_ds_store: args.is_present("no_ds_store"),
+            allow_other: args.is_present("allow_other"),
+            per_user_root: args.is_present("per_user_root"),
+            journal_mode: args.value_of("journal_mode").map(parse_journal_mode).transpose()?,
+            auto_vacuum: args.value_of("auto_vacuum").map(parse_auto_vacuum).transpose()?,
+            vacuum_interval: args.value_of("vacuum_interval").map(parse_seconds).transpose()?,
+            journal_size_limit: args.value_of("journal_size_limit").map(parse_bytes).transpose()?.map(|bytes| bytes as i64),
+        })
+    }
+}
+
+/// Parse an octal permission like 0444 (a leading 0 or 0o is optional)
+fn parse_mode(text: &str) -> Fallible<u16> {
+    let digits = text.trim_start_matches("0o");
+    let mode = u16::from_str_radix(digits, 8)
+        .map_err(|_| format_err!("Invalid octal mode: {}", text))?;
+    ensure!(mode <= 0o7777, "Mode out of range: {}", text);
+    Ok(mode)
+}
+
+/// Parse an --owner-map pair of ids, stored:reported
+fn parse_owner_mapping(text: &str) -> Fallible<(u32, u32)> {
+    let parsed = text.split_once(':').and_then(|(from, to)| Some((from.parse().ok()?, to.parse().ok()?)));
+    parsed.ok_or_else(|| format_err!("Invalid owner mapping (expected STORED:SHOWN, e.g. 0:1000): {}", text))
+}
+
+/// Parse a size in bytes
+fn parse_bytes(text: &str) -> Fallible<u64> {
+    text.parse().map_err(|_| format_err!("Invalid size in bytes: {}", text))
+}
+
+/// Parse an allocation unit, which has to be a whole number of 512 byte blocks
+fn parse_alloc_size(text: &str) -> Fallible<u64> {
+    match text.parse::<u64>() {
+        Ok(size) if size > 0 && size % 512 == 0 => Ok(size),
+        _ => bail!("Invalid allocation size (expected a positive multiple of 512 bytes): {}", text)
+    }
+}
+
+/// Parse a cache size, either a number of pages or a number of KiB with a K or KiB suffix
+fn parse_cache_size(text: &str) -> Fallible<i64> {
+    let (digits, kib) = match text.strip_suffix("KiB").or_else(|| text.strip_suffix('K')) {
+        Some(digits) => (digits, true),
+        None => (text, false)
+    };
+    match digits.parse::<i64>() {
+        Ok(size) if size > 0 => Ok(if kib { -size } else { size }),
+        _ => bail!("Invalid cache size (expected pages, or KiB with a K suffix): {}", text)
+    }
+}
+
+/// Parse a positive number of seconds
+fn parse_seconds(text: &str) -> Fallible<Duration> {
+    match text.parse() {
+        Ok(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+        _ => bail!("Invalid number of seconds: {}", text)
+    }
+}
+
+/// Parse a time as seconds since the epoch, or as a UTC date and time like 2024-05-01T02:00:00Z
+fn parse_time(text: &str) -> Fallible<i64> {
+    if let Ok(secs) = text.parse() {
+        return Ok(secs);
+    }
+    let utc = text.strip_suffix('Z').unwrap_or(text);
+    match time::strptime(utc, "%Y-%m-%dT%H:%M:%S") {
+        Ok(tm) => Ok(tm.to_timespec().sec),
+        Err(_) => bail!("Invalid time (expected seconds since the epoch or YYYY-MM-DDTHH:MM:SSZ): {}", text)
+    }
+}
+
+/// Parse an atime policy by its mount option name
+fn parse_atime(text: &str) -> Fallible<AtimePolicy> {
+    match text {
+        "noatime" => Ok(AtimePolicy::Never),
+        "relatime" => Ok(AtimePolicy::Relative),
+        "strictatime" => Ok(AtimePolicy::Strict),
+        _ => bail!("Invalid atime policy (expected noatime, relatime or strictatime): {}", text)
+    }
+}
+
+/// Parse a --readdir-order
+fn parse_readdir_order(text: &str) -> Fallible<ReaddirOrder> {
+    match text {
+        "natural" => Ok(ReaddirOrder::Natural),
+        "name" => Ok(ReaddirOrder::Name),
+        "mtime" => Ok(ReaddirOrder::Mtime),
+        "inode" => Ok(ReaddirOrder::Inode),
+        _ => bail!("Invalid readdir order (expected natural, name, mtime or inode): {}", text)
+    }
+}
+
+/// Parse which time --max-age is measured from
+fn parse_age_by(text: &str) -> Fallible<AgeBy> {
+    match text {
+        "atime" => Ok(AgeBy::Atime),
+        "mtime" => Ok(AgeBy::Mtime),
+        _ => bail!("Invalid time to age files by (expected atime or mtime): {}", text)
+    }
+}
+
+/// Parse a --normalize form, where none means no normalizing at all
+fn parse_name_form(text: &str) -> Fallible<Option<NameForm>> {
+    match text {
+        "none" => Ok(None),
+        "nfc" => Ok(Some(NameForm::Nfc)),
+        "nfd" => Ok(Some(NameForm::Nfd)),
+        _ => bail!("Invalid normal form (expected none, nfc or nfd): {}", text)
+    }
+}
+
+/// Parse a journal mode by its PRAGMA journal_mode name
+fn parse_journal_mode(text: &str) -> Fallible<JournalMode> {
+    match text {
+        "delete" => Ok(JournalMode::Delete),
+        "truncate" => Ok(JournalMode::Truncate),
+        "persist" => Ok(JournalMode::Persist),
+        "memory" => Ok(JournalMode::Memory),
+        "wal" => Ok(JournalMode::Wal),
+        "off" => Ok(JournalMode::Off),
+        _ => bail!("Invalid journal mode (expected delete, truncate, persist, memory, wal or off): {}", text)
+    }
+}
+
+/// Parse an auto_vacuum mode by its PRAGMA auto_vacuum name
+fn parse_auto_vacuum(text: &str) -> Fallible<AutoVacuum> {
+    match text {
+        "none" => Ok(AutoVacuum::None),
+        "full" => Ok(AutoVacuum::Full),
+        "incremental" => Ok(AutoVacuum::Incremental),
+        _ => bail!("Invalid auto_vacuum mode (expected none, full or incremental): {}", text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_octal_modes() {
+        assert_eq!(parse_mode("0444").unwrap(), 0o444);
+        assert_eq!(parse_mode("755").unwrap(), 0o755);
+        assert_eq!(parse_mode("0o2775").unwrap(), 0o2775);
+        assert_eq!(parse_mode("7777").unwrap(), 0o7777);
+    }
+
+    #[test]
+    fn rejects_bad_modes() {
+        assert!(parse_mode("").is_err());
+        assert!(parse_mode("0999").is_err());
+        assert!(parse_mode("rw-r--r--").is_err());
+        assert!(parse_mode("17777").is_err());
+    }
+
+
+    #[test]
+    fn allocation_units_are_whole_blocks() {
+        assert_eq!(parse_alloc_size("4096").unwrap(), 4096);
+        assert_eq!(parse_alloc_size("512").unwrap(), 512);
+        assert!(parse_alloc_size("0").is_err());
+        assert!(parse_alloc_size("1000").is_err());
+        assert!(parse_alloc_size("4K").is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use failure::Fallible;
+use libc::{self, c_char, c_int};
+use rusqlite as sql;
+use rusqlite::OptionalExtension;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::mem;
+use std::ptr;
+
+/// How ownership crosses between the host and the database on import and export
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdMapping {
+    /// Store and restore uid and gid numbers as they are
+    #[default]
+    Numeric,
+    /// Carry owners across by user and group name, like rsync without --numeric-ids
+    Names
+}
+
+/// Whether an id is a user's or a group's, as stored in the Owner table's kind column
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Kind {
+    User,
+    Group
+}
+
+impl Kind {
+    fn code(self) -> &'static str {
+        match self {
+            Kind::User => "user",
+            Kind::Group => "group"
+        }
+    }
+}
+
+/// Translates host ids into database ids by name during one import
+///
+/// The database's ids stay whatever the host that first stored each name used, and the Owner
+/// table remembers which name each one stood for. An id whose name this host doesn't know, or
+/// one the database already uses for a different name, is kept numerically.
+#[derive(Default)]
+pub struct OwnerMap {
+    seen: HashMap<(Kind, u32), u32>,
+    named: HashMap<(Kind, String), u32>
+}
+
+impl OwnerMap {
+    /// The id to store in the database for this host's id
+    pub fn database_id(&mut self, txn: &sql::Transaction, kind: Kind, host_id: u32) -> Fallible<u32> {
+        if let Some(&id) = self.seen.get(&(kind, host_id)) {
+            return Ok(id);
+        }
+        let id = match host_name(kind, host_id) {
+            Some(name) => record(txn, kind, host_id, &name)?,
+            None => host_id
+        };
+        self.seen.insert((kind, host_id), id);
+        Ok(id)
+    }
+
+    /// The id to store in the database for an owner given by name, as in a tar header,
+    /// with the number that came with it used if the name is new to the database
+    pub fn database_id_named(&mut self, txn: &sql::Transaction, kind: Kind, id: u32, name: &str) -> Fallible<u32> {
+        if let Some(&id) = self.named.get(&(kind, name.to_string())) {
+            return Ok(id);
+        }
+        let id = record(txn, kind, id, name)?;
+        self.named.insert((kind, name.to_string()), id);
+        Ok(id)
+    }
+}
+
+/// The name recorded for one of the database's ids, if it was imported by name
+pub fn database_name(conn: &sql::Connection, kind: Kind, id: u32) -> sql::Result<Option<String>> {
+    conn.prepare_cached("SELECT name FROM Owner WHERE kind = ? AND id = ?")?
+        .query_row(&[&kind.code() as &dyn sql::ToSql, &id], |row| row.get(0))
+        .optional()
+}
+
+/// Find or record the database's id for a name, preferring the one it came with
+fn record(txn: &sql::Transaction, kind: Kind, id: u32, name: &str) -> Fallible<u32> {
+    let known : Option<u32> = txn.query_row(
+        "SELECT id FROM Owner WHERE kind = ? AND name = ?",
+        &[kind.code(), name],
+        |row| row.get(0)).optional()?;
+    if let Some(id) = known {
+        return Ok(id);
+    }
+    let taken : Option<String> = txn.query_row(
+        "SELECT name FROM Owner WHERE kind = ? AND id = ?",
+        &[&kind.code() as &dyn sql::ToSql, &id],
+        |row| row.get(0)).optional()?;
+    match taken {
+        Some(other) => warn!(
+            "{} {} is {} here but {} in the database, keeping the number",
+            kind.code(), id, name, other),
+        None => {
+            txn.execute(
+                "INSERT INTO Owner(kind, id, name) VALUES (?,?,?)",
+                &[&kind.code() as &dyn sql::ToSql, &id, &name])?;
+        }
+    }
+    Ok(id)
+}
+
+/// This host's name for a uid or gid, if it has one
+fn host_name(kind: Kind, id: u32) -> Option<String> {
+    let mut buf = vec![0 as c_char; 1024];
+    loop {
+        let found = unsafe {
+            match kind {
+                Kind::User => {
+                    let mut entry : libc::passwd = mem::zeroed();
+                    let mut result = ptr::null_mut();
+                    let ret = libc::getpwuid_r(id, &mut entry, buf.as_mut_ptr(), buf.len(), &mut result);
+                    lookup_result(ret, !result.is_null(), entry.pw_name)
+                },
+                Kind::Group => {
+                    let mut entry : libc::group = mem::zeroed();
+                    let mut result = ptr::null_mut();
+                    let ret = libc::getgrgid_r(id, &mut entry, buf.as_mut_ptr(), buf.len(), &mut result);
+                    lookup_result(ret, !result.is_null(), entry.gr_name)
+                }
+            }
+        };
+        match found {
+            Err(libc::ERANGE) if buf.len() < 1 << 20 => {
+                let grown = buf.len() * 2;
+                buf.resize(grown, 0);
+            },
+            Err(_) => return None,
+            Ok(name) => return name
+        }
+    }
+}
+
+/// Interpret what a get*_r call returned, copying out the name before the buffer goes away
+unsafe fn lookup_result(ret: c_int, found: bool, name: *const c_char) -> Result<Option<String>, c_int> {
+    if ret != 0 {
+        Err(ret)
+    } else if found {
+        Ok(Some(CStr::from_ptr(name).to_string_lossy().into_owned()))
+    } else {
+        Ok(None)
+    }
+}
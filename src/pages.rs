@@ -0,0 +1,311 @@
+use failure::Fallible;
+use rusqlite as sql;
+use rusqlite::{OptionalExtension, NO_PARAMS};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Where the content of each page is kept
+///
+/// The Page table always records which pages a file has and the span each covers, so holes,
+/// sizes and truncation work the same whatever the store; only where the bytes live changes.
+/// Every method takes the connection or transaction the caller is already in, so pages
+/// written as part of a larger change are committed or rolled back along with it.
+pub trait PageStore: Send {
+    /// Call `each` with the start and content of every page of `inode` overlapping `lo..hi`,
+    /// oldest first
+    ///
+    /// Elkridge never lets an inode's pages overlap, but something else writing the database
+    /// might; copying each page over the ones before it means the newest bytes win wherever
+    /// that happens.
+    fn scan(
+        &self,
+        conn: &sql::Connection,
+        inode: u64,
+        lo: i64,
+        hi: i64,
+        each: &mut dyn FnMut(i64, &[u8])
+    ) -> Fallible<()>;
+
+    /// Add a page starting at `start`, where no other page overlaps it
+    fn put(&self, conn: &sql::Connection, inode: u64, start: i64, content: &[u8]) -> Fallible<()>;
+
+    /// Delete every page of `inode` overlapping `lo..hi`
+    ///
+    /// Content kept outside the database stays until the deletion is committed, see collect.
+    fn delete(&self, conn: &sql::Connection, inode: u64, lo: i64, hi: i64) -> Fallible<()> {
+        conn.prepare_cached("DELETE FROM Page WHERE inode = ? AND start < ? AND finish > ?")?
+            .execute([inode as i64, hi, lo])?;
+        Ok(())
+    }
+
+    /// Make sure the content of every page of `inode` is on disk, for fsync
+    ///
+    /// Content in the database is as durable as SQLite's commit made it, which is the
+    /// caller's business.
+    fn sync(&self, _conn: &sql::Connection, _inode: u64) -> Fallible<()> {
+        Ok(())
+    }
+
+    /// Remove content whose pages have been deleted, once that's committed
+    ///
+    /// Deleting an inode deletes its pages by cascade, so this is how their content goes too.
+    /// Call it outside any transaction.
+    fn collect(&self, _conn: &sql::Connection) -> Fallible<()> {
+        Ok(())
+    }
+
+    /// Files of content that no page refers to, for fsck
+    ///
+    /// collect removes content as its pages go, so these are what a crash or a rolled back
+    /// transaction leaves behind. A transaction still in progress has files like that
+    /// too, so they're only safe to remove while nothing else has the database open.
+    fn orphans(&self, _conn: &sql::Connection) -> Fallible<Vec<PathBuf>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Open the store a database's pages are in
+///
+/// Once a database has been given a blob directory it remembers it in Meta, so opening it
+/// again (to mount, import or export) finds its content without being told where. Giving
+/// one where it had none before keeps existing pages where they are and puts new ones in it.
+/// `remember` false leaves Meta alone, for read-only mounts.
+pub fn open(conn: &sql::Connection, blob_dir: Option<&Path>, remember: bool) -> Fallible<Box<dyn PageStore>> {
+    let recorded : Option<String> = conn.query_row(
+        "SELECT value FROM Meta WHERE key = 'blob_dir'",
+        NO_PARAMS,
+        |row| row.get(0)).optional()?;
+    let dir = match blob_dir {
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            // Absolute, so the recorded path doesn't depend on where the next open runs from
+            let dir = dir.canonicalize()?;
+            let text = dir.to_str().ok_or_else(|| format_err!("Blob directory {} is not valid UTF-8", dir.display()))?;
+            if remember && recorded.as_deref() != Some(text) {
+                conn.execute("INSERT OR REPLACE INTO Meta(key, value) VALUES ('blob_dir', ?)", [text])?;
+            }
+            Some(dir)
+        },
+        None => recorded.map(PathBuf::from)
+    };
+    Ok(match dir {
+        Some(dir) => Box::new(BlobDir{dir, schema: "main".to_string()}),
+        None => Box::new(Sqlite{schema: "main".to_string()})
+    })
+}
+
+/// Open the store of a database attached as `schema`, for reading only
+///
+/// Only scan is meant to be used on it, like on a lower layer (see layers), since the other
+/// methods work on the main database's tables whatever the schema.
+pub fn open_attached(conn: &sql::Connection, schema: &str) -> Fallible<Box<dyn PageStore>> {
+    let recorded : Option<String> = conn.query_row(
+        &format!("SELECT value FROM {}.Meta WHERE key = 'blob_dir'", schema),
+        NO_PARAMS,
+        |row| row.get(0)).optional()?;
+    let schema = schema.to_string();
+    Ok(match recorded {
+        Some(dir) => Box::new(BlobDir{dir: PathBuf::from(dir), schema}),
+        None => Box::new(Sqlite{schema})
+    })
+}
+
+/// Pages kept in the Page table's content column, the default
+pub struct Sqlite {
+    /// Which attached database's Page table to scan, normally main
+    schema: String
+}
+
+impl PageStore for Sqlite {
+    fn scan(
+        &self,
+        conn: &sql::Connection,
+        inode: u64,
+        lo: i64,
+        hi: i64,
+        each: &mut dyn FnMut(i64, &[u8])
+    ) -> Fallible<()> {
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT start, content FROM {}.Page WHERE inode = ? AND start < ? AND finish > ? ORDER BY rowid",
+            self.schema))?;
+        let mut rows = stmt.query([inode as i64, hi, lo])?;
+        while let Some(row) = rows.next()? {
+            // Borrow the blob straight out of SQLite rather than copying it into a Vec first
+            each(row.get("start")?, row.get_raw("content").as_blob()?);
+        }
+        Ok(())
+    }
+
+    fn put(&self, conn: &sql::Connection, inode: u64, start: i64, content: &[u8]) -> Fallible<()> {
+        conn.prepare_cached("INSERT INTO Page(inode, start, finish, content) VALUES (?,?,?,?)")?
+            .execute(&[
+                &(inode as i64) as &dyn sql::ToSql,
+                &start,
+                &(start + content.len() as i64),
+                &content
+            ])?;
+        Ok(())
+    }
+}
+
+/// Pages kept as files in a directory, with only their spans in the database
+///
+/// Each page's content is a chunk file named by its SHA-256, and its Page row has empty
+/// content and the hash in `chunk`, so pages with the same content share one file. Chunks
+/// are written to a temporary name and renamed into place, so a crash never leaves a chunk
+/// with the wrong content under its hash; a chunk that already exists isn't written again.
+/// Chunks are only removed, by collect, once no page refers to them. Pages written before
+/// chunks existed have the number of a file named by a counter in `blob` instead, and rows
+/// with neither still hold their content themselves. The files aren't synced when written,
+/// only on fsync, so without one content that SQLite has made durable may not be yet after
+/// a power failure.
+pub struct BlobDir {
+    dir: PathBuf,
+    /// As for Sqlite
+    schema: String
+}
+
+impl BlobDir {
+    /// Where a blob's file is, spread over 256 subdirectories so none grows too large
+    fn path(&self, blob: i64) -> PathBuf {
+        self.dir.join(format!("{:02x}", blob & 0xff)).join(blob.to_string())
+    }
+
+    /// Where a chunk's file is, in the same subdirectories by the first byte of its hash
+    fn chunk_path(&self, chunk: &str) -> PathBuf {
+        self.dir.join(&chunk[..2]).join(chunk)
+    }
+}
+
+/// The name of a chunk: the SHA-256 of its content, in hex
+fn chunk_name(content: &[u8]) -> String {
+    Sha256::digest(content).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl PageStore for BlobDir {
+    fn scan(
+        &self,
+        conn: &sql::Connection,
+        inode: u64,
+        lo: i64,
+        hi: i64,
+        each: &mut dyn FnMut(i64, &[u8])
+    ) -> Fallible<()> {
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT start, content, blob, chunk FROM {}.Page WHERE inode = ? AND start < ? AND finish > ? ORDER BY rowid",
+            self.schema))?;
+        let mut rows = stmt.query([inode as i64, hi, lo])?;
+        while let Some(row) = rows.next()? {
+            let start = row.get("start")?;
+            match (row.get::<&str, Option<String>>("chunk")?, row.get::<&str, Option<i64>>("blob")?) {
+                (Some(chunk), _) => each(start, &fs::read(self.chunk_path(&chunk))?),
+                (None, Some(blob)) => each(start, &fs::read(self.path(blob))?),
+                (None, None) => each(start, row.get_raw("content").as_blob()?)
+            }
+        }
+        Ok(())
+    }
+
+    fn put(&self, conn: &sql::Connection, inode: u64, start: i64, content: &[u8]) -> Fallible<()> {
+        let chunk = chunk_name(content);
+        let path = self.chunk_path(&chunk);
+        if !path.exists() {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // Named for this process, so two writing the same chunk at once don't mix
+            let temporary = path.with_extension(format!("{}.tmp", process::id()));
+            fs::write(&temporary, content)?;
+            fs::rename(&temporary, &path)?;
+        }
+        conn.prepare_cached("INSERT INTO Page(inode, start, finish, content, chunk) VALUES (?,?,?,x'',?)")?
+            .execute(&[
+                &(inode as i64) as &dyn sql::ToSql,
+                &start,
+                &(start + content.len() as i64),
+                &chunk
+            ])?;
+        Ok(())
+    }
+
+    fn sync(&self, conn: &sql::Connection, inode: u64) -> Fallible<()> {
+        let files : Vec<PathBuf> = conn.prepare_cached(
+            "SELECT blob, chunk FROM Page WHERE inode = ? AND (blob IS NOT NULL OR chunk IS NOT NULL)")?
+            .query_map([inode as i64], |row| Ok((row.get::<usize, Option<i64>>(0)?, row.get::<usize, Option<String>>(1)?)))?
+            .map(|row| row.map(|row| match row {
+                (_, Some(chunk)) => self.chunk_path(&chunk),
+                (blob, None) => self.path(blob.unwrap_or_default())
+            }))
+            .collect::<sql::Result<_>>()?;
+        // The directories too, or a new file's name might not survive even if its content does
+        let mut dirs = BTreeSet::new();
+        for path in files {
+            File::open(&path)?.sync_all()?;
+            if let Some(dir) = path.parent() {
+                dirs.insert(dir.to_path_buf());
+            }
+        }
+        for dir in dirs {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn collect(&self, conn: &sql::Connection) -> Fallible<()> {
+        let dead : Vec<i64> = conn.prepare_cached("SELECT blob FROM DeadBlob")?
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<sql::Result<_>>()?;
+        for &blob in &dead {
+            // Already gone is fine, as when an earlier collect was interrupted
+            if let Err(e) = fs::remove_file(self.path(blob)) {
+                ensure!(e.kind() == io::ErrorKind::NotFound, "Cannot remove blob {}: {}", blob, e);
+            }
+            conn.prepare_cached("DELETE FROM DeadBlob WHERE blob = ?")?.execute([blob])?;
+        }
+        // A chunk can still be shared by another page, or have been written again since
+        let dead : Vec<String> = conn.prepare_cached(
+            "SELECT chunk FROM DeadChunk WHERE NOT EXISTS(SELECT 1 FROM Page WHERE Page.chunk = DeadChunk.chunk)")?
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<sql::Result<_>>()?;
+        for chunk in &dead {
+            if let Err(e) = fs::remove_file(self.chunk_path(chunk)) {
+                ensure!(e.kind() == io::ErrorKind::NotFound, "Cannot remove chunk {}: {}", chunk, e);
+            }
+            // Only this one: a chunk still shared stays listed, for when its last page goes
+            conn.prepare_cached("DELETE FROM DeadChunk WHERE chunk = ?")?.execute([chunk])?;
+        }
+        Ok(())
+    }
+
+    fn orphans(&self, conn: &sql::Connection) -> Fallible<Vec<PathBuf>> {
+        let mut orphans = Vec::new();
+        let mut blob_used = conn.prepare_cached("SELECT EXISTS(SELECT 1 FROM Page WHERE blob = ?)")?;
+        let mut chunk_used = conn.prepare_cached("SELECT EXISTS(SELECT 1 FROM Page WHERE chunk = ?)")?;
+        for shard in 0..=0xff {
+            let shard = self.dir.join(format!("{:02x}", shard));
+            if !shard.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&shard)? {
+                let path = entry?.path();
+                let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+                // Anything else is a chunk written halfway, or not ours to judge
+                let used = if let Ok(blob) = name.parse::<i64>() {
+                    blob_used.query_row([blob], |row| row.get(0))?
+                } else if name.len() == 64 && name.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                    chunk_used.query_row([&name], |row| row.get(0))?
+                } else {
+                    !name.ends_with(".tmp")
+                };
+                if !used {
+                    orphans.push(path);
+                }
+            }
+        }
+        Ok(orphans)
+    }
+}
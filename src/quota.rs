@@ -0,0 +1,125 @@
+use basic::is_within;
+use errors::Error;
+use failure::Fallible;
+use rusqlite as sql;
+use rusqlite::OptionalExtension;
+
+/// The xattr that caps how many bytes the files under a directory may add up to
+pub const XATTR: &str = "user.elkridge.quota";
+
+/// The limit a quota xattr's value gives, a number of bytes in decimal as setfattr writes it
+pub fn parse(value: &[u8]) -> Fallible<u64> {
+    let text = String::from_utf8_lossy(value);
+    // One trailing newline is allowed, as `echo 1000 > ...` style tools leave one
+    match text.strip_suffix('\n').unwrap_or(&text).parse() {
+        Ok(limit) => Ok(limit),
+        Err(_) => raise!(Error::InvalidArgument)
+    }
+}
+
+/// Every quota over `dir`, its own included, as (directory, limit), innermost first
+///
+/// Only the directory's ancestors are looked at, by the unique index on Path.inode and the
+/// primary key of Xattr, so when there are no quotas this costs a lookup per level.
+pub fn over(conn: &sql::Connection, dir: u64) -> Fallible<Vec<(u64, u64)>> {
+    let mut stmt = conn.prepare_cached(
+        "WITH RECURSIVE Up(inode, depth) AS (
+            SELECT ?1, 0
+            UNION
+            SELECT Path.parent, depth + 1 FROM Path JOIN Up USING (inode) WHERE Path.inode != Path.parent
+        )
+        SELECT Up.inode, value FROM Up JOIN Xattr ON Xattr.inode = Up.inode AND Xattr.name = ?2
+        ORDER BY depth")?;
+    let rows = stmt.query_map(
+        &[ &(dir as i64) as &dyn sql::ToSql, &XATTR ],
+        |row| Ok((row.get::<usize, i64>(0)?, row.get::<usize, Vec<u8>>(1)?)))?;
+    let mut quotas = Vec::new();
+    for row in rows {
+        let (dir, value) = row?;
+        // Checked when set, so only something writing the database directly leaves a bad
+        // one, which limits nothing rather than failing every write under it
+        if let Ok(limit) = parse(&value) {
+            quotas.push((dir as u64, limit));
+        }
+    }
+    Ok(quotas)
+}
+
+/// How many bytes the files at or under `ino` add up to, by their sizes, holes and all
+pub fn usage(conn: &sql::Connection, ino: u64) -> Fallible<u64> {
+    let used : i64 = conn.prepare_cached(
+        "WITH RECURSIVE Down(inode) AS (
+            SELECT ?
+            UNION
+            SELECT Path.inode FROM Path JOIN Down ON Path.parent = Down.inode WHERE Path.inode != Path.parent
+        )
+        SELECT coalesce(sum(size), 0) FROM Inode JOIN Down USING (inode) WHERE kind != 3")?
+        .query_row([ino as i64], |row| row.get(0))?;
+    Ok(used as u64)
+}
+
+/// Fail with EDQUOT if `growth` more bytes would take any of `quotas` past its limit
+///
+/// Each quota's usage is summed over its whole subtree then and there, which is what makes
+/// a write under a quota cost more than one elsewhere. `buffered` is how far each file with
+/// writes in the write buffer has grown past its stored size, which counts too.
+pub fn check(conn: &sql::Connection, quotas: &[(u64, u64)], growth: u64, buffered: &[(u64, u64)]) -> Fallible<()> {
+    for &(dir, limit) in quotas {
+        let mut used = usage(conn, dir)?;
+        for &(ino, bytes) in buffered {
+            if is_within(conn, ino, dir as i64)? {
+                used += bytes;
+            }
+        }
+        require!(used.saturating_add(growth) <= limit, Error::QuotaExceeded);
+    }
+    Ok(())
+}
+
+/// Fail with EDQUOT if moving `ino` out of directory `from` into `to` would take a quota
+/// past its limit, leaving out the quotas over both, which count it already
+pub fn check_move(conn: &sql::Connection, ino: u64, from: u64, to: u64, buffered: &[(u64, u64)]) -> Fallible<()> {
+    if from == to {
+        return Ok(());
+    }
+    let before = over(conn, from)?;
+    let quotas : Vec<(u64, u64)> = over(conn, to)?.into_iter().filter(|quota| !before.contains(quota)).collect();
+    if quotas.is_empty() {
+        return Ok(());
+    }
+    let mut moved = usage(conn, ino)?;
+    for &(other, bytes) in buffered {
+        if is_within(conn, other, ino as i64)? {
+            moved += bytes;
+        }
+    }
+    check(conn, &quotas, moved, buffered)
+}
+
+/// The directory a file's size counts against, or None if it has no name left
+pub fn parent_of(conn: &sql::Connection, ino: u64) -> Fallible<Option<u64>> {
+    Ok(conn.prepare_cached("SELECT parent FROM Path WHERE inode = ? AND inode != parent")?
+        .query_row([ino as i64], |row| row.get::<usize, i64>(0))
+        .optional()?
+        .map(|parent| parent as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_are_decimal_bytes() {
+        assert_eq!(parse(b"1000").unwrap(), 1000);
+        assert_eq!(parse(b"1000\n").unwrap(), 1000);
+        assert_eq!(parse(b"0").unwrap(), 0);
+    }
+
+    #[test]
+    fn anything_else_is_invalid() {
+        for value in &[&b""[..], b"-1", b"1k", b" 1000", b"1000\n\n", b"\xff"] {
+            let err = parse(value).unwrap_err();
+            assert!(matches!(err.downcast_ref(), Some(Error::InvalidArgument)), "{:?}", value);
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use PAGE_SIZE;
+
+/// Pages fetched before anyone asked for them, for files being read sequentially
+///
+/// Entries are keyed by inode and page start, which is always a multiple of PAGE_SIZE; a page
+/// with less content than that is short (a hole or the end of the file), and empty for a hole.
+/// Pages leave the cache once a read has used them, so it holds at most the readahead depth
+/// for each file being read.
+#[derive(Default)]
+pub struct PageCache {
+    pages: HashMap<(u64, i64), Vec<u8>>,
+    /// PRAGMA data_version when the pages were read, which moves on whenever another
+    /// connection commits
+    version: i64
+}
+
+impl PageCache {
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// Drop everything if the database has changed under us since it was cached
+    ///
+    /// Our own writes forget what they touch, but nothing tells us about another process
+    /// writing to the database directly, except the data_version it leaves behind.
+    pub fn revalidate(&mut self, version: i64) {
+        if version != self.version {
+            self.pages.clear();
+            self.version = version;
+        }
+    }
+
+    pub fn contains(&self, ino: u64, start: i64) -> bool {
+        self.pages.contains_key(&(ino, start))
+    }
+
+    pub fn insert(&mut self, ino: u64, start: i64, content: Vec<u8>) {
+        self.pages.insert((ino, start), content);
+    }
+
+    /// Fill `buf` with the file's contents starting at `offset`, if every page it covers is cached
+    ///
+    /// `buf` should already be zeroed, as holes are simply skipped. Pages that end within the
+    /// range are dropped afterwards, while the last one stays in case the next read continues it.
+    pub fn take(&mut self, ino: u64, offset: i64, buf: &mut [u8]) -> bool {
+        let end = offset + buf.len() as i64;
+        let first = offset - offset % PAGE_SIZE;
+        let starts = (first..end).step_by(PAGE_SIZE as usize);
+        if !starts.clone().all(|start| self.contains(ino, start)) {
+            return false;
+        }
+        for start in starts {
+            let content = &self.pages[&(ino, start)];
+            let lo = start.max(offset);
+            let hi = (start + content.len() as i64).min(end);
+            if lo < hi {
+                buf[(lo - offset) as usize .. (hi - offset) as usize]
+                    .copy_from_slice(&content[(lo - start) as usize .. (hi - start) as usize]);
+            }
+            if start + PAGE_SIZE <= end {
+                self.pages.remove(&(ino, start));
+            }
+        }
+        true
+    }
+
+    /// Drop everything cached for an inode, which must happen whenever its contents change
+    pub fn forget(&mut self, ino: u64) {
+        self.pages.retain(|&(cached, _), _| cached != ino);
+    }
+}
@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+
+/// Most entries one readdir reads; a 4 KiB reply never holds more
+pub const BATCH: usize = 128;
+
+/// Most entries kept for each directory, a few batches' worth
+const MAX_KEPT: usize = 4 * BATCH;
+
+/// Most directories whose entries are kept at once, before starting over
+const MAX_LISTINGS: usize = 256;
+
+/// Where an entry sorts under --readdir-order, as a number and a name (see readdir_order)
+pub type SortKey = (i64, String);
+
+/// The sort keys of the entries readdir handed out lately, by directory and offset
+///
+/// The kernel asks for the rest of a listing from the offset of the last entry it took, and
+/// readdir carries on after that entry's sort key. Programs like `rm -r` delete each batch
+/// of entries before asking for the next, so by then the entry may be gone from the
+/// database, and this is where its key is found instead. A listing that reaches the end
+/// forgets its directory's keys; one given up partway leaves them until there are too many.
+#[derive(Default)]
+pub struct ReaddirCursors {
+    listings: HashMap<u64, Vec<(i64, SortKey)>>
+}
+
+impl ReaddirCursors {
+    /// Keep the keys of a batch of `dir`'s entries, with the offset each was given
+    pub fn remember<I: IntoIterator<Item = (i64, SortKey)>>(&mut self, dir: u64, batch: I) {
+        if self.listings.len() >= MAX_LISTINGS && !self.listings.contains_key(&dir) {
+            self.listings.clear();
+        }
+        let kept = self.listings.entry(dir).or_default();
+        kept.extend(batch);
+        if kept.len() > MAX_KEPT {
+            kept.drain(.. kept.len() - MAX_KEPT);
+        }
+    }
+
+    /// The key of the entry of `dir` given `offset`, if it was handed out lately
+    pub fn find(&self, dir: u64, offset: i64) -> Option<SortKey> {
+        self.listings.get(&dir)?.iter().rev().find(|(kept, _)| *kept == offset).map(|(_, key)| key.clone())
+    }
+
+    /// Forget `dir`'s keys, once a listing of it has reached the end
+    pub fn finish(&mut self, dir: u64) {
+        self.listings.remove(&dir);
+    }
+}
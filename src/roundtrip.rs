@@ -0,0 +1,323 @@
+use export;
+use failure::Fallible;
+use import;
+use libc;
+use options::Options;
+use owners::IdMapping;
+use rusqlite as sql;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::thread;
+use tar::{Archive, EntryType};
+use {Elkridge, PAGE_SIZE, ROOT_INODE};
+
+/// What verify found
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Entries in the source that were compared, the source directory itself not included
+    pub entries: u64,
+    pub differences: Vec<Difference>
+}
+
+/// One way an entry came back from the round trip other than it went in
+#[derive(Debug)]
+pub struct Difference {
+    /// Where it is, relative to the source directory
+    pub path: PathBuf,
+    pub what: String
+}
+
+/// Import `source` into a new database at `sqlite_path`, export it again, and compare the
+/// archive with the source, for `elkridge verify-roundtrip`
+///
+/// The archive is streamed from a second connection on a thread of its own and compared as
+/// it comes, rather than extracted, so nothing but the database touches the disk and no
+/// root is needed to compare owners and devices. Everything a tar header can carry is
+/// compared: type, mode, owner, mtime, content, symlink targets, device numbers and hard
+/// links. Times are compared in whole seconds, all the database keeps. What the archive
+/// can't carry at all, xattrs and sockets, is reported like any other difference.
+pub fn verify(source: &Path, sqlite_path: &Path) -> Fallible<Report> {
+    let mut fs = Elkridge::new(sql::Connection::open(sqlite_path)?, Options::default())?;
+    let txn = fs.conn.transaction()?;
+    import::import_tree(&txn, source, ROOT_INODE, IdMapping::Numeric, false, false)?;
+    txn.commit()?;
+    drop(fs);
+
+    let mut expected = walk(source)?;
+    let mut report = Report{entries: expected.len() as u64, differences: vec![]};
+    // Every path of each inode with more than one, in path order, so the first is the file
+    let mut links : HashMap<(u64, u64), Vec<PathBuf>> = HashMap::new();
+    for (path, meta) in &expected {
+        if !meta.is_dir() && meta.nlink() > 1 {
+            links.entry((meta.dev(), meta.ino())).or_default().push(path.clone());
+        }
+    }
+
+    let (reader, writer) = io::pipe()?;
+    let exported = sqlite_path.to_path_buf();
+    let exporter = thread::spawn(move || -> Fallible<()> {
+        let conn = sql::Connection::open(&exported)?;
+        export::export_tar(&conn, ROOT_INODE, writer, IdMapping::Numeric, false)?;
+        Ok(())
+    });
+    let compared = compare_archive(source, reader, &mut expected, &links, &mut report.differences);
+    // Joined either way: a comparison that stopped short dropped the reader, so the export
+    // fails on its next write, and a failed export only ends the archive early
+    let exported = exporter.join();
+    compared?;
+    match exported {
+        Ok(exported) => exported?,
+        Err(_) => bail!("The export panicked")
+    }
+
+    for (path, meta) in expected {
+        let what = if meta.file_type().is_socket() {
+            "is a socket, which tar can't hold".to_string()
+        } else {
+            "is missing from the archive".to_string()
+        };
+        report.differences.push(Difference{path, what});
+    }
+    report.differences.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(report)
+}
+
+/// Compare each entry of the archive with the source entry at its path, taking it out of
+/// `expected`, so what's left there afterwards never came back
+fn compare_archive<R: Read>(
+    source: &Path,
+    archive: R,
+    expected: &mut BTreeMap<PathBuf, Metadata>,
+    links: &HashMap<(u64, u64), Vec<PathBuf>>,
+    differences: &mut Vec<Difference>
+) -> Fallible<()> {
+    let mut archive = Archive::new(archive);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let meta = match expected.remove(&path) {
+            Some(meta) => meta,
+            None => {
+                differences.push(Difference{path, what: "is in the archive but not the source".to_string()});
+                continue;
+            }
+        };
+        let mut differ = |what: String| differences.push(Difference{path: path.clone(), what});
+        let group = links.get(&(meta.dev(), meta.ino()));
+        let kind = entry.header().entry_type();
+        let wanted = match group {
+            Some(group) if group[0] != path => EntryType::Link,
+            _ => kind_of(&meta)
+        };
+        if kind != wanted {
+            match (wanted, group) {
+                (EntryType::Link, Some(group)) =>
+                    differ(format!("is a hard link to {}, but came back as a separate copy", group[0].display())),
+                _ => differ(format!("is {}, but came back as {}", describe(wanted), describe(kind)))
+            }
+        }
+        if kind == EntryType::Link {
+            let target = entry.link_name()?.map(|target| target.into_owned());
+            if !group.is_some_and(|group| target.as_ref().is_some_and(|target| group.contains(target))) {
+                differ(format!("came back as a hard link to {}, which it isn't",
+                    target.map_or("nothing".to_string(), |target| target.display().to_string())));
+            }
+            continue;
+        }
+        let header = entry.header();
+        let (mode, uid, gid, mtime) = (header.mode()? & 0o7777, header.uid()?, header.gid()?, header.mtime()?);
+        compare_field(&mut differ, "mode", format!("{:o}", meta.mode() & 0o7777), format!("{:o}", mode));
+        compare_field(&mut differ, "uid", meta.uid().to_string(), uid.to_string());
+        compare_field(&mut differ, "gid", meta.gid().to_string(), gid.to_string());
+        compare_field(&mut differ, "mtime", meta.mtime().to_string(), mtime.to_string());
+        if kind != wanted {
+            continue;
+        }
+        match kind {
+            EntryType::Regular => {
+                let size = entry.header().size()?;
+                compare_field(&mut differ, "size", meta.len().to_string(), size.to_string());
+                if let Some(offset) = first_difference(&mut File::open(source.join(&path))?, &mut entry)? {
+                    differ(format!("content differs from byte {}", offset));
+                }
+            },
+            EntryType::Symlink => {
+                let target = fs::read_link(source.join(&path))?;
+                let exported = entry.link_name()?.map(|target| target.into_owned()).unwrap_or_default();
+                compare_field(&mut differ, "target", target.display().to_string(), exported.display().to_string());
+            },
+            EntryType::Char | EntryType::Block => {
+                let rdev = meta.rdev() as libc::dev_t;
+                let device = format!("{}:{}", libc::major(rdev), libc::minor(rdev));
+                let header = entry.header();
+                let exported = format!("{}:{}", header.device_major()?.unwrap_or(0), header.device_minor()?.unwrap_or(0));
+                compare_field(&mut differ, "device", device, exported);
+            },
+            _ => {}
+        }
+        let names = xattr_names(&source.join(&path))?;
+        if !names.is_empty() {
+            differ(format!("has xattrs, which the archive doesn't carry: {}", names.join(", ")));
+        }
+    }
+    Ok(())
+}
+
+/// Report a field that changed on the way, with what it was and what it came back as
+fn compare_field<F: FnMut(String)>(differ: &mut F, field: &str, source: String, exported: String) {
+    if source != exported {
+        differ(format!("{} was {}, but came back as {}", field, source, exported));
+    }
+}
+
+/// Every entry under `source`, the way import_tree finds them, by path relative to it
+fn walk(source: &Path) -> Fallible<BTreeMap<PathBuf, Metadata>> {
+    let mut found = BTreeMap::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(source.join(&dir))? {
+            let entry = entry?;
+            let meta = entry.path().symlink_metadata()?;
+            let path = dir.join(entry.file_name());
+            if meta.is_dir() {
+                pending.push(path.clone());
+            }
+            found.insert(path, meta);
+        }
+    }
+    Ok(found)
+}
+
+/// The archive entry type a source entry should come back as, if tar has one for it
+fn kind_of(meta: &Metadata) -> EntryType {
+    let kind = meta.file_type();
+    if kind.is_dir() { EntryType::Directory }
+    else if kind.is_symlink() { EntryType::Symlink }
+    else if kind.is_fifo() { EntryType::Fifo }
+    else if kind.is_char_device() { EntryType::Char }
+    else if kind.is_block_device() { EntryType::Block }
+    else { EntryType::Regular }
+}
+
+fn describe(kind: EntryType) -> &'static str {
+    match kind {
+        EntryType::Directory => "a directory",
+        EntryType::Symlink => "a symlink",
+        EntryType::Fifo => "a FIFO",
+        EntryType::Char => "a character device",
+        EntryType::Block => "a block device",
+        EntryType::Link => "a hard link",
+        EntryType::Regular => "a regular file",
+        _ => "something else"
+    }
+}
+
+/// Where two streams first differ, one ending before the other included, or None if they don't
+fn first_difference<A: Read, B: Read>(a: &mut A, b: &mut B) -> Fallible<Option<u64>> {
+    let (mut left, mut right) = (vec![0u8; PAGE_SIZE as usize], vec![0u8; PAGE_SIZE as usize]);
+    let mut offset = 0;
+    loop {
+        let (l, r) = (import::fill(a, &mut left)?, import::fill(b, &mut right)?);
+        if let Some(at) = left[..l.min(r)].iter().zip(&right[..l.min(r)]).position(|(x, y)| x != y) {
+            return Ok(Some(offset + at as u64));
+        }
+        if l != r {
+            return Ok(Some(offset + l.min(r) as u64));
+        }
+        if l == 0 {
+            return Ok(None);
+        }
+        offset += l as u64;
+    }
+}
+
+/// The names of a file's xattrs, not following a symlink, as llistxattr(2) gives them
+fn xattr_names(path: &Path) -> Fallible<Vec<String>> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut list = vec![0u8; 4096];
+    loop {
+        let len = unsafe { libc::llistxattr(path.as_ptr(), list.as_mut_ptr() as *mut libc::c_char, list.len()) };
+        if len >= 0 {
+            list.truncate(len as usize);
+            break;
+        }
+        match io::Error::last_os_error().raw_os_error() {
+            Some(libc::ERANGE) => list.resize(list.len() * 2, 0),
+            // The source's filesystem has none to keep
+            Some(libc::ENOTSUP) => return Ok(vec![]),
+            _ => return Err(io::Error::last_os_error().into())
+        }
+    }
+    Ok(list.split(|&byte| byte == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn differ(a: &[u8], b: &[u8]) -> Option<u64> {
+        first_difference(&mut &a[..], &mut &b[..]).unwrap()
+    }
+
+    #[test]
+    fn equal_streams_have_no_difference() {
+        assert_eq!(differ(b"", b""), None);
+        let long = vec![7u8; 3 * PAGE_SIZE as usize + 5];
+        assert_eq!(differ(&long, &long), None);
+    }
+
+    #[test]
+    fn finds_the_first_differing_byte() {
+        assert_eq!(differ(b"abcdef", b"abcxex"), Some(3));
+        let mut long = vec![7u8; 3 * PAGE_SIZE as usize];
+        let other = long.clone();
+        long[PAGE_SIZE as usize + 1] = 8;
+        long[2 * PAGE_SIZE as usize] = 8;
+        assert_eq!(differ(&long, &other), Some(PAGE_SIZE as u64 + 1));
+    }
+
+    #[test]
+    fn a_shorter_stream_differs_where_it_ends() {
+        assert_eq!(differ(b"abc", b"abcdef"), Some(3));
+        assert_eq!(differ(b"abcdef", b""), Some(0));
+        let long = vec![7u8; 2 * PAGE_SIZE as usize];
+        assert_eq!(differ(&long, &long[..PAGE_SIZE as usize]), Some(PAGE_SIZE as u64));
+    }
+
+
+    #[test]
+    fn a_fixture_tree_comes_back_the_same() {
+        let source = ::std::env::temp_dir().join(format!("elkridge-test-{}-roundtrip", ::std::process::id()));
+        let _ = fs::remove_dir_all(&source);
+        let db = ::tests::Scratch::new("roundtrip");
+        fs::create_dir_all(source.join("dir/nested")).unwrap();
+        fs::write(source.join("dir/small"), b"small file").unwrap();
+        fs::write(source.join("empty"), b"").unwrap();
+        let big : Vec<u8> = (0..5 * PAGE_SIZE as u32 + 17).map(|at| (at % 251) as u8).collect();
+        fs::write(source.join("dir/nested/big"), &big).unwrap();
+        ::std::os::unix::fs::symlink("dir/small", source.join("link")).unwrap();
+        let fifo = CString::new(source.join("fifo").as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o640) }, 0);
+
+        let report = verify(&source, Path::new(db.path())).unwrap();
+        assert_eq!(report.entries, 7);
+        assert!(report.differences.is_empty(), "{:?}", report.differences);
+
+        // What the database or tar can't hold is reported rather than passed over
+        fs::hard_link(source.join("dir/small"), source.join("also small")).unwrap();
+        let _socket = ::std::os::unix::net::UnixListener::bind(source.join("socket")).unwrap();
+        let again = ::tests::Scratch::new("roundtrip-socket");
+        let report = verify(&source, Path::new(again.path())).unwrap();
+        let paths : Vec<_> = report.differences.iter().map(|difference| difference.path.clone()).collect();
+        assert_eq!(paths, vec![PathBuf::from("dir/small"), PathBuf::from("socket")]);
+        fs::remove_dir_all(&source).unwrap();
+    }
+}
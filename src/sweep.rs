@@ -0,0 +1,137 @@
+use failure::Fallible;
+use freeze;
+use handles::Pinned;
+use options::AgeBy;
+use pages;
+use rusqlite as sql;
+use rusqlite::NO_PARAMS;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often files are checked for --max-age and --evict unless --sweep-interval says otherwise
+pub const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What the sweeper deletes
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    /// Delete files whose `age_by` time is older than this
+    pub max_age: Option<Duration>,
+    pub age_by: AgeBy,
+    /// Delete the least recently read files while the database is larger than this
+    pub capacity: Option<u64>
+}
+
+/// What one sweep deleted
+#[derive(Debug, Default)]
+pub struct SweepStats {
+    pub aged: usize,
+    pub evicted: usize,
+    pub reclaimed: usize
+}
+
+/// A thread that deletes old files in the background, for databases used as a cache
+///
+/// Files are only ever unlinked, as if by `rm`: an inode the kernel still has open or looked
+/// up (see Handles::pinned) keeps its contents until the filesystem reclaims it, just as it
+/// would after unlink. Directories are never deleted, even once they are empty.
+pub struct Sweeper {
+    stop: Sender<()>,
+    thread: JoinHandle<()>
+}
+
+impl Sweeper {
+    /// Start sweeping every `interval` with a connection of its own
+    pub fn start(sqlite_path: &str, interval: Duration, policy: Policy, pinned: Pinned) -> Fallible<Sweeper> {
+        let mut conn = sql::Connection::open(sqlite_path)?;
+        conn.busy_timeout(Duration::from_secs(1))?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        let store = pages::open(&conn, None, false)?;
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            // Any message, or the other end going away, means stop
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if freeze::is_frozen() {
+                    continue;
+                }
+                match sweep(&mut conn, policy, &pinned) {
+                    Ok(stats) => debug!(
+                        "Swept {} aged and {} evicted files, reclaiming {} inodes",
+                        stats.aged, stats.evicted, stats.reclaimed),
+                    Err(e) => warn!("Sweep failed: {}", e)
+                }
+                if let Err(e) = store.collect(&conn) {
+                    warn!("Removing the blobs of swept files failed: {}", e);
+                }
+            }
+        });
+        Ok(Sweeper{stop, thread})
+    }
+
+    /// Stop the thread and wait for any sweep in progress to finish
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+}
+
+/// Unlink every file the policy rules out and delete the inodes nothing refers to any more,
+/// all in one transaction
+pub fn sweep(conn: &mut sql::Connection, policy: Policy, pinned: &Pinned) -> Fallible<SweepStats> {
+    let mut stats = SweepStats::default();
+    let txn = conn.transaction_with_behavior(sql::TransactionBehavior::Immediate)?;
+    if let Some(max_age) = policy.max_age {
+        stats.aged = txn.execute(
+            &format!(
+                "DELETE FROM Path WHERE inode IN (
+                    SELECT inode FROM Inode WHERE kind != 3 AND {} < strftime('%s') - ?)",
+                match policy.age_by {
+                    AgeBy::Atime => "atime",
+                    AgeBy::Mtime => "mtime"
+                }),
+            [max_age.as_secs() as i64])?;
+    }
+    if let Some(capacity) = policy.capacity {
+        let used : i64 = txn.query_row(
+            "SELECT (page_count - freelist_count) * page_size
+            FROM pragma_page_count, pragma_freelist_count, pragma_page_size",
+            NO_PARAMS,
+            |row| row.get(0))?;
+        if used > capacity as i64 {
+            // Evict down to nine tenths of capacity, so a full database isn't swept every time
+            let mut excess = used - (capacity - capacity / 10) as i64;
+            let mut oldest = txn.prepare(
+                "SELECT inode, size FROM Inode
+                WHERE kind != 3 AND EXISTS(SELECT 1 FROM Path WHERE Path.inode = Inode.inode)
+                ORDER BY atime, inode")?;
+            // Read them all first, since deleting from Path would disturb the query
+            let oldest : Vec<(i64, i64)> = oldest.query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<sql::Result<_>>()?;
+            for (inode, size) in oldest {
+                if excess <= 0 {
+                    break;
+                }
+                txn.execute("DELETE FROM Path WHERE inode = ?", [inode])?;
+                stats.evicted += 1;
+                // Even an empty file frees its rows, so count at least a little for it
+                excess -= size.max(1);
+            }
+        }
+    }
+    {
+        // Holding the lock keeps the kernel from picking up a reference while this decides
+        let pinned = pinned.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut orphans = txn.prepare(
+            "SELECT inode FROM Inode
+            WHERE inode != 0 AND NOT EXISTS(SELECT 1 FROM Path WHERE Path.inode = Inode.inode)")?;
+        let orphans : Vec<i64> = orphans.query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<sql::Result<_>>()?;
+        for inode in orphans {
+            if !pinned.contains(&(inode as u64)) {
+                stats.reclaimed += txn.execute("DELETE FROM Inode WHERE inode = ?", [inode])?;
+            }
+        }
+    }
+    txn.commit()?;
+    Ok(stats)
+}
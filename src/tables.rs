@@ -0,0 +1,119 @@
+use failure::Fallible;
+use fuse::{FileAttr, FileType};
+use rusqlite as sql;
+use rusqlite::types::ValueRef;
+use rusqlite::{OptionalExtension, NO_PARAMS};
+use time::Timespec;
+
+/// Name of the synthetic directory holding the table dumps, directly under the root
+pub const TABLES_DIR_NAME: &str = ".tables";
+
+/// Inode of the synthetic directory, far above anything SQLite will hand out as a rowid
+///
+/// Each table's file is this plus the table's rowid in sqlite_master, so none of them
+/// can collide with a real inode either.
+pub const TABLES_DIR_INODE: u64 = 1 << 62;
+
+/// Tables that make up the filesystem itself and so aren't worth exposing
+const ELKRIDGE_TABLES: &[&str] = &["Inode", "Path", "Page", "Xattr", "Meta", "Owner", "DeadBlob", "DeadChunk", "Origin"];
+
+/// Whether this inode belongs to the synthetic tables tree
+pub fn is_synthetic(ino: u64) -> bool {
+    ino >= TABLES_DIR_INODE
+}
+
+/// List the exposed tables as (inode, name), in name order
+pub fn list(conn: &sql::Connection) -> Fallible<Vec<(u64, String)>> {
+    let mut stmt = conn.prepare(
+        "SELECT rowid, name FROM sqlite_master
+        WHERE type = 'table' AND name NOT LIKE 'sqlite_%'
+        ORDER BY name")?;
+    let tables = stmt.query_map(
+        NO_PARAMS,
+        |row| Ok((TABLES_DIR_INODE + row.get::<&str, i64>("rowid")? as u64, row.get("name")?))
+    )?.collect::<sql::Result<Vec<(u64, String)>>>()?;
+    Ok(tables.into_iter()
+        .filter(|(_, name)| !ELKRIDGE_TABLES.contains(&name.as_str()))
+        .collect())
+}
+
+/// Find the inode of an exposed table by name
+pub fn find(conn: &sql::Connection, name: &str) -> Fallible<Option<u64>> {
+    Ok(list(conn)?.into_iter()
+        .find(|(_, table)| table == name)
+        .map(|(ino, _)| ino))
+}
+
+/// Find the name of an exposed table by inode
+pub fn name_of(conn: &sql::Connection, ino: u64) -> Fallible<String> {
+    conn.query_row(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND rowid = ?",
+        [(ino - TABLES_DIR_INODE) as i64],
+        |row| row.get(0)
+    ).optional()?
+    .filter(|name: &String| !ELKRIDGE_TABLES.contains(&name.as_str()))
+    .ok_or_else(|| sql::Error::QueryReturnedNoRows.into())
+}
+
+/// Dump a whole table as CSV, with a header row of column names
+pub fn dump_csv(conn: &sql::Connection, table: &str) -> Fallible<Vec<u8>> {
+    query_csv(conn, &format!("SELECT * FROM \"{}\"", table.replace('"', "\"\"")))
+}
+
+/// Run a query and write its rows as CSV, with a header row of column names
+///
+/// Blobs are written as hex, and NULLs as empty fields.
+pub fn query_csv(conn: &sql::Connection, query: &str) -> Fallible<Vec<u8>> {
+    let mut stmt = conn.prepare(query)?;
+    let names : Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+    let mut out = String::new();
+    push_csv_line(&mut out, names.iter().map(|name| csv_field(name)));
+    let mut rows = stmt.query(NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        push_csv_line(&mut out, (0..names.len()).map(|i| match row.get_raw(i) {
+            ValueRef::Null => String::new(),
+            ValueRef::Integer(i) => i.to_string(),
+            ValueRef::Real(f) => f.to_string(),
+            ValueRef::Text(text) => csv_field(&String::from_utf8_lossy(text)),
+            ValueRef::Blob(blob) => blob.iter().map(|b| format!("{:02x}", b)).collect()
+        }));
+    }
+    Ok(out.into_bytes())
+}
+
+/// Attributes for a synthetic directory or one of its files
+///
+/// Everything is read-only and owned by root; the times are those of the mount itself.
+pub fn attr(ino: u64, is_dir: bool, size: u64, mounted: Timespec) -> FileAttr {
+    FileAttr {
+        ino,
+        size,
+        blocks: 0,
+        atime: mounted,
+        mtime: mounted,
+        ctime: mounted,
+        crtime: mounted,
+        kind: if is_dir { FileType::Directory } else { FileType::RegularFile },
+        perm: if is_dir { 0o555 } else { 0o444 },
+        nlink: if is_dir { 2 } else { 1 },
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0
+    }
+}
+
+fn push_csv_line<I: Iterator<Item=String>>(out: &mut String, fields: I) {
+    let fields : Vec<String> = fields.collect();
+    out.push_str(&fields.join(","));
+    out.push('\n');
+}
+
+/// Quote a field if it would otherwise be ambiguous
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
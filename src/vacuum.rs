@@ -0,0 +1,61 @@
+use failure::Fallible;
+use freeze;
+use rusqlite as sql;
+use rusqlite::NO_PARAMS;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Most free pages given back at once, so the write lock it takes is never held for long
+pub const VACUUM_STEP: i64 = 1024;
+
+/// A thread that gives the database's free pages back to the disk, for --vacuum-interval
+///
+/// Under PRAGMA auto_vacuum = incremental, deleting a file only moves its pages to the
+/// freelist, where statfs already counts them as free; this is what actually shrinks the
+/// file. Like Checkpointer it has its own connection, so no FUSE request waits on it, only
+/// behind the write lock each step takes.
+pub struct Vacuumer {
+    stop: Sender<()>,
+    thread: JoinHandle<()>
+}
+
+impl Vacuumer {
+    /// Start giving back up to VACUUM_STEP free pages every `interval`
+    pub fn start(sqlite_path: &str, interval: Duration) -> Fallible<Vacuumer> {
+        let conn = sql::Connection::open(sqlite_path)?;
+        conn.busy_timeout(Duration::from_millis(100))?;
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            // Any message, or the other end going away, means stop
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if freeze::is_frozen() {
+                    continue;
+                }
+                match vacuum(&conn) {
+                    Ok((before, after)) if before != after => debug!(
+                        "Incremental vacuum gave back {} pages, leaving {} free", before - after, after),
+                    Ok(_) => {},
+                    Err(e) => warn!("Incremental vacuum failed: {}", e)
+                }
+            }
+        });
+        Ok(Vacuumer{stop, thread})
+    }
+
+    /// Stop the thread and wait for any step in progress to finish
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+}
+
+/// Give back one step's worth of free pages, returning how many were free before and after
+pub fn vacuum(conn: &sql::Connection) -> sql::Result<(i64, i64)> {
+    let freelist = || conn.query_row("PRAGMA freelist_count", NO_PARAMS, |row| row.get::<usize, i64>(0));
+    let before = freelist()?;
+    if before > 0 {
+        conn.execute_batch(&format!("PRAGMA incremental_vacuum({})", VACUUM_STEP))?;
+    }
+    Ok((before, freelist()?))
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a write waits in the buffer at most, without --write-buffer-delay
+pub const DEFAULT_WRITE_BUFFER_DELAY: Duration = Duration::from_secs(1);
+
+/// Writes already answered but not yet stored, for --write-buffer
+///
+/// Each file's writes are kept in the order they came, so where they overlap the later one
+/// wins, as it would have in the Page table; a write carrying straight on from the one before
+/// it is appended to that one, so a file written start to end is a single run. Nothing here
+/// survives the process: until the writes are stored, the database doesn't know about them.
+#[derive(Default)]
+pub struct WriteBuffer {
+    files: HashMap<u64, Dirty>,
+    /// Bytes of every file's writes together
+    bytes: usize
+}
+
+/// One file's buffered writes
+pub struct Dirty {
+    /// (offset, bytes) of each write, oldest first
+    pub writes: Vec<(i64, Vec<u8>)>,
+    /// Where the furthest write ends, so the file is at least this big
+    pub end: i64,
+    /// When the latest write came in, in seconds, for mtime and ctime
+    pub modified: i64,
+    /// When the earliest write came in, for --write-buffer-delay
+    since: Instant
+}
+
+impl WriteBuffer {
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Buffer one write, made at `now` in seconds
+    pub fn add(&mut self, ino: u64, offset: i64, data: &[u8], now: i64) {
+        let dirty = self.files.entry(ino).or_insert_with(|| Dirty{
+            writes: Vec::new(),
+            end: 0,
+            modified: now,
+            since: Instant::now()
+        });
+        match dirty.writes.last_mut() {
+            Some(&mut (start, ref mut last)) if start + last.len() as i64 == offset => last.extend_from_slice(data),
+            _ => dirty.writes.push((offset, data.to_vec()))
+        }
+        dirty.end = dirty.end.max(offset + data.len() as i64);
+        dirty.modified = now;
+        self.bytes += data.len();
+    }
+
+    /// How long the earliest write still buffered has waited
+    pub fn age(&self) -> Option<Duration> {
+        self.files.values().map(|dirty| dirty.since.elapsed()).max()
+    }
+
+    /// The end of a file's furthest buffered write and when its latest came in, if it has any
+    pub fn extent(&self, ino: u64) -> Option<(i64, i64)> {
+        self.files.get(&ino).map(|dirty| (dirty.end, dirty.modified))
+    }
+
+    /// Every file with buffered writes, with the end of its furthest one
+    pub fn ends(&self) -> impl Iterator<Item = (u64, i64)> + '_ {
+        self.files.iter().map(|(&ino, dirty)| (ino, dirty.end))
+    }
+
+    /// Copy a file's buffered bytes over `buf`, which holds what's stored from `offset` on
+    pub fn overlay(&self, ino: u64, offset: i64, buf: &mut [u8]) {
+        let end = offset + buf.len() as i64;
+        for (start, data) in self.files.get(&ino).into_iter().flat_map(|dirty| &dirty.writes) {
+            let lo = (*start).max(offset);
+            let hi = (start + data.len() as i64).min(end);
+            if lo < hi {
+                buf[(lo - offset) as usize .. (hi - offset) as usize]
+                    .copy_from_slice(&data[(lo - start) as usize .. (hi - start) as usize]);
+            }
+        }
+    }
+
+    /// Cut a file's writes off at `size`, for a truncate, which would cut them off anyway
+    pub fn truncate(&mut self, ino: u64, size: i64) {
+        if let Some(dirty) = self.files.get_mut(&ino) {
+            let before : usize = dirty.writes.iter().map(|(_, data)| data.len()).sum();
+            dirty.writes.retain(|&(start, _)| start < size);
+            for (start, data) in &mut dirty.writes {
+                data.truncate((size - *start).min(data.len() as i64) as usize);
+            }
+            dirty.end = dirty.end.min(size);
+            self.bytes -= before - dirty.writes.iter().map(|(_, data)| data.len()).sum::<usize>();
+        }
+    }
+
+    /// Take out the writes of one file, or of every file, to store them
+    pub fn take(&mut self, ino: Option<u64>) -> Vec<(u64, Dirty)> {
+        let taken : Vec<(u64, Dirty)> = match ino {
+            Some(ino) => self.files.remove(&ino).map(|dirty| (ino, dirty)).into_iter().collect(),
+            None => self.files.drain().collect()
+        };
+        self.bytes -= taken.iter().flat_map(|(_, dirty)| &dirty.writes).map(|(_, data)| data.len()).sum::<usize>();
+        taken
+    }
+
+    /// Put back writes that were taken out but couldn't be stored, to try again later
+    pub fn restore(&mut self, taken: Vec<(u64, Dirty)>) {
+        for (ino, dirty) in taken {
+            self.bytes += dirty.writes.iter().map(|(_, data)| data.len()).sum::<usize>();
+            self.files.insert(ino, dirty);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adding_runs_on_from_the_last_write() {
+        let mut buffer = WriteBuffer::default();
+        buffer.add(1, 0, b"abc", 10);
+        buffer.add(1, 3, b"def", 11);
+        buffer.add(1, 10, b"gh", 12);
+        assert_eq!(buffer.bytes(), 8);
+        assert_eq!(buffer.extent(1), Some((12, 12)));
+        assert_eq!(buffer.extent(2), None);
+        let taken = buffer.take(Some(1));
+        assert_eq!(taken[0].1.writes, [(0, b"abcdef".to_vec()), (10, b"gh".to_vec())]);
+        assert_eq!(buffer.bytes(), 0);
+    }
+
+    #[test]
+    fn later_writes_win_in_the_overlay() {
+        let mut buffer = WriteBuffer::default();
+        buffer.add(1, 2, b"aaaa", 0);
+        buffer.add(1, 4, b"bb", 0);
+        buffer.add(2, 0, b"zzzzzzzz", 0);
+        let mut buf = *b"........";
+        buffer.overlay(1, 0, &mut buf);
+        assert_eq!(&buf, b"..aabb..");
+        // Starting partway, and stopping short of the end of a write
+        let mut buf = *b"...";
+        buffer.overlay(1, 3, &mut buf);
+        assert_eq!(&buf, b"abb");
+    }
+
+    #[test]
+    fn truncating_cuts_writes_short() {
+        let mut buffer = WriteBuffer::default();
+        buffer.add(1, 0, b"abcdef", 0);
+        buffer.add(1, 10, b"ghij", 0);
+        buffer.add(2, 0, b"kl", 0);
+        buffer.truncate(1, 4);
+        assert_eq!(buffer.bytes(), 6);
+        assert_eq!(buffer.extent(1), Some((4, 0)));
+        let mut buf = *b"............";
+        buffer.overlay(1, 0, &mut buf);
+        assert_eq!(&buf, b"abcd........");
+        buffer.truncate(1, 0);
+        assert_eq!(buffer.bytes(), 2);
+        assert_eq!(buffer.extent(1), Some((0, 0)));
+    }
+
+    #[test]
+    fn restored_writes_are_counted_again() {
+        let mut buffer = WriteBuffer::default();
+        buffer.add(1, 0, b"abc", 0);
+        buffer.add(2, 0, b"de", 0);
+        let taken = buffer.take(None);
+        assert_eq!((taken.len(), buffer.bytes()), (2, 0));
+        buffer.restore(taken);
+        assert_eq!(buffer.bytes(), 5);
+        assert!(buffer.age().is_some());
+    }
+}